@@ -1,11 +1,17 @@
 use crate::tools::{Tool, ToolCall};
+use crate::wrap::StreamPrinter;
 use anyhow::Result;
 use eventsource_stream::Eventsource;
 use futures_util::StreamExt;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::io::{self, Write};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Streamed replies start after the "Rusty: " label.
+pub const REPLY_LABEL_WIDTH: usize = 7;
 
 #[derive(Debug, Clone)]
 pub struct DeepSeekClient {
@@ -13,22 +19,395 @@ pub struct DeepSeekClient {
     api_key: String,
     model: String,
     base_url: String,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    usage: Arc<Mutex<Option<Usage>>>,
+    rate_limits: Arc<Mutex<Option<RateLimits>>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub role: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub content: Option<String>,
+    pub content: Option<Content>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<ToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>,
+    /// Completion tokens the API reported for this reply; stored with the
+    /// session, never sent.
+    #[serde(skip)]
+    pub tokens: Option<u32>,
+}
+
+/// Message content: a bare string, or an array of text/image parts for vision models.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageUrl {
+    pub url: String,
+}
+
+impl Content {
+    /// Text of the message with any image parts dropped.
+    pub fn text(&self) -> String {
+        match self {
+            Content::Text(t) => t.clone(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|p| match p {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    /// Build text plus image parts; `images` are URLs or local file paths.
+    pub fn with_images(text: String, images: &[String]) -> Result<Self> {
+        if images.is_empty() {
+            return Ok(Content::Text(text));
+        }
+        let mut parts = vec![ContentPart::Text { text }];
+        for image in images {
+            parts.push(ContentPart::ImageUrl {
+                image_url: ImageUrl {
+                    url: image_url(image)?,
+                },
+            });
+        }
+        Ok(Content::Parts(parts))
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Content::Text(text.to_string())
+    }
+}
+
+/// Whether `model` takes image parts, going by its name; unknown models are
+/// assumed text-only.
+pub fn supports_vision(model: &str) -> bool {
+    let m = model.to_lowercase();
+    const VISION: &[&str] = &[
+        "gpt-4o",
+        "gpt-4.1",
+        "gpt-4-turbo",
+        "gpt-5",
+        "vision",
+        "grok-4",
+        "llama-4",
+        "llava",
+        "pixtral",
+        "gemini",
+        "claude",
+    ];
+    VISION.iter().any(|v| m.contains(v)) || ["o1", "o3", "o4"].iter().any(|o| m.starts_with(o))
+}
+
+/// Token prices in USD per million tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Pricing {
+    pub input: f64,
+    pub output: f64,
+    /// Rate for prompt tokens served from the provider's cache (default: `input`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cached_input: Option<f64>,
+}
+
+/// List prices (USD per million tokens: input, output, cached input). A model
+/// uses the longest entry its name starts with, so dated snapshots share their
+/// family's rate.
+const PRICING: &[(&str, f64, f64, Option<f64>)] = &[
+    ("deepseek-chat", 0.27, 1.10, Some(0.07)),
+    ("deepseek-reasoner", 0.55, 2.19, Some(0.14)),
+    ("gpt-4o", 2.50, 10.00, Some(1.25)),
+    ("gpt-4o-mini", 0.15, 0.60, Some(0.075)),
+    ("gpt-4.1", 2.00, 8.00, Some(0.50)),
+    ("gpt-4.1-mini", 0.40, 1.60, Some(0.10)),
+    ("gpt-4.1-nano", 0.10, 0.40, Some(0.025)),
+    ("o3-mini", 1.10, 4.40, Some(0.55)),
+    ("grok-3", 3.00, 15.00, None),
+    ("grok-3-mini", 0.30, 0.50, None),
+    ("grok-code-fast-1", 0.20, 1.50, None),
+    ("llama3-70b-8192", 0.59, 0.79, None),
+    ("llama3-8b-8192", 0.05, 0.08, None),
+];
+
+impl Pricing {
+    /// Rates for `model`: an exact `[pricing]` entry from the config, else the
+    /// built-in table; `None` when the model is in neither.
+    pub fn for_model(model: &str, overrides: &HashMap<String, Pricing>) -> Option<Pricing> {
+        if let Some(p) = overrides.get(model) {
+            return Some(*p);
+        }
+        PRICING
+            .iter()
+            .filter(|(name, ..)| model.starts_with(name))
+            .max_by_key(|(name, ..)| name.len())
+            .map(|&(_, input, output, cached_input)| Pricing {
+                input,
+                output,
+                cached_input,
+            })
+    }
+
+    /// Dollar cost of one response, with cache hits at the cached rate;
+    /// `None` if the usage lacks either count.
+    pub fn cost(&self, usage: &Usage) -> Option<f64> {
+        let prompt = usage.prompt_tokens?;
+        let cached = usage.cached_tokens().unwrap_or(0).min(prompt);
+        let output = usage.completion_tokens? as f64;
+        let input = (prompt - cached) as f64 * self.input
+            + cached as f64 * self.cached_input.unwrap_or(self.input);
+        Some((input + output * self.output) / 1_000_000.0)
+    }
+}
+
+/// Pass http(s)/data URLs through; read local files into a base64 data URL.
+fn image_url(image: &str) -> Result<String> {
+    use base64::Engine;
+    if image.starts_with("http://") || image.starts_with("https://") || image.starts_with("data:") {
+        return Ok(image.to_string());
+    }
+    let path = std::path::Path::new(image);
+    let mime = match path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => anyhow::bail!("unsupported image type: {} (png, jpeg, gif, webp)", image),
+    };
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("failed to read image {}: {}", image, e))?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(bytes);
+    Ok(format!("data:{mime};base64,{encoded}"))
+}
+
+/// Sampling settings for a conversation. Temperature is passed with each
+/// request; top_p and max_tokens ride on the client (see `with_sampling`) and
+/// are only sent when set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+}
+
+impl Sampling {
+    pub const DEFAULT_TEMPERATURE: f32 = 0.7;
+
+    pub fn temperature(&self) -> f32 {
+        self.temperature.unwrap_or(Self::DEFAULT_TEMPERATURE)
+    }
+
+    /// Apply a REPL setting (`temperature`, `top_p` or `max_tokens`); `off`
+    /// returns it to its default.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let off = matches!(value, "off" | "default");
+        match key {
+            "temperature" if off => self.temperature = None,
+            "temperature" => {
+                let t: f32 = value.parse()?;
+                if !(0.0..=2.0).contains(&t) {
+                    anyhow::bail!("temperature must be between 0 and 2");
+                }
+                self.temperature = Some(t);
+            }
+            "top_p" if off => self.top_p = None,
+            "top_p" => {
+                let p: f32 = value.parse()?;
+                if !(0.0..=1.0).contains(&p) {
+                    anyhow::bail!("top_p must be between 0 and 1");
+                }
+                self.top_p = Some(p);
+            }
+            "max_tokens" if off => self.max_tokens = None,
+            "max_tokens" => {
+                let n: u32 = value.parse()?;
+                if n == 0 {
+                    anyhow::bail!("max_tokens must be at least 1");
+                }
+                self.max_tokens = Some(n);
+            }
+            _ => anyhow::bail!("unknown setting {}", key),
+        }
+        Ok(())
+    }
+
+    /// Fill unset fields from `fallback`.
+    pub fn or(self, fallback: Sampling) -> Sampling {
+        Sampling {
+            temperature: self.temperature.or(fallback.temperature),
+            top_p: self.top_p.or(fallback.top_p),
+            max_tokens: self.max_tokens.or(fallback.max_tokens),
+        }
+    }
+}
+
+impl std::fmt::Display for Sampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "temperature={}", self.temperature())?;
+        if let Some(top_p) = self.top_p {
+            write!(f, " top_p={}", top_p)?;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            write!(f, " max_tokens={}", max_tokens)?;
+        }
+        Ok(())
+    }
+}
+
+/// Add top_p / max_tokens to a chat request body when they are set.
+fn sampling_body(
+    mut body: serde_json::Value,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+) -> serde_json::Value {
+    if let Some(top_p) = top_p {
+        body["top_p"] = json!(top_p);
+    }
+    if let Some(max_tokens) = max_tokens {
+        body["max_tokens"] = json!(max_tokens);
+    }
+    body
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CompletionResponse {
     pub choices: Vec<Choice>,
+    #[serde(default)]
+    pub usage: Option<Usage>,
+}
+
+/// Token counts from a response's `usage` object.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: Option<u32>,
+    pub completion_tokens: Option<u32>,
+    pub total_tokens: Option<u32>,
+    /// DeepSeek: prompt tokens served from its context cache
+    pub prompt_cache_hit_tokens: Option<u32>,
+    /// OpenAI: `cached_tokens` lives here
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct PromptTokensDetails {
+    pub cached_tokens: Option<u32>,
+}
+
+impl Usage {
+    /// Prompt tokens the provider served from its prompt cache, if it says.
+    pub fn cached_tokens(&self) -> Option<u32> {
+        self.prompt_cache_hit_tokens
+            .or(self.prompt_tokens_details.and_then(|d| d.cached_tokens))
+    }
+}
+
+/// Request/token budget from the `x-ratelimit-*` headers (OpenAI, Groq and
+/// others), kept as the server wrote them.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub limit_requests: Option<String>,
+    pub remaining_requests: Option<String>,
+    pub reset_requests: Option<String>,
+    pub limit_tokens: Option<String>,
+    pub remaining_tokens: Option<String>,
+    pub reset_tokens: Option<String>,
+}
+
+impl RateLimits {
+    /// `None` when the response carries none of the headers.
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> Option<Self> {
+        let get = |name: &str| {
+            headers
+                .get(format!("x-ratelimit-{}", name))
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().to_string())
+        };
+        let limits = Self {
+            limit_requests: get("limit-requests"),
+            remaining_requests: get("remaining-requests"),
+            reset_requests: get("reset-requests"),
+            limit_tokens: get("limit-tokens"),
+            remaining_tokens: get("remaining-tokens"),
+            reset_tokens: get("reset-tokens"),
+        };
+        (limits.remaining_requests.is_some() || limits.remaining_tokens.is_some()).then_some(limits)
+    }
+}
+
+impl std::fmt::Display for RateLimits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let part = |what: &str,
+                    remaining: &Option<String>,
+                    limit: &Option<String>,
+                    reset: &Option<String>| {
+            remaining.as_ref().map(|left| {
+                let mut s = match limit {
+                    Some(limit) => format!("{}/{} {}", left, limit, what),
+                    None => format!("{} {}", left, what),
+                };
+                if let Some(reset) = reset {
+                    s.push_str(&format!(" (resets in {})", reset));
+                }
+                s
+            })
+        };
+        let parts: Vec<String> = [
+            part(
+                "requests",
+                &self.remaining_requests,
+                &self.limit_requests,
+                &self.reset_requests,
+            ),
+            part(
+                "tokens",
+                &self.remaining_tokens,
+                &self.limit_tokens,
+                &self.reset_tokens,
+            ),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        write!(f, "{} left", parts.join(", "))
+    }
+}
+
+/// Keeps the limits from a successful response; responses without the
+/// headers leave the last-seen values alone.
+fn store_rate_limits(slot: &Mutex<Option<RateLimits>>, response: &reqwest::Response) {
+    if let Some(limits) = RateLimits::from_headers(response.headers()) {
+        tracing::debug!("rate limit: {}", limits);
+        *slot.lock().unwrap() = Some(limits);
+    }
 }
 
 #[allow(dead_code)]
@@ -50,16 +429,24 @@ pub struct Delta {
 
 #[derive(Debug, Deserialize)]
 pub struct StreamResponse {
+    #[serde(default)]
     pub choices: Vec<StreamChoice>,
+    /// Sent on the final chunk when the server reports usage for streams.
+    #[serde(default)]
+    pub usage: Option<Usage>,
 }
 
 impl DeepSeekClient {
-    pub fn new(api_key: String, model: String) -> Self {
+    pub fn new(client: Client, api_key: String, model: String) -> Self {
         Self {
-            client: Client::new(),
+            client,
             api_key,
             model,
             base_url: "https://api.deepseek.com".to_string(),
+            top_p: None,
+            max_tokens: None,
+            usage: Arc::default(),
+            rate_limits: Arc::default(),
         }
     }
 
@@ -67,6 +454,11 @@ impl DeepSeekClient {
         &self.model
     }
 
+    /// Send requests to another DeepSeek-compatible server (`--base-url`).
+    pub fn with_base_url(self, base_url: String) -> Self {
+        Self { base_url, ..self }
+    }
+
     #[allow(dead_code)]
     pub async fn complete(
         &self,
@@ -80,21 +472,24 @@ impl DeepSeekClient {
         if let Some(sys) = system {
             messages.push(Message {
                 role: "system".to_string(),
-                content: Some(sys),
+                content: Some(sys.into()),
                 tool_calls: None,
                 tool_call_id: None,
+                tokens: None,
             });
         }
 
         messages.push(Message {
             role: "user".to_string(),
-            content: Some(message),
+            content: Some(message.into()),
             tool_calls: None,
             tool_call_id: None,
+            tokens: None,
         });
 
         if stream {
-            self.stream_completion(messages, temperature).await
+            self.stream_completion(messages, temperature, &mut None)
+                .await
         } else {
             self.simple_completion(messages, temperature).await
         }
@@ -103,15 +498,19 @@ impl DeepSeekClient {
     async fn simple_completion(&self, messages: Vec<Message>, temperature: f32) -> Result<String> {
         let response = self
             .client
-            .post(format!("{}/v1/chat/completions", self.base_url))
+            .post(v1_url(&self.base_url, "chat/completions"))
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "model": self.model,
-                "messages": messages,
-                "temperature": temperature,
-                "stream": false,
-            }))
+            .json(&sampling_body(
+                json!({
+                    "model": self.model,
+                    "messages": messages,
+                    "temperature": temperature,
+                    "stream": false,
+                }),
+                self.top_p,
+                self.max_tokens,
+            ))
             .send()
             .await?;
 
@@ -119,28 +518,40 @@ impl DeepSeekClient {
             let error_text = response.text().await?;
             return Err(anyhow::anyhow!("API Error: {}", error_text));
         }
+        store_rate_limits(&self.rate_limits, &response);
 
         let completion: CompletionResponse = response.json().await?;
+        *self.usage.lock().unwrap() = completion.usage;
         Ok(completion
             .choices
             .first()
-            .and_then(|c| c.message.content.clone())
+            .and_then(|c| c.message.content.as_ref().map(Content::text))
             .unwrap_or_default())
     }
 
-    async fn stream_completion(&self, messages: Vec<Message>, temperature: f32) -> Result<String> {
+    async fn stream_completion(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        first_token: &mut Option<Instant>,
+    ) -> Result<String> {
         let response = self
             .client
-            .post(format!("{}/v1/chat/completions", self.base_url))
+            .post(v1_url(&self.base_url, "chat/completions"))
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Accept", "text/event-stream")
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "model": self.model,
-                "messages": messages,
-                "temperature": temperature,
-                "stream": true,
-            }))
+            .json(&sampling_body(
+                json!({
+                    "model": self.model,
+                    "messages": messages,
+                    "temperature": temperature,
+                    "stream": true,
+                    "stream_options": {"include_usage": true},
+                }),
+                self.top_p,
+                self.max_tokens,
+            ))
             .send()
             .await?;
 
@@ -148,9 +559,11 @@ impl DeepSeekClient {
             let error_text = response.text().await?;
             return Err(anyhow::anyhow!("API Error: {}", error_text));
         }
+        store_rate_limits(&self.rate_limits, &response);
 
         let mut stream = response.bytes_stream().eventsource();
         let mut full_response = String::new();
+        let mut printer = StreamPrinter::new(REPLY_LABEL_WIDTH);
 
         let mut errored = false;
         while let Some(event) = stream.next().await {
@@ -161,10 +574,13 @@ impl DeepSeekClient {
                     }
 
                     if let Ok(chunk) = serde_json::from_str::<StreamResponse>(&event.data) {
+                        if chunk.usage.is_some() {
+                            *self.usage.lock().unwrap() = chunk.usage;
+                        }
                         if let Some(choice) = chunk.choices.first() {
                             if let Some(content) = &choice.delta.content {
-                                print!("{content}");
-                                io::stdout().flush()?;
+                                first_token.get_or_insert_with(Instant::now);
+                                printer.push(content)?;
                                 full_response.push_str(content);
                             }
                         }
@@ -178,6 +594,7 @@ impl DeepSeekClient {
             }
         }
 
+        printer.finish()?;
         println!();
         if errored && full_response.is_empty() {
             // Best-effort fallback
@@ -192,11 +609,25 @@ impl DeepSeekClient {
         temperature: f32,
         stream: bool,
     ) -> Result<String> {
-        if stream {
-            self.stream_completion(messages, temperature).await
+        Ok(self.complete_timed(messages, temperature, stream).await?.0)
+    }
+
+    /// Complete and report when the first streamed token arrived.
+    pub async fn complete_timed(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<(String, Option<Instant>)> {
+        *self.usage.lock().unwrap() = None;
+        let mut first_token = None;
+        let text = if stream {
+            self.stream_completion(messages, temperature, &mut first_token)
+                .await?
         } else {
-            self.simple_completion(messages, temperature).await
-        }
+            self.simple_completion(messages, temperature).await?
+        };
+        Ok((text, first_token))
     }
 
     pub async fn complete_with_tools(
@@ -207,17 +638,21 @@ impl DeepSeekClient {
     ) -> Result<CompletionResponse> {
         let response = self
             .client
-            .post(format!("{}/v1/chat/completions", self.base_url))
+            .post(v1_url(&self.base_url, "chat/completions"))
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "model": self.model,
-                "messages": messages,
-                "temperature": temperature,
-                "tools": tools,
-                "tool_choice": "auto",
-                "stream": false,
-            }))
+            .json(&sampling_body(
+                json!({
+                    "model": self.model,
+                    "messages": messages,
+                    "temperature": temperature,
+                    "tools": tools,
+                    "tool_choice": "auto",
+                    "stream": false,
+                }),
+                self.top_p,
+                self.max_tokens,
+            ))
             .send()
             .await?;
 
@@ -225,8 +660,10 @@ impl DeepSeekClient {
             let error_text = response.text().await?;
             return Err(anyhow::anyhow!("API Error: {}", error_text));
         }
+        store_rate_limits(&self.rate_limits, &response);
 
         let completion: CompletionResponse = response.json().await?;
+        *self.usage.lock().unwrap() = completion.usage;
         Ok(completion)
     }
 }
@@ -237,15 +674,23 @@ pub struct OaiCompatClient {
     api_key: String,
     model: String,
     base_url: String,
+    top_p: Option<f32>,
+    max_tokens: Option<u32>,
+    usage: Arc<Mutex<Option<Usage>>>,
+    rate_limits: Arc<Mutex<Option<RateLimits>>>,
 }
 
 impl OaiCompatClient {
-    pub fn new(api_key: String, model: String, base_url: String) -> Self {
+    pub fn new(client: Client, api_key: String, model: String, base_url: String) -> Self {
         Self {
-            client: Client::new(),
+            client,
             api_key,
             model,
             base_url,
+            top_p: None,
+            max_tokens: None,
+            usage: Arc::default(),
+            rate_limits: Arc::default(),
         }
     }
     pub fn model_name(&self) -> &str {
@@ -253,12 +698,7 @@ impl OaiCompatClient {
     }
 
     fn completions_url(&self) -> String {
-        let base = self.base_url.trim_end_matches('/');
-        if base.ends_with("/v1") {
-            format!("{}/chat/completions", base)
-        } else {
-            format!("{}/v1/chat/completions", base)
-        }
+        v1_url(&self.base_url, "chat/completions")
     }
 
     pub async fn simple_completion(
@@ -271,42 +711,46 @@ impl OaiCompatClient {
             .post(self.completions_url())
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "model": self.model,
-                "messages": messages,
-                "temperature": temperature,
-                "stream": false,
-            }))
+            .json(&sampling_body(
+                json!({
+                    "model": self.model,
+                    "messages": messages,
+                    "temperature": temperature,
+                    "stream": false,
+                }),
+                self.top_p,
+                self.max_tokens,
+            ))
             .send()
             .await?;
         if !response.status().is_success() {
             let error_text = response.text().await?;
             return Err(anyhow::anyhow!("API Error: {}", error_text));
         }
+        store_rate_limits(&self.rate_limits, &response);
         #[derive(Deserialize)]
         struct Resp {
             choices: Vec<Choice>,
             #[serde(default)]
-            usage: Option<UsageLike>,
-        }
-        #[derive(Deserialize)]
-        struct UsageLike {
-            prompt_tokens: Option<u32>,
-            completion_tokens: Option<u32>,
-            total_tokens: Option<u32>,
+            usage: Option<Usage>,
         }
         let completion: Resp = response.json().await?;
+        *self.usage.lock().unwrap() = completion.usage;
         if let Some(u) = completion.usage {
             if let (Some(pi), Some(co), Some(tt)) =
                 (u.prompt_tokens, u.completion_tokens, u.total_tokens)
             {
-                eprintln!("[usage] in={} out={} total={}", pi, co, tt);
+                let cached = u
+                    .cached_tokens()
+                    .map(|n| format!(" cached={}", n))
+                    .unwrap_or_default();
+                eprintln!("[usage] in={} out={} total={}{}", pi, co, tt, cached);
             }
         }
         Ok(completion
             .choices
             .first()
-            .and_then(|c| c.message.content.clone())
+            .and_then(|c| c.message.content.as_ref().map(Content::text))
             .unwrap_or_default())
     }
 
@@ -314,26 +758,33 @@ impl OaiCompatClient {
         &self,
         messages: Vec<Message>,
         temperature: f32,
+        first_token: &mut Option<Instant>,
     ) -> Result<String> {
         let response = self
             .client
             .post(self.completions_url())
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&json!({
-                "model": self.model,
-                "messages": messages,
-                "temperature": temperature,
-                "stream": true,
-            }))
+            .json(&sampling_body(
+                json!({
+                    "model": self.model,
+                    "messages": messages,
+                    "temperature": temperature,
+                    "stream": true,
+                }),
+                self.top_p,
+                self.max_tokens,
+            ))
             .send()
             .await?;
         if !response.status().is_success() {
             let error_text = response.text().await?;
             return Err(anyhow::anyhow!("API Error: {}", error_text));
         }
+        store_rate_limits(&self.rate_limits, &response);
         let mut stream = response.bytes_stream().eventsource();
         let mut full = String::new();
+        let mut printer = StreamPrinter::new(REPLY_LABEL_WIDTH);
         while let Some(ev) = stream.next().await {
             match ev {
                 Ok(ev) => {
@@ -341,10 +792,13 @@ impl OaiCompatClient {
                         break;
                     }
                     if let Ok(chunk) = serde_json::from_str::<StreamResponse>(&ev.data) {
+                        if chunk.usage.is_some() {
+                            *self.usage.lock().unwrap() = chunk.usage;
+                        }
                         if let Some(choice) = chunk.choices.first() {
                             if let Some(content) = &choice.delta.content {
-                                print!("{}", content);
-                                io::stdout().flush()?;
+                                first_token.get_or_insert_with(Instant::now);
+                                printer.push(content)?;
                                 full.push_str(content);
                             }
                         }
@@ -356,6 +810,7 @@ impl OaiCompatClient {
                 }
             }
         }
+        printer.finish()?;
         println!();
         Ok(full)
     }
@@ -366,44 +821,70 @@ impl OaiCompatClient {
         temperature: f32,
         stream: bool,
     ) -> Result<String> {
-        if stream {
-            self.stream_completion(messages, temperature).await
+        Ok(self.complete_timed(messages, temperature, stream).await?.0)
+    }
+
+    /// Complete and report when the first streamed token arrived.
+    pub async fn complete_timed(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<(String, Option<Instant>)> {
+        *self.usage.lock().unwrap() = None;
+        let mut first_token = None;
+        let text = if stream {
+            self.stream_completion(messages, temperature, &mut first_token)
+                .await?
         } else {
-            self.simple_completion(messages, temperature).await
-        }
+            self.simple_completion(messages, temperature).await?
+        };
+        Ok((text, first_token))
     }
 
     fn models_url(&self) -> String {
-        let base = self.base_url.trim_end_matches('/');
-        if base.ends_with("/v1") {
-            format!("{}/models", base)
-        } else {
-            format!("{}/v1/models", base)
-        }
+        v1_url(&self.base_url, "models")
     }
 
     pub async fn list_models_inner(&self) -> Result<Vec<String>> {
-        #[derive(Deserialize)]
-        struct Model {
-            id: String,
-        }
-        #[derive(Deserialize)]
-        struct Resp {
-            data: Vec<Model>,
-        }
-        let resp = self
-            .client
-            .get(self.models_url())
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()
-            .await?;
-        if !resp.status().is_success() {
-            let t = resp.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(t));
-        }
-        let r: Resp = resp.json().await?;
-        Ok(r.data.into_iter().map(|m| m.id).collect())
+        list_models(&self.client, &self.models_url(), &self.api_key).await
+    }
+}
+
+/// `path` under the server's `/v1`, whether or not `base` already ends in it.
+fn v1_url(base: &str, path: &str) -> String {
+    let base = base.trim_end_matches('/');
+    if base.ends_with("/v1") {
+        format!("{}/{}", base, path)
+    } else {
+        format!("{}/v1/{}", base, path)
+    }
+}
+
+/// Model ids from an OpenAI-style `GET /v1/models`.
+async fn list_models(client: &Client, url: &str, api_key: &str) -> Result<Vec<String>> {
+    #[derive(Deserialize)]
+    struct Model {
+        id: String,
+    }
+    #[derive(Deserialize)]
+    struct Resp {
+        data: Vec<Model>,
     }
+    let resp = client
+        .get(url)
+        .header("Authorization", format!("Bearer {}", api_key))
+        .send()
+        .await?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        anyhow::bail!("{} returned 404; this server doesn't list its models", url);
+    }
+    if !resp.status().is_success() {
+        let t = resp.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!(t));
+    }
+    let r: Resp = resp.json().await?;
+    Ok(r.data.into_iter().map(|m| m.id).collect())
 }
 
 #[async_trait::async_trait]
@@ -423,6 +904,35 @@ pub trait ChatClient: Send + Sync + 'static {
     ) -> Result<CompletionResponse>;
     async fn list_models(&self) -> Result<Vec<String>>;
     fn with_model(&self, model: &str) -> Box<dyn ChatClient>;
+    /// A copy that sends `sampling`'s top_p and max_tokens with each request.
+    fn with_sampling(&self, sampling: &Sampling) -> Box<dyn ChatClient>;
+    /// Usage reported for the most recent completion, if the server sent any.
+    fn last_usage(&self) -> Option<Usage> {
+        None
+    }
+    /// Rate-limit budget from the most recent response that reported one.
+    fn rate_limits(&self) -> Option<RateLimits> {
+        None
+    }
+    /// Like `complete_with_history`, also returning the time the first streamed
+    /// token arrived when the client can observe it.
+    async fn complete_timed(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<(String, Option<Instant>)> {
+        Ok((
+            self.complete_with_history(messages, temperature, stream)
+                .await?,
+            None,
+        ))
+    }
+}
+
+/// Rough token count (~4 characters per token) for when usage is not reported.
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(4)
 }
 
 #[async_trait::async_trait]
@@ -438,6 +948,14 @@ impl ChatClient for DeepSeekClient {
     ) -> Result<String> {
         DeepSeekClient::complete_with_history(self, messages, temperature, stream).await
     }
+    async fn complete_timed(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<(String, Option<Instant>)> {
+        DeepSeekClient::complete_timed(self, messages, temperature, stream).await
+    }
     async fn complete_with_tools(
         &self,
         messages: Vec<Message>,
@@ -448,27 +966,12 @@ impl ChatClient for DeepSeekClient {
     }
     async fn list_models(&self) -> Result<Vec<String>> {
         // DeepSeek is OpenAI-compatible for models list
-        #[derive(Deserialize)]
-        struct Model {
-            id: String,
-        }
-        #[derive(Deserialize)]
-        struct Resp {
-            data: Vec<Model>,
-        }
-        let url = format!("{}/v1/models", self.base_url.trim_end_matches('/'));
-        let resp = self
-            .client
-            .get(url)
-            .header("Authorization", format!("Bearer {}", self.api_key.clone()))
-            .send()
-            .await?;
-        if !resp.status().is_success() {
-            let t = resp.text().await.unwrap_or_default();
-            return Err(anyhow::anyhow!(t));
-        }
-        let r: Resp = resp.json().await?;
-        Ok(r.data.into_iter().map(|m| m.id).collect())
+        list_models(
+            &self.client,
+            &v1_url(&self.base_url, "models"),
+            &self.api_key,
+        )
+        .await
     }
     fn with_model(&self, model: &str) -> Box<dyn ChatClient> {
         Box::new(DeepSeekClient {
@@ -476,6 +979,19 @@ impl ChatClient for DeepSeekClient {
             ..self.clone()
         })
     }
+    fn with_sampling(&self, sampling: &Sampling) -> Box<dyn ChatClient> {
+        Box::new(DeepSeekClient {
+            top_p: sampling.top_p,
+            max_tokens: sampling.max_tokens,
+            ..self.clone()
+        })
+    }
+    fn last_usage(&self) -> Option<Usage> {
+        *self.usage.lock().unwrap()
+    }
+    fn rate_limits(&self) -> Option<RateLimits> {
+        self.rate_limits.lock().unwrap().clone()
+    }
 }
 
 #[async_trait::async_trait]
@@ -491,6 +1007,14 @@ impl ChatClient for OaiCompatClient {
     ) -> Result<String> {
         OaiCompatClient::complete_with_history(self, messages, temperature, stream).await
     }
+    async fn complete_timed(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<(String, Option<Instant>)> {
+        OaiCompatClient::complete_timed(self, messages, temperature, stream).await
+    }
     async fn complete_with_tools(
         &self,
         messages: Vec<Message>,
@@ -503,21 +1027,27 @@ impl ChatClient for OaiCompatClient {
             .post(self.completions_url())
             .header("Authorization", format!("Bearer {}", self.api_key))
             .header("Content-Type", "application/json")
-            .json(&serde_json::json!({
-                "model": self.model,
-                "messages": messages,
-                "temperature": temperature,
-                "tools": tools,
-                "tool_choice": "auto",
-                "stream": false,
-            }))
+            .json(&sampling_body(
+                json!({
+                    "model": self.model,
+                    "messages": messages,
+                    "temperature": temperature,
+                    "tools": tools,
+                    "tool_choice": "auto",
+                    "stream": false,
+                }),
+                self.top_p,
+                self.max_tokens,
+            ))
             .send()
             .await?;
         if !response.status().is_success() {
             let error_text = response.text().await?;
             return Err(anyhow::anyhow!("API Error: {}", error_text));
         }
+        store_rate_limits(&self.rate_limits, &response);
         let completion: CompletionResponse = response.json().await?;
+        *self.usage.lock().unwrap() = completion.usage;
         Ok(completion)
     }
     async fn list_models(&self) -> Result<Vec<String>> {
@@ -529,4 +1059,170 @@ impl ChatClient for OaiCompatClient {
             ..self.clone()
         })
     }
+    fn with_sampling(&self, sampling: &Sampling) -> Box<dyn ChatClient> {
+        Box::new(OaiCompatClient {
+            top_p: sampling.top_p,
+            max_tokens: sampling.max_tokens,
+            ..self.clone()
+        })
+    }
+    fn last_usage(&self) -> Option<Usage> {
+        *self.usage.lock().unwrap()
+    }
+    fn rate_limits(&self) -> Option<RateLimits> {
+        self.rate_limits.lock().unwrap().clone()
+    }
+}
+
+/// Offline client for demos and tests. Replies come from a script when one is
+/// given, otherwise the last user message is echoed back. A user message line
+/// of the form `@tool <name> <json-args>` makes `complete_with_tools` request
+/// that tool call; script entries of the same form do too, so a script can
+/// drive multi-step tool sequences.
+#[derive(Debug, Clone)]
+pub struct MockClient {
+    model: String,
+    script: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<String>>>,
+}
+
+impl MockClient {
+    pub fn new(model: String) -> Self {
+        Self::scripted(model, Vec::new())
+    }
+
+    pub fn scripted(model: String, responses: Vec<String>) -> Self {
+        Self {
+            model,
+            script: std::sync::Arc::new(std::sync::Mutex::new(responses.into())),
+        }
+    }
+
+    /// Load a JSON array of canned replies from `RUSTY_MOCK_SCRIPT` if set.
+    pub fn from_env(model: String) -> Result<Self> {
+        match std::env::var("RUSTY_MOCK_SCRIPT") {
+            Ok(path) => {
+                let text = std::fs::read_to_string(&path)?;
+                let responses: Vec<String> = serde_json::from_str(&text)?;
+                Ok(Self::scripted(model, responses))
+            }
+            Err(_) => Ok(Self::new(model)),
+        }
+    }
+
+    fn reply(&self, messages: &[Message]) -> String {
+        if let Some(next) = self.script.lock().unwrap().pop_front() {
+            return next;
+        }
+        match messages.last() {
+            Some(m) if m.role == "tool" => {
+                format!(
+                    "tool result: {}",
+                    m.content.as_ref().map(Content::text).unwrap_or_default()
+                )
+            }
+            Some(m) => format!(
+                "echo: {}",
+                m.content.as_ref().map(Content::text).unwrap_or_default()
+            ),
+            None => "echo:".to_string(),
+        }
+    }
+
+    fn requested_tool(&self, messages: &[Message]) -> Option<(String, String)> {
+        let parse = |line: &str| {
+            let rest = line.trim().strip_prefix("@tool ")?;
+            let (name, args) = rest.split_once(' ').unwrap_or((rest, "{}"));
+            Some((name.to_string(), args.trim().to_string()))
+        };
+        {
+            let mut script = self.script.lock().unwrap();
+            if let Some(call) = script.front().and_then(|next| parse(next)) {
+                script.pop_front();
+                return Some(call);
+            }
+        }
+        let last = messages.last().filter(|m| m.role == "user")?;
+        let content = last.content.as_ref()?.text();
+        content.lines().find_map(parse)
+    }
+}
+
+#[async_trait::async_trait]
+impl ChatClient for MockClient {
+    fn model_name(&self) -> &str {
+        &self.model
+    }
+    async fn complete_with_history(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<String> {
+        Ok(self.complete_timed(messages, temperature, stream).await?.0)
+    }
+    async fn complete_timed(
+        &self,
+        messages: Vec<Message>,
+        _temperature: f32,
+        stream: bool,
+    ) -> Result<(String, Option<Instant>)> {
+        let reply = self.reply(&messages);
+        let mut first_token = None;
+        if stream {
+            let mut printer = StreamPrinter::new(REPLY_LABEL_WIDTH);
+            for word in reply.split_inclusive(' ') {
+                first_token.get_or_insert_with(Instant::now);
+                printer.push(word)?;
+            }
+            printer.finish()?;
+            println!();
+        }
+        Ok((reply, first_token))
+    }
+    async fn complete_with_tools(
+        &self,
+        messages: Vec<Message>,
+        _tools: Vec<Tool>,
+        _temperature: f32,
+    ) -> Result<CompletionResponse> {
+        let message = match self.requested_tool(&messages) {
+            Some((name, arguments)) => Message {
+                role: "assistant".into(),
+                content: None,
+                tool_calls: Some(vec![ToolCall {
+                    id: format!("mock-call-{}", messages.len()),
+                    r#type: "function".into(),
+                    function: crate::tools::FunctionCall { name, arguments },
+                }]),
+                tool_call_id: None,
+                tokens: None,
+            },
+            None => Message {
+                role: "assistant".into(),
+                content: Some(self.reply(&messages).into()),
+                tool_calls: None,
+                tool_call_id: None,
+                tokens: None,
+            },
+        };
+        Ok(CompletionResponse {
+            choices: vec![Choice {
+                message,
+                finish_reason: Some("stop".into()),
+            }],
+            usage: None,
+        })
+    }
+    async fn list_models(&self) -> Result<Vec<String>> {
+        Ok(vec!["mock-echo".into(), "mock-tools".into()])
+    }
+    fn with_model(&self, model: &str) -> Box<dyn ChatClient> {
+        Box::new(MockClient {
+            model: model.to_string(),
+            ..self.clone()
+        })
+    }
+    fn with_sampling(&self, _sampling: &Sampling) -> Box<dyn ChatClient> {
+        Box::new(self.clone())
+    }
 }
@@ -0,0 +1,234 @@
+use anyhow::{bail, Result};
+use colored::*;
+use std::path::{Path, PathBuf};
+
+/// Files bigger than this aren't read at all, whatever the budget.
+const MAX_FILE_BYTES: u64 = 1 << 20;
+
+/// Files queued by `:attach`, sent ahead of the next message.
+#[derive(Default)]
+pub struct Attachments {
+    files: Vec<Attached>,
+}
+
+struct Attached {
+    path: String,
+    block: String,
+    chars: usize,
+}
+
+impl Attachments {
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+
+    /// Attach each path or glob, reporting what was added or skipped. All
+    /// attachments together get `limit` chars of file contents; the file
+    /// that crosses it is cut short and the rest are left out.
+    pub fn add(&mut self, patterns: &[String], limit: usize) {
+        let mut paths = vec![];
+        for pattern in patterns {
+            match expand(pattern) {
+                Ok(found) if found.is_empty() => {
+                    println!("{} no files match {}", "warning:".yellow(), pattern)
+                }
+                Ok(found) => paths.extend(found),
+                Err(e) => println!("{} {}: {}", "error:".red(), pattern, e),
+            }
+        }
+        let mut trimmed = vec![];
+        for path in paths {
+            let shown = display(&path);
+            if self.files.iter().any(|f| f.path == shown) {
+                continue;
+            }
+            let text = match read(&path) {
+                Ok(text) => text,
+                Err(e) => {
+                    println!("{} {}: {}", "skipped".yellow(), shown, e);
+                    continue;
+                }
+            };
+            let used: usize = self.files.iter().map(|f| f.chars).sum();
+            let room = limit.saturating_sub(used);
+            if room == 0 {
+                trimmed.push(shown);
+                continue;
+            }
+            let total = text.chars().count();
+            let text = if total > room {
+                trimmed.push(shown.clone());
+                let mut cut: String = text.chars().take(room).collect();
+                cut.push_str(&format!(
+                    "\n[... truncated: showing {} of {} chars ...]",
+                    room, total
+                ));
+                cut
+            } else {
+                text
+            };
+            println!("{} {}", "Attached".green(), shown);
+            self.files.push(Attached {
+                block: fence(&shown, &text),
+                chars: total.min(room),
+                path: shown,
+            });
+        }
+        if !trimmed.is_empty() {
+            println!(
+                "{} over the {} char budget (attachment_max_chars); cut or left out: {}",
+                "warning:".yellow(),
+                limit,
+                trimmed.join(", ")
+            );
+        }
+    }
+
+    /// Attached paths, for `:attach` with no arguments.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|f| f.path.as_str())
+    }
+
+    /// Shown at the prompt, e.g. "[2 files attached, 3.1 KB]".
+    pub fn summary(&self) -> String {
+        let bytes: usize = self.files.iter().map(|f| f.block.len()).sum();
+        let size = if bytes < 1024 {
+            format!("{} B", bytes)
+        } else {
+            format!("{:.1} KB", bytes as f64 / 1024.0)
+        };
+        let count = self.files.len();
+        format!(
+            "[{} file{} attached, {}]",
+            count,
+            if count == 1 { "" } else { "s" },
+            size
+        )
+    }
+
+    /// The fenced files, emptying the queue.
+    pub fn take(&mut self) -> String {
+        let blocks: Vec<String> = self.files.drain(..).map(|f| f.block).collect();
+        blocks.join("\n\n")
+    }
+}
+
+/// A plain path as is; a glob matched from its literal leading directories,
+/// skipping what .gitignore skips.
+fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    if !pattern.contains(['*', '?', '[', '{']) {
+        let path = PathBuf::from(pattern);
+        if path.is_dir() {
+            bail!(
+                "is a directory; attach {}/* instead",
+                pattern.trim_end_matches('/')
+            );
+        }
+        return Ok(vec![path]);
+    }
+    let pattern = pattern.strip_prefix("./").unwrap_or(pattern);
+    let matcher = globset::GlobBuilder::new(pattern)
+        .literal_separator(true)
+        .build()?
+        .compile_matcher();
+    let root: PathBuf = Path::new(pattern)
+        .components()
+        .take_while(|c| {
+            !c.as_os_str()
+                .to_string_lossy()
+                .contains(['*', '?', '[', '{'])
+        })
+        .collect();
+    let root = if root.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        root
+    };
+    let mut paths: Vec<PathBuf> = ignore::WalkBuilder::new(&root)
+        .require_git(false)
+        .build()
+        .flatten()
+        .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+        .map(|e| e.into_path())
+        .map(|p| p.strip_prefix("./").map(Path::to_path_buf).unwrap_or(p))
+        .filter(|p| matcher.is_match(p))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn read(path: &Path) -> Result<String> {
+    let size = std::fs::metadata(path)?.len();
+    if size > MAX_FILE_BYTES {
+        bail!("{} bytes is over the {} byte limit", size, MAX_FILE_BYTES);
+    }
+    let bytes = std::fs::read(path)?;
+    if bytes.contains(&0) {
+        bail!("binary file");
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow::anyhow!("not UTF-8 text"))
+}
+
+fn display(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
+
+/// The path on its own line, then the contents fenced with the language
+/// guessed from the extension. The fence outgrows any backtick run inside.
+fn fence(path: &str, text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let ticks = "`".repeat(longest.max(2) + 1);
+    format!(
+        "{}:\n{}{}\n{}\n{}",
+        path,
+        ticks,
+        language(path),
+        text.trim_end_matches('\n'),
+        ticks
+    )
+}
+
+fn language(path: &str) -> &'static str {
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    match ext.as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "tsx" => "tsx",
+        "jsx" => "jsx",
+        "go" => "go",
+        "java" => "java",
+        "kt" => "kotlin",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "swift" => "swift",
+        "sh" | "bash" | "zsh" => "bash",
+        "toml" => "toml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "md" => "markdown",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "sql" => "sql",
+        "xml" => "xml",
+        "lua" => "lua",
+        _ => "",
+    }
+}
@@ -0,0 +1,136 @@
+use crate::api::{
+    ChatClient, CompletionResponse, Message, RateLimits, Sampling, Usage, REPLY_LABEL_WIDTH,
+};
+use crate::session::SessionStore;
+use crate::tools::Tool;
+use crate::wrap::StreamPrinter;
+use anyhow::Result;
+use colored::*;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long a cached reply is reused when `cache_ttl_secs` is not set.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// `--cache`: replays the stored reply for a request identical to an earlier
+/// one instead of calling the API. Tool-calling requests always go through,
+/// since running the tools again is the point.
+pub struct CachedClient {
+    inner: Box<dyn ChatClient>,
+    sampling: Sampling,
+    ttl: Duration,
+    /// The last completion came from the cache
+    hit: AtomicBool,
+}
+
+impl CachedClient {
+    pub fn new(inner: Box<dyn ChatClient>, ttl: Duration) -> Self {
+        Self {
+            inner,
+            sampling: Sampling::default(),
+            ttl,
+            hit: AtomicBool::new(false),
+        }
+    }
+
+    fn wrap(&self, inner: Box<dyn ChatClient>, sampling: Sampling) -> Box<dyn ChatClient> {
+        Box::new(Self {
+            inner,
+            sampling,
+            ttl: self.ttl,
+            hit: AtomicBool::new(false),
+        })
+    }
+
+    /// Hash of everything that shapes the reply.
+    fn key(&self, messages: &[Message], temperature: f32) -> String {
+        let request = json!({
+            "model": self.inner.model_name(),
+            "messages": messages,
+            "temperature": temperature,
+            "top_p": self.sampling.top_p,
+            "max_tokens": self.sampling.max_tokens,
+        });
+        let digest = Sha256::digest(request.to_string().as_bytes());
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl ChatClient for CachedClient {
+    fn model_name(&self) -> &str {
+        self.inner.model_name()
+    }
+    async fn complete_with_history(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<String> {
+        Ok(self.complete_timed(messages, temperature, stream).await?.0)
+    }
+    async fn complete_timed(
+        &self,
+        messages: Vec<Message>,
+        temperature: f32,
+        stream: bool,
+    ) -> Result<(String, Option<Instant>)> {
+        let key = self.key(&messages, temperature);
+        if let Some(text) = SessionStore::cached_response(&key, self.ttl)? {
+            self.hit.store(true, Ordering::Relaxed);
+            // Replay a streamed reply all at once, through the same printer
+            if stream {
+                let mut printer = StreamPrinter::new(REPLY_LABEL_WIDTH);
+                printer.push(&text)?;
+                printer.finish()?;
+                println!();
+            }
+            eprintln!("{}", "(cached reply)".dimmed());
+            return Ok((text, None));
+        }
+        self.hit.store(false, Ordering::Relaxed);
+        let (text, first_token) = self
+            .inner
+            .complete_timed(messages, temperature, stream)
+            .await?;
+        SessionStore::cache_response(&key, &text)?;
+        Ok((text, first_token))
+    }
+    async fn complete_with_tools(
+        &self,
+        messages: Vec<Message>,
+        tools: Vec<Tool>,
+        temperature: f32,
+    ) -> Result<CompletionResponse> {
+        self.hit.store(false, Ordering::Relaxed);
+        self.inner
+            .complete_with_tools(messages, tools, temperature)
+            .await
+    }
+    async fn list_models(&self) -> Result<Vec<String>> {
+        self.inner.list_models().await
+    }
+    fn with_model(&self, model: &str) -> Box<dyn ChatClient> {
+        self.wrap(self.inner.with_model(model), self.sampling)
+    }
+    fn with_sampling(&self, sampling: &Sampling) -> Box<dyn ChatClient> {
+        self.wrap(self.inner.with_sampling(sampling), *sampling)
+    }
+    fn last_usage(&self) -> Option<Usage> {
+        if self.hit.load(Ordering::Relaxed) {
+            // Nothing was billed for a replayed reply
+            return Some(Usage {
+                prompt_tokens: Some(0),
+                completion_tokens: Some(0),
+                total_tokens: Some(0),
+                ..Default::default()
+            });
+        }
+        self.inner.last_usage()
+    }
+    fn rate_limits(&self) -> Option<RateLimits> {
+        self.inner.rate_limits()
+    }
+}
@@ -1,64 +1,149 @@
-use crate::api::{ChatClient, Message};
+use crate::api::{ChatClient, Message, Pricing, Sampling, Usage};
+use crate::history::LineEditor;
 use crate::session::SessionStore;
 use crate::tools::ToolRegistry;
 use anyhow::Result;
 use colored::*;
+use std::collections::BTreeSet;
 use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+/// Print each reply's estimated cost (`--show-cost`).
+static SHOW_COST: AtomicBool = AtomicBool::new(false);
+
+pub fn set_show_cost(on: bool) {
+    SHOW_COST.store(on, Ordering::Relaxed);
+}
+
+/// Add a reply's estimated cost to the session total and, with `--show-cost`,
+/// print it. Replies without usage or from unpriced models count as unpriced.
+pub fn record_cost(session_id: Option<&str>, model: &str, usage: Option<Usage>) {
+    let overrides = crate::config::Config::load()
+        .map(|c| c.pricing)
+        .unwrap_or_default();
+    let pricing = Pricing::for_model(model, &overrides);
+    let cost = usage.as_ref().zip(pricing).and_then(|(u, p)| p.cost(u));
+    if let Some(id) = session_id {
+        let _ = SessionStore::add_cost(id, cost);
+    }
+    if !SHOW_COST.load(Ordering::Relaxed) {
+        return;
+    }
+    let line = match (usage, cost) {
+        (Some(u), Some(cost)) => {
+            let cached = match u.cached_tokens() {
+                Some(n) if n > 0 => format!(", {} cached", n),
+                _ => String::new(),
+            };
+            format!(
+                "cost: ~${:.4} ({} in{} / {} out)",
+                cost,
+                u.prompt_tokens.unwrap_or(0),
+                cached,
+                u.completion_tokens.unwrap_or(0)
+            )
+        }
+        (None, _) => "cost: unknown (no usage reported)".to_string(),
+        _ if pricing.is_none() => format!("cost: unknown pricing for {}", model),
+        _ => "cost: unknown (incomplete usage)".to_string(),
+    };
+    println!("{}", line.dimmed());
+}
+
+/// Generate a fresh timestamped session id.
+pub fn new_session_id() -> String {
+    format!("s-{}", time::OffsetDateTime::now_utc().unix_timestamp())
+}
+
+/// Pick the session to use: an explicit id wins, otherwise resume the last
+/// session unless a fresh one is requested.
+pub fn resolve_session(requested: Option<String>, new_session: bool) -> Result<String> {
+    if let Some(id) = requested {
+        return Ok(id);
+    }
+    if !new_session {
+        if let Some(id) = SessionStore::last()? {
+            return Ok(id);
+        }
+    }
+    Ok(new_session_id())
+}
 
 pub async fn interactive_mode(
     client: &dyn ChatClient,
     system_prompt: Option<String>,
+    session: Option<String>,
+    new_session: bool,
+    requested: Sampling,
+    auto_approve: bool,
+    timings: bool,
 ) -> Result<()> {
     println!("{}", "Rusty Interactive Chat".bold().cyan());
-    println!("{}", "Type 'exit' or 'quit' to end the session".dimmed());
-    println!("{}", "Type 'clear' to clear chat history".dimmed());
-    println!("{}", "Type ':new [id]' to start a new session".dimmed());
-    println!("{}", "Type ':session <id>' to switch sessions".dimmed());
-    println!("{}", "Type ':status' to show current session info".dimmed());
-    println!(
-        "{}",
-        "Type ':models' for model tips; switch provider with --provider at launch".dimmed()
-    );
-    println!(
-        "{}",
-        "Type ':tools list' to view tools; ':tools on' to enter tools mode".dimmed()
-    );
-    println!("{}", "Type ':keys' to set API keys for providers".dimmed());
-    println!(
-        "{}",
-        "Type 'system <prompt>' to set a new system prompt".dimmed()
-    );
+    print_commands(true);
     println!();
 
-    // Determine session: resume last or start a new one
-    let mut session_id = SessionStore::last()?
-        .unwrap_or_else(|| format!("s-{}", time::OffsetDateTime::now_utc().unix_timestamp()));
-    let mut messages = SessionStore::load(&session_id).unwrap_or_default();
+    // Determine session: explicit id, resume last, or start a new one
+    let mut session_id = resolve_session(session, new_session)?;
+    let mut messages = if new_session {
+        Vec::new()
+    } else {
+        SessionStore::load(&session_id).unwrap_or_default()
+    };
     if !messages.is_empty() {
         println!("{} {}", "Resumed session".yellow(), session_id.dimmed());
     }
+    if SessionStore::ephemeral() {
+        println!("{}", "Ephemeral session: nothing will be saved".yellow());
+    }
+    // Flags win over what the session last used, which wins over the config default
+    let defaults = crate::config::Config::load().unwrap_or_default().sampling();
+    let stored = if new_session {
+        None
+    } else {
+        SessionStore::load_sampling(&session_id).unwrap_or_default()
+    };
+    let mut sampling = requested.or(stored.unwrap_or_default()).or(defaults);
 
-    let mut current_system = system_prompt.clone();
+    // Indexes into `messages` kept through `clear` and `:summarize --replace`
+    let mut pins: BTreeSet<usize> = BTreeSet::new();
+    // Tries at the current turn's reply, counting `:retry`
+    let mut attempt = 1;
+    // Files from `:attach`, sent with the next message
+    let mut attachments = crate::attach::Attachments::default();
     let mut current_model = client.model_name().to_string();
     let mut stream = true;
+    let mut timings = timings;
+    crate::wrap::set_live_rate(timings);
     let mut cached_models: Vec<String> = Vec::new();
+    // Opened by the first `:copy` and kept: on X11 the copied text is served
+    // by this process, so it would vanish with the handle
+    let mut clipboard: Option<arboard::Clipboard> = None;
+    // MCP servers run while tools mode is on; `:resource` and `:prompt` start them too
+    let mut mcp = crate::mcp::MCPRegistry::new();
+    // --system replaces a resumed session's prompt rather than adding a second one
     if let Some(sys) = system_prompt {
-        messages.push(Message {
-            role: "system".to_string(),
-            content: Some(sys),
-            tool_calls: None,
-            tool_call_id: None,
-        });
-        println!("{}", "System prompt set".green());
+        let stored = system_text(&messages);
+        set_system(&mut messages, &mut pins, Some(&sys));
+        match stored {
+            Some(stored) if stored != sys => {
+                println!("{}", "System prompt replaced the session's".green())
+            }
+            _ => println!("{}", "System prompt set".green()),
+        }
     }
 
+    let mut editor = LineEditor::new();
     loop {
-        print!("{} ", "You:".bold().green());
-        io::stdout().flush()?;
-
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let input = input.trim();
+        if !attachments.is_empty() {
+            println!("{}", attachments.summary().dimmed());
+        }
+        let Some(line) = editor.read_line("You:")? else {
+            // Ctrl-D or end of piped input
+            println!("{}", "Goodbye!".yellow());
+            break;
+        };
+        let input = line.trim();
 
         if input.is_empty() {
             continue;
@@ -69,18 +154,66 @@ pub async fn interactive_mode(
                 println!("{}", "Goodbye!".yellow());
                 break;
             }
-            "clear" => {
-                messages.clear();
-                println!("{}", "Chat history cleared".yellow());
+            "clear" | "clear --all" => {
+                if input.ends_with("--all") {
+                    pins.clear();
+                }
+                // The system prompt stays; `:system clear` removes it
+                retain_pinned(&mut messages, &mut pins, |m| m.role == "system");
+                if pins.is_empty() {
+                    println!("{}", "Chat history cleared".yellow());
+                } else {
+                    println!(
+                        "{}",
+                        format!(
+                            "Chat history cleared except {} pinned message(s) (clear --all drops them too)",
+                            pins.len()
+                        )
+                        .yellow()
+                    );
+                }
+                continue;
+            }
+            ":pin" => {
+                print_pins(&messages, &pins);
+                continue;
+            }
+            _ if input.starts_with(":pin ") || input.starts_with(":unpin ") => {
+                let pin = input.starts_with(":pin ");
+                let index = input
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|n| n.parse::<usize>().ok())
+                    .filter(|n| (1..=messages.len()).contains(n));
+                let Some(n) = index else {
+                    println!(
+                        "usage: :pin|:unpin <n>, where n is a message number from :pin (1-{})",
+                        messages.len()
+                    );
+                    continue;
+                };
+                if !pin {
+                    if pins.remove(&(n - 1)) {
+                        println!("Unpinned message {}", n);
+                    } else {
+                        println!("Message {} is not pinned", n);
+                    }
+                } else if messages[n - 1].role == "system" {
+                    println!("The system prompt is set with `system <prompt>`; it can't be pinned");
+                } else {
+                    pins.insert(n - 1);
+                    println!("📌 Pinned message {}", n);
+                }
                 continue;
             }
             _ if input.starts_with(":new") => {
                 session_id = if let Some(rest) = input.split_whitespace().nth(1) {
                     rest.to_string()
                 } else {
-                    format!("s-{}", time::OffsetDateTime::now_utc().unix_timestamp())
+                    new_session_id()
                 };
                 messages.clear();
+                pins.clear();
                 println!("{} {}", "Started new session".green(), session_id.dimmed());
                 continue;
             }
@@ -91,38 +224,101 @@ pub async fn interactive_mode(
                 } else {
                     session_id = id.to_string();
                     messages = SessionStore::load(&session_id).unwrap_or_default();
+                    pins.clear();
+                    if let Ok(Some(stored)) = SessionStore::load_sampling(&session_id) {
+                        sampling = stored.or(defaults);
+                    }
                     println!(
-                        "{} {} ({} messages)",
+                        "{} {} ({} messages, {})",
                         "Loaded session".green(),
                         session_id.dimmed(),
-                        messages.len()
+                        messages.len(),
+                        sampling
                     );
                 }
                 continue;
             }
+            _ if input == ":fork" || input.starts_with(":fork ") => {
+                let fork_id = match input.split_whitespace().nth(1) {
+                    Some(id) => id.to_string(),
+                    None => new_session_id(),
+                };
+                if fork_id == session_id
+                    || !SessionStore::load(&fork_id).unwrap_or_default().is_empty()
+                {
+                    println!(
+                        "{} session {} already exists; pass a new id: :fork <new-id>",
+                        "Error:".red(),
+                        fork_id
+                    );
+                    continue;
+                }
+                if let Err(e) = SessionStore::save(&fork_id, &messages)
+                    .and_then(|_| SessionStore::save_sampling(&fork_id, &sampling))
+                {
+                    println!("{} {}", "Error:".red(), e);
+                    continue;
+                }
+                println!(
+                    "{} {} from {} ({} messages); the original is unchanged",
+                    "Forked to".green(),
+                    fork_id.dimmed(),
+                    session_id.dimmed(),
+                    messages.len()
+                );
+                session_id = fork_id;
+                continue;
+            }
+            ":help" => {
+                print_commands(false);
+                continue;
+            }
             _ if input == ":status" => {
+                let persist = if SessionStore::ephemeral() {
+                    " (ephemeral, not saved)"
+                } else {
+                    ""
+                };
                 println!(
-                    "session={} messages={} model={} stream={}",
+                    "session={}{} messages={} model={} stream={} {}",
                     session_id,
+                    persist,
                     messages.len(),
                     current_model,
-                    stream
+                    stream,
+                    sampling
                 );
+                match system_text(&messages) {
+                    Some(text) => println!("system prompt: {}", preview(&text, 60)),
+                    None => println!("system prompt: none"),
+                }
+                println!("data dir: {}", SessionStore::data_dir().display());
+                if let Some(limits) = client.rate_limits() {
+                    println!("rate limit: {}", limits);
+                }
                 continue;
             }
-            _ if input.starts_with("system ") => {
-                let system_content = input.strip_prefix("system ").unwrap();
-                messages.retain(|m| m.role != "system");
-                messages.insert(
-                    0,
-                    Message {
-                        role: "system".to_string(),
-                        content: Some(system_content.to_string()),
-                        tool_calls: None,
-                        tool_call_id: None,
-                    },
-                );
-                current_system = Some(system_content.to_string());
+            ":system" => {
+                match system_text(&messages) {
+                    Some(text) => println!("{}", text),
+                    None => println!("no system prompt (set one with `system <prompt>`)"),
+                }
+                continue;
+            }
+            ":system clear" => {
+                if system_text(&messages).is_none() {
+                    println!("no system prompt to clear");
+                    continue;
+                }
+                set_system(&mut messages, &mut pins, None);
+                let _ = SessionStore::save(&session_id, &messages);
+                println!("{}", "System prompt removed".yellow());
+                continue;
+            }
+            _ if input.starts_with("system ") || input.starts_with(":system ") => {
+                let system_content = input.split_once(' ').map_or("", |(_, s)| s).trim();
+                set_system(&mut messages, &mut pins, Some(system_content));
+                let _ = SessionStore::save(&session_id, &messages);
                 println!("{}", "System prompt updated".green());
                 continue;
             }
@@ -170,11 +366,25 @@ pub async fn interactive_mode(
             }
             _ if input == ":tools on" => {
                 println!("Switching to tools mode...");
-                let _ = crate::chat_with_tools::interactive_mode_with_tools(
+                let result = crate::chat_with_tools::interactive_mode_with_tools(
                     client,
-                    current_system.clone(),
+                    // Already in `messages`
+                    None,
+                    &mut session_id,
+                    &mut messages,
+                    &mut sampling,
+                    &mut mcp,
+                    auto_approve,
                 )
                 .await;
+                if let Err(e) = result {
+                    eprintln!("{} {}", "Error:".red(), e);
+                }
+                // Leaving tools mode stops its servers; the next `:tools on` restarts them
+                mcp.close().await;
+                if crate::chat_with_tools::quit_requested() {
+                    break;
+                }
                 println!("(exited tools mode)\n");
                 continue;
             }
@@ -227,46 +437,386 @@ pub async fn interactive_mode(
   read_file: {{\"path\": \"src/main.rs\", \"start_line\": 1, \"end_line\": 80}}
   write_file: {{\"path\": \"notes.txt\", \"content\": \"Hello\", \"append\": true}}
   find_text: {{\"root\": \"src\", \"pattern\": \"async fn\", \"max_results\": 50}}
+  search_replace: {{\"root\": \"src\", \"pattern\": \"old_name\", \"replacement\": \"new_name\", \"glob\": \"**/*.rs\", \"dry_run\": true}}
+  list_dir: {{\"path\": \"src\", \"max_entries\": 100, \"show_hidden\": false}}
+  glob: {{\"pattern\": \"src/**/*.rs\", \"root\": \".\", \"max_results\": 100}}
   git_diff: {{\"rev\": \"HEAD\", \"path\": \"src\"}}
+  git_log: {{\"max_count\": 10}}
+  cargo_test: {{\"package\": \"core\", \"test_name\": \"parse\"}}
   http_get: {{\"url\": \"https://example.com\", \"max_bytes\": 65536}}
+  fetch_page: {{\"url\": \"https://example.com/post\", \"max_bytes\": 16384}}
   edit_file: {{\"path\": \"src/lib.rs\", \"diff\": \"--- a\\n+++ b\\n@@ -1 +1 @@\\n-old\\n+new\\n\"}}
 "
                 );
+                print_custom_tools_help();
                 continue;
             }
-            _ if input == ":models" => {
-                match client.list_models().await {
-                    Ok(mut list) => {
-                        list.sort();
-                        cached_models = list.clone();
-                        for (i, m) in list.iter().enumerate().take(50) {
-                            println!("{:>2}. {}", i + 1, m);
-                        }
-                        if list.len() > 50 {
-                            println!("... {} more", list.len() - 50);
+            _ if input == ":copy" || input == ":copy code" => {
+                let Some(last) = messages
+                    .iter()
+                    .rev()
+                    .find(|m| m.role == "assistant")
+                    .and_then(|m| m.content.as_ref())
+                    .map(|c| c.text())
+                else {
+                    println!("nothing to copy yet");
+                    continue;
+                };
+                let text = if input == ":copy code" {
+                    match first_code_block(&last) {
+                        Some(code) => code,
+                        None => {
+                            println!("no code block in the last response");
+                            continue;
                         }
-                        println!("use :model <number> to select");
                     }
-                    Err(e) => eprintln!("models error: {}", e),
+                } else {
+                    last
+                };
+                let cb = match &mut clipboard {
+                    Some(cb) => cb,
+                    None => match arboard::Clipboard::new() {
+                        Ok(cb) => clipboard.insert(cb),
+                        Err(e) => {
+                            println!("{} clipboard unavailable: {}", "warning:".yellow(), e);
+                            continue;
+                        }
+                    },
+                };
+                match cb.set_text(text.clone()) {
+                    Ok(()) => println!("{} ({} chars)", "Copied".green(), text.chars().count()),
+                    Err(e) => println!("{} clipboard unavailable: {}", "warning:".yellow(), e),
                 }
                 continue;
             }
-            _ if input.starts_with(":model ") => {
-                let arg = input.split_whitespace().nth(1).unwrap_or("");
-                if arg.is_empty() {
-                    println!("usage: :model <name|index>");
+            _ if input.starts_with(":timings") => {
+                if let Some(val) = input.split_whitespace().nth(1) {
+                    timings = matches!(val.to_lowercase().as_str(), "on" | "true" | "1");
+                    crate::wrap::set_live_rate(timings);
+                }
+                println!("timings={}", timings);
+                continue;
+            }
+            _ if input == ":summarize" || input == ":summarize --replace" => {
+                if !messages.iter().any(|m| m.role != "system") {
+                    println!("nothing to summarize yet");
                     continue;
                 }
-                if let Ok(idx) = arg.parse::<usize>() {
-                    if idx == 0 || idx > cached_models.len() {
-                        println!("invalid index");
+                let tools = crate::config::Config::load().unwrap_or_default().tools;
+                let mut request = crate::chat_with_tools::for_model(&messages, &tools);
+                request.push(Message {
+                    role: "user".to_string(),
+                    content: Some(SUMMARIZE_PROMPT.into()),
+                    tool_calls: None,
+                    tool_call_id: None,
+                    tokens: None,
+                });
+                print!("{} ", "Summary:".bold().blue());
+                io::stdout().flush()?;
+                let summary = match client
+                    .with_model(&current_model)
+                    .complete_with_history(request, 0.3, stream)
+                    .await
+                {
+                    Ok(summary) => summary,
+                    Err(e) => {
+                        eprintln!("summarize failed: {}", e);
                         continue;
                     }
-                    current_model = cached_models[idx - 1].clone();
+                };
+                if !stream {
+                    println!("{}", summary);
+                }
+                if input.ends_with("--replace") {
+                    let before = messages.len();
+                    retain_pinned(&mut messages, &mut pins, |m| m.role == "system");
+                    messages.push(Message {
+                        role: "assistant".to_string(),
+                        content: Some(
+                            format!("Summary of the conversation so far:\n{}", summary).into(),
+                        ),
+                        tool_calls: None,
+                        tool_call_id: None,
+                        tokens: None,
+                    });
+                    let _ = SessionStore::save(&session_id, &messages);
+                    println!(
+                        "{}",
+                        format!(
+                            "History replaced by the summary ({} -> {} messages)",
+                            before,
+                            messages.len()
+                        )
+                        .yellow()
+                    );
+                }
+                continue;
+            }
+            _ if input.starts_with(":history") => {
+                let count = input
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|n| n.parse().ok())
+                    .unwrap_or(20);
+                let entries = editor.history().entries();
+                let start = entries.len().saturating_sub(count + 1);
+                // Skip the ":history" line that was just recorded
+                for (i, line) in entries[..entries.len().saturating_sub(1)]
+                    .iter()
+                    .enumerate()
+                    .skip(start)
+                {
+                    // Multi-line entries continue under the first line
+                    println!("{:>4}  {}", i + 1, line.replace('\n', "\n      "));
+                }
+                continue;
+            }
+            _ if input.starts_with(":wrap") => {
+                if let Some(val) = input.split_whitespace().nth(1) {
+                    match crate::wrap::parse_mode(val) {
+                        Some(mode) => crate::wrap::set_mode(mode),
+                        None => println!("usage: :wrap <cols|auto|off>"),
+                    }
+                }
+                println!("wrap={:?}", crate::wrap::mode());
+                continue;
+            }
+            _ if matches!(
+                input.split_whitespace().next(),
+                Some(":temperature" | ":top_p" | ":max_tokens")
+            ) =>
+            {
+                let mut parts = input.split_whitespace();
+                let key = parts.next().unwrap_or("").trim_start_matches(':');
+                if let Some(value) = parts.next() {
+                    if let Err(e) = sampling.set(key, value) {
+                        println!("{} {}", "error:".red(), e);
+                        continue;
+                    }
+                    if let Err(e) = SessionStore::save_sampling(&session_id, &sampling) {
+                        println!("{} not saved to the session: {}", "warning:".yellow(), e);
+                    }
+                }
+                println!("{}", sampling);
+                continue;
+            }
+            ":attach" => {
+                if attachments.is_empty() {
+                    println!("usage: :attach <path|glob>... | :attach clear");
                 } else {
-                    current_model = arg.to_string();
+                    for path in attachments.paths() {
+                        println!("  {}", path);
+                    }
                 }
-                println!("model set to {}", current_model);
+                continue;
+            }
+            ":attach clear" => {
+                attachments.clear();
+                println!("{}", "Attachments dropped".yellow());
+                continue;
+            }
+            _ if input.starts_with(":attach ") => {
+                let paths = match attach_paths(input) {
+                    Ok(paths) => paths,
+                    Err(e) => {
+                        println!("{} {}", "error:".red(), e);
+                        continue;
+                    }
+                };
+                let cfg = crate::config::Config::load().unwrap_or_default();
+                attachments.add(&paths, cfg.attachment_limit());
+                continue;
+            }
+            _ if input.starts_with(":resource") => {
+                let mut args = input.split_whitespace().skip(1);
+                let (Some(server), Some(uri)) = (args.next(), args.next()) else {
+                    println!("usage: :resource <server> <uri>");
+                    continue;
+                };
+                let cfg = crate::config::Config::load().unwrap_or_default();
+                mcp.start(&cfg.mcp_servers).await;
+                let Some(found) = mcp.server(server) else {
+                    println!("{} no running MCP server named {}", "error:".red(), server);
+                    continue;
+                };
+                let contents = match found.read_resource(uri).await {
+                    Ok(contents) => contents,
+                    Err(e) => {
+                        println!("{} {}", "error:".red(), e);
+                        continue;
+                    }
+                };
+                let (text, truncated) = resource_message(uri, &contents, cfg.attachment_limit());
+                if let Some(total) = truncated {
+                    println!(
+                        "{} resource is {} chars; kept the first {} (attachment_max_chars)",
+                        "warning:".yellow(),
+                        total,
+                        cfg.attachment_limit()
+                    );
+                }
+                println!(
+                    "{} {} ({} chars)",
+                    "Added resource".green(),
+                    uri,
+                    text.chars().count()
+                );
+                messages.push(Message {
+                    role: "user".to_string(),
+                    content: Some(text.into()),
+                    tool_calls: None,
+                    tool_call_id: None,
+                    tokens: None,
+                });
+                let _ = SessionStore::save(&session_id, &messages);
+                continue;
+            }
+            _ if input.starts_with(":prompt") => {
+                // Values may be quoted: :prompt team review code="fn main() {}"
+                let words = shlex::split(input).unwrap_or_default();
+                let (Some(server), Some(name)) = (words.get(1), words.get(2)) else {
+                    println!("usage: :prompt <server> <name> [key=value...]");
+                    continue;
+                };
+                let mut args = std::collections::HashMap::new();
+                let mut bad = None;
+                for word in &words[3..] {
+                    match word.split_once('=') {
+                        Some((k, v)) => {
+                            args.insert(k.to_string(), v.to_string());
+                        }
+                        None => bad = Some(word),
+                    }
+                }
+                if let Some(word) = bad {
+                    println!("{} {}: expected key=value", "error:".red(), word);
+                    continue;
+                }
+                let cfg = crate::config::Config::load().unwrap_or_default();
+                mcp.start(&cfg.mcp_servers).await;
+                let Some(found) = mcp.server(server) else {
+                    println!("{} no running MCP server named {}", "error:".red(), server);
+                    continue;
+                };
+                let prompts = match found.list_prompts().await {
+                    Ok(prompts) => prompts,
+                    Err(e) => {
+                        println!("{} {}", "error:".red(), e);
+                        continue;
+                    }
+                };
+                let Some(prompt) = prompts.iter().find(|p| &p.name == name) else {
+                    let names: Vec<&str> = prompts.iter().map(|p| p.name.as_str()).collect();
+                    println!(
+                        "{} {} has no prompt named {} (available: {})",
+                        "error:".red(),
+                        server,
+                        name,
+                        if names.is_empty() {
+                            "none".to_string()
+                        } else {
+                            names.join(", ")
+                        }
+                    );
+                    continue;
+                };
+                let missing = prompt.missing(&args);
+                if !missing.is_empty() {
+                    let names: Vec<&str> = missing.iter().map(|a| a.name.as_str()).collect();
+                    println!("{} missing {}", "error:".red(), names.join(", "));
+                    print_prompt_usage(server, prompt);
+                    continue;
+                }
+                let prompt_messages = match found.get_prompt(name, &args).await {
+                    Ok(m) => m,
+                    Err(e) => {
+                        println!("{} {}", "error:".red(), e);
+                        continue;
+                    }
+                };
+                for m in &prompt_messages {
+                    messages.push(Message {
+                        role: m.role.clone(),
+                        content: Some(m.text().into()),
+                        tool_calls: None,
+                        tool_call_id: None,
+                        tokens: None,
+                    });
+                }
+                let _ = SessionStore::save(&session_id, &messages);
+                println!(
+                    "{} {} ({} messages); they go out with your next message",
+                    "Added prompt".green(),
+                    name,
+                    prompt_messages.len()
+                );
+                continue;
+            }
+            // `!cmd` runs a command for you; `!!cmd` also adds its output to the conversation
+            _ if input.starts_with('!') => {
+                let inject = input.starts_with("!!");
+                let command = input.trim_start_matches('!').trim();
+                if command.is_empty() {
+                    println!("usage: !<command> | !!<command>");
+                    continue;
+                }
+                if !inject {
+                    if let Err(e) = run_inline(command).await {
+                        eprintln!("{} {}", "Error:".red(), e);
+                    }
+                    continue;
+                }
+                let cfg = crate::config::Config::load().unwrap_or_default();
+                if !crate::tools::policy_allows(&cfg.tool_policy, crate::tools::ToolAccess::Shell) {
+                    println!("!! is off: [tool_policy] doesn't allow shell commands (use ! to run without adding the output)");
+                    continue;
+                }
+                let interrupt = crate::chat_with_tools::ctrl_c();
+                interrupt.begin();
+                let output = tokio::select! {
+                    output = crate::tools::run_shell(command, &cfg.tools.shell) => Some(output),
+                    _ = interrupt.wait() => None,
+                };
+                interrupt.end();
+                let output = match output {
+                    Some(Ok(output)) => output,
+                    Some(Err(e)) => {
+                        eprintln!("{} {}", "Error:".red(), e);
+                        continue;
+                    }
+                    None => {
+                        println!("{}", "interrupted; nothing added".yellow());
+                        continue;
+                    }
+                };
+                println!("{}", output);
+                // The same approval the shell tool needs in tools mode
+                let asks = !cfg.tools.auto_approve.unwrap_or(false)
+                    && cfg.tools.confirm_list().iter().any(|t| t == "shell");
+                if asks {
+                    print!("Add this output to the conversation? [y/N] ");
+                    io::stdout().flush()?;
+                    let mut answer = String::new();
+                    io::stdin().read_line(&mut answer)?;
+                    if !matches!(answer.trim().to_lowercase().as_str(), "y" | "yes") {
+                        println!("not added");
+                        continue;
+                    }
+                }
+                messages.push(Message {
+                    role: "user".to_string(),
+                    content: Some(
+                        format!("I ran `{}`:\n```\n{}\n```", command, output.trim_end()).into(),
+                    ),
+                    tool_calls: None,
+                    tool_call_id: None,
+                    tokens: None,
+                });
+                let _ = SessionStore::save(&session_id, &messages);
+                println!(
+                    "{}",
+                    "Added to the conversation; it goes out with your next message".green()
+                );
                 continue;
             }
             _ if input.starts_with(":stream ") => {
@@ -278,25 +828,87 @@ pub async fn interactive_mode(
             _ => {}
         }
 
-        messages.push(Message {
-            role: "user".to_string(),
-            content: Some(input.to_string()),
-            tool_calls: None,
-            tool_call_id: None,
-        });
+        // `:retry [temperature]` rerolls the last reply; other input is a new turn
+        let mut turn_sampling = sampling;
+        if input == ":retry" || input.starts_with(":retry ") {
+            if let Some(value) = input.split_whitespace().nth(1) {
+                match value.parse::<f32>() {
+                    Ok(t) if (0.0..=2.0).contains(&t) => turn_sampling.temperature = Some(t),
+                    _ => {
+                        println!("usage: :retry [temperature 0-2]");
+                        continue;
+                    }
+                }
+            }
+            // Rewind to just after the last prompt, dropping the reply and any tool calls
+            let Some(last_user) = messages.iter().rposition(|m| m.role == "user") else {
+                println!("nothing to retry yet");
+                continue;
+            };
+            messages.truncate(last_user + 1);
+            pins.retain(|&i| i <= last_user);
+            attempt += 1;
+        } else {
+            // `:template <name>` sends the filled-in template as this turn's prompt
+            let input = match input.strip_prefix(":template") {
+                Some(name) if name.trim().is_empty() => {
+                    let names = crate::templates::names();
+                    if names.is_empty() {
+                        println!(
+                            "{}",
+                            "No templates; add them under [templates] in config".dimmed()
+                        );
+                    } else {
+                        println!("{}", names.join("\n"));
+                    }
+                    continue;
+                }
+                Some(name) if name.starts_with(' ') => match fill_template(name.trim()) {
+                    Ok(prompt) => {
+                        println!("{}", prompt.dimmed());
+                        prompt
+                    }
+                    Err(e) => {
+                        eprintln!("{} {}", "Error:".red(), e);
+                        continue;
+                    }
+                },
+                _ => input.to_string(),
+            };
+            let input = if attachments.is_empty() {
+                input
+            } else {
+                format!("{}\n\n{}", attachments.take(), input)
+            };
 
-        print!("{} ", "Rusty:".bold().blue());
+            messages.push(Message {
+                role: "user".to_string(),
+                content: Some(input.clone().into()),
+                tool_calls: None,
+                tool_call_id: None,
+                tokens: None,
+            });
+            attempt = 1;
+        }
+
+        let label = if attempt > 1 {
+            format!("Rusty (attempt {}):", attempt)
+        } else {
+            "Rusty:".to_string()
+        };
+        print!("{} ", label.bold().blue());
         io::stdout().flush()?;
         // Thinking indicator for non‑streaming responses
         let show_thinking = !stream;
         let thinking = if show_thinking {
+            let label = label.clone();
             Some(tokio::spawn(async move {
                 let mut i = 0u64;
                 loop {
                     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
                     i += 1;
                     let status = format!("[thinking {}s]", i).bold().bright_black();
-                    print!("\r{} {} ", "Rusty:".bold().blue(), status);
+                    print!("\r{} {} ", label.bold().blue(), status);
                     let _ = io::stdout().flush();
                 }
             }))
@@ -304,32 +916,515 @@ pub async fn interactive_mode(
             None
         };
 
-        let derived = client.with_model(&current_model);
+        let derived = client
+            .with_model(&current_model)
+            .with_sampling(&turn_sampling);
+        let started = Instant::now();
+        // Tool results from tools mode are stored whole
+        let tools = crate::config::Config::load().unwrap_or_default().tools;
+        let request = crate::chat_with_tools::for_model(&messages, &tools);
         let response = derived
-            .complete_with_history(messages.clone(), 0.7, stream)
+            .complete_timed(request, turn_sampling.temperature(), stream)
             .await;
         if let Some(handle) = thinking {
             handle.abort();
         }
         // Clear the thinking status and restore the label
         if show_thinking {
-            print!("\r{} ", "Rusty:".bold().blue());
+            print!("\r{} ", label.bold().blue());
             io::stdout().flush()?;
         }
-        let response = response?;
+        let (response, first_token) = response?;
+        if timings {
+            println!(
+                "{}",
+                timing_summary(started, first_token, &response).dimmed()
+            );
+        }
 
         messages.push(Message {
             role: "assistant".to_string(),
-            content: Some(response),
+            content: Some(response.into()),
             tool_calls: None,
             tool_call_id: None,
+            tokens: derived.last_usage().and_then(|u| u.completion_tokens),
         });
+        record_cost(
+            Some(&session_id),
+            derived.model_name(),
+            derived.last_usage(),
+        );
 
         // Persist after each turn
         let _ = SessionStore::save(&session_id, &messages);
+        let _ = SessionStore::save_sampling(&session_id, &sampling);
 
         println!();
     }
 
+    mcp.close().await;
+    Ok(())
+}
+
+/// Run a `!` command on the terminal, output going straight to it. Ctrl-C
+/// stops the command, not the REPL.
+async fn run_inline(command: &str) -> Result<()> {
+    let mut child = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .kill_on_drop(true)
+        .spawn()?;
+    let interrupt = crate::chat_with_tools::ctrl_c();
+    interrupt.begin();
+    let status = tokio::select! {
+        status = child.wait() => Some(status),
+        _ = interrupt.wait() => None,
+    };
+    interrupt.end();
+    match status {
+        Some(status) => {
+            let status = status?;
+            if !status.success() {
+                println!("{}", format!("[{}]", status).dimmed());
+            }
+        }
+        None => println!("{}", "interrupted".yellow()),
+    }
     Ok(())
 }
+
+/// The paths of an `:attach` line, which may be quoted:
+/// `:attach "notes/meeting 1.md" src/*.rs`.
+fn attach_paths(input: &str) -> std::result::Result<Vec<String>, String> {
+    let words = shlex::split(input).ok_or("unbalanced quotes; usage: :attach <path|glob>...")?;
+    match words.get(1..) {
+        Some(paths) if !paths.is_empty() => Ok(paths.to_vec()),
+        _ => Err("usage: :attach <path|glob>...".into()),
+    }
+}
+
+/// Keep the messages `keep` accepts and the pinned ones, renumbering the pins
+/// to match.
+fn retain_pinned(
+    messages: &mut Vec<Message>,
+    pins: &mut BTreeSet<usize>,
+    keep: impl Fn(&Message) -> bool,
+) {
+    let mut kept = BTreeSet::new();
+    let mut index = 0;
+    let mut i = 0;
+    messages.retain(|m| {
+        let pinned = pins.contains(&i);
+        i += 1;
+        if pinned || keep(m) {
+            if pinned {
+                kept.insert(index);
+            }
+            index += 1;
+            true
+        } else {
+            false
+        }
+    });
+    *pins = kept;
+}
+
+/// The conversation's system prompt, if it has one.
+fn system_text(messages: &[Message]) -> Option<String> {
+    messages
+        .iter()
+        .find(|m| m.role == "system")
+        .and_then(|m| m.content.as_ref())
+        .map(|c| c.text())
+}
+
+/// Make `text` the only system message, first in the conversation, or drop
+/// the system prompt with `None`. Pins follow their messages.
+fn set_system(messages: &mut Vec<Message>, pins: &mut BTreeSet<usize>, text: Option<&str>) {
+    retain_pinned(messages, pins, |m| m.role != "system");
+    let Some(text) = text else {
+        return;
+    };
+    *pins = pins.iter().map(|i| i + 1).collect();
+    messages.insert(
+        0,
+        Message {
+            role: "system".to_string(),
+            content: Some(text.to_string().into()),
+            tool_calls: None,
+            tool_call_id: None,
+            tokens: None,
+        },
+    );
+}
+
+/// The first line of `text`, cut to `max` chars with an ellipsis if anything was left out.
+fn preview(text: &str, max: usize) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    let short: String = line.chars().take(max).collect();
+    if short.len() < text.len() {
+        format!("{}…", short)
+    } else {
+        short
+    }
+}
+
+/// Number the conversation for `:pin`, marking pinned messages.
+fn print_pins(messages: &[Message], pins: &BTreeSet<usize>) {
+    if messages.is_empty() {
+        println!("no messages yet");
+        return;
+    }
+    for (i, m) in messages.iter().enumerate() {
+        let text = m.content.as_ref().map(|c| c.text()).unwrap_or_default();
+        let marker = if pins.contains(&i) { "📌" } else { "  " };
+        println!(
+            "{:>4} {} {:<9} {}",
+            i + 1,
+            marker,
+            m.role.dimmed(),
+            preview(&text, 60)
+        );
+    }
+}
+
+/// The user message carrying an MCP resource: each part headed by its URI and
+/// MIME type, cut to `limit` chars. Also returns the full length when cut.
+fn resource_message(
+    uri: &str,
+    contents: &[crate::mcp::ResourceContents],
+    limit: usize,
+) -> (String, Option<usize>) {
+    let mut text = String::new();
+    for part in contents {
+        let mime = part.mime_type.as_deref().unwrap_or("text/plain");
+        text.push_str(&format!(
+            "Resource {} ({}):\n{}\n\n",
+            part.uri,
+            mime,
+            part.to_text()
+        ));
+    }
+    if contents.is_empty() {
+        text = format!("Resource {} is empty.", uri);
+    }
+    let text = text.trim_end().to_string();
+    let total = text.chars().count();
+    if total <= limit {
+        return (text, None);
+    }
+    let mut cut: String = text.chars().take(limit).collect();
+    cut.push_str(&format!(
+        "\n[... truncated: showing {} of {} chars ...]",
+        limit, total
+    ));
+    (cut, Some(total))
+}
+
+/// Load template `name` and ask for each of its variables on stdin; blank
+/// answers are left unfilled so the error lists them.
+/// Usage line for an MCP prompt followed by its arguments and descriptions.
+fn print_prompt_usage(server: &str, prompt: &crate::mcp::MCPPrompt) {
+    println!("usage: {}", prompt.usage(server));
+    for arg in &prompt.arguments {
+        let required = if arg.required { " (required)" } else { "" };
+        println!(
+            "  {}{}  {}",
+            arg.name.yellow(),
+            required,
+            arg.description.as_deref().unwrap_or("").dimmed()
+        );
+    }
+}
+
+fn fill_template(name: &str) -> Result<String> {
+    let text = crate::templates::load(name)?;
+    let mut vars = std::collections::HashMap::new();
+    for var in crate::templates::placeholders(&text) {
+        print!("{}: ", var.cyan());
+        io::stdout().flush()?;
+        let mut value = String::new();
+        io::stdin().read_line(&mut value)?;
+        let value = value.trim_end_matches(['\n', '\r']);
+        if !value.is_empty() {
+            vars.insert(var, value.to_string());
+        }
+    }
+    crate::templates::render(name, &text, &vars)
+}
+
+const SUMMARIZE_PROMPT: &str =
+    "Summarize our conversation so far in a few short paragraphs or bullet points. \
+Keep decisions, open questions, file names and code identifiers; drop pleasantries.";
+
+/// Every REPL command as (usage, description, shown in the startup banner).
+/// `:help` and the banner both read from this list.
+const COMMANDS: &[(&str, &str, bool)] = &[
+    ("exit | quit", "End the session", true),
+    ("clear", "Clear chat history", true),
+    (":help", "List all commands", true),
+    (":new [id]", "Start a new session", true),
+    (":session <id>", "Switch to another session", true),
+    (
+        ":fork [new-id]",
+        "Copy this session to a new id and continue there",
+        false,
+    ),
+    (
+        ":status",
+        "Show session, model, stream and sampling settings",
+        false,
+    ),
+    ("system <prompt>", "Set a new system prompt", true),
+    (
+        ":system [clear]",
+        "Show the system prompt, or remove it",
+        false,
+    ),
+    (
+        ":models",
+        "List the provider's models (pick the provider with --provider)",
+        false,
+    ),
+    (
+        ":model <name|number>",
+        "Switch model by name or :models index",
+        false,
+    ),
+    (
+        ":attach <path|glob>...",
+        "Send files with the next message (:attach clear drops them)",
+        false,
+    ),
+    (
+        ":resource <server> <uri>",
+        "Add an MCP server's resource to the conversation",
+        false,
+    ),
+    (
+        ":prompt <server> <name> [key=value...]",
+        "Add an MCP server's prompt to the conversation",
+        false,
+    ),
+    (":stream on|off", "Toggle streaming output", false),
+    (
+        ":temperature <0-2|off>",
+        "Set the sampling temperature (saved with the session)",
+        false,
+    ),
+    (":top_p <0-1|off>", "Set nucleus sampling", false),
+    (":max_tokens <n|off>", "Cap the reply length", false),
+    (":timings on|off", "Show latency after each reply", false),
+    (":wrap <cols|auto|off>", "Word-wrap streamed replies", false),
+    (
+        ":template [name]",
+        "Fill in a prompt template and send it (no name lists them)",
+        false,
+    ),
+    (":tools list", "List available tools", false),
+    (":tools help", "Show example tool arguments", false),
+    (":tools on", "Enter tools mode", true),
+    (
+        ":tools enable|disable <name>",
+        "In tools mode, stop or resume offering a tool",
+        false,
+    ),
+    (
+        ":pin [n]",
+        "Keep message n through clear and :summarize --replace (no n lists them)",
+        false,
+    ),
+    (":unpin <n>", "Stop keeping message n", false),
+    (
+        "clear --all",
+        "Clear chat history, pinned messages too",
+        false,
+    ),
+    (
+        ":retry [temperature]",
+        "Regenerate the last reply, optionally at another temperature",
+        false,
+    ),
+    ("!<command>", "Run a shell command", false),
+    (
+        "!!<command>",
+        "Run a shell command and add its output to the conversation",
+        false,
+    ),
+    (":summarize", "Print a recap of this session", false),
+    (
+        ":summarize --replace",
+        "Swap the history for its summary (keeps the system prompt)",
+        false,
+    ),
+    (
+        ":history [n]",
+        "Show the last n prompts (saved across runs)",
+        false,
+    ),
+    (":copy", "Copy the last response to the clipboard", false),
+    (
+        ":copy code",
+        "Copy the first code block of the last response",
+        false,
+    ),
+    (":keys", "Set API keys for providers", false),
+];
+
+/// Print the command list; the banner shows only the essentials.
+fn print_commands(banner: bool) {
+    let width = COMMANDS
+        .iter()
+        .map(|(usage, _, _)| usage.len())
+        .max()
+        .unwrap_or(0);
+    for (usage, description, in_banner) in COMMANDS {
+        if banner && !in_banner {
+            continue;
+        }
+        println!("  {:<width$}  {}", usage.yellow(), description.dimmed());
+    }
+    if banner {
+        println!("{}", "  Type :help for all commands".dimmed());
+    }
+}
+
+/// Describe `[[custom_tools]]` from config and how their templates are filled.
+fn print_custom_tools_help() {
+    let cfg = match crate::config::Config::load() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            println!("Custom tools: config error: {}", e);
+            return;
+        }
+    };
+    if cfg.custom_tools.is_empty() {
+        println!("No custom tools; add [[custom_tools]] entries to the config to define some.");
+        return;
+    }
+    println!("Custom tools (from config):");
+    for tool in &cfg.custom_tools {
+        let params = tool.placeholders();
+        println!(
+            "  {}: {}\n    command: {}{}\n    params: {}",
+            tool.name.get_ref(),
+            tool.description,
+            tool.command.line(),
+            if tool.shell { "  (via sh -c)" } else { "" },
+            if params.is_empty() {
+                "none".to_string()
+            } else {
+                params.join(", ")
+            }
+        );
+    }
+    println!(
+        "Templates: {{param}} is replaced by the argument's value. A word that is only\n\
+         {{param}} becomes one argument (one per item for arrays, none if omitted).\n\
+         Values are passed as separate argv entries, never through a shell, unless\n\
+         shell = true, where they are quoted into an sh -c line."
+    );
+}
+
+/// Body of the first ``` or ~~~ fenced block, without the fence lines. As in
+/// Markdown, the block ends at a fence of the same character at least as long.
+fn first_code_block(text: &str) -> Option<String> {
+    let mut lines = text.lines();
+    let (mark, len) = lines.by_ref().find_map(opening_fence)?;
+    let mut body = Vec::new();
+    for line in lines {
+        let fence = line.trim();
+        if fence.len() >= len && fence.chars().all(|c| c == mark) {
+            return Some(body.join("\n"));
+        }
+        body.push(line);
+    }
+    // Unclosed fence: take the rest
+    Some(body.join("\n"))
+}
+
+/// The fence character and length if `line` opens a fenced block.
+fn opening_fence(line: &str) -> Option<(char, usize)> {
+    let line = line.trim_start();
+    let mark = line.chars().next().filter(|c| matches!(c, '`' | '~'))?;
+    let len = line.chars().take_while(|&c| c == mark).count();
+    (len >= 3).then_some((mark, len))
+}
+
+/// Format `(1.8s, first token 0.4s, ~42 tok/s)` for a finished response.
+fn timing_summary(started: Instant, first_token: Option<Instant>, text: &str) -> String {
+    let elapsed = started.elapsed().as_secs_f64();
+    let tokens = crate::api::estimate_tokens(text);
+    let mut parts = vec![format!("{:.1}s", elapsed)];
+    if let Some(first) = first_token {
+        parts.push(format!(
+            "first token {:.1}s",
+            first.duration_since(started).as_secs_f64()
+        ));
+    }
+    if elapsed > 0.0 {
+        parts.push(format!("~{:.0} tok/s", tokens as f64 / elapsed));
+    }
+    format!("({})", parts.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_code_block_takes_the_body_of_the_first_fence() {
+        let reply = "Try this:\n```rust\nfn main() {}\n```\nand then\n```\nsecond\n```";
+        assert_eq!(first_code_block(reply).as_deref(), Some("fn main() {}"));
+        let reply = "  ```sh\n  ls -l\n  ```";
+        assert_eq!(first_code_block(reply).as_deref(), Some("  ls -l"));
+    }
+
+    #[test]
+    fn first_code_block_understands_tilde_fences() {
+        let reply = "~~~python\nprint(1)\n~~~";
+        assert_eq!(first_code_block(reply).as_deref(), Some("print(1)"));
+        // A backtick fence inside a tilde block is content, and vice versa
+        let reply = "~~~markdown\n```\ncode\n```\n~~~~";
+        assert_eq!(first_code_block(reply).as_deref(), Some("```\ncode\n```"));
+        let reply = "````\n~~~\n```\n````";
+        assert_eq!(first_code_block(reply).as_deref(), Some("~~~\n```"));
+    }
+
+    #[test]
+    fn an_unterminated_fence_runs_to_the_end() {
+        let reply = "Here:\n```js\nconsole.log(1);\nconsole.log(2);";
+        assert_eq!(
+            first_code_block(reply).as_deref(),
+            Some("console.log(1);\nconsole.log(2);")
+        );
+        assert_eq!(first_code_block("```").as_deref(), Some(""));
+    }
+
+    #[test]
+    fn text_without_a_fence_has_no_code_block() {
+        assert_eq!(first_code_block("just `inline` code"), None);
+        assert_eq!(first_code_block("``\nnot a fence\n``"), None);
+        assert_eq!(first_code_block(""), None);
+    }
+
+    #[test]
+    fn attach_paths_splits_like_a_shell() {
+        assert_eq!(
+            attach_paths(r#":attach "notes/meeting 1.md" src/*.rs"#),
+            Ok(vec![
+                "notes/meeting 1.md".to_string(),
+                "src/*.rs".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn attach_paths_reports_unbalanced_quotes() {
+        let err = attach_paths(r#":attach "foo"#).unwrap_err();
+        assert!(err.starts_with("unbalanced quotes"), "{}", err);
+        assert!(attach_paths(":attach   ")
+            .unwrap_err()
+            .starts_with("usage:"));
+    }
+}
@@ -1,45 +1,370 @@
-use crate::api::{ChatClient, Message};
+use crate::api::{ChatClient, Content, ContentPart, ImageUrl, Message, Sampling};
+use crate::chat::new_session_id;
+use crate::config::Config;
+use crate::history::LineEditor;
+use crate::mcp::MCPRegistry;
+use crate::session::SessionStore;
 use crate::tools::Tool;
-use crate::tools::ToolRegistry;
+use crate::tools::{ToolOutcome, ToolRegistry, ENVELOPE_NOTE};
 use anyhow::Result;
 use colored::*;
+use std::collections::{HashMap, HashSet};
 use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
+/// Outcome of asking the user about a tool call.
+enum Decision {
+    Run(String),
+    Decline,
+}
+
+/// Per-session approval state for tools that need confirmation.
+struct Approvals {
+    auto_approve: bool,
+    confirm: HashSet<String>,
+    always: HashSet<String>,
+}
+
+impl Approvals {
+    fn new(cfg: &Config, yolo: bool) -> Self {
+        let mut confirm: HashSet<String> = cfg.tools.confirm_list().into_iter().collect();
+        // Custom tools run arbitrary programs, so they ask unless told otherwise
+        for tool in &cfg.custom_tools {
+            if tool.confirm.unwrap_or(true) {
+                confirm.insert(tool.name.get_ref().clone());
+            }
+        }
+        Self {
+            auto_approve: yolo || cfg.tools.auto_approve.unwrap_or(false),
+            confirm,
+            always: HashSet::new(),
+        }
+    }
+
+    fn review(&mut self, name: &str, args: &str) -> Result<Decision> {
+        if self.auto_approve || !self.confirm.contains(name) || self.always.contains(name) {
+            return Ok(Decision::Run(args.to_string()));
+        }
+        let mut args = args.to_string();
+        loop {
+            let pretty = serde_json::from_str::<serde_json::Value>(&args)
+                .and_then(|v| serde_json::to_string_pretty(&v))
+                .unwrap_or_else(|_| args.clone());
+            println!("  {} {}", "Approve".bold().yellow(), name.yellow());
+            for line in pretty.lines() {
+                println!("    {}", line);
+            }
+            print!("  [y]es / [n]o / [a]lways this tool / [e]dit args: ");
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            if io::stdin().read_line(&mut answer)? == 0 {
+                return Ok(Decision::Decline);
+            }
+            match answer.trim().to_lowercase().as_str() {
+                "y" | "yes" => return Ok(Decision::Run(args)),
+                "n" | "no" => return Ok(Decision::Decline),
+                "a" | "always" => {
+                    self.always.insert(name.to_string());
+                    return Ok(Decision::Run(args));
+                }
+                "e" | "edit" => match edit_args(&pretty) {
+                    Ok(edited) => args = edited,
+                    Err(e) => eprintln!("  edit failed: {}", e),
+                },
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Let the user rewrite tool arguments in their editor; the result must be valid JSON.
+fn edit_args(pretty: &str) -> Result<String> {
+    let path = std::env::temp_dir().join(format!("rusty-tool-args-{}.json", std::process::id()));
+    std::fs::write(&path, pretty)?;
+    let edited = crate::open_in_editor(&path).and_then(|_| Ok(std::fs::read_to_string(&path)?));
+    let _ = std::fs::remove_file(&path);
+    let edited = edited?;
+    let value: serde_json::Value = serde_json::from_str(&edited)
+        .map_err(|e| anyhow::anyhow!("arguments are not valid JSON: {}", e))?;
+    Ok(value.to_string())
+}
+
+/// Ctrl-C state: while a tool loop runs it cancels the loop, otherwise it
+/// asks the REPL to exit at its next prompt, which saves the session and
+/// shuts servers down on the way out. A second Ctrl-C exits at once.
+pub struct CtrlC {
+    busy: AtomicBool,
+    hit: AtomicBool,
+    quit: AtomicBool,
+}
+
+impl CtrlC {
+    pub fn begin(&self) {
+        self.hit.store(false, Ordering::SeqCst);
+        self.busy.store(true, Ordering::SeqCst);
+    }
+
+    pub fn end(&self) {
+        self.busy.store(false, Ordering::SeqCst);
+    }
+
+    fn hit(&self) -> bool {
+        self.hit.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait(&self) {
+        while !self.hit() {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+    }
+}
+
+static CTRL_C: CtrlC = CtrlC {
+    busy: AtomicBool::new(false),
+    hit: AtomicBool::new(false),
+    quit: AtomicBool::new(false),
+};
+
+/// The Ctrl-C state, installing the handler on first use.
+pub fn ctrl_c() -> &'static CtrlC {
+    static HANDLER: std::sync::Once = std::sync::Once::new();
+    HANDLER.call_once(|| {
+        tokio::spawn(async {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                let state = &CTRL_C;
+                if state.busy.load(Ordering::SeqCst) {
+                    state.hit.store(true, Ordering::SeqCst);
+                } else if !state.quit.swap(true, Ordering::SeqCst) {
+                    println!("\n{}", "Exiting (Ctrl-C again to quit now)".yellow());
+                } else {
+                    println!();
+                    crate::tools::kill_processes();
+                    crate::mcp::kill_servers();
+                    std::process::exit(130);
+                }
+            }
+        });
+    });
+    &CTRL_C
+}
+
+/// Whether Ctrl-C at an idle prompt asked the REPL to exit.
+pub fn quit_requested() -> bool {
+    CTRL_C.quit.load(Ordering::SeqCst)
+}
+
+/// Keep the head and tail of a long tool result within `max_chars`,
+/// cutting on char boundaries and telling the model what was dropped.
+fn truncate_result(result: &str, max_chars: usize) -> String {
+    let total = result.chars().count();
+    if total <= max_chars {
+        return result.to_string();
+    }
+    let head = max_chars / 2;
+    let tail = max_chars - head;
+    let omitted = total - head - tail;
+    let head_end = result
+        .char_indices()
+        .nth(head)
+        .map_or(result.len(), |(i, _)| i);
+    let tail_start = result
+        .char_indices()
+        .nth(total - tail)
+        .map_or(result.len(), |(i, _)| i);
+    format!(
+        "{}\n[truncated, {} chars omitted — re-call with narrower args]\n{}",
+        &result[..head_end],
+        omitted,
+        &result[tail_start..]
+    )
+}
+
+/// The conversation as sent to the model. Tool results are kept whole in the
+/// session and trimmed here to each tool's `tool_result_limits` budget.
+pub fn for_model(messages: &[Message], cfg: &crate::config::ToolsConfig) -> Vec<Message> {
+    let mut names: HashMap<&str, &str> = HashMap::new();
+    messages
+        .iter()
+        .map(|m| {
+            for call in m.tool_calls.iter().flatten() {
+                names.insert(&call.id, &call.function.name);
+            }
+            let (Some(content), Some(id)) = (&m.content, &m.tool_call_id) else {
+                return m.clone();
+            };
+            let limit = cfg.result_limit(names.get(id.as_str()).copied().unwrap_or_default());
+            let text = content.text();
+            let sent = match serde_json::from_str::<ToolOutcome>(&text) {
+                Ok(outcome) => outcome.to_message(|output| truncate_result(output, limit)),
+                Err(_) => truncate_result(&text, limit),
+            };
+            Message {
+                content: Some(sent.into()),
+                ..m.clone()
+            }
+        })
+        .collect()
+}
+
+/// Move the data URL out of a read_image result so only its metadata goes in
+/// the tool message; vision models get the image in `images`.
+fn attach_image(mut outcome: ToolOutcome, vision: bool, images: &mut Vec<String>) -> ToolOutcome {
+    let Ok(mut info) = serde_json::from_str::<serde_json::Value>(&outcome.output) else {
+        return outcome;
+    };
+    let Some(url) = info
+        .as_object_mut()
+        .and_then(|o| o.remove("data_url"))
+        .and_then(|u| u.as_str().map(str::to_string))
+    else {
+        return outcome;
+    };
+    if vision {
+        images.push(url);
+        info["note"] = "image attached in the next message".into();
+    } else {
+        info["note"] = "this model can't view images; only the metadata above is available".into();
+    }
+    outcome.output = info.to_string();
+    outcome
+}
+
+/// Consecutive failures of one tool before the loop stops running it for the turn.
+const MAX_TOOL_FAILURES: usize = 3;
+
+/// Ask for a plain-text wrap-up once the step or time budget is spent;
+/// `limit` names which one, e.g. "Tool step limit".
+async fn summarize_progress(
+    client: &dyn ChatClient,
+    messages: &mut Vec<Message>,
+    cfg: &Config,
+    temperature: f32,
+    limit: &str,
+) {
+    let mut request = for_model(messages, &cfg.tools);
+    request.push(Message {
+        role: "user".to_string(),
+        content: Some(
+            format!(
+                "{} reached. Summarize what you did, the current state, and what remains, without calling tools.",
+                limit
+            )
+            .into(),
+        ),
+        tool_calls: None,
+        tool_call_id: None,
+        tokens: None,
+    });
+    // Like the rest of the tool loop, not streamed
+    match client
+        .complete_with_history(request, temperature, false)
+        .await
+    {
+        Ok(summary) => {
+            println!("{} {}", "Rusty:".bold().blue(), summary);
+            messages.push(Message {
+                role: "assistant".to_string(),
+                content: Some(summary.into()),
+                tool_calls: None,
+                tool_call_id: None,
+                tokens: client.last_usage().and_then(|u| u.completion_tokens),
+            });
+        }
+        Err(e) => eprintln!("{} {}", "Error:".red(), e),
+    }
+}
+
+/// Run the tools REPL on `session_id`/`messages`, which are updated in place so
+/// a caller switching back from tools mode keeps the conversation.
 pub async fn interactive_mode_with_tools(
     client: &dyn ChatClient,
     system_prompt: Option<String>,
+    session_id: &mut String,
+    messages: &mut Vec<Message>,
+    sampling: &mut Sampling,
+    mcp: &mut MCPRegistry,
+    yolo: bool,
 ) -> Result<()> {
+    crate::tools::check_sandbox_root()?;
     println!("{}", "Rusty Interactive Chat with Tools".bold().cyan());
     println!(
         "{}",
-        "Available tools: shell, calculator, read_file, write_file".green()
+        "Available tools: shell, read_file, write_file, edit_file, list_dir, glob, find_text, git_*, and more".green()
     );
+    match crate::tools::sandbox_root() {
+        Some(root) => println!("{} {}", "Sandbox:".green(), root.display()),
+        None => println!(
+            "{}",
+            "Sandbox: off (file tools can reach any path)".yellow()
+        ),
+    }
     println!("{}", "Type 'exit' or 'quit' to end the session".dimmed());
     println!("{}", "Type 'clear' to clear chat history".dimmed());
+    println!("{}", "Type ':new [id]' to start a new session".dimmed());
+    println!("{}", "Type ':session <id>' to switch sessions".dimmed());
     println!("{}", "Type ':tools off' to return to normal chat".dimmed());
+    println!(
+        "{}",
+        "Type ':tools disable <name>' / ':tools enable <name>' to toggle a tool".dimmed()
+    );
     println!();
 
-    let mut messages = Vec::new();
-    let registry = ToolRegistry::new();
+    let cfg = Config::load().unwrap_or_default();
+    let mut registry = ToolRegistry::new();
+    mcp.start(&cfg.mcp_servers).await;
+    let mcp_confirm = mcp.register_into(
+        &mut registry,
+        &cfg.tool_policy,
+        cfg.tools.prefer.unwrap_or_default(),
+    );
     let tools: Vec<Tool> = registry.get_tool_definitions();
+    if !registry.hidden().is_empty() {
+        println!(
+            "{} {}",
+            "Disabled by tool policy:".yellow(),
+            registry.hidden().join(", ")
+        );
+    }
+    let mut approvals = Approvals::new(&cfg, yolo);
+    approvals.confirm.extend(mcp_confirm);
+    let max_iterations = cfg.tools.max_tool_iterations.unwrap_or(10);
+    let vision = crate::api::supports_vision(client.model_name());
+    let turn_budget = Duration::from_secs(cfg.tools.turn_budget_secs.unwrap_or(300));
+    if approvals.auto_approve {
+        println!("{}", "Tool confirmations disabled".yellow());
+    }
 
+    if !messages.is_empty() {
+        println!("{} {}", "Resumed session".yellow(), session_id.dimmed());
+    }
     if let Some(sys) = system_prompt {
-        messages.push(Message {
-            role: "system".to_string(),
-            content: Some(sys),
-            tool_calls: None,
-            tool_call_id: None,
-        });
+        messages.retain(|m| m.role != "system");
+        messages.insert(
+            0,
+            Message {
+                role: "system".to_string(),
+                content: Some(sys.into()),
+                tool_calls: None,
+                tool_call_id: None,
+                tokens: None,
+            },
+        );
         println!("{}", "System prompt set".green());
     }
 
+    // Tools switched off with `:tools disable` are not advertised this session
+    let mut disabled: HashSet<String> = HashSet::new();
+    // From here Ctrl-C at the prompt leaves through the exit path below
+    ctrl_c();
+    let mut editor = LineEditor::new();
     loop {
-        print!("{} ", "You:".bold().green());
-        io::stdout().flush()?;
-
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let input = input.trim();
+        let Some(line) = editor.read_line("You:")? else {
+            // Ctrl-D or end of piped input
+            println!("{}", "Goodbye!".yellow());
+            break;
+        };
+        let input = line.trim();
 
         if input.is_empty() {
             continue;
@@ -59,85 +384,530 @@ pub async fn interactive_mode_with_tools(
                 println!("{}", "Chat history cleared".yellow());
                 continue;
             }
+            _ if input.starts_with(":new") => {
+                *session_id = match input.split_whitespace().nth(1) {
+                    Some(id) => id.to_string(),
+                    None => new_session_id(),
+                };
+                messages.clear();
+                println!("{} {}", "Started new session".green(), session_id.dimmed());
+                continue;
+            }
+            ":tools list" => {
+                for t in &tools {
+                    let name = &t.function.name;
+                    let state = if disabled.contains(name) || !registry.available(name) {
+                        "off".red()
+                    } else {
+                        "on ".green()
+                    };
+                    println!(
+                        "  [{}] {}: {}",
+                        state,
+                        name,
+                        t.function.description.dimmed()
+                    );
+                }
+                continue;
+            }
+            _ if input.starts_with(":tools enable ") || input.starts_with(":tools disable ") => {
+                let mut words = input.split_whitespace().skip(1);
+                let enable = words.next() == Some("enable");
+                for name in words {
+                    if !tools.iter().any(|t| t.function.name == name) {
+                        println!("{} no tool named {}", "warning:".yellow(), name);
+                    } else if enable {
+                        disabled.remove(name);
+                        println!("{} {}", "Enabled".green(), name);
+                    } else {
+                        disabled.insert(name.to_string());
+                        println!("{} {}", "Disabled".yellow(), name);
+                    }
+                }
+                continue;
+            }
+            _ if input.starts_with(":session ") => {
+                let id = input.split_whitespace().nth(1).unwrap_or("");
+                if id.is_empty() {
+                    println!("usage: :session <id>");
+                } else {
+                    *session_id = id.to_string();
+                    *messages = SessionStore::load(session_id).unwrap_or_default();
+                    if let Ok(Some(stored)) = SessionStore::load_sampling(session_id) {
+                        *sampling =
+                            stored.or(crate::config::Config::load().unwrap_or_default().sampling());
+                    }
+                    println!(
+                        "{} {} ({} messages, {})",
+                        "Loaded session".green(),
+                        session_id.dimmed(),
+                        messages.len(),
+                        sampling
+                    );
+                }
+                continue;
+            }
+            ":status" => {
+                let persist = if SessionStore::ephemeral() {
+                    " (ephemeral, not saved)"
+                } else {
+                    ""
+                };
+                println!(
+                    "session={}{} messages={} model={} {}",
+                    session_id,
+                    persist,
+                    messages.len(),
+                    client.model_name(),
+                    sampling
+                );
+                continue;
+            }
+            _ if matches!(
+                input.split_whitespace().next(),
+                Some(":temperature" | ":top_p" | ":max_tokens")
+            ) =>
+            {
+                let mut parts = input.split_whitespace();
+                let key = parts.next().unwrap_or("").trim_start_matches(':');
+                if let Some(value) = parts.next() {
+                    if let Err(e) = sampling.set(key, value) {
+                        println!("{} {}", "error:".red(), e);
+                        continue;
+                    }
+                    if let Err(e) = SessionStore::save_sampling(session_id, sampling) {
+                        println!("{} not saved to the session: {}", "warning:".yellow(), e);
+                    }
+                }
+                println!("{}", sampling);
+                continue;
+            }
             _ => {}
         }
 
+        // Settings may have changed since the last turn
+        let sampled = client.with_sampling(sampling);
+        let client = sampled.as_ref();
+        let temperature = sampling.temperature();
+
         messages.push(Message {
             role: "user".to_string(),
-            content: Some(input.to_string()),
+            content: Some(input.to_string().into()),
             tool_calls: None,
             tool_call_id: None,
+            tokens: None,
         });
 
-        // Get response with tools
-        let response = client
-            .complete_with_tools(messages.clone(), tools.clone(), 0.7)
-            .await?;
+        let offered: Vec<Tool> = tools
+            .iter()
+            .filter(|t| {
+                !disabled.contains(&t.function.name) && registry.available(&t.function.name)
+            })
+            .map(|t| {
+                let mut t = t.clone();
+                t.function.description =
+                    format!("{} {}", t.function.description.trim_end(), ENVELOPE_NOTE);
+                t
+            })
+            .collect();
+        let turn = ToolTurn {
+            client,
+            registry: &registry,
+            approvals: &mut approvals,
+            offered,
+            disabled: &disabled,
+            cfg: &cfg,
+            session_id,
+            temperature,
+            max_iterations,
+            turn_budget,
+            vision,
+        };
+        // Keep calling the model and running its tools until it answers in
+        // plain text, the step budget runs out, or the user hits Ctrl-C
+        let interrupt = ctrl_c();
+        interrupt.begin();
+        let result = run_tool_loop(turn, messages).await;
+        interrupt.end();
+        result?;
 
-        if let Some(choice) = response.choices.first() {
-            let assistant_msg = &choice.message;
+        // Persist after each turn
+        let _ = SessionStore::save(session_id, messages);
 
-            // Check if the model wants to use tools
-            if let Some(tool_calls) = &assistant_msg.tool_calls {
-                println!("{}", "Rusty (using tools):".bold().blue());
+        println!();
+    }
 
-                // Add assistant's message with tool calls
-                messages.push(assistant_msg.clone());
+    Ok(())
+}
 
-                for tool_call in tool_calls {
-                    let func_name = &tool_call.function.name;
-                    let func_args = &tool_call.function.arguments;
+/// What the tool loop needs for one turn.
+struct ToolTurn<'a> {
+    client: &'a dyn ChatClient,
+    registry: &'a ToolRegistry,
+    approvals: &'a mut Approvals,
+    /// Tools advertised this turn
+    offered: Vec<Tool>,
+    disabled: &'a HashSet<String>,
+    cfg: &'a Config,
+    session_id: &'a str,
+    temperature: f32,
+    max_iterations: usize,
+    turn_budget: Duration,
+    vision: bool,
+}
 
-                    println!(
-                        "  {} {} with args: {}",
-                        "→ Calling".dimmed(),
-                        func_name.yellow(),
-                        func_args.dimmed()
-                    );
+/// Call the model and run the tools it asks for until it answers in plain
+/// text, the step or time budget runs out, or Ctrl-C interrupts the turn.
+/// `messages` gains every assistant and tool message along the way.
+async fn run_tool_loop(turn: ToolTurn<'_>, messages: &mut Vec<Message>) -> Result<()> {
+    let ToolTurn {
+        client,
+        registry,
+        approvals,
+        offered,
+        disabled,
+        cfg,
+        session_id,
+        temperature,
+        max_iterations,
+        turn_budget,
+        vision,
+    } = turn;
+    let interrupt = ctrl_c();
+    let mut step = 0;
+    let turn_started = Instant::now();
+    let mut failures: HashMap<String, usize> = HashMap::new();
+    let mut images: Vec<String> = Vec::new();
+    loop {
+        let response = tokio::select! {
+            r = client.complete_with_tools(for_model(messages, &cfg.tools), offered.clone(), temperature) => r,
+            _ = interrupt.wait() => {
+                println!("{}", "Interrupted; partial history kept".yellow());
+                break;
+            }
+        };
+        let response = match response {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("{} {}", "Error:".red(), e);
+                break;
+            }
+        };
+        crate::chat::record_cost(Some(session_id), client.model_name(), response.usage);
+        let Some(choice) = response.choices.first() else {
+            break;
+        };
+        let assistant_msg = &Message {
+            tokens: response.usage.and_then(|u| u.completion_tokens),
+            ..choice.message.clone()
+        };
+        let tool_calls = match &assistant_msg.tool_calls {
+            Some(calls) if !calls.is_empty() => calls,
+            _ => {
+                if let Some(content) = &assistant_msg.content {
+                    print!("{} ", "Rusty:".bold().blue());
+                    io::stdout().flush()?;
+                    println!("{}", content.text());
+                    messages.push(assistant_msg.clone());
+                }
+                break;
+            }
+        };
 
-                    // Execute the tool
-                    let result = match registry.execute(func_name, func_args).await {
-                        Ok(res) => res,
-                        Err(e) => format!("Error: {}", e),
-                    };
+        if step == max_iterations {
+            println!(
+                "{}",
+                format!(
+                    "warning: stopped after {} tool steps (max_tool_iterations); asking for a summary",
+                    max_iterations
+                )
+                .yellow()
+            );
+            summarize_progress(client, messages, cfg, temperature, "Tool step limit").await;
+            break;
+        }
+        if turn_started.elapsed() >= turn_budget {
+            println!(
+                "{}",
+                format!(
+                    "warning: stopped after {}s of tool calls (turn_budget_secs); asking for a summary",
+                    turn_budget.as_secs()
+                )
+                .yellow()
+            );
+            summarize_progress(client, messages, cfg, temperature, "Tool time budget").await;
+            break;
+        }
+        step += 1;
+        let names: Vec<&str> = tool_calls
+            .iter()
+            .map(|c| c.function.name.as_str())
+            .collect();
+        println!(
+            "{}",
+            format!("[step {}/{}] {}", step, max_iterations, names.join(", ")).dimmed()
+        );
 
-                    println!("  {} {}", "← Result:".dimmed(), result.green());
+        // Add assistant's message with tool calls
+        messages.push(assistant_msg.clone());
 
-                    // Add tool response to messages
-                    messages.push(Message {
-                        role: "tool".to_string(),
-                        content: Some(result),
-                        tool_calls: None,
-                        tool_call_id: Some(tool_call.id.clone()),
-                    });
+        for tool_call in tool_calls {
+            let func_name = &tool_call.function.name;
+            let func_args = &tool_call.function.arguments;
+
+            // Every call needs a result, even once interrupted
+            let outcome = if interrupt.hit() {
+                ToolOutcome::failed("cancelled: user interrupted")
+            } else if disabled.contains(func_name) {
+                println!(
+                    "  {} {} (disabled)",
+                    "✗ Skipped".dimmed(),
+                    func_name.yellow()
+                );
+                ToolOutcome::failed(format!(
+                    "tool disabled: {} was turned off by the user for this session",
+                    func_name
+                ))
+            } else if failures.get(func_name).copied().unwrap_or(0) >= MAX_TOOL_FAILURES {
+                println!(
+                    "  {} {} (failed {} times in a row)",
+                    "✗ Skipped".dimmed(),
+                    func_name.yellow(),
+                    MAX_TOOL_FAILURES
+                );
+                ToolOutcome::failed(format!(
+                    "{} failed {} times in a row and won't run again this turn; try another approach or explain the problem",
+                    func_name, MAX_TOOL_FAILURES
+                ))
+            } else {
+                println!(
+                    "  {} {} with args: {}",
+                    "→ Calling".dimmed(),
+                    func_name.yellow(),
+                    func_args.dimmed()
+                );
+                // Execute the tool once approved
+                let started = Instant::now();
+                let outcome = match approvals.review(func_name, func_args)? {
+                    Decision::Run(args) => tokio::select! {
+                        outcome = registry.run(func_name, &args) => {
+                            let streak = failures.entry(func_name.clone()).or_default();
+                            *streak = if outcome.ok { 0 } else { *streak + 1 };
+                            outcome
+                        },
+                        _ = interrupt.wait() => ToolOutcome::failed("cancelled: user interrupted"),
+                    },
+                    Decision::Decline => ToolOutcome::failed("user declined to run this tool call"),
+                };
+                let outcome = if func_name == "read_image" {
+                    attach_image(outcome, vision, &mut images)
+                } else {
+                    outcome
+                };
+                let label = format!("← Result ({:.1}s):", started.elapsed().as_secs_f64());
+                match &outcome.error {
+                    None => println!("  {} {}", label.dimmed(), outcome.output.green()),
+                    Some(error) => {
+                        println!("  {} {}", label.dimmed(), error.red());
+                        if !outcome.output.is_empty() {
+                            println!("{}", outcome.output);
+                        }
+                    }
                 }
+                outcome
+            };
 
-                // Get final response after tool execution
-                println!();
-                print!("{} ", "Rusty:".bold().blue());
-                io::stdout().flush()?;
-
-                let final_response = client
-                    .complete_with_history(messages.clone(), 0.7, true)
-                    .await?;
-
-                messages.push(Message {
-                    role: "assistant".to_string(),
-                    content: Some(final_response),
-                    tool_calls: None,
-                    tool_call_id: None,
-                });
-            } else if let Some(content) = &assistant_msg.content {
-                // Normal response without tools
-                print!("{} ", "Rusty:".bold().blue());
-                io::stdout().flush()?;
-                println!("{}", content);
-                messages.push(assistant_msg.clone());
-            }
+            // Kept whole; `for_model` trims it to the context budget
+            let result = outcome.to_message(str::to_string);
+            messages.push(Message {
+                role: "tool".to_string(),
+                content: Some(result.into()),
+                tool_calls: None,
+                tool_call_id: Some(tool_call.id.clone()),
+                tokens: None,
+            });
         }
+        // Tool messages can't carry images, so they follow as a user message
+        if !images.is_empty() {
+            let mut parts = vec![ContentPart::Text {
+                text: "Images requested with read_image, in call order:".into(),
+            }];
+            parts.extend(images.drain(..).map(|url| ContentPart::ImageUrl {
+                image_url: ImageUrl { url },
+            }));
+            messages.push(Message {
+                role: "user".to_string(),
+                content: Some(Content::Parts(parts)),
+                tool_calls: None,
+                tool_call_id: None,
+                tokens: None,
+            });
+        }
+        // Persist tool results before the next request can fail
+        let _ = SessionStore::save(session_id, messages);
+        if interrupt.hit() {
+            println!("{}", "Interrupted; partial history kept".yellow());
+            break;
+        }
+    }
+    Ok(())
+}
 
-        println!();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tools::{FunctionCall, ToolCall};
+
+    fn message(role: &str) -> Message {
+        Message {
+            role: role.to_string(),
+            content: None,
+            tool_calls: None,
+            tool_call_id: None,
+            tokens: None,
+        }
     }
 
-    Ok(())
+    #[test]
+    fn tool_results_are_trimmed_for_the_model_only() {
+        let mut call = message("assistant");
+        call.tool_calls = Some(vec![ToolCall {
+            id: "call_1".into(),
+            r#type: "function".into(),
+            function: FunctionCall {
+                name: "git_diff".into(),
+                arguments: "{}".into(),
+            },
+        }]);
+        let output = "x".repeat(500);
+        let mut result = message("tool");
+        result.tool_call_id = Some("call_1".into());
+        result.content = Some(
+            ToolOutcome {
+                ok: true,
+                output: output.clone(),
+                error: None,
+                meta: Default::default(),
+            }
+            .to_message(str::to_string)
+            .into(),
+        );
+        let messages = vec![call, result];
+        let mut cfg = crate::config::ToolsConfig::default();
+        cfg.tool_result_limits.insert("git_diff".into(), 100);
+
+        let sent = for_model(&messages, &cfg);
+        let sent: ToolOutcome =
+            serde_json::from_str(&sent[1].content.as_ref().unwrap().text()).unwrap();
+        assert!(sent.output.contains("[truncated, 400 chars omitted"));
+        assert_eq!(sent.output.matches('x').count(), 100);
+        // The session keeps every byte
+        let kept = messages[1].content.as_ref().unwrap().text();
+        assert!(kept.contains(&output));
+    }
+
+    /// Run one user turn against a mock model replying from `script`.
+    async fn scripted_turn(script: &[&str], max_iterations: usize) -> Vec<Message> {
+        crate::session::test_support::isolate_data_dir();
+        let client = crate::api::MockClient::scripted(
+            "mock".into(),
+            script.iter().map(|s| s.to_string()).collect(),
+        );
+        let registry = ToolRegistry::new();
+        let cfg = Config::default();
+        let mut approvals = Approvals::new(&cfg, true);
+        let mut messages = vec![message("user")];
+        messages[0].content = Some("what is this crate?".to_string().into());
+        let turn = ToolTurn {
+            client: &client,
+            registry: &registry,
+            approvals: &mut approvals,
+            offered: registry.get_tool_definitions(),
+            disabled: &HashSet::new(),
+            cfg: &cfg,
+            session_id: "tool-loop-test",
+            temperature: 0.0,
+            max_iterations,
+            turn_budget: Duration::from_secs(60),
+            vision: false,
+        };
+        run_tool_loop(turn, &mut messages).await.unwrap();
+        messages
+    }
+
+    fn roles(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.role.as_str()).collect()
+    }
+
+    fn output(message: &Message) -> String {
+        let outcome: ToolOutcome =
+            serde_json::from_str(&message.content.as_ref().unwrap().text()).unwrap();
+        outcome.output
+    }
+
+    #[tokio::test]
+    async fn tool_calls_loop_until_a_plain_answer() {
+        let messages = scripted_turn(
+            &[
+                r#"@tool read_file {"path": "Cargo.toml", "end_line": 3}"#,
+                r#"@tool read_file {"path": "README.md", "end_line": 1}"#,
+                "a chat CLI",
+            ],
+            10,
+        )
+        .await;
+        assert_eq!(
+            roles(&messages),
+            [
+                "user",
+                "assistant",
+                "tool",
+                "assistant",
+                "tool",
+                "assistant"
+            ]
+        );
+        for (call, result) in [(1, 2), (3, 4)] {
+            let id = &messages[call].tool_calls.as_ref().unwrap()[0].id;
+            assert_eq!(messages[result].tool_call_id.as_ref(), Some(id));
+        }
+        assert!(
+            output(&messages[2]).contains("rusty-cli"),
+            "{}",
+            output(&messages[2])
+        );
+        assert!(!output(&messages[4]).is_empty(), "{}", output(&messages[4]));
+        assert_eq!(messages[5].content.as_ref().unwrap().text(), "a chat CLI");
+    }
+
+    #[tokio::test]
+    async fn the_step_cap_asks_for_a_summary() {
+        let messages = scripted_turn(
+            &[
+                r#"@tool read_file {"path": "Cargo.toml", "end_line": 1}"#,
+                r#"@tool read_file {"path": "Cargo.toml", "end_line": 2}"#,
+                r#"@tool read_file {"path": "Cargo.toml", "end_line": 3}"#,
+                "ran out of steps",
+            ],
+            2,
+        )
+        .await;
+        // The third call is never made; the summary ends the turn
+        assert_eq!(
+            roles(&messages),
+            [
+                "user",
+                "assistant",
+                "tool",
+                "assistant",
+                "tool",
+                "assistant"
+            ]
+        );
+        assert!(messages[5].tool_calls.is_none());
+        assert_eq!(
+            messages[5].content.as_ref().unwrap().text(),
+            "ran out of steps"
+        );
+    }
 }
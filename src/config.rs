@@ -1,22 +1,504 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+/// Value left in the TOML file when a key lives in the OS credential store.
+const KEYRING_PLACEHOLDER: &str = "<keyring>";
+const KEYRING_SERVICE: &str = "rusty-cli";
+/// Set once the keyring read warning has been printed; config is loaded often.
+static KEYRING_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Value `config export --redact-keys` writes in place of a key; ignored on import.
+const REDACTED_PLACEHOLDER: &str = "<redacted>";
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Config {
     pub api_key: Option<String>,
     pub default_model: Option<String>,
     pub default_temperature: Option<f32>,
+    /// Longest text pulled into a chat by `:resource` or `:attach`, in chars (default 32000)
+    pub attachment_max_chars: Option<usize>,
+    /// How long `--cache` reuses a stored reply, in seconds (default one day)
+    pub cache_ttl_secs: Option<u64>,
     // Optional keys for other providers
     pub openai_api_key: Option<String>,
     pub xai_api_key: Option<String>, // Grok/xAI
     pub grok_api_key: Option<String>,
     pub groq_api_key: Option<String>,
+    /// Store API keys in the OS keyring instead of this file
+    pub secure_keys: Option<bool>,
+    /// Keys left in the keyring because reading them failed; `save` writes
+    /// their placeholder back rather than dropping it
+    #[serde(skip)]
+    unreadable_keys: Vec<&'static str>,
+    // Default models for other providers (`default_model` covers DeepSeek)
+    pub openai_model: Option<String>,
+    pub grok_model: Option<String>,
+    pub groq_model: Option<String>,
+    #[serde(default, skip_serializing_if = "ToolsConfig::is_empty")]
+    pub tools: ToolsConfig,
+    #[serde(default, skip_serializing_if = "ToolPolicy::is_empty")]
+    pub tool_policy: ToolPolicy,
+    /// `[[custom_tools]]`: external commands exposed as tools
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub custom_tools: Vec<CustomTool>,
+    /// `[templates]`: named prompts with `{var}` placeholders
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub templates: HashMap<String, String>,
+    /// `[[mcp_servers]]`: MCP servers started over stdio
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mcp_servers: Vec<McpServerConfig>,
+    /// `[mcp]`: settings shared by all MCP servers
+    #[serde(default, skip_serializing_if = "McpConfig::is_empty")]
+    pub mcp: McpConfig,
+    /// `[pricing."<model>"]`: USD per million tokens, overriding the built-in rates
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub pricing: HashMap<String, crate::api::Pricing>,
+}
+
+/// Tool names that ask before running when `[tools] confirm` is not set.
+const DEFAULT_CONFIRM: &[&str] = &[
+    "shell",
+    "process_start",
+    "write_file",
+    "edit_file",
+    "search_replace",
+    "apply_patch",
+    "delete_file",
+    "git_commit",
+];
+
+/// `[tools]` section: approval rules for tools mode.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ToolsConfig {
+    /// Tools that need a y/n before running
+    pub confirm: Option<Vec<String>>,
+    /// Skip all confirmation prompts (same as --yolo)
+    pub auto_approve: Option<bool>,
+    /// Offer http_get, http_post, fetch_page and web_search (default true)
+    pub network: Option<bool>,
+    /// Offer the clipboard tool; off by default since it can read whatever you copied
+    pub clipboard: Option<bool>,
+    /// Directory file tools are confined to; defaults to the working directory
+    pub sandbox_root: Option<String>,
+    /// Tool-call rounds per prompt before the model is asked to wrap up (default 10)
+    pub max_tool_iterations: Option<usize>,
+    /// Longest tool result sent back to the model, in chars (default 8000)
+    pub tool_result_max_chars: Option<usize>,
+    /// Per-tool overrides of `tool_result_max_chars`, e.g. `git_diff = 32000`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tool_result_limits: HashMap<String, usize>,
+    /// Longest a single tool call may run, in seconds (default 600)
+    pub timeout_secs: Option<u64>,
+    /// Per-tool overrides of `timeout_secs`, e.g. `shell = 30`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub timeouts: HashMap<String, u64>,
+    /// Wall-clock seconds of tool calls per prompt before the model is asked to wrap up (default 300)
+    pub turn_budget_secs: Option<u64>,
+    /// Append a JSON line per tool call to this file (or pass --tool-log)
+    pub log_file: Option<String>,
+    /// Argument keys whose values are written as "[redacted]" in the tool log
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub log_redact: Vec<String>,
+    /// What an unprefixed name means when one MCP tool shares it with a
+    /// built-in (default `builtin`)
+    pub prefer: Option<ToolPreference>,
+    #[serde(default)]
+    pub shell: ShellConfig,
+    #[serde(default)]
+    pub web_search: WebSearchConfig,
+}
+
+/// `[tools.shell]`: limits for the shell tool.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ShellConfig {
+    /// Kill the command after this many seconds (default 30)
+    pub timeout_secs: Option<u64>,
+    /// Cap on combined stdout+stderr returned to the model (default 32 KiB)
+    pub max_output_bytes: Option<usize>,
+}
+
+/// `[tool_policy]`: which kinds of tools tools mode may offer at all.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ToolPolicy {
+    /// Only tools that read (same as --read-only); overrides the allow_* switches
+    pub read_only: Option<bool>,
+    /// shell, cargo and custom command tools (default true)
+    pub allow_shell: Option<bool>,
+    /// http_get, http_post, fetch_page and web_search (default true)
+    pub allow_network: Option<bool>,
+    /// Tools that write, move or delete files, commit, or set the clipboard (default true)
+    pub allow_file_write: Option<bool>,
+}
+
+impl ToolPolicy {
+    fn is_empty(&self) -> bool {
+        self.read_only.is_none()
+            && self.allow_shell.is_none()
+            && self.allow_network.is_none()
+            && self.allow_file_write.is_none()
+    }
+}
+
+/// `[tools] prefer`: which tool a bare name like `read_file` runs when an
+/// MCP server offers one of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolPreference {
+    #[default]
+    Builtin,
+    Mcp,
+}
+
+/// `[mcp]` section: settings shared by every MCP server.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct McpConfig {
+    /// Directories (or `file://` URIs) reported to servers that ask for
+    /// roots; defaults to the sandbox root or working directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<String>,
+}
+
+impl McpConfig {
+    fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+}
+
+/// A `[[custom_tools]]` entry wrapping an external command.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomTool {
+    pub name: toml::Spanned<String>,
+    pub description: String,
+    /// argv template (or a string split like a shell would); `{param}` is
+    /// replaced with the parameter's value
+    pub command: CommandTemplate,
+    /// JSON schema for the arguments; defaults to an object with no properties
+    pub parameters: Option<serde_json::Value>,
+    /// Kill the command after this many seconds (default 30)
+    pub timeout_secs: Option<u64>,
+    /// Ask before running (default true)
+    pub confirm: Option<bool>,
+    /// Run through `sh -c` with quoted values instead of as argv; placeholders
+    /// must then stand outside quotes
+    #[serde(default)]
+    pub shell: bool,
+}
+
+/// A `[[mcp_servers]]` entry: a Model Context Protocol server run as a child
+/// process (`command`), or a remote one reached over HTTP (`url`).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpServerConfig {
+    pub name: toml::Spanned<String>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub command: String,
+    /// Endpoint of a remote server, instead of `command`
+    pub url: Option<String>,
+    /// Sent as `Authorization: Bearer <token>` to a `url` server
+    pub auth_token: Option<String>,
+    /// Other headers for a `url` server, such as an API key (supports `${VAR}`)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// How to talk to a `url` server; `sse` when the url ends in `/sse`,
+    /// otherwise streamable HTTP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transport: Option<McpTransport>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Extra environment variables for the server process; `${VAR}` in a
+    /// value is filled in from our own environment
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub env: HashMap<String, String>,
+    /// Working directory for the server process (supports `${VAR}`)
+    pub cwd: Option<PathBuf>,
+    /// Ask before running its tools (default true)
+    pub confirm: Option<bool>,
+    /// Give up on a request after this many seconds (default 30)
+    pub timeout_secs: Option<u64>,
+    /// Capture the server's stderr for the debug log and `mcp logs` (default true)
+    pub stderr: Option<bool>,
+    /// Stderr lines kept per server (default 200)
+    pub stderr_lines: Option<usize>,
+}
+
+/// The wire protocol of a `url` MCP server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum McpTransport {
+    /// POST every message to one endpoint (the current spec)
+    StreamableHttp,
+    /// The older HTTP+SSE transport: an event stream plus a POST endpoint
+    Sse,
+}
+
+impl McpServerConfig {
+    /// `env` with `${VAR}` references filled in; fails naming the first unset one.
+    pub fn resolved_env(&self) -> Result<HashMap<String, String>> {
+        self.env
+            .iter()
+            .map(|(key, value)| {
+                Ok((
+                    key.clone(),
+                    self.interpolate(&format!("env {}", key), value)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn resolved_cwd(&self) -> Result<Option<PathBuf>> {
+        let Some(cwd) = &self.cwd else {
+            return Ok(None);
+        };
+        let cwd = PathBuf::from(self.interpolate("cwd", &cwd.to_string_lossy())?);
+        if !cwd.is_dir() {
+            anyhow::bail!(
+                "MCP server `{}`: cwd {} is not a directory",
+                self.name.get_ref(),
+                cwd.display()
+            );
+        }
+        Ok(Some(cwd))
+    }
+
+    pub fn resolved_headers(&self) -> Result<HashMap<String, String>> {
+        self.headers
+            .iter()
+            .map(|(key, value)| {
+                Ok((
+                    key.clone(),
+                    self.interpolate(&format!("header {}", key), value)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn http_transport(&self) -> McpTransport {
+        self.transport.unwrap_or_else(|| {
+            let url = self.url.as_deref().unwrap_or_default();
+            if url.trim_end_matches('/').ends_with("/sse") {
+                McpTransport::Sse
+            } else {
+                McpTransport::StreamableHttp
+            }
+        })
+    }
+
+    pub fn resolved_auth_token(&self) -> Result<Option<String>> {
+        self.auth_token
+            .as_deref()
+            .map(|token| self.interpolate("auth_token", token))
+            .transpose()
+    }
+
+    fn interpolate(&self, field: &str, value: &str) -> Result<String> {
+        interpolate_env(value).map_err(|var| {
+            anyhow::anyhow!(
+                "MCP server `{}`: {} uses ${{{}}}, which is not set",
+                self.name.get_ref(),
+                field,
+                var
+            )
+        })
+    }
+}
+
+/// Replace each `${VAR}` in `value` with that environment variable; the
+/// error is the name of the first one that isn't set.
+fn interpolate_env(value: &str) -> std::result::Result<String, String> {
+    let re = regex::Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}").expect("valid regex");
+    let mut missing = None;
+    let out = re.replace_all(value, |caps: &regex::Captures| {
+        std::env::var(&caps[1]).unwrap_or_else(|_| {
+            missing.get_or_insert_with(|| caps[1].to_string());
+            String::new()
+        })
+    });
+    match missing {
+        Some(var) => Err(var),
+        None => Ok(out.into_owned()),
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum CommandTemplate {
+    Args(Vec<String>),
+    Line(String),
+}
+
+/// `[tools.web_search]`: backend for the web_search tool, which is only
+/// offered to the model when this is set.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct WebSearchConfig {
+    /// `brave` or `searxng`
+    pub backend: Option<String>,
+    /// SearXNG instance URL, e.g. http://localhost:8888
+    pub url: Option<String>,
+    /// Environment variable holding the Brave API key (default BRAVE_API_KEY)
+    pub api_key_env: Option<String>,
+    /// Results per query when the model doesn't ask (default 5)
+    pub max_results: Option<usize>,
+}
+
+impl CommandTemplate {
+    /// argv elements of the template, before substitution.
+    pub fn args(&self) -> Option<Vec<String>> {
+        match self {
+            CommandTemplate::Args(args) => Some(args.clone()),
+            CommandTemplate::Line(line) => shlex::split(line),
+        }
+    }
+
+    /// The template as a single line, for `shell = true` and display.
+    pub fn line(&self) -> String {
+        match self {
+            CommandTemplate::Args(args) => args.join(" "),
+            CommandTemplate::Line(line) => line.clone(),
+        }
+    }
+}
+
+impl CustomTool {
+    pub fn parameters(&self) -> serde_json::Value {
+        self.parameters
+            .clone()
+            .unwrap_or_else(|| serde_json::json!({"type": "object", "properties": {}}))
+    }
+
+    /// Names referenced as `{name}` in the command template.
+    pub fn placeholders(&self) -> Vec<String> {
+        let re = regex::Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+        let line = self.command.line();
+        let mut names: Vec<String> = re.captures_iter(&line).map(|c| c[1].to_string()).collect();
+        names.dedup();
+        names
+    }
+
+    fn validate(&self) -> std::result::Result<(), String> {
+        let name = self.name.get_ref();
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err("name must be non-empty and use only letters, digits, _ or -".into());
+        }
+        match self.command.args() {
+            Some(args) if !args.is_empty() => {}
+            _ if self.shell && !self.command.line().trim().is_empty() => {}
+            Some(_) => return Err("command is empty".into()),
+            None => return Err("command has unbalanced quotes".into()),
+        }
+        let params = self.parameters();
+        if params.get("type").and_then(|t| t.as_str()) != Some("object") {
+            return Err("parameters must be a JSON schema with type = \"object\"".into());
+        }
+        let props = params.get("properties").and_then(|p| p.as_object());
+        for placeholder in self.placeholders() {
+            if !props.is_some_and(|p| p.contains_key(&placeholder)) {
+                return Err(format!(
+                    "command uses {{{}}} but parameters.properties has no `{}`",
+                    placeholder, placeholder
+                ));
+            }
+        }
+        if self.shell {
+            if let Some(placeholder) = quoted_placeholder(&self.command.line()) {
+                return Err(format!(
+                    "{{{}}} is inside quotes, where the shell would still expand $(...) in its value; \
+                     leave it unquoted (values are quoted for you)",
+                    placeholder
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The first `{name}` placeholder inside single or double quotes in a shell
+/// command line.
+fn quoted_placeholder(line: &str) -> Option<String> {
+    let re = regex::Regex::new(r"^\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut quote = None;
+    let mut chars = line.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None | Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(_), '{') => {
+                if let Some(caps) = re.captures(&line[i..]) {
+                    return Some(caps[1].to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+impl ToolsConfig {
+    pub fn confirm_list(&self) -> Vec<String> {
+        match &self.confirm {
+            Some(list) => list.clone(),
+            None => DEFAULT_CONFIRM.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Character budget for results of tool `name`.
+    pub fn result_limit(&self, name: &str) -> usize {
+        self.tool_result_limits
+            .get(name)
+            .copied()
+            .or(self.tool_result_max_chars)
+            .unwrap_or(8000)
+    }
+
+    /// How long tool `name` may run before it is cancelled.
+    pub fn timeout(&self, name: &str) -> std::time::Duration {
+        let secs = self
+            .timeouts
+            .get(name)
+            .copied()
+            .or(self.timeout_secs)
+            .unwrap_or(600);
+        std::time::Duration::from_secs(secs)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.confirm.is_none()
+            && self.auto_approve.is_none()
+            && self.network.is_none()
+            && self.clipboard.is_none()
+            && self.sandbox_root.is_none()
+            && self.max_tool_iterations.is_none()
+            && self.tool_result_max_chars.is_none()
+            && self.tool_result_limits.is_empty()
+            && self.timeout_secs.is_none()
+            && self.timeouts.is_empty()
+            && self.turn_budget_secs.is_none()
+            && self.log_file.is_none()
+            && self.log_redact.is_empty()
+            && self.prefer.is_none()
+            && self.shell.timeout_secs.is_none()
+            && self.shell.max_output_bytes.is_none()
+            && self.web_search.backend.is_none()
+            && self.web_search.url.is_none()
+            && self.web_search.api_key_env.is_none()
+            && self.web_search.max_results.is_none()
+    }
 }
 
 impl Config {
     pub fn config_path() -> PathBuf {
+        if let Some(path) = std::env::var_os("RUSTY_CONFIG_PATH") {
+            return PathBuf::from(path);
+        }
         let mut path = dirs::config_dir().expect("Could not find config directory");
         path.push("rusty-cli");
         path.push("config.toml");
@@ -29,11 +511,169 @@ impl Config {
             return Ok(Self::default());
         }
 
-        let contents = fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&contents)?;
+        let contents = fs::read_to_string(&path)?;
+        let mut config = Self::parse(&path, &contents)?;
+        let mut unreadable = vec![];
+        for (name, slot) in config.key_slots() {
+            if slot.as_deref() != Some(KEYRING_PLACEHOLDER) {
+                continue;
+            }
+            *slot = match keyring::Entry::new(KEYRING_SERVICE, name).and_then(|e| e.get_password())
+            {
+                Ok(key) => Some(key),
+                Err(e) => {
+                    if !KEYRING_WARNED.swap(true, Ordering::Relaxed) {
+                        eprintln!(
+                            "warning: couldn't read {} from the OS keyring ({}); it stays there",
+                            name, e
+                        );
+                    }
+                    unreadable.push(name);
+                    None
+                }
+            };
+        }
+        config.unreadable_keys = unreadable;
         Ok(config)
     }
 
+    /// Parse and check config text; `path` is only used in error messages.
+    fn parse(path: &std::path::Path, contents: &str) -> Result<Self> {
+        let config: Config = match toml::from_str(contents) {
+            Ok(config) => config,
+            Err(e) => {
+                // `[tools]` is the settings table, so `[[tools]]` entries can't coexist with it
+                let tools_is_array = toml::from_str::<toml::Value>(contents)
+                    .ok()
+                    .and_then(|v| v.get("tools").map(|t| t.is_array()))
+                    .unwrap_or(false);
+                if tools_is_array {
+                    anyhow::bail!(
+                        "{}: command tools are defined with [[custom_tools]], not [[tools]] \
+                         ([tools] holds tool settings)",
+                        path.display()
+                    );
+                }
+                return Err(e.into());
+            }
+        };
+        config.validate_custom_tools(path, contents)?;
+        config.validate_mcp_servers(path, contents)?;
+        Ok(config)
+    }
+
+    /// The config as TOML, with API keys replaced by a placeholder when `redact`.
+    pub fn export(&self, redact: bool) -> Result<String> {
+        let mut out = self.clone();
+        if redact {
+            for (_, slot) in out.key_slots() {
+                if slot.is_some() {
+                    *slot = Some(REDACTED_PLACEHOLDER.to_string());
+                }
+            }
+        }
+        Ok(toml::to_string_pretty(&out)?)
+    }
+
+    /// Merge the config file at `path` into the saved config: every setting it
+    /// has wins, tables are merged key by key, and redacted keys are skipped.
+    /// Returns the settings that were imported.
+    pub fn import(path: &std::path::Path) -> Result<Vec<String>> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        Self::parse(path, &contents)?;
+        let incoming: toml::Table = toml::from_str(&contents)?;
+
+        let current_path = Self::config_path();
+        let mut merged: toml::Table = match fs::read_to_string(&current_path) {
+            Ok(text) => toml::from_str(&text)
+                .map_err(|e| anyhow::anyhow!("{}: {}", current_path.display(), e))?,
+            Err(_) => toml::Table::new(),
+        };
+        let mut imported = Vec::new();
+        merge_tables(&mut merged, incoming, "", &mut imported);
+
+        let text = toml::to_string_pretty(&merged)?;
+        let config = Self::parse(path, &text)?;
+        config.save()?;
+        Ok(imported)
+    }
+
+    /// Check `[[custom_tools]]`, pointing errors at the entry's `name` line.
+    fn validate_custom_tools(&self, path: &std::path::Path, contents: &str) -> Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for tool in &self.custom_tools {
+            let name = tool.name.get_ref();
+            let line = contents[..tool.name.span().start.min(contents.len())]
+                .matches('\n')
+                .count()
+                + 1;
+            let problem = match tool.validate() {
+                Err(e) => Some(e),
+                Ok(()) if !seen.insert(name.clone()) => Some("defined more than once".into()),
+                Ok(()) => None,
+            };
+            if let Some(problem) = problem {
+                anyhow::bail!(
+                    "{}:{}: custom tool `{}`: {}",
+                    path.display(),
+                    line,
+                    name,
+                    problem
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_mcp_servers(&self, path: &std::path::Path, contents: &str) -> Result<()> {
+        let mut seen = std::collections::HashSet::new();
+        for server in &self.mcp_servers {
+            let name = server.name.get_ref();
+            let line = contents[..server.name.span().start.min(contents.len())]
+                .matches('\n')
+                .count()
+                + 1;
+            let problem = if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                Some("name must be non-empty and use only letters, digits, _ or -")
+            } else if server.url.is_some() && !server.command.is_empty() {
+                Some("set either command or url, not both")
+            } else if server
+                .url
+                .as_deref()
+                .is_some_and(|u| !u.starts_with("http://") && !u.starts_with("https://"))
+            {
+                Some("url must start with http:// or https://")
+            } else if server.url.is_none() && server.command.trim().is_empty() {
+                Some("needs a command (or a url for a remote server)")
+            } else if server.url.is_none()
+                && (server.transport.is_some()
+                    || server.auth_token.is_some()
+                    || !server.headers.is_empty())
+            {
+                Some("transport, auth_token and headers only apply to a url server")
+            } else if !seen.insert(name.clone()) {
+                Some("defined more than once")
+            } else {
+                None
+            };
+            if let Some(problem) = problem {
+                anyhow::bail!(
+                    "{}:{}: MCP server `{}`: {}",
+                    path.display(),
+                    line,
+                    name,
+                    problem
+                );
+            }
+        }
+        Ok(())
+    }
+
     pub fn save(&self) -> Result<()> {
         let path = Self::config_path();
 
@@ -41,8 +681,150 @@ impl Config {
             fs::create_dir_all(parent)?;
         }
 
-        let contents = toml::to_string_pretty(self)?;
+        let mut stored = self.clone();
+        let unreadable = std::mem::take(&mut stored.unreadable_keys);
+        for (name, slot) in stored.key_slots() {
+            if slot.is_none() && unreadable.contains(&name) {
+                *slot = Some(KEYRING_PLACEHOLDER.to_string());
+            }
+        }
+        if self.secure_keys.unwrap_or(false) {
+            for (name, slot) in stored.key_slots() {
+                let Some(value) = slot.as_deref() else {
+                    continue;
+                };
+                if value == KEYRING_PLACEHOLDER {
+                    continue;
+                }
+                match keyring_entry(name).map(|e| e.set_password(value)) {
+                    Some(Ok(())) => *slot = Some(KEYRING_PLACEHOLDER.to_string()),
+                    _ => eprintln!(
+                        "warning: OS keyring unavailable; storing {} in plaintext",
+                        name
+                    ),
+                }
+            }
+        }
+
+        let contents = toml::to_string_pretty(&stored)?;
         fs::write(path, contents)?;
         Ok(())
     }
+
+    /// Character budget for content pulled into the conversation.
+    pub fn attachment_limit(&self) -> usize {
+        self.attachment_max_chars.unwrap_or(32_000)
+    }
+
+    pub fn cache_ttl(&self) -> std::time::Duration {
+        self.cache_ttl_secs
+            .map(std::time::Duration::from_secs)
+            .unwrap_or(crate::cache::DEFAULT_TTL)
+    }
+
+    /// Sampling used when neither a flag nor the session sets a value.
+    pub fn sampling(&self) -> crate::api::Sampling {
+        crate::api::Sampling {
+            temperature: self.default_temperature,
+            ..Default::default()
+        }
+    }
+
+    /// Default model for a provider.
+    pub fn provider_model(&self, provider: crate::Provider) -> String {
+        use crate::Provider;
+        let (configured, fallback) = match provider {
+            Provider::Deepseek => (&self.default_model, "deepseek-chat"),
+            Provider::Openai => (&self.openai_model, "gpt-4o-mini"),
+            Provider::Grok => (&self.grok_model, "grok-code-fast-1"),
+            Provider::Groq => (&self.groq_model, "llama3-70b-8192"),
+            Provider::Mock => (&None, "mock-echo"),
+        };
+        configured.clone().unwrap_or_else(|| fallback.to_string())
+    }
+
+    /// Whether `name` (e.g. "api_key") is stored in the keyring but couldn't
+    /// be read this run.
+    pub fn key_unreadable(&self, name: &str) -> bool {
+        self.unreadable_keys.contains(&name)
+    }
+
+    fn key_slots(&mut self) -> [(&'static str, &mut Option<String>); 5] {
+        [
+            ("api_key", &mut self.api_key),
+            ("openai_api_key", &mut self.openai_api_key),
+            ("xai_api_key", &mut self.xai_api_key),
+            ("grok_api_key", &mut self.grok_api_key),
+            ("groq_api_key", &mut self.groq_api_key),
+        ]
+    }
+}
+
+/// Copy `from` into `into`, recursing into tables; records dotted key paths set.
+fn merge_tables(into: &mut toml::Table, from: toml::Table, prefix: &str, set: &mut Vec<String>) {
+    for (key, value) in from {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match (into.get_mut(&key), value) {
+            (_, toml::Value::String(s))
+                if s == REDACTED_PLACEHOLDER || s == KEYRING_PLACEHOLDER => {}
+            (Some(toml::Value::Table(existing)), toml::Value::Table(table)) => {
+                merge_tables(existing, table, &path, set)
+            }
+            (_, value) => {
+                into.insert(key, value);
+                set.push(path);
+            }
+        }
+    }
+}
+
+fn keyring_entry(name: &str) -> Option<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, name).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell_tool(command: &str) -> CustomTool {
+        CustomTool {
+            name: toml::Spanned::new(0..0, "greet".to_string()),
+            description: String::new(),
+            command: CommandTemplate::Line(command.to_string()),
+            parameters: Some(serde_json::json!({
+                "type": "object",
+                "properties": {"name": {"type": "string"}}
+            })),
+            timeout_secs: None,
+            confirm: None,
+            shell: true,
+        }
+    }
+
+    #[test]
+    fn shell_tools_refuse_quoted_placeholders() {
+        assert!(shell_tool("echo hi {name} | tr a-z A-Z").validate().is_ok());
+        assert!(shell_tool(r#"echo "it's" {name} \"x\""#).validate().is_ok());
+        for quoted in [
+            r#"echo "hi {name}""#,
+            "echo 'hi {name}'",
+            r#"echo "a \" {name}""#,
+        ] {
+            let err = shell_tool(quoted).validate().unwrap_err();
+            assert!(
+                err.contains("{name} is inside quotes"),
+                "{}: {}",
+                quoted,
+                err
+            );
+        }
+        // argv tools never go through a shell
+        let mut argv = shell_tool(r#"echo "hi {name}""#);
+        argv.shell = false;
+        assert!(argv.validate().is_ok());
+    }
 }
@@ -0,0 +1,272 @@
+use crate::session::SessionStore;
+use colored::*;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::history::DefaultHistory;
+use rustyline::{Completer, Editor, Helper, Hinter, Validator};
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+/// Most entries kept on disk; older ones are dropped when the file is rewritten.
+const MAX_ENTRIES: usize = 1000;
+
+/// Opens and closes a block of lines sent as one message.
+const FENCE: &str = "\"\"\"";
+
+/// Lines typed at the REPL prompt, persisted across runs.
+pub struct InputHistory {
+    path: PathBuf,
+    entries: Vec<String>,
+}
+
+impl InputHistory {
+    /// Load the history file; a missing or unreadable file starts empty.
+    pub fn load() -> Self {
+        let path = SessionStore::history_path();
+        let entries = fs::read_to_string(&path)
+            .map(|text| text.lines().map(decode).collect())
+            .unwrap_or_default();
+        Self { path, entries }
+    }
+
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Record a line unless it repeats the previous one or looks sensitive,
+    /// returning whether it was kept. Write failures are ignored; history is
+    /// a convenience. With `--no-persist` the line is kept in memory only.
+    pub fn add(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        if line.is_empty()
+            || is_sensitive(line)
+            || self.entries.last().map(String::as_str) == Some(line)
+        {
+            return false;
+        }
+        self.entries.push(line.to_string());
+        if SessionStore::ephemeral() {
+            return true;
+        }
+        if self.entries.len() > MAX_ENTRIES {
+            let excess = self.entries.len() - MAX_ENTRIES;
+            self.entries.drain(..excess);
+            let _ = self.rewrite();
+        } else {
+            let _ = self.append(line);
+        }
+        true
+    }
+
+    fn append(&self, line: &str) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", encode(line))
+    }
+
+    fn rewrite(&self) -> std::io::Result<()> {
+        let lines: Vec<String> = self.entries.iter().map(|e| encode(e)).collect();
+        let mut text = lines.join("\n");
+        text.push('\n');
+        fs::write(&self.path, text)
+    }
+}
+
+/// One line of the history file per entry: entries with newlines are stored
+/// as JSON strings, everything else as is.
+fn encode(entry: &str) -> String {
+    if entry.contains('\n') {
+        serde_json::to_string(entry).unwrap_or_default()
+    } else {
+        entry.to_string()
+    }
+}
+
+fn decode(line: &str) -> String {
+    if line.starts_with('"') {
+        if let Ok(entry) = serde_json::from_str::<String>(line) {
+            if entry.contains('\n') {
+                return entry;
+            }
+        }
+    }
+    line.to_string()
+}
+
+/// Commands that may carry secrets, and lines holding something shaped like
+/// an API key or token, never reach the history file.
+fn is_sensitive(line: &str) -> bool {
+    let secret = regex::Regex::new(
+        r"(?i)\b(sk-[a-z0-9_-]{16,}|xai-[a-z0-9]{16,}|gsk_[a-z0-9]{16,}|gh[pousr]_[a-z0-9]{20,}|github_pat_[a-z0-9_]{20,}|AKIA[0-9A-Z]{16})|bearer\s+\S{16,}|(api[_-]?key|token|secret|password)\s*[=:]\s*\S+",
+    )
+    .unwrap();
+    line.starts_with(":keys") || secret.is_match(line)
+}
+
+/// Reads REPL input. On a terminal that means Emacs-style editing and up/down
+/// through `InputHistory`, Ctrl-C clearing the line and Ctrl-D ending input;
+/// piped input is read line by line as before. Either way a trailing `\`
+/// continues onto the next line and `"""` opens a block closed by another
+/// `"""`; a multi-line paste arrives whole.
+pub struct LineEditor {
+    history: InputHistory,
+    editor: Option<Editor<Prompt, DefaultHistory>>,
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        let history = InputHistory::load();
+        let editor = io::stdin()
+            .is_terminal()
+            .then(|| {
+                let config = rustyline::Config::builder()
+                    .max_history_size(MAX_ENTRIES)
+                    .ok()?
+                    .build();
+                let mut editor = Editor::with_config(config).ok()?;
+                editor.set_helper(Some(Prompt));
+                for line in history.entries() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                Some(editor)
+            })
+            .flatten();
+        Self { history, editor }
+    }
+
+    pub fn history(&self) -> &InputHistory {
+        &self.history
+    }
+
+    /// Show `prompt` and read a message, which may span several lines,
+    /// recording it in the history. `None` at end of input or once Ctrl-C
+    /// has asked the REPL to exit; Ctrl-C in the editor returns an empty
+    /// message.
+    pub fn read_line(&mut self, prompt: &str) -> anyhow::Result<Option<String>> {
+        if crate::chat_with_tools::quit_requested() {
+            return Ok(None);
+        }
+        let first = match self.read_raw(prompt)? {
+            Input::Line(line) => line,
+            Input::Cancelled => return Ok(Some(String::new())),
+            Input::End => return Ok(None),
+        };
+        let text = match first.trim().strip_prefix(FENCE) {
+            Some(rest) if rest.len() >= FENCE.len() && rest.ends_with(FENCE) => {
+                rest[..rest.len() - FENCE.len()].to_string()
+            }
+            Some(rest) => {
+                let mut lines = vec![];
+                if !rest.is_empty() {
+                    lines.push(rest.to_string());
+                }
+                loop {
+                    match self.read_raw("...")? {
+                        Input::Line(line) => match line.trim_end().strip_suffix(FENCE) {
+                            Some(last) => {
+                                if !last.is_empty() {
+                                    lines.push(last.to_string());
+                                }
+                                break;
+                            }
+                            None => lines.push(line),
+                        },
+                        Input::Cancelled => return Ok(Some(String::new())),
+                        // Send what there is
+                        Input::End => break,
+                    }
+                }
+                lines.join("\n")
+            }
+            None => {
+                let mut text = first;
+                while text.ends_with('\\') {
+                    text.pop();
+                    match self.read_raw("...")? {
+                        Input::Line(line) => {
+                            text.push('\n');
+                            text.push_str(&line);
+                        }
+                        Input::Cancelled => return Ok(Some(String::new())),
+                        Input::End => break,
+                    }
+                }
+                text
+            }
+        };
+        let lines = text.trim().lines().count();
+        if lines > 1 {
+            println!("{}", format!("[sent {} lines]", lines).dimmed());
+        }
+        if self.history.add(&text) {
+            if let Some(editor) = &mut self.editor {
+                let _ = editor.add_history_entry(text.trim());
+            }
+        }
+        Ok(Some(text))
+    }
+
+    fn read_raw(&mut self, prompt: &str) -> anyhow::Result<Input> {
+        let Some(editor) = &mut self.editor else {
+            print!("{} ", prompt.bold().green());
+            io::stdout().flush()?;
+            // Read on a thread so a Ctrl-C asking to exit isn't stuck behind
+            // the blocking read
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                let mut line = String::new();
+                let _ = tx.send(io::stdin().lock().read_line(&mut line).map(|n| (n, line)));
+            });
+            let (n, line) = loop {
+                match rx.recv_timeout(std::time::Duration::from_millis(100)) {
+                    Ok(read) => break read?,
+                    Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                        if crate::chat_with_tools::quit_requested() {
+                            return Ok(Input::End);
+                        }
+                    }
+                    Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return Ok(Input::End),
+                }
+            };
+            if n == 0 {
+                return Ok(Input::End);
+            }
+            return Ok(Input::Line(line.trim_end_matches(['\n', '\r']).to_string()));
+        };
+        match editor.readline(&format!("{} ", prompt)) {
+            Ok(line) => Ok(Input::Line(line)),
+            Err(ReadlineError::Interrupted) => Ok(Input::Cancelled),
+            Err(ReadlineError::Eof) => Ok(Input::End),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+enum Input {
+    Line(String),
+    /// Ctrl-C
+    Cancelled,
+    /// Ctrl-D or the end of piped input
+    End,
+}
+
+/// Styles the prompt; rustyline measures the plain text for cursor placement.
+#[derive(Completer, Helper, Hinter, Validator)]
+pub struct Prompt;
+
+impl Highlighter for Prompt {
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        Cow::Owned(format!("{} ", prompt.trim_end().bold().green()))
+    }
+}
@@ -0,0 +1,165 @@
+use crate::api::Message;
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// One conversation from a ChatGPT `conversations.json` export, flattened to
+/// the messages visible in the web UI.
+pub struct Conversation {
+    pub id: String,
+    pub title: String,
+    /// Unix seconds, when the export has them
+    pub created: Option<f64>,
+    pub updated: Option<f64>,
+    pub messages: Vec<Message>,
+}
+
+impl Conversation {
+    /// `chatgpt-<title words>-<first 8 chars of the export's id>`: readable,
+    /// and the same on every import so re-importing replaces the session.
+    pub fn session_id(&self) -> String {
+        let mut slug = String::new();
+        for word in self
+            .title
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|w| !w.is_empty())
+        {
+            if slug.len() + word.len() > 40 {
+                break;
+            }
+            if !slug.is_empty() {
+                slug.push('-');
+            }
+            slug.push_str(&word.to_ascii_lowercase());
+        }
+        let short: String = self
+            .id
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .take(8)
+            .collect();
+        if slug.is_empty() {
+            format!("chatgpt-{}", short)
+        } else {
+            format!("chatgpt-{}-{}", slug, short)
+        }
+    }
+}
+
+/// Parse a ChatGPT export. Each conversation's `mapping` is a tree of message
+/// nodes (edits and regenerations branch it); the branch shown in the UI is
+/// the path from the root to `current_node`.
+pub fn chatgpt(text: &str) -> Result<Vec<Conversation>> {
+    let export: Value = serde_json::from_str(text)
+        .map_err(|e| anyhow::anyhow!("not a ChatGPT conversations.json export: {}", e))?;
+    let Some(conversations) = export.as_array() else {
+        anyhow::bail!("not a ChatGPT conversations.json export: expected a list of conversations");
+    };
+    Ok(conversations.iter().filter_map(conversation).collect())
+}
+
+fn conversation(value: &Value) -> Option<Conversation> {
+    let mapping = value.get("mapping")?.as_object()?;
+    let id = value
+        .get("conversation_id")
+        .or_else(|| value.get("id"))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    // Walk up from the current node; fall back to the newest leaf
+    let leaf = value
+        .get("current_node")
+        .and_then(Value::as_str)
+        .filter(|node| mapping.contains_key(*node))
+        .map(str::to_string)
+        .or_else(|| newest_leaf(mapping))?;
+    let mut path = vec![];
+    let mut seen = HashSet::new();
+    let mut node = Some(leaf);
+    while let Some(current) = node {
+        // A malformed export could link parents in a loop
+        if !seen.insert(current.clone()) {
+            break;
+        }
+        let entry = mapping.get(&current)?;
+        path.push(entry);
+        node = entry
+            .get("parent")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+    }
+    let messages = path
+        .iter()
+        .rev()
+        .filter_map(|entry| message(entry.get("message")?))
+        .collect();
+    Some(Conversation {
+        id,
+        title: value
+            .get("title")
+            .and_then(Value::as_str)
+            .unwrap_or("untitled")
+            .to_string(),
+        created: value.get("create_time").and_then(Value::as_f64),
+        updated: value.get("update_time").and_then(Value::as_f64),
+        messages,
+    })
+}
+
+/// The most recently created node with no children.
+fn newest_leaf(mapping: &serde_json::Map<String, Value>) -> Option<String> {
+    mapping
+        .iter()
+        .filter(|(_, entry)| {
+            entry
+                .get("children")
+                .and_then(Value::as_array)
+                .is_none_or(|c| c.is_empty())
+        })
+        .max_by(|(_, a), (_, b)| created(a).total_cmp(&created(b)))
+        .map(|(id, _)| id.clone())
+}
+
+fn created(entry: &Value) -> f64 {
+    entry
+        .pointer("/message/create_time")
+        .and_then(Value::as_f64)
+        .unwrap_or(0.0)
+}
+
+/// A user or assistant message with text, or `None` for system prompts,
+/// tool traffic, hidden nodes and non-text content.
+fn message(value: &Value) -> Option<Message> {
+    let role = value.pointer("/author/role")?.as_str()?;
+    if role != "user" && role != "assistant" {
+        return None;
+    }
+    let hidden = value
+        .pointer("/metadata/is_visually_hidden_from_conversation")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    // Assistant messages addressed to a plugin or the browser are tool calls
+    let recipient = value.get("recipient").and_then(Value::as_str);
+    if hidden || recipient.is_some_and(|r| r != "all") {
+        return None;
+    }
+    let content = value.get("content")?;
+    let text = match content.get("parts").and_then(Value::as_array) {
+        Some(parts) => parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        None => content.get("text")?.as_str()?.to_string(),
+    };
+    if text.trim().is_empty() {
+        return None;
+    }
+    Some(Message {
+        role: role.to_string(),
+        content: Some(text.into()),
+        tool_calls: None,
+        tool_call_id: None,
+        tokens: None,
+    })
+}
@@ -1,9 +1,16 @@
 mod api;
+mod attach;
+mod cache;
 mod chat;
 mod chat_with_tools;
 mod config;
+mod history;
+mod import;
+mod mcp;
 mod session;
+mod templates;
 mod tools;
+mod wrap;
 
 use anyhow::Result;
 use clap::{Parser, Subcommand, ValueEnum};
@@ -19,13 +26,51 @@ struct Cli {
     #[arg(short, long, env = "DEEPSEEK_API_KEY", global = true)]
     api_key: Option<String>,
 
-    #[arg(short, long, default_value = "deepseek-chat", global = true)]
-    model: String,
+    /// Model name; defaults to the provider's configured model
+    #[arg(short, long, global = true)]
+    model: Option<String>,
 
     #[arg(long, global = true)]
     no_stream: bool,
 
-    /// Provider to use: deepseek | openai | grok | groq
+    /// Run tools without confirmation prompts
+    #[arg(long, global = true)]
+    yolo: bool,
+
+    /// Directory file tools are confined to (default: current directory)
+    #[arg(long, global = true, value_name = "PATH")]
+    sandbox: Option<std::path::PathBuf>,
+
+    /// Wrap streamed replies at this many columns (0 = off; default: terminal width)
+    #[arg(long, global = true, value_name = "COLS")]
+    wrap: Option<usize>,
+
+    /// Let file tools touch any path
+    #[arg(long, global = true, conflicts_with = "sandbox")]
+    no_sandbox: bool,
+
+    /// Only offer tools that read (no shell, network or file changes)
+    #[arg(long, global = true)]
+    read_only: bool,
+
+    /// Append a JSON line per tool call to this file (overrides `[tools] log_file`)
+    #[arg(long, global = true, value_name = "FILE")]
+    tool_log: Option<std::path::PathBuf>,
+
+    /// Print each reply's estimated cost from the reported token usage
+    #[arg(long, global = true)]
+    show_cost: bool,
+
+    /// Reuse the stored reply for a request identical to a recent one (not in tools mode)
+    #[arg(long, global = true)]
+    cache: bool,
+
+    /// Talk to this OpenAI-compatible server instead of the provider's own
+    /// (LM Studio, vLLM, LocalAI...); an API key is then optional
+    #[arg(long, global = true, value_name = "URL")]
+    base_url: Option<String>,
+
+    /// Provider to use: deepseek | openai | grok | groq | mock (offline)
     #[arg(long, value_enum, default_value_t = Provider::Deepseek, global = true)]
     provider: Provider,
 }
@@ -38,14 +83,64 @@ enum Commands {
         #[arg(short, long)]
         system: Option<String>,
 
-        #[arg(short, long, default_value = "0.7")]
-        temperature: f32,
+        /// Send a prompt template (from `[templates]` or the templates dir) as the message
+        #[arg(long, value_name = "NAME", conflicts_with = "message")]
+        template: Option<String>,
+
+        /// Fill a template variable; repeatable
+        #[arg(long = "var", value_name = "KEY=VALUE", requires = "template")]
+        vars: Vec<String>,
+
+        /// Read the system prompt from a file (`-` for stdin)
+        #[arg(long, value_name = "PATH", conflicts_with = "system")]
+        system_file: Option<std::path::PathBuf>,
+
+        /// Sampling temperature (default: the session's, then `default_temperature`, then 0.7)
+        #[arg(short, long)]
+        temperature: Option<f32>,
+
+        /// Nucleus sampling cutoff sent as `top_p`
+        #[arg(long, value_name = "P")]
+        top_p: Option<f32>,
+
+        /// Cap on the reply length sent as `max_tokens`
+        #[arg(long, value_name = "N")]
+        max_tokens: Option<u32>,
 
         #[arg(long)]
         interactive: bool,
 
         #[arg(long)]
         tools: bool,
+
+        /// Session id to resume (created if missing); one-shot messages are persisted to it
+        #[arg(long)]
+        session: Option<String>,
+
+        /// Start a fresh session instead of resuming; can't be combined with
+        /// --session, whose stored conversation it would overwrite
+        #[arg(long, conflicts_with = "session")]
+        new_session: bool,
+
+        /// Keep the conversation in memory only: no session is loaded or saved
+        #[arg(long, visible_alias = "ephemeral", conflicts_with = "session")]
+        no_persist: bool,
+
+        /// Show live tokens/sec while streaming and latency after each interactive reply
+        #[arg(long)]
+        timings: bool,
+
+        /// Also write a one-shot response to this file (streamed replies are copied as they arrive)
+        #[arg(short, long, value_name = "PATH")]
+        output: Option<std::path::PathBuf>,
+
+        /// Append to --output instead of replacing it
+        #[arg(long, requires = "output")]
+        append: bool,
+
+        /// Attach an image (local path or URL) to the message; repeatable
+        #[arg(long = "image", value_name = "PATH|URL")]
+        images: Vec<String>,
     },
 
     Config {
@@ -53,7 +148,145 @@ enum Commands {
         action: ConfigAction,
     },
 
-    Models,
+    /// List the selected provider's models
+    Models {
+        /// Print the model ids as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Restore files removed by the delete_file tool
+    Undelete {
+        /// Original path of the deleted file
+        path: Option<String>,
+
+        /// List recorded deletions instead of restoring
+        #[arg(long)]
+        list: bool,
+    },
+
+    /// Report on saved chat sessions
+    Sessions {
+        #[command(subcommand)]
+        action: SessionsAction,
+    },
+
+    /// Maintain the local session database
+    Db {
+        #[command(subcommand)]
+        action: DbAction,
+    },
+
+    /// Inspect and call the MCP servers configured under [[mcp_servers]]
+    Mcp {
+        #[command(subcommand)]
+        action: McpAction,
+    },
+
+    /// Manage the reply cache used by --cache
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Delete every cached reply
+    Clear,
+}
+
+#[derive(Subcommand)]
+enum McpAction {
+    /// Start each configured server and list the tools it offers
+    List,
+    /// Start one server, call one of its tools and print the result; exits
+    /// non-zero when the tool reports an error
+    Call {
+        server: String,
+        tool: String,
+        /// Tool arguments as a JSON object (default `{}`; `-` reads stdin)
+        args: Option<String>,
+        /// Read the arguments from a file instead
+        #[arg(long, value_name = "FILE", conflicts_with = "args")]
+        args_file: Option<std::path::PathBuf>,
+        /// Print the raw JSON result instead of its text
+        #[arg(long)]
+        raw: bool,
+        /// Give up after this many seconds (overrides `timeout_secs`)
+        #[arg(long, value_name = "SECS")]
+        timeout: Option<u64>,
+        /// Show the server's stderr as it runs
+        #[arg(long)]
+        verbose: bool,
+    },
+    /// Print the stderr a server wrote the last time it ran
+    Logs { server: String },
+    /// List the resources a server offers
+    Resources { server: String },
+    /// List the prompts a server offers and their arguments
+    Prompts { server: String },
+    /// Start servers again and re-discover their tools, replacing the cached lists
+    Refresh {
+        /// Only this server (default: all)
+        server: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionsAction {
+    /// Count sessions and messages (by role) in the session database
+    Stats {
+        /// Only sessions active on or after this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long, value_name = "DATE")]
+        since: Option<String>,
+    },
+    /// Total a session's estimated cost from the usage recorded for its replies
+    Cost {
+        /// Session id
+        id: String,
+    },
+    /// Print a session as one line of training data
+    Export {
+        id: String,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+        format: ExportFormat,
+        /// Keep tool calls and tool results (dropped by default)
+        #[arg(long)]
+        include_tools: bool,
+    },
+    /// Write every session to a file, one line each
+    ExportAll {
+        file: std::path::PathBuf,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Jsonl)]
+        format: ExportFormat,
+        /// Keep tool calls and tool results (dropped by default)
+        #[arg(long)]
+        include_tools: bool,
+    },
+    /// Create a session per conversation in a ChatGPT `conversations.json` export
+    ImportChatgpt { file: std::path::PathBuf },
+    /// Compare two sessions turn by turn, from the point where they diverge
+    Diff {
+        /// Shown as `-`
+        a: String,
+        /// Shown as `+`
+        b: String,
+    },
+}
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+enum ExportFormat {
+    /// `{"messages": [...]}` per line, the OpenAI chat fine-tuning format
+    Jsonl,
+}
+
+#[derive(Subcommand)]
+enum DbAction {
+    /// Rebuild the database and truncate the WAL to reclaim space
+    Vacuum,
+    /// Run SQLite's integrity check and report problems
+    IntegrityCheck,
 }
 
 #[derive(Copy, Clone, Debug, ValueEnum)]
@@ -62,6 +295,19 @@ enum Provider {
     Openai,
     Grok,
     Groq,
+    Mock,
+}
+
+impl Provider {
+    fn key(self) -> &'static str {
+        match self {
+            Provider::Deepseek => "deepseek",
+            Provider::Openai => "openai",
+            Provider::Grok => "grok",
+            Provider::Groq => "groq",
+            Provider::Mock => "mock",
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -70,11 +316,35 @@ enum ConfigAction {
         #[arg(value_enum)]
         key: ConfigKey,
         value: String,
+
+        /// Keep API keys in the OS keyring (persists `secure_keys = true`)
+        #[arg(long)]
+        secure: bool,
     },
     Get {
         #[arg(value_enum)]
         key: Option<ConfigKey>,
     },
+    /// Print the config file location
+    Path,
+    /// Open the config file in $EDITOR, then validate it
+    Edit,
+    /// Write the current config to a file (`-` for stdout)
+    Export {
+        file: std::path::PathBuf,
+
+        /// Replace API keys with a placeholder so the file can be shared
+        #[arg(long)]
+        redact_keys: bool,
+    },
+    /// Merge settings from a config file into the current config
+    Import { file: std::path::PathBuf },
+    /// Check the config file, provider keys, and optionally connectivity
+    Validate {
+        /// Also list models from each provider to confirm its key works
+        #[arg(long)]
+        check_network: bool,
+    },
 }
 
 #[derive(clap::ValueEnum, Clone)]
@@ -82,6 +352,9 @@ enum ConfigKey {
     ApiKey,
     Model,
     DefaultTemperature,
+    OpenaiModel,
+    GrokModel,
+    GroqModel,
 }
 
 #[tokio::main]
@@ -91,170 +364,1338 @@ async fn main() -> Result<()> {
 
     let cli = Cli::parse();
 
-    // Models command doesn't need an API key
-    if let Some(Commands::Models) = &cli.command {
-        println!("{}", "Available DeepSeek models:".bold());
-        println!("  • deepseek-chat (latest chat model)");
-        println!("  • deepseek-chat-v3");
-        println!("  • deepseek-coder (latest coder model)");
-        println!("  • deepseek-coder-v2");
-        println!("  • deepseek-reasoner (latest reasoning model)");
-        println!("  • deepseek-reasoner-r1");
-        println!("  • deepseek-reasoner-r1-distill-qwen-32b");
-        println!("  • deepseek-reasoner-r1-distill-llama-70b");
-        println!();
-        println!(
-            "{}",
-            "Note: You can use any valid DeepSeek model name with -m flag".dimmed()
-        );
+    // Models command doesn't prompt for an API key
+    if let Some(Commands::Models { json }) = &cli.command {
+        return models_command(
+            cli.provider,
+            cli.api_key.clone(),
+            cli.base_url.as_deref(),
+            *json,
+        )
+        .await;
+    }
+
+    if let Some(Commands::Undelete { path, list }) = &cli.command {
+        return undelete_command(path.as_deref(), *list).await;
+    }
+
+    if let Some(Commands::Db { action }) = &cli.command {
+        return db_command(action);
+    }
+
+    if let Some(Commands::Sessions { action }) = &cli.command {
+        return sessions_command(action);
+    }
+
+    if let Some(Commands::Cache {
+        action: CacheAction::Clear,
+    }) = &cli.command
+    {
+        let removed = session::SessionStore::clear_cache()?;
+        println!("{} {} cached replies", "Cleared".green(), removed);
         return Ok(());
     }
 
-    let client: Box<dyn api::ChatClient> = match cli.provider {
+    if let Some(Commands::Mcp { action }) = &cli.command {
+        return mcp_command(action).await;
+    }
+
+    // Config commands work without a provider key
+    if let Some(Commands::Config { action }) = cli.command {
+        return config_command(action).await;
+    }
+
+    if let Some(cols) = cli.wrap {
+        wrap::set_mode(if cols == 0 {
+            wrap::WrapMode::Off
+        } else {
+            wrap::WrapMode::Columns(cols)
+        });
+    }
+    chat::set_show_cost(cli.show_cost);
+    let cfg = config::Config::load().unwrap_or_default();
+    let model = cli
+        .model
+        .clone()
+        .unwrap_or_else(|| cfg.provider_model(cli.provider));
+    tools::set_read_only(cli.read_only);
+    tools::set_tool_log(cli.tool_log.clone());
+    if cli.no_sandbox {
+        tools::set_sandbox_root(None);
+    } else {
+        let root = match cli.sandbox.clone() {
+            Some(root) => root,
+            None => match &cfg.tools.sandbox_root {
+                Some(root) => root.into(),
+                None => std::env::current_dir()?,
+            },
+        };
+        tools::set_sandbox_root(Some(&root));
+    }
+    let api_key = match cli.provider {
+        // Local gateways usually accept any key, or none
+        _ if cli.base_url.is_some() => cli
+            .api_key
+            .clone()
+            .or_else(|| provider_key(cli.provider, &cfg))
+            .unwrap_or_default(),
         Provider::Deepseek => {
-            let api_key = if let Some(key) = cli.api_key {
+            if let Some(key) = cli.api_key {
                 key
             } else if let Ok(key) = std::env::var("DEEPSEEK_API_KEY") {
                 key
             } else if let Ok(cfg) = config::Config::load() {
-                cfg.api_key
-                    .unwrap_or_else(|| prompt_and_save_key().expect("key"))
+                match cfg.api_key {
+                    Some(key) => key,
+                    // Prompting would replace a key that is still in the keyring
+                    None if cfg.key_unreadable("api_key") => anyhow::bail!(
+                        "api_key is in the OS keyring but couldn't be read; unlock the keyring or set DEEPSEEK_API_KEY"
+                    ),
+                    None => prompt_and_save_key()?,
+                }
             } else {
                 prompt_and_save_key()?
-            };
-            let c = api::DeepSeekClient::new(api_key, cli.model.clone());
-            // Using trait object for dynamic provider dispatch
-            Box::new(c) as Box<dyn api::ChatClient>
+            }
         }
         Provider::Openai => {
-            let api_key = std::env::var("OPENAI_API_KEY")
-                .map_err(|_| anyhow::anyhow!("Set OPENAI_API_KEY"))?;
-            let base = "https://api.openai.com".to_string();
-            Box::new(api::OaiCompatClient::new(api_key, cli.model.clone(), base))
-                as Box<dyn api::ChatClient>
-        }
-        Provider::Grok => {
-            let api_key = std::env::var("XAI_API_KEY")
-                .or_else(|_| std::env::var("GROK_API_KEY"))
-                .map_err(|_| anyhow::anyhow!("Set XAI_API_KEY or GROK_API_KEY"))?;
-            let base = "https://api.x.ai/v1".to_string();
-            Box::new(api::OaiCompatClient::new(api_key, cli.model.clone(), base))
-                as Box<dyn api::ChatClient>
+            std::env::var("OPENAI_API_KEY").map_err(|_| anyhow::anyhow!("Set OPENAI_API_KEY"))?
         }
+        Provider::Grok => std::env::var("XAI_API_KEY")
+            .or_else(|_| std::env::var("GROK_API_KEY"))
+            .map_err(|_| anyhow::anyhow!("Set XAI_API_KEY or GROK_API_KEY"))?,
         Provider::Groq => {
-            let api_key =
-                std::env::var("GROQ_API_KEY").map_err(|_| anyhow::anyhow!("Set GROQ_API_KEY"))?;
-            let base = "https://api.groq.com/openai".to_string();
-            Box::new(api::OaiCompatClient::new(api_key, cli.model.clone(), base))
-                as Box<dyn api::ChatClient>
+            std::env::var("GROQ_API_KEY").map_err(|_| anyhow::anyhow!("Set GROQ_API_KEY"))?
         }
+        Provider::Mock => String::new(),
     };
+    let http = http_client()?;
+    // Using trait object for dynamic provider dispatch
+    let mut client = provider_client(&http, cli.provider, api_key, model, cli.base_url.as_deref());
+    if cli.cache {
+        client = Box::new(cache::CachedClient::new(client, cfg.cache_ttl()));
+    }
 
     match cli.command {
         Some(Commands::Chat {
             message,
             system,
+            system_file,
+            template,
+            vars,
             temperature,
+            top_p,
+            max_tokens,
             interactive,
             tools,
+            session,
+            new_session,
+            no_persist,
+            images,
+            timings,
+            output,
+            append,
         }) => {
+            session::SessionStore::set_ephemeral(no_persist);
+            let system = match system_file {
+                Some(path) => Some(read_system_file(&path)?),
+                None => system,
+            };
+            let message = match template {
+                Some(name) => Some(templates::render(
+                    &name,
+                    &templates::load(&name)?,
+                    &templates::parse_vars(&vars)?,
+                )?),
+                None => message,
+            };
+            let sampling = api::Sampling {
+                temperature,
+                top_p,
+                max_tokens,
+            };
+            if !images.is_empty() && (tools || interactive || message.is_none()) {
+                anyhow::bail!("--image only works with a one-shot message");
+            }
+            if output.is_some() && (tools || interactive || message.is_none()) {
+                anyhow::bail!("--output only works with a one-shot message");
+            }
             if tools {
                 if interactive || message.is_none() {
-                    chat_with_tools::interactive_mode_with_tools(client.as_ref(), system).await?;
+                    let mut session_id = chat::resolve_session(session, new_session)?;
+                    let mut messages = if new_session {
+                        Vec::new()
+                    } else {
+                        session::SessionStore::load(&session_id).unwrap_or_default()
+                    };
+                    let stored = if new_session {
+                        None
+                    } else {
+                        session::SessionStore::load_sampling(&session_id)?
+                    };
+                    let mut sampling = sampling.or(stored.unwrap_or_default()).or(cfg.sampling());
+                    let mut mcp = mcp::MCPRegistry::new();
+                    let result = chat_with_tools::interactive_mode_with_tools(
+                        client.as_ref(),
+                        system,
+                        &mut session_id,
+                        &mut messages,
+                        &mut sampling,
+                        &mut mcp,
+                        cli.yolo,
+                    )
+                    .await;
+                    mcp.close().await;
+                    result?;
                 } else {
                     println!(
                         "Tools mode only works in interactive mode. Use --interactive --tools"
                     );
                 }
             } else if interactive || message.is_none() {
-                chat::interactive_mode(client.as_ref(), system).await?;
+                chat::interactive_mode(
+                    client.as_ref(),
+                    system,
+                    session,
+                    new_session,
+                    sampling,
+                    cli.yolo,
+                    timings,
+                )
+                .await?;
             } else if let Some(msg) = message {
                 // Build simple messages array and call via trait
                 use crate::api::Message;
-                let mut msgs = Vec::new();
+                let session_id = if session.is_some() || new_session {
+                    Some(chat::resolve_session(session, new_session)?)
+                } else {
+                    None
+                };
+                let mut msgs = match &session_id {
+                    Some(id) if !new_session => session::SessionStore::load(id)?,
+                    _ => Vec::new(),
+                };
+                let stored = match &session_id {
+                    Some(id) if !new_session => session::SessionStore::load_sampling(id)?,
+                    _ => None,
+                };
+                let sampling = sampling.or(stored.unwrap_or_default()).or(cfg.sampling());
                 if let Some(sys) = system.clone() {
-                    msgs.push(Message {
-                        role: "system".into(),
-                        content: Some(sys),
-                        tool_calls: None,
-                        tool_call_id: None,
-                    });
+                    msgs.retain(|m| m.role != "system");
+                    msgs.insert(
+                        0,
+                        Message {
+                            role: "system".into(),
+                            content: Some(sys.into()),
+                            tool_calls: None,
+                            tool_call_id: None,
+                            tokens: None,
+                        },
+                    );
                 }
                 msgs.push(Message {
                     role: "user".into(),
-                    content: Some(msg),
+                    content: Some(api::Content::with_images(msg, &images)?),
                     tool_calls: None,
                     tool_call_id: None,
+                    tokens: None,
                 });
+                if let Some(path) = &output {
+                    wrap::set_tee(open_output(path, append)?);
+                }
+                let client = client.with_sampling(&sampling);
                 let response = client
-                    .complete_with_history(msgs, temperature, !cli.no_stream)
-                    .await?;
+                    .complete_with_history(msgs.clone(), sampling.temperature(), !cli.no_stream)
+                    .await;
+                if let Some((mut file, streamed)) = wrap::take_tee() {
+                    use std::io::Write;
+                    // Non-streaming replies (or providers that don't stream) land here in one go
+                    if let (false, Ok(text)) = (streamed, &response) {
+                        file.write_all(text.as_bytes())?;
+                    }
+                    if response.as_ref().is_ok_and(|text| !text.ends_with('\n')) {
+                        writeln!(file)?;
+                    }
+                }
+                let response = response?;
+                if let Some(id) = &session_id {
+                    msgs.push(Message {
+                        role: "assistant".into(),
+                        content: Some(response.clone().into()),
+                        tool_calls: None,
+                        tool_call_id: None,
+                        tokens: client.last_usage().and_then(|u| u.completion_tokens),
+                    });
+                    session::SessionStore::save(id, &msgs)?;
+                    session::SessionStore::save_sampling(id, &sampling)?;
+                }
                 println!("{response}");
+                chat::record_cost(
+                    session_id.as_deref(),
+                    client.model_name(),
+                    client.last_usage(),
+                );
+            }
+        }
+
+        Some(Commands::Models { .. })
+        | Some(Commands::Undelete { .. })
+        | Some(Commands::Db { .. })
+        | Some(Commands::Sessions { .. })
+        | Some(Commands::Mcp { .. })
+        | Some(Commands::Cache { .. })
+        | Some(Commands::Config { .. }) => {
+            // Already handled above
+            unreachable!()
+        }
+
+        None => {
+            let cfg = config::Config::load().unwrap_or_default();
+            let mut picked = pick_provider_and_model_interactive(&http, &cfg).await?;
+            if cli.cache {
+                picked = Box::new(cache::CachedClient::new(picked, cfg.cache_ttl()));
             }
+            chat::interactive_mode(
+                picked.as_ref(),
+                None,
+                None,
+                false,
+                api::Sampling::default(),
+                cli.yolo,
+                false,
+            )
+            .await?;
         }
+    }
+
+    Ok(())
+}
 
-        Some(Commands::Config { action }) => match action {
-            ConfigAction::Set { key, value } => {
-                let mut config = config::Config::load().unwrap_or_default();
+async fn config_command(action: ConfigAction) -> Result<()> {
+    match action {
+        ConfigAction::Set { key, value, secure } => {
+            let mut config = config::Config::load().unwrap_or_default();
+            if secure {
+                config.secure_keys = Some(true);
+            }
+            match key {
+                ConfigKey::ApiKey => config.api_key = Some(value),
+                ConfigKey::Model => config.default_model = Some(value),
+                ConfigKey::DefaultTemperature => {
+                    config.default_temperature = Some(value.parse()?);
+                }
+                ConfigKey::OpenaiModel => config.openai_model = Some(value),
+                ConfigKey::GrokModel => config.grok_model = Some(value),
+                ConfigKey::GroqModel => config.groq_model = Some(value),
+            }
+            config.save()?;
+            println!("{}", "Configuration saved".green());
+        }
+        ConfigAction::Get { key } => {
+            let config = config::Config::load()?;
+            if let Some(key) = key {
                 match key {
-                    ConfigKey::ApiKey => config.api_key = Some(value),
-                    ConfigKey::Model => config.default_model = Some(value),
+                    ConfigKey::ApiKey => {
+                        if let Some(k) = &config.api_key {
+                            let masked = if k.len() > 10 {
+                                format!("{}...{}", &k[..6], &k[k.len() - 4..])
+                            } else if k.len() > 6 {
+                                format!("{}...{}", &k[..3], &k[k.len() - 3..])
+                            } else {
+                                format!("**** ({} chars)", k.len())
+                            };
+                            println!("API Key: {}", masked);
+                        }
+                    }
+                    ConfigKey::Model => {
+                        println!("Model: {}", config.provider_model(Provider::Deepseek));
+                    }
+                    ConfigKey::OpenaiModel => {
+                        println!("OpenAI model: {}", config.provider_model(Provider::Openai));
+                    }
+                    ConfigKey::GrokModel => {
+                        println!("Grok model: {}", config.provider_model(Provider::Grok));
+                    }
+                    ConfigKey::GroqModel => {
+                        println!("Groq model: {}", config.provider_model(Provider::Groq));
+                    }
                     ConfigKey::DefaultTemperature => {
-                        config.default_temperature = Some(value.parse()?);
+                        println!("Temperature: {}", config.default_temperature.unwrap_or(0.7));
                     }
                 }
-                config.save()?;
-                println!("{}", "Configuration saved".green());
-            }
-            ConfigAction::Get { key } => {
-                let config = config::Config::load()?;
-                if let Some(key) = key {
-                    match key {
-                        ConfigKey::ApiKey => {
-                            if let Some(k) = &config.api_key {
-                                let masked = if k.len() > 10 {
-                                    format!("{}...{}", &k[..6], &k[k.len() - 4..])
-                                } else if k.len() > 6 {
-                                    format!("{}...{}", &k[..3], &k[k.len() - 3..])
-                                } else {
-                                    format!("**** ({} chars)", k.len())
-                                };
-                                println!("API Key: {}", masked);
-                            }
-                        }
-                        ConfigKey::Model => {
-                            println!(
-                                "Model: {}",
-                                config
-                                    .default_model
-                                    .unwrap_or_else(|| "deepseek-chat".to_string())
-                            );
-                        }
-                        ConfigKey::DefaultTemperature => {
-                            println!("Temperature: {}", config.default_temperature.unwrap_or(0.7));
-                        }
-                    }
-                } else {
-                    println!("{}", toml::to_string_pretty(&config)?);
+            } else {
+                println!("{}", toml::to_string_pretty(&config)?);
+            }
+        }
+
+        ConfigAction::Validate { check_network } => validate_config(check_network, true).await?,
+        ConfigAction::Path => println!("{}", config::Config::config_path().display()),
+        ConfigAction::Export { file, redact_keys } => {
+            let text = config::Config::load()?.export(redact_keys)?;
+            if file.as_os_str() == "-" {
+                print!("{}", text);
+            } else {
+                std::fs::write(&file, text)?;
+                println!("{} {}", "Exported config to".green(), file.display());
+                if !redact_keys {
+                    println!(
+                        "{}",
+                        "Note: API keys are included; use --redact-keys before sharing".yellow()
+                    );
                 }
             }
-        },
+        }
+        ConfigAction::Import { file } => {
+            let imported = config::Config::import(&file)
+                .map_err(|e| anyhow::anyhow!("import {}: {}", file.display(), e))?;
+            if imported.is_empty() {
+                println!("{}", "Nothing to import".dimmed());
+            } else {
+                println!(
+                    "{} {} into {}",
+                    "Imported".green(),
+                    imported.join(", "),
+                    config::Config::config_path().display()
+                );
+            }
+        }
+        ConfigAction::Edit => edit_config().await?,
+    }
+    Ok(())
+}
 
-        Some(Commands::Models) => {
-            // Already handled above
-            unreachable!()
+const CONFIG_TEMPLATE: &str = r#"# rusty-cli configuration
+# api_key = "sk-..."            # DeepSeek
+# default_model = "deepseek-chat"
+# default_temperature = 0.7
+# attachment_max_chars = 32000  # cap on text pulled in by :resource and :attach
+# cache_ttl_secs = 86400         # how long --cache reuses a reply
+# openai_api_key = "sk-..."
+# openai_model = "gpt-4o-mini"
+# xai_api_key = "xai-..."
+# grok_model = "grok-code-fast-1"
+# groq_api_key = "gsk_..."
+# groq_model = "llama3-70b-8192"
+# secure_keys = false
+#
+# [tools]
+# confirm = ["shell", "process_start", "write_file", "edit_file", "search_replace", "apply_patch", "delete_file", "git_commit"]
+# auto_approve = false
+# max_tool_iterations = 10
+# network = true   # false hides http_get, http_post, fetch_page and web_search
+# clipboard = false   # true offers a clipboard get/set tool
+# tool_result_max_chars = 8000
+# sandbox_root = "/path/to/project"   # default: current directory
+# timeout_secs = 600        # per tool call
+# turn_budget_secs = 300    # tool time per prompt before asking for a summary
+# log_file = "/path/to/tool-log.jsonl"   # or pass --tool-log
+# log_redact = ["api_key", "password"]
+# prefer = "builtin"   # or "mcp": a bare name shared with one MCP tool (read_file) runs the server's
+#
+# [tools.tool_result_limits]
+# git_diff = 32000
+#
+# [tools.timeouts]
+# cargo_test = 900
+#
+# [tools.shell]
+# timeout_secs = 30
+# max_output_bytes = 32768
+#
+# [tools.web_search]
+# backend = "brave"   # or "searxng" with url = "http://localhost:8888"
+#
+# [tool_policy]
+# read_only = false        # or pass --read-only
+# allow_shell = true
+# allow_network = true
+# allow_file_write = true
+#
+# [templates]
+# translate = "Translate {text} to {lang}"   # chat --template translate --var text=... --var lang=...
+#
+# [pricing."gpt-4o-mini"]                 # USD per million tokens, for --show-cost and sessions cost
+# input = 0.15
+# output = 0.60
+#
+# [[custom_tools]]
+# name = "deploy_preview"
+# description = "Deploy a preview build of a branch"
+# command = ["./scripts/deploy-preview.sh", "--branch", "{branch}"]
+# parameters = { type = "object", properties = { branch = { type = "string" } }, required = ["branch"] }
+
+# MCP servers started over stdio (`rusty-cli mcp list` shows their tools)
+# [[mcp_servers]]
+# name = "fs"
+# command = "npx"
+# args = ["-y", "@modelcontextprotocol/server-filesystem", "/path/to/project"]
+# env = { NODE_NO_WARNINGS = "1", GITHUB_TOKEN = "${GITHUB_TOKEN}" }   # ${VAR} comes from your environment
+# cwd = "/path/to/project"
+# confirm = true   # ask before each of its tools runs
+# timeout_secs = 30
+# stderr = true        # keep its stderr for `rusty-cli mcp logs fs`
+# stderr_lines = 200
+#
+# [[mcp_servers]]   # a remote server over streamable HTTP
+# name = "docs"
+# url = "https://mcp.example.com/mcp"
+# auth_token = "..."   # sent as Authorization: Bearer
+# headers = { "X-API-Key" = "${DOCS_API_KEY}" }   # any other headers it needs
+#
+# [[mcp_servers]]   # a remote server on the older HTTP+SSE transport
+# name = "legacy"
+# url = "https://legacy.example.com/sse"   # a url ending in /sse implies transport = "sse"
+# transport = "sse"   # or "streamable-http"
+#
+# [mcp]
+# roots = ["/path/to/project"]   # directories servers may ask for (default: sandbox root or cwd)
+"#;
+
+async fn edit_config() -> Result<()> {
+    let path = config::Config::config_path();
+    if !path.exists() {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
         }
+        std::fs::write(&path, CONFIG_TEMPLATE)?;
+    }
+    open_in_editor(&path)?;
+    // A freshly written template has no key yet; that's worth a note, not a failure
+    validate_config(false, false).await
+}
 
-        None => {
-            let cfg = config::Config::load().unwrap_or_default();
-            let picked = pick_provider_and_model_interactive(&cfg).await?;
-            chat::interactive_mode(picked.as_ref(), None).await?;
+/// Open `path` in $VISUAL / $EDITOR (falling back to vi) and wait for it to exit.
+fn open_in_editor(path: &std::path::Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut parts = shlex::Shlex::new(&editor);
+    let program = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("EDITOR is empty"))?;
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        anyhow::bail!("editor exited with {}", status);
+    }
+    Ok(())
+}
+
+/// Check the config file and provider keys. Without `require_key`, having no
+/// key at all is only a warning.
+async fn validate_config(check_network: bool, require_key: bool) -> Result<()> {
+    let path = config::Config::config_path();
+    let cfg = match config::Config::load() {
+        Ok(cfg) => {
+            println!("{} {}", "OK  ".green(), path.display());
+            cfg
+        }
+        Err(e) => {
+            println!("{} {}: {}", "FAIL".red(), path.display(), e);
+            anyhow::bail!("config validation failed");
+        }
+    };
+    let mut invalid = false;
+    if let Some(t) = cfg.default_temperature {
+        if !(0.0..=2.0).contains(&t) {
+            println!(
+                "{} default_temperature {} is outside [0, 2]",
+                "FAIL".red(),
+                t
+            );
+            invalid = true;
+        }
+    }
+    let http = http_client()?;
+    let mut keys_found = 0;
+    for provider in [
+        Provider::Deepseek,
+        Provider::Openai,
+        Provider::Grok,
+        Provider::Groq,
+    ] {
+        let name = provider.key();
+        let Some(key) = provider_key(provider, &cfg) else {
+            println!("{} {:<9} no key", "--  ".dimmed(), name);
+            continue;
+        };
+        keys_found += 1;
+        let mut notes = Vec::new();
+        if let Some(warning) = key_format_warning(provider, &key) {
+            notes.push(format!("warning: {}", warning).yellow().to_string());
+        }
+        let mut ok = true;
+        if check_network {
+            let client = provider_client(&http, provider, key, cfg.provider_model(provider), None);
+            match client.list_models().await {
+                Ok(list) => notes.push(format!("{} models", list.len())),
+                Err(e) => {
+                    ok = false;
+                    notes.push(format!("models request failed: {}", e.to_string().trim()));
+                }
+            }
+        }
+        let status = if ok { "OK  ".green() } else { "FAIL".red() };
+        println!("{} {:<9} {}", status, name, notes.join("; "));
+        invalid |= !ok;
+    }
+    if keys_found == 0 && require_key {
+        println!("{} no provider API key configured", "FAIL".red());
+        invalid = true;
+    } else if keys_found == 0 {
+        println!(
+            "{} no provider API key configured yet; set one with `rusty-cli config set api-key <key>` or an environment variable",
+            "WARN".yellow()
+        );
+    }
+    if invalid {
+        anyhow::bail!("config validation failed");
+    }
+    Ok(())
+}
+
+/// Open `chat --output`, creating parent directories.
+fn open_output(path: &std::path::Path, append: bool) -> Result<std::fs::File> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|e| anyhow::anyhow!("--output {}: {}", path.display(), e))
+}
+
+/// System prompt from `--system-file`; `-` reads stdin.
+fn read_system_file(path: &std::path::Path) -> Result<String> {
+    let text = if path.as_os_str() == "-" {
+        let mut text = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut text)?;
+        text
+    } else {
+        std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("--system-file {}: {}", path.display(), e))?
+    };
+    let text = text.trim_end().to_string();
+    if text.is_empty() {
+        anyhow::bail!("--system-file {} is empty", path.display());
+    }
+    Ok(text)
+}
+
+/// API key for a provider from its environment variable or the config file.
+fn provider_key(provider: Provider, cfg: &config::Config) -> Option<String> {
+    let (vars, configured): (&[&str], Option<&String>) = match provider {
+        Provider::Deepseek => (&["DEEPSEEK_API_KEY"], cfg.api_key.as_ref()),
+        Provider::Openai => (&["OPENAI_API_KEY"], cfg.openai_api_key.as_ref()),
+        Provider::Grok => (
+            &["XAI_API_KEY", "GROK_API_KEY"],
+            cfg.xai_api_key.as_ref().or(cfg.grok_api_key.as_ref()),
+        ),
+        Provider::Groq => (&["GROQ_API_KEY"], cfg.groq_api_key.as_ref()),
+        Provider::Mock => return None,
+    };
+    vars.iter()
+        .find_map(|v| std::env::var(v).ok())
+        .or_else(|| configured.cloned())
+        .filter(|k| !k.is_empty())
+}
+
+/// Cheap plausibility checks; providers don't document exact key formats.
+fn key_format_warning(provider: Provider, key: &str) -> Option<String> {
+    let prefix = match provider {
+        Provider::Deepseek | Provider::Openai => "sk-",
+        Provider::Grok => "xai-",
+        Provider::Groq => "gsk_",
+        Provider::Mock => return None,
+    };
+    if key.chars().any(char::is_whitespace) {
+        Some("key contains whitespace".into())
+    } else if key.len() < 20 {
+        Some(format!("key looks too short ({} chars)", key.len()))
+    } else if !key.starts_with(prefix) {
+        Some(format!("key does not start with \"{}\"", prefix))
+    } else {
+        None
+    }
+}
+
+/// One HTTP client shared by every provider so they reuse a connection pool.
+/// Proxies come from the standard HTTP(S)_PROXY / NO_PROXY variables.
+fn http_client() -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .connect_timeout(std::time::Duration::from_secs(15))
+        .timeout(std::time::Duration::from_secs(600))
+        .user_agent(concat!("rusty-cli/", env!("CARGO_PKG_VERSION")))
+        .build()?)
+}
+
+/// A client for `provider`, sending to `base_url` instead of the provider's
+/// own endpoint when given (ignored for mock).
+fn provider_client(
+    http: &reqwest::Client,
+    provider: Provider,
+    api_key: String,
+    model: String,
+    base_url: Option<&str>,
+) -> Box<dyn api::ChatClient> {
+    let url = |default: &str| base_url.unwrap_or(default).to_string();
+    match provider {
+        Provider::Deepseek => {
+            let client = api::DeepSeekClient::new(http.clone(), api_key, model);
+            match base_url {
+                Some(url) => Box::new(client.with_base_url(url.to_string())),
+                None => Box::new(client),
+            }
+        }
+        Provider::Openai => Box::new(api::OaiCompatClient::new(
+            http.clone(),
+            api_key,
+            model,
+            url("https://api.openai.com"),
+        )),
+        Provider::Grok => Box::new(api::OaiCompatClient::new(
+            http.clone(),
+            api_key,
+            model,
+            url("https://api.x.ai/v1"),
+        )),
+        Provider::Groq => Box::new(api::OaiCompatClient::new(
+            http.clone(),
+            api_key,
+            model,
+            url("https://api.groq.com/openai"),
+        )),
+        Provider::Mock => Box::new(
+            api::MockClient::from_env(model.clone()).unwrap_or_else(|e| {
+                eprintln!("warning: ignoring RUSTY_MOCK_SCRIPT: {}", e);
+                api::MockClient::new(model)
+            }),
+        ),
+    }
+}
+
+/// Models to show when the provider can't be asked (no key, offline).
+fn fallback_models(provider: Provider) -> &'static [&'static str] {
+    match provider {
+        Provider::Deepseek => &["deepseek-chat", "deepseek-coder", "deepseek-reasoner"],
+        Provider::Openai => &["gpt-4o", "gpt-4o-mini", "o3-mini"],
+        Provider::Grok => &["grok-code-fast-1", "grok-3", "grok-3-mini"],
+        Provider::Groq => &["llama3-70b-8192", "llama3-8b-8192", "mixtral-8x7b-32768"],
+        Provider::Mock => &["mock-echo", "mock-tools"],
+    }
+}
+
+async fn models_command(
+    provider: Provider,
+    api_key: Option<String>,
+    base_url: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let cfg = config::Config::load().unwrap_or_default();
+    let key = match provider {
+        Provider::Mock => Some(String::new()),
+        _ => api_key.or_else(|| provider_key(provider, &cfg)),
+    };
+    // A gateway may not need a key
+    let key = key.or_else(|| base_url.map(|_| String::new()));
+    let live = match key {
+        Some(key) => {
+            let client = provider_client(
+                &http_client()?,
+                provider,
+                key,
+                cfg.provider_model(provider),
+                base_url,
+            );
+            client.list_models().await.map_err(|e| e.to_string())
+        }
+        None => Err("no API key".to_string()),
+    };
+    // The provider's known models say nothing about what a gateway serves
+    if let Some(url) = base_url {
+        let mut models = match live {
+            Ok(models) => models,
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    format!("warning: could not list models from {} ({})", url, e.trim()).yellow()
+                );
+                println!(
+                    "{}",
+                    "Pass the name of a model the server has loaded with -m".dimmed()
+                );
+                return Ok(());
+            }
+        };
+        models.sort();
+        if json {
+            println!("{}", serde_json::to_string(&models)?);
+            return Ok(());
         }
+        println!("{}", format!("Models served by {}:", url).bold());
+        for model in &models {
+            println!("  • {}", model);
+        }
+        return Ok(());
+    }
+    let (mut models, error) = match live {
+        Ok(models) => (models, None),
+        Err(e) => (
+            fallback_models(provider)
+                .iter()
+                .map(|m| m.to_string())
+                .collect(),
+            Some(e),
+        ),
+    };
+    models.sort();
+    if let Some(e) = &error {
+        eprintln!(
+            "{}",
+            format!(
+                "warning: could not list {} models ({}); showing known models instead",
+                provider.key(),
+                e.trim()
+            )
+            .yellow()
+        );
+    }
+    if json {
+        println!("{}", serde_json::to_string(&models)?);
+        return Ok(());
+    }
+    println!("{}", format!("Available {} models:", provider.key()).bold());
+    for model in &models {
+        println!("  • {}", model);
+    }
+    println!();
+    println!(
+        "{}",
+        "Note: You can use any model name the provider accepts with -m flag".dimmed()
+    );
+    Ok(())
+}
+
+async fn undelete_command(path: Option<&str>, list: bool) -> Result<()> {
+    if list || path.is_none() {
+        let rows = session::SessionStore::list_deleted(50)?;
+        if rows.is_empty() {
+            println!("{}", "No deleted files recorded".dimmed());
+        }
+        for (path, ts, kind) in rows {
+            println!("{}  {:<11}  {}", ts.dimmed(), kind, path);
+        }
+        return Ok(());
+    }
+    let path = path.unwrap_or_default();
+    match tools::restore_deleted(path).await? {
+        Some(restored) => println!("{} {}", "Restored".green(), restored),
+        None => anyhow::bail!("nothing to restore for {}", path),
     }
+    Ok(())
+}
 
+fn db_command(action: &DbAction) -> Result<()> {
+    match action {
+        DbAction::Vacuum => {
+            let (before, after) = session::SessionStore::vacuum()?;
+            println!(
+                "{} {} -> {} ({} reclaimed)",
+                "Vacuumed".green(),
+                human_bytes(before),
+                human_bytes(after),
+                human_bytes(before.saturating_sub(after))
+            );
+        }
+        DbAction::IntegrityCheck => {
+            let problems = session::SessionStore::integrity_check()?;
+            if problems.is_empty() {
+                println!("{} database integrity check passed", "OK".green());
+            } else {
+                for problem in &problems {
+                    println!("{} {}", "FAIL".red(), problem);
+                }
+                anyhow::bail!("integrity check found {} problem(s)", problems.len());
+            }
+        }
+    }
     Ok(())
 }
 
+async fn mcp_command(action: &McpAction) -> Result<()> {
+    let cfg = config::Config::load()?;
+    if cfg.mcp_servers.is_empty() {
+        anyhow::bail!(
+            "no MCP servers configured; add [[mcp_servers]] entries to {}",
+            config::Config::config_path().display()
+        );
+    }
+    match action {
+        McpAction::List => {
+            for server in &cfg.mcp_servers {
+                let mut registry = mcp::MCPRegistry::new();
+                let command = match &server.url {
+                    Some(url) if server.http_transport() == config::McpTransport::Sse => {
+                        format!("{} (sse)", url)
+                    }
+                    Some(url) => url.clone(),
+                    None => std::iter::once(server.command.as_str())
+                        .chain(server.args.iter().map(String::as_str))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                };
+                println!("{} {}", server.name.get_ref().bold(), command.dimmed());
+                if let Some(cwd) = &server.cwd {
+                    println!("  {}", format!("cwd {}", cwd.display()).dimmed());
+                }
+                if !server.env.is_empty() {
+                    // Values may hold secrets; show only which keys are set
+                    let mut keys: Vec<&str> = server.env.keys().map(String::as_str).collect();
+                    keys.sort();
+                    let masked: Vec<String> = keys.iter().map(|k| format!("{}=***", k)).collect();
+                    println!("  {}", format!("env {}", masked.join(" ")).dimmed());
+                }
+                if !server.headers.is_empty() {
+                    let mut keys: Vec<&str> = server.headers.keys().map(String::as_str).collect();
+                    keys.sort();
+                    let masked: Vec<String> = keys.iter().map(|k| format!("{}: ***", k)).collect();
+                    println!("  {}", format!("headers {}", masked.join(", ")).dimmed());
+                }
+                if let Err(e) = registry.add_server(server).await {
+                    println!("  {} {}", "failed:".red(), e);
+                    continue;
+                }
+                let started = &registry.servers()[0];
+                println!(
+                    "  {}",
+                    format!("protocol {}", started.protocol_version).dimmed()
+                );
+                let tools = &started.tools;
+                if tools.is_empty() {
+                    println!("  {}", "no tools".dimmed());
+                }
+                for tool in tools {
+                    let def = tools::ToolExecutor::definition(tool);
+                    println!("  - {}: {}", def.function.name, def.function.description);
+                }
+                registry.close().await;
+            }
+        }
+        McpAction::Resources { server } => {
+            let config = cfg
+                .mcp_servers
+                .iter()
+                .find(|s| s.name.get_ref() == server)
+                .ok_or_else(|| anyhow::anyhow!("no MCP server named `{}` in the config", server))?;
+            let mut registry = mcp::MCPRegistry::new();
+            registry.add_server(config).await?;
+            let listed = registry.servers()[0].list_resources().await;
+            registry.close().await;
+            let resources = listed?;
+            if resources.is_empty() {
+                println!("{}", format!("{} offers no resources", server).dimmed());
+            }
+            for resource in resources {
+                let mime = resource.mime_type.as_deref().unwrap_or("");
+                println!(
+                    "{}  {}  {}",
+                    resource.uri,
+                    resource.name.bold(),
+                    mime.dimmed()
+                );
+                if let Some(description) = &resource.description {
+                    println!("    {}", description.dimmed());
+                }
+            }
+        }
+        McpAction::Prompts { server } => {
+            let config = cfg
+                .mcp_servers
+                .iter()
+                .find(|s| s.name.get_ref() == server)
+                .ok_or_else(|| anyhow::anyhow!("no MCP server named `{}` in the config", server))?;
+            let mut registry = mcp::MCPRegistry::new();
+            registry.add_server(config).await?;
+            let listed = registry.servers()[0].list_prompts().await;
+            registry.close().await;
+            let prompts = listed?;
+            if prompts.is_empty() {
+                println!("{}", format!("{} offers no prompts", server).dimmed());
+            }
+            for prompt in prompts {
+                let description = prompt.description.as_deref().unwrap_or("");
+                println!("{}  {}", prompt.name.bold(), description.dimmed());
+                for arg in &prompt.arguments {
+                    let required = if arg.required { " (required)" } else { "" };
+                    println!(
+                        "    {}{}  {}",
+                        arg.name,
+                        required,
+                        arg.description.as_deref().unwrap_or("").dimmed()
+                    );
+                }
+            }
+        }
+        McpAction::Refresh { server } => {
+            if let Some(server) = server {
+                if !cfg.mcp_servers.iter().any(|s| s.name.get_ref() == server) {
+                    anyhow::bail!("no MCP server named `{}` in the config", server);
+                }
+            }
+            session::SessionStore::clear_mcp_tools(server.as_deref())?;
+            for config in &cfg.mcp_servers {
+                let name = config.name.get_ref();
+                if server.as_ref().is_some_and(|s| s != name) {
+                    continue;
+                }
+                let mut registry = mcp::MCPRegistry::new();
+                match registry.add_server(config).await {
+                    Ok(()) => println!(
+                        "{} {}",
+                        name.bold(),
+                        format!("{} tools", registry.servers()[0].tools.len()).dimmed()
+                    ),
+                    Err(e) => println!("{} {} {}", name.bold(), "failed:".red(), e),
+                }
+                registry.close().await;
+            }
+        }
+        McpAction::Logs { server } => {
+            if !cfg.mcp_servers.iter().any(|s| s.name.get_ref() == server) {
+                anyhow::bail!("no MCP server named `{}` in the config", server);
+            }
+            match std::fs::read_to_string(mcp::stderr_log_path(server)) {
+                Ok(text) => print!("{}", text),
+                Err(_) => println!(
+                    "{}",
+                    format!("no stderr captured for {} yet", server).dimmed()
+                ),
+            }
+        }
+        McpAction::Call {
+            server,
+            tool,
+            args,
+            args_file,
+            raw,
+            timeout,
+            verbose,
+        } => {
+            let mut config = cfg
+                .mcp_servers
+                .iter()
+                .find(|s| s.name.get_ref() == server)
+                .ok_or_else(|| anyhow::anyhow!("no MCP server named `{}` in the config", server))?
+                .clone();
+            if timeout.is_some() {
+                config.timeout_secs = *timeout;
+            }
+            if *verbose {
+                config.stderr = Some(true);
+                mcp::set_echo_stderr(true);
+            }
+            let text = match (args.as_deref(), args_file) {
+                (_, Some(path)) => std::fs::read_to_string(path)
+                    .map_err(|e| anyhow::anyhow!("reading {}: {}", path.display(), e))?,
+                (Some("-"), None) => std::io::read_to_string(std::io::stdin())?,
+                (Some(args), None) => args.to_string(),
+                (None, None) => String::new(),
+            };
+            let arguments: serde_json::Value = if text.trim().is_empty() {
+                serde_json::json!({})
+            } else {
+                serde_json::from_str(&text)
+                    .map_err(|e| anyhow::anyhow!("tool arguments are not valid JSON: {}", e))?
+            };
+            let mut registry = mcp::MCPRegistry::new();
+            registry.add_server(&config).await?;
+            let result = registry.servers()[0].call_tool(tool, arguments).await;
+            registry.close().await;
+            let result = result?;
+            let is_error = result.get("isError").and_then(|v| v.as_bool()) == Some(true);
+            if *raw {
+                println!("{}", serde_json::to_string_pretty(&result)?);
+                if is_error {
+                    std::process::exit(1);
+                }
+                return Ok(());
+            }
+            match mcp::call_result_text(&result) {
+                Some(Ok(text)) => println!("{}", text),
+                Some(Err(text)) => anyhow::bail!("{} reported an error: {}", tool, text),
+                None => println!("{}", serde_json::to_string_pretty(&result)?),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sessions_command(action: &SessionsAction) -> Result<()> {
+    match action {
+        SessionsAction::Stats { since } => {
+            if let Some(since) = since {
+                use time::format_description::{self, well_known::Rfc3339};
+                let day = format_description::parse("[year]-[month]-[day]")?;
+                let valid = time::OffsetDateTime::parse(since, &Rfc3339).is_ok()
+                    || time::Date::parse(since, &day).is_ok();
+                if !valid {
+                    anyhow::bail!(
+                        "--since expects YYYY-MM-DD or an RFC 3339 timestamp, got `{}`",
+                        since
+                    );
+                }
+            }
+            let stats = session::SessionStore::stats(since.as_deref())?;
+            match since {
+                Some(since) => println!("{}", format!("Sessions active since {}", since).bold()),
+                None => println!("{}", "All sessions".bold()),
+            }
+            println!("  {:<12} {:>8}", "sessions", stats.sessions);
+            println!("  {:<12} {:>8}", "messages", stats.messages);
+            for (role, count) in &stats.by_role {
+                println!("  {:<12} {:>8}", format!("  {}", role), count);
+            }
+            let unknown = if stats.tokens_unknown > 0 {
+                format!(" ({} replies unknown)", stats.tokens_unknown)
+            } else {
+                String::new()
+            };
+            println!("  {:<12} {:>8}{}", "tokens out", stats.tokens, unknown);
+        }
+        SessionsAction::Cost { id } => {
+            let Some((cost, unpriced)) = session::SessionStore::cost(id)? else {
+                anyhow::bail!("no session named `{}`", id);
+            };
+            println!("{}  ~${:.4}", id, cost);
+            if unpriced > 0 {
+                println!(
+                    "{}",
+                    format!(
+                        "{} replies had no usage or unknown pricing and are not counted",
+                        unpriced
+                    )
+                    .dimmed()
+                );
+            }
+        }
+        SessionsAction::Export {
+            id,
+            format: ExportFormat::Jsonl,
+            include_tools,
+        } => {
+            let messages = session::SessionStore::load(id)?;
+            if messages.is_empty() {
+                anyhow::bail!("no session named `{}` (or it has no messages)", id);
+            }
+            match fine_tune_line(&messages, *include_tools)? {
+                Some(line) => println!("{}", line),
+                None => anyhow::bail!("session `{}` has no conversation to export", id),
+            }
+        }
+        SessionsAction::ExportAll {
+            file,
+            format: ExportFormat::Jsonl,
+            include_tools,
+        } => {
+            let mut out = String::new();
+            let (mut written, mut skipped) = (0, 0);
+            for id in session::SessionStore::ids()? {
+                match fine_tune_line(&session::SessionStore::load(&id)?, *include_tools)? {
+                    Some(line) => {
+                        out.push_str(&line);
+                        out.push('\n');
+                        written += 1;
+                    }
+                    None => skipped += 1,
+                }
+            }
+            std::fs::write(file, out)?;
+            println!(
+                "{} {} sessions to {}",
+                "Exported".green(),
+                written,
+                file.display()
+            );
+            if skipped > 0 {
+                println!(
+                    "{}",
+                    format!("skipped {} with no conversation", skipped).dimmed()
+                );
+            }
+        }
+        SessionsAction::ImportChatgpt { file } => {
+            let text = std::fs::read_to_string(file)
+                .map_err(|e| anyhow::anyhow!("reading {}: {}", file.display(), e))?;
+            let conversations = import::chatgpt(&text)?;
+            let (mut imported, mut empty) = (0, 0);
+            for conversation in &conversations {
+                if conversation.messages.is_empty() {
+                    empty += 1;
+                    continue;
+                }
+                let id = conversation.session_id();
+                session::SessionStore::save(&id, &conversation.messages)?;
+                // Keep the export's dates so imports don't become the session to resume
+                let when = |secs: Option<f64>| {
+                    time::OffsetDateTime::from_unix_timestamp(secs? as i64)
+                        .ok()?
+                        .format(&time::format_description::well_known::Rfc3339)
+                        .ok()
+                };
+                if let (Some(created), Some(updated)) =
+                    (when(conversation.created), when(conversation.updated))
+                {
+                    session::SessionStore::set_times(&id, &created, &updated)?;
+                }
+                println!(
+                    "{}  {} ({} messages)",
+                    id,
+                    conversation.title.dimmed(),
+                    conversation.messages.len()
+                );
+                imported += 1;
+            }
+            println!(
+                "{} {} conversations from {}",
+                "Imported".green(),
+                imported,
+                file.display()
+            );
+            if empty > 0 {
+                println!(
+                    "{}",
+                    format!("skipped {} with no user or assistant text", empty).dimmed()
+                );
+            }
+        }
+        SessionsAction::Diff { a, b } => {
+            let left = session::SessionStore::load(a)?;
+            let right = session::SessionStore::load(b)?;
+            for (id, messages) in [(a, &left), (b, &right)] {
+                if messages.is_empty() {
+                    anyhow::bail!("no session named `{}` (or it has no messages)", id);
+                }
+            }
+            print_session_diff(a, &left, b, &right);
+        }
+    }
+    Ok(())
+}
+
+/// Longest common prefix of the two conversations, then each message after
+/// it: a line diff where both have a message of the same role, otherwise the
+/// message from each side in full.
+fn print_session_diff(a: &str, left: &[api::Message], b: &str, right: &[api::Message]) {
+    use similar::{ChangeTag, TextDiff};
+    let same =
+        |x: &api::Message, y: &api::Message| x.role == y.role && message_text(x) == message_text(y);
+    let common = left
+        .iter()
+        .zip(right)
+        .take_while(|(x, y)| same(x, y))
+        .count();
+    println!("{}", format!("--- {} ({} messages)", a, left.len()).red());
+    println!(
+        "{}",
+        format!("+++ {} ({} messages)", b, right.len()).green()
+    );
+    if common == left.len() && common == right.len() {
+        println!("{}", "the sessions are identical".dimmed());
+        return;
+    }
+    println!(
+        "{}",
+        format!(
+            "{} messages in common, diverging at #{}",
+            common,
+            common + 1
+        )
+        .dimmed()
+    );
+    for i in common..left.len().max(right.len()) {
+        let (x, y) = (left.get(i), right.get(i));
+        match (x, y) {
+            (Some(x), Some(y)) if x.role == y.role => {
+                println!("{}", format!("#{} {}", i + 1, x.role).bold());
+                let (old, new) = (message_text(x), message_text(y));
+                for change in TextDiff::from_lines(&old, &new).iter_all_changes() {
+                    let line = change.value().trim_end_matches('\n');
+                    match change.tag() {
+                        ChangeTag::Delete => println!("{}", format!("- {}", line).red()),
+                        ChangeTag::Insert => println!("{}", format!("+ {}", line).green()),
+                        ChangeTag::Equal => println!("  {}", line.dimmed()),
+                    }
+                }
+            }
+            _ => {
+                for (sign, message) in [("-", x), ("+", y)] {
+                    let Some(message) = message else { continue };
+                    println!("{}", format!("#{} {} {}", i + 1, sign, message.role).bold());
+                    for line in message_text(message).lines() {
+                        let line = format!("{} {}", sign, line);
+                        match sign {
+                            "-" => println!("{}", line.red()),
+                            _ => println!("{}", line.green()),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A session as a `{"messages": [...]}` fine-tuning line, or `None` when it
+/// has no user/assistant exchange. Without `include_tools`, tool results and
+/// tool-call-only replies are dropped and other replies lose their tool calls.
+fn fine_tune_line(messages: &[api::Message], include_tools: bool) -> Result<Option<String>> {
+    let kept: Vec<api::Message> = messages
+        .iter()
+        .filter(|m| include_tools || (m.role != "tool" && m.content.is_some()))
+        .map(|m| api::Message {
+            tool_calls: m.tool_calls.clone().filter(|_| include_tools),
+            tool_call_id: m.tool_call_id.clone().filter(|_| include_tools),
+            ..m.clone()
+        })
+        .collect();
+    if !kept.iter().any(|m| m.role == "assistant") || !kept.iter().any(|m| m.role == "user") {
+        return Ok(None);
+    }
+    #[derive(serde::Serialize)]
+    struct Line<'a> {
+        messages: &'a [api::Message],
+    }
+    let line = serde_json::to_string(&Line { messages: &kept })?;
+    // A line that doesn't parse back would poison the whole training file
+    serde_json::from_str::<serde_json::Value>(&line)?;
+    Ok(Some(line))
+}
+
+/// A message's text, or the tools it called when it has none.
+fn message_text(message: &api::Message) -> String {
+    if let Some(content) = &message.content {
+        return content.text();
+    }
+    let calls: Vec<&str> = message
+        .tool_calls
+        .iter()
+        .flatten()
+        .map(|c| c.function.name.as_str())
+        .collect();
+    format!("[calls {}]", calls.join(", "))
+}
+
+fn human_bytes(bytes: u64) -> String {
+    match bytes {
+        b if b >= 1 << 20 => format!("{:.1} MiB", b as f64 / (1u64 << 20) as f64),
+        b if b >= 1 << 10 => format!("{:.1} KiB", b as f64 / 1024.0),
+        b => format!("{} B", b),
+    }
+}
+
 fn prompt_and_save_key() -> anyhow::Result<String> {
     use std::io::{self, Write};
     print!("Enter DEEPSEEK_API_KEY: ");
@@ -273,6 +1714,7 @@ fn prompt_and_save_key() -> anyhow::Result<String> {
 }
 
 async fn pick_provider_and_model_interactive(
+    http: &reqwest::Client,
     cfg: &config::Config,
 ) -> anyhow::Result<Box<dyn api::ChatClient>> {
     use std::io::{self, Write};
@@ -285,7 +1727,11 @@ async fn pick_provider_and_model_interactive(
     }) {
         items.push((
             "DeepSeek",
-            Box::new(api::DeepSeekClient::new(k, "deepseek-chat".into())),
+            Box::new(api::DeepSeekClient::new(
+                http.clone(),
+                k,
+                cfg.provider_model(Provider::Deepseek),
+            )),
         ));
     }
     if let Ok(k) = std::env::var("OPENAI_API_KEY").or_else(|_| {
@@ -296,8 +1742,9 @@ async fn pick_provider_and_model_interactive(
         items.push((
             "OpenAI",
             Box::new(api::OaiCompatClient::new(
+                http.clone(),
                 k,
-                "gpt-4o-mini".into(),
+                cfg.provider_model(Provider::Openai),
                 "https://api.openai.com".into(),
             )),
         ));
@@ -314,8 +1761,9 @@ async fn pick_provider_and_model_interactive(
         items.push((
             "Grok (xAI)",
             Box::new(api::OaiCompatClient::new(
+                http.clone(),
                 k,
-                "grok-code-fast-1".into(),
+                cfg.provider_model(Provider::Grok),
                 "https://api.x.ai/v1".into(),
             )),
         ));
@@ -328,8 +1776,9 @@ async fn pick_provider_and_model_interactive(
         items.push((
             "Groq",
             Box::new(api::OaiCompatClient::new(
+                http.clone(),
                 k,
-                "llama3-70b-8192".into(),
+                cfg.provider_model(Provider::Groq),
                 "https://api.groq.com/openai".into(),
             )),
         ));
@@ -339,7 +1788,11 @@ async fn pick_provider_and_model_interactive(
         let key = prompt_and_save_key()?;
         items.push((
             "DeepSeek",
-            Box::new(api::DeepSeekClient::new(key, "deepseek-chat".into())),
+            Box::new(api::DeepSeekClient::new(
+                http.clone(),
+                key,
+                cfg.provider_model(Provider::Deepseek),
+            )),
         ));
     }
     let mut idx = 0usize;
@@ -383,3 +1836,23 @@ async fn pick_provider_and_model_interactive(
     }
     Ok(client)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_session_refuses_a_named_session() {
+        let parsed = Cli::try_parse_from([
+            "rusty-cli",
+            "chat",
+            "--session",
+            "foo",
+            "--new-session",
+            "hi",
+        ]);
+        assert!(parsed.is_err());
+        assert!(Cli::try_parse_from(["rusty-cli", "chat", "--new-session", "hi"]).is_ok());
+        assert!(Cli::try_parse_from(["rusty-cli", "chat", "--session", "foo", "hi"]).is_ok());
+    }
+}
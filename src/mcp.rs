@@ -1,26 +1,43 @@
+use crate::config::{McpServerConfig, McpTransport, ToolPolicy, ToolPreference};
+use crate::session::SessionStore;
+use crate::tools::{
+    signal_process, Function, Signal, Tool, ToolAccess, ToolExecutor, ToolRegistry,
+};
 use anyhow::Result;
 use async_trait::async_trait;
+use colored::*;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
 use std::process::Stdio;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::process::{Child, Command};
-use tokio::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+use tokio::sync::{oneshot, Mutex};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MCPRequest {
     pub jsonrpc: String,
     pub method: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub params: Option<Value>,
+    /// Absent for notifications
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub id: Option<Value>,
 }
 
+/// Protocol revision we ask for; servers answer with the one they speak.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MCPResponse {
     pub jsonrpc: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<MCPError>,
     pub id: Option<Value>,
 }
@@ -29,36 +46,239 @@ pub struct MCPResponse {
 pub struct MCPError {
     pub code: i32,
     pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<Value>,
 }
 
+/// JSON-RPC "method not found", for server requests we don't implement.
+const METHOD_NOT_FOUND: i32 = -32601;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MCPTool {
     pub name: String,
     pub description: Option<String>,
+    #[serde(rename = "inputSchema", default = "empty_schema")]
     pub input_schema: Value,
 }
 
+fn empty_schema() -> Value {
+    json!({"type": "object", "properties": {}})
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolsListResult {
     pub tools: Vec<MCPTool>,
 }
 
-pub struct MCPClient {
-    process: Arc<Mutex<Child>>,
-    stdin: Arc<Mutex<tokio::process::ChildStdin>>,
-    reader: Arc<Mutex<BufReader<tokio::process::ChildStdout>>>,
-    request_id: Arc<Mutex<u64>>,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MCPResource {
+    pub uri: String,
+    #[serde(default)]
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
 }
 
-impl MCPClient {
-    pub async fn new(command: &str, args: Vec<String>) -> Result<Self> {
-        let mut child = Command::new(command)
-            .args(args)
+/// One entry of a `resources/read` result: `text`, or base64 `blob`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResourceContents {
+    pub uri: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: Option<String>,
+    pub text: Option<String>,
+    pub blob: Option<String>,
+}
+
+impl ResourceContents {
+    /// The contents as text. Blobs that decode to UTF-8 are shown as text;
+    /// other binary data is replaced by a note with its size.
+    pub fn to_text(&self) -> String {
+        use base64::Engine;
+        if let Some(text) = &self.text {
+            return text.clone();
+        }
+        let Some(blob) = &self.blob else {
+            return String::new();
+        };
+        let mime = self
+            .mime_type
+            .as_deref()
+            .unwrap_or("application/octet-stream");
+        match base64::engine::general_purpose::STANDARD.decode(blob.trim()) {
+            Ok(bytes) => match String::from_utf8(bytes) {
+                Ok(text) => text,
+                Err(e) => format!("[binary {}, {} bytes omitted]", mime, e.as_bytes().len()),
+            },
+            Err(_) => format!("[{} blob that isn't valid base64]", mime),
+        }
+    }
+}
+
+/// A prompt template from `prompts/list`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MCPPrompt {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub arguments: Vec<MCPPromptArgument>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MCPPromptArgument {
+    pub name: String,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl MCPPrompt {
+    /// Required arguments not present in `args`.
+    pub fn missing<'a>(&'a self, args: &HashMap<String, String>) -> Vec<&'a MCPPromptArgument> {
+        self.arguments
+            .iter()
+            .filter(|a| a.required && !args.contains_key(&a.name))
+            .collect()
+    }
+
+    /// `:prompt <server> <name> key=<key> [opt=<opt>]` with optional arguments bracketed.
+    pub fn usage(&self, server: &str) -> String {
+        let mut line = format!(":prompt {} {}", server, self.name);
+        for arg in &self.arguments {
+            if arg.required {
+                line.push_str(&format!(" {}=<{}>", arg.name, arg.name));
+            } else {
+                line.push_str(&format!(" [{}=<{}>]", arg.name, arg.name));
+            }
+        }
+        line
+    }
+}
+
+/// One message of a `prompts/get` result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptMessage {
+    pub role: String,
+    pub content: Value,
+}
+
+impl PromptMessage {
+    /// Text of the message; embedded resources are inlined and images noted.
+    pub fn text(&self) -> String {
+        match self.content.get("type").and_then(Value::as_str) {
+            Some("text") => self.content["text"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            Some("resource") => {
+                serde_json::from_value::<ResourceContents>(self.content["resource"].clone())
+                    .map(|r| r.to_text())
+                    .unwrap_or_default()
+            }
+            Some(kind) => {
+                let mime = self.content["mimeType"].as_str().unwrap_or(kind);
+                format!("[{} content omitted]", mime)
+            }
+            None => String::new(),
+        }
+    }
+}
+
+type Pending = Arc<std::sync::Mutex<HashMap<u64, oneshot::Sender<MCPResponse>>>>;
+
+/// The last lines a server wrote to stderr.
+type StderrLog = Arc<std::sync::Mutex<VecDeque<String>>>;
+
+/// Stderr lines kept per server unless `stderr_lines` says otherwise.
+const DEFAULT_STDERR_LINES: usize = 200;
+
+/// Stderr lines quoted in the error when a server fails to initialize.
+const INIT_ERROR_STDERR_LINES: usize = 5;
+
+/// How long a failed `initialize` waits for the server's last stderr output.
+const STDERR_SETTLE: Duration = Duration::from_millis(500);
+
+/// How long `shutdown` waits for a server process to exit after closing its
+/// stdin, and again after SIGTERM.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Pids of stdio servers that haven't been shut down, for exits that skip
+/// `shutdown`.
+static LIVE_SERVERS: std::sync::Mutex<Vec<u32>> = std::sync::Mutex::new(Vec::new());
+
+/// Send SIGTERM to every stdio server still running. For exits that can't
+/// wait on `MCPRegistry::close`, such as a second Ctrl-C.
+pub fn kill_servers() {
+    for pid in LIVE_SERVERS.lock().unwrap().drain(..) {
+        signal_process(pid, Signal::Term);
+    }
+}
+
+fn forget_server(pid: Option<u32>) {
+    LIVE_SERVERS.lock().unwrap().retain(|p| Some(*p) != pid);
+}
+
+/// The MCP stdio shutdown once stdin is closed: SIGTERM, then SIGKILL,
+/// waiting `SHUTDOWN_GRACE` for the server to exit before each signal.
+async fn shut_down(process: &mut Child, pid: Option<u32>) {
+    if tokio::time::timeout(SHUTDOWN_GRACE, process.wait())
+        .await
+        .is_ok()
+    {
+        return;
+    }
+    if let Some(pid) = pid {
+        signal_process(pid, Signal::Term);
+    }
+    if tokio::time::timeout(SHUTDOWN_GRACE, process.wait())
+        .await
+        .is_err()
+    {
+        let _ = process.kill().await;
+    }
+}
+
+/// How JSON-RPC messages travel between us and a server.
+#[async_trait]
+trait Transport: Send + Sync {
+    /// Deliver one JSON-RPC message: a request or notification of ours, or
+    /// our response to a request from the server.
+    async fn send_message(&self, message: &Value) -> Result<()>;
+    /// The next raw message from the server; `None` once it can send no more.
+    async fn receive_message(&self) -> Option<String>;
+    /// End the connection.
+    async fn close(&self);
+}
+
+/// A child process speaking newline-delimited JSON on stdin/stdout.
+struct StdioTransport {
+    /// Killed on drop if no one shuts it down; `None` once handed to a reaper
+    process: Mutex<Option<Child>>,
+    pid: Option<u32>,
+    /// `None` once closed
+    stdin: Mutex<Option<ChildStdin>>,
+    stdout: Mutex<BufReader<ChildStdout>>,
+}
+
+impl StdioTransport {
+    fn spawn(config: &McpServerConfig) -> Result<(Self, Option<ChildStderr>)> {
+        let mut command = Command::new(&config.command);
+        if let Some(cwd) = config.resolved_cwd()? {
+            command.current_dir(cwd);
+        }
+        let mut child = command
+            .args(&config.args)
+            .envs(config.resolved_env()?)
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .spawn()?;
+            .stderr(if config.stderr.unwrap_or(true) {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| anyhow::anyhow!("failed to start `{}`: {}", config.command, e))?;
 
         let stdin = child
             .stdin
@@ -68,26 +288,522 @@ impl MCPClient {
             .stdout
             .take()
             .ok_or_else(|| anyhow::anyhow!("Failed to get stdout"))?;
+        let stderr = child.stderr.take();
+        let pid = child.id();
+        LIVE_SERVERS.lock().unwrap().extend(pid);
+        Ok((
+            Self {
+                process: Mutex::new(Some(child)),
+                pid,
+                stdin: Mutex::new(Some(stdin)),
+                stdout: Mutex::new(BufReader::new(stdout)),
+            },
+            stderr,
+        ))
+    }
+}
+
+#[async_trait]
+impl Transport for StdioTransport {
+    async fn send_message(&self, message: &Value) -> Result<()> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        let mut stdin = self.stdin.lock().await;
+        let stdin = stdin
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("write failed: stdin is closed"))?;
+        stdin
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|e| anyhow::anyhow!("write failed: {}", e))?;
+        stdin
+            .flush()
+            .await
+            .map_err(|e| anyhow::anyhow!("write failed: {}", e))
+    }
+
+    async fn receive_message(&self) -> Option<String> {
+        let mut line = String::new();
+        match self.stdout.lock().await.read_line(&mut line).await {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line),
+        }
+    }
+
+    /// Close stdin, then `shut_down` the server.
+    async fn close(&self) {
+        self.stdin.lock().await.take();
+        if let Some(process) = self.process.lock().await.as_mut() {
+            shut_down(process, self.pid).await;
+        }
+        forget_server(self.pid);
+    }
+}
+
+impl Drop for StdioTransport {
+    fn drop(&mut self) {
+        // Dropped without `close`, e.g. on an early return. Drop can't wait
+        // without stalling a runtime worker, so a detached task gives the
+        // server the same grace; if the runtime is going away, it is killed.
+        let stdin = self.stdin.get_mut().take();
+        if let (Some(stdin), Some(mut process)) = (stdin, self.process.get_mut().take()) {
+            drop(stdin);
+            let pid = self.pid;
+            match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => {
+                    runtime.spawn(async move {
+                        shut_down(&mut process, pid).await;
+                        forget_server(pid);
+                    });
+                    return;
+                }
+                Err(_) => {
+                    let _ = process.start_kill();
+                }
+            }
+        }
+        forget_server(self.pid);
+    }
+}
+
+/// A streamable HTTP server answered 404 to our session id: it restarted or
+/// timed the session out, and a new client has to initialize again.
+#[derive(Debug)]
+struct SessionExpired;
+
+impl std::fmt::Display for SessionExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MCP session expired")
+    }
+}
+
+impl std::error::Error for SessionExpired {}
+
+/// Header carrying the session a streamable HTTP server assigned in `initialize`.
+const SESSION_HEADER: &str = "Mcp-Session-Id";
+
+/// The streamable HTTP transport: each message is POSTed to one URL and the
+/// server answers with plain JSON or an SSE stream, whose messages are queued
+/// for `receive_message`.
+struct HttpTransport {
+    name: String,
+    http: reqwest::Client,
+    url: String,
+    headers: reqwest::header::HeaderMap,
+    /// Sent back with every request once the server hands one out
+    session_id: Arc<std::sync::Mutex<Option<String>>>,
+    incoming: tokio::sync::mpsc::UnboundedSender<String>,
+    received: Mutex<tokio::sync::mpsc::UnboundedReceiver<String>>,
+}
+
+impl HttpTransport {
+    fn new(
+        name: &str,
+        url: &str,
+        headers: reqwest::header::HeaderMap,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(15))
+            .timeout(timeout)
+            .user_agent(concat!("rusty-cli/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        let (incoming, received) = tokio::sync::mpsc::unbounded_channel();
+        Ok(Self {
+            name: name.to_string(),
+            http,
+            url: url.to_string(),
+            headers,
+            session_id: Default::default(),
+            incoming,
+            received: Mutex::new(received),
+        })
+    }
+
+    fn request(&self, method: reqwest::Method) -> reqwest::RequestBuilder {
+        let mut request = self
+            .http
+            .request(method, &self.url)
+            .headers(self.headers.clone())
+            .header("Accept", "application/json, text/event-stream");
+        if let Some(id) = self.session_id.lock().unwrap().as_deref() {
+            request = request.header(SESSION_HEADER, id);
+        }
+        request
+    }
+
+    /// Queue each message of an SSE response as it arrives. If the stream
+    /// drops early, resume once with a GET carrying the same session and the
+    /// last event id, as the server may replay what we missed.
+    fn drain_events(&self, response: reqwest::Response) {
+        use eventsource_stream::Eventsource;
+        use futures_util::StreamExt;
+        let name = self.name.clone();
+        let incoming = self.incoming.clone();
+        let resume = self.request(reqwest::Method::GET);
+        tokio::spawn(async move {
+            let mut last_event_id = None;
+            let mut stream = response.bytes_stream().eventsource().boxed();
+            let mut resumed = false;
+            loop {
+                match stream.next().await {
+                    Some(Ok(event)) => {
+                        if !event.id.is_empty() {
+                            last_event_id = Some(event.id.clone());
+                        }
+                        if !event.data.is_empty() {
+                            let _ = incoming.send(event.data);
+                        }
+                    }
+                    None => break,
+                    Some(Err(e)) => {
+                        let (Some(id), false) = (&last_event_id, resumed) else {
+                            tracing::debug!(server = %name, "event stream ended: {}", e);
+                            break;
+                        };
+                        resumed = true;
+                        let Some(request) = resume.try_clone() else {
+                            break;
+                        };
+                        match request.header("Last-Event-ID", id).send().await {
+                            Ok(r) if r.status().is_success() => {
+                                stream = r.bytes_stream().eventsource().boxed();
+                            }
+                            _ => break,
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Transport for HttpTransport {
+    async fn send_message(&self, message: &Value) -> Result<()> {
+        let send = || self.request(reqwest::Method::POST).json(message).send();
+        // Retried once only if the connection was never made: a request
+        // that reached the server (say a tools/call) must not run twice
+        let response = match send().await {
+            Err(e) if e.is_connect() => send().await,
+            other => other,
+        }
+        .map_err(|e| anyhow::anyhow!("POST {} failed: {}", self.url, e))?;
+
+        if let Some(id) = response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+        {
+            *self.session_id.lock().unwrap() = Some(id.to_string());
+        }
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND && self.session_id.lock().unwrap().is_some() {
+            self.session_id.lock().unwrap().take();
+            return Err(SessionExpired.into());
+        }
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("HTTP {}: {}", status, body.trim());
+        }
+        let is_stream = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/event-stream"));
+        if is_stream {
+            self.drain_events(response);
+            return Ok(());
+        }
+        // 202 Accepted (notifications) has no body
+        let body = response.text().await?;
+        match serde_json::from_str::<Value>(&body) {
+            Ok(Value::Array(batch)) => {
+                for message in batch {
+                    let _ = self.incoming.send(message.to_string());
+                }
+            }
+            _ => {
+                let _ = self.incoming.send(body);
+            }
+        }
+        Ok(())
+    }
+
+    async fn receive_message(&self) -> Option<String> {
+        self.received.lock().await.recv().await
+    }
+
+    async fn close(&self) {
+        // Tell the server it can drop the session; it may not support this
+        if self.session_id.lock().unwrap().is_some() {
+            let _ = self.request(reqwest::Method::DELETE).send().await;
+        }
+    }
+}
 
-        let reader = BufReader::new(stdout);
+/// The HTTP+SSE transport of the 2024-11-05 spec: a GET opens an event
+/// stream whose first `endpoint` event names the URL to POST messages to;
+/// replies and server requests arrive on the stream.
+struct SseTransport {
+    http: reqwest::Client,
+    headers: reqwest::header::HeaderMap,
+    endpoint: reqwest::Url,
+    timeout: Duration,
+    received: Mutex<tokio::sync::mpsc::UnboundedReceiver<String>>,
+    /// Reads the event stream; the session ends when it stops
+    stream: tokio::task::JoinHandle<()>,
+}
 
-        let client = Self {
-            process: Arc::new(Mutex::new(child)),
-            stdin: Arc::new(Mutex::new(stdin)),
-            reader: Arc::new(Mutex::new(reader)),
-            request_id: Arc::new(Mutex::new(0)),
+impl SseTransport {
+    async fn connect(
+        name: &str,
+        url: &str,
+        headers: reqwest::header::HeaderMap,
+        timeout: Duration,
+    ) -> Result<Self> {
+        use eventsource_stream::Eventsource;
+        use futures_util::StreamExt;
+        // No overall timeout: the event stream stays open for the whole session
+        let http = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(15))
+            .user_agent(concat!("rusty-cli/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        let open = http
+            .get(url)
+            .headers(headers.clone())
+            .header("Accept", "text/event-stream")
+            .send();
+        let response = tokio::time::timeout(timeout, open)
+            .await
+            .map_err(|_| anyhow::anyhow!("GET {} timed out", url))?
+            .map_err(|e| anyhow::anyhow!("GET {} failed: {}", url, e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("GET {}: HTTP {}: {}", url, status, body.trim());
+        }
+        let base = response.url().clone();
+        let (endpoint_tx, endpoint_rx) = oneshot::channel();
+        let (incoming, received) = tokio::sync::mpsc::unbounded_channel();
+        let name = name.to_string();
+        let stream = tokio::spawn(async move {
+            let mut events = response.bytes_stream().eventsource();
+            let mut endpoint_tx = Some(endpoint_tx);
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(event) if event.event == "endpoint" => {
+                        if let Some(tx) = endpoint_tx.take() {
+                            let _ = tx.send(event.data);
+                        }
+                    }
+                    Ok(event) if !event.data.is_empty() => {
+                        if incoming.send(event.data).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::debug!(server = %name, "event stream ended: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        let endpoint = match tokio::time::timeout(timeout, endpoint_rx).await {
+            Ok(Ok(endpoint)) => base.join(endpoint.trim())?,
+            _ => {
+                stream.abort();
+                anyhow::bail!(
+                    "{} sent no endpoint event; is it an MCP server using the SSE transport?",
+                    url
+                );
+            }
         };
+        // Our headers (maybe a token) only go to the server we were given
+        if endpoint.origin() != base.origin() {
+            stream.abort();
+            anyhow::bail!(
+                "{} named an endpoint on another origin ({}); not following it",
+                url,
+                endpoint
+            );
+        }
+        Ok(Self {
+            http,
+            headers,
+            endpoint,
+            timeout,
+            received: Mutex::new(received),
+            stream,
+        })
+    }
+}
 
-        // Initialize the MCP server
-        client.initialize().await?;
+#[async_trait]
+impl Transport for SseTransport {
+    async fn send_message(&self, message: &Value) -> Result<()> {
+        // The reply comes on the event stream; the POST itself just says 202
+        let response = self
+            .http
+            .post(self.endpoint.clone())
+            .headers(self.headers.clone())
+            .timeout(self.timeout)
+            .json(message)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("POST {} failed: {}", self.endpoint, e))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            anyhow::bail!("HTTP {}: {}", status, body.trim());
+        }
+        Ok(())
+    }
+
+    async fn receive_message(&self) -> Option<String> {
+        self.received.lock().await.recv().await
+    }
+
+    async fn close(&self) {
+        self.stream.abort();
+    }
+}
+
+impl Drop for SseTransport {
+    fn drop(&mut self) {
+        self.stream.abort();
+    }
+}
+
+/// `Authorization` from `auth_token` plus the configured `headers`.
+fn http_headers(config: &McpServerConfig) -> Result<reqwest::header::HeaderMap> {
+    use reqwest::header::{HeaderName, HeaderValue};
+    let mut headers = reqwest::header::HeaderMap::new();
+    let name = config.name.get_ref();
+    if let Some(token) = config.resolved_auth_token()? {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| {
+            anyhow::anyhow!(
+                "MCP server `{}`: auth_token is not a valid header value",
+                name
+            )
+        })?;
+        headers.insert(reqwest::header::AUTHORIZATION, value);
+    }
+    for (key, value) in config.resolved_headers()? {
+        let header = HeaderName::from_bytes(key.as_bytes()).map_err(|_| {
+            anyhow::anyhow!("MCP server `{}`: {} is not a valid header name", name, key)
+        })?;
+        let value = HeaderValue::from_str(&value).map_err(|_| {
+            anyhow::anyhow!("MCP server `{}`: header {} has an invalid value", name, key)
+        })?;
+        headers.insert(header, value);
+    }
+    Ok(headers)
+}
+
+pub struct MCPClient {
+    name: String,
+    transport: Arc<dyn Transport>,
+    /// Requests waiting for a response, by id
+    pending: Pending,
+    next_id: AtomicU64,
+    timeout: Duration,
+    reader: tokio::task::JoinHandle<()>,
+    /// Set when the connection breaks; the client is then replaced
+    dead: Arc<AtomicBool>,
+    stderr_log: StderrLog,
+    /// Ends on its own when the server closes stderr
+    stderr_reader: Option<tokio::task::JoinHandle<()>>,
+    /// Negotiated in `initialize`
+    protocol_version: String,
+    /// What the server said it supports in `initialize`
+    capabilities: Value,
+}
+
+impl MCPClient {
+    pub async fn new(config: &McpServerConfig) -> Result<Self> {
+        let name = config.name.get_ref().clone();
+        let timeout = Duration::from_secs(config.timeout_secs.unwrap_or(30));
+        let stderr_log: StderrLog = Default::default();
+        let mut stderr_reader = None;
+        let transport: Arc<dyn Transport> = match &config.url {
+            Some(url) => match config.http_transport() {
+                McpTransport::StreamableHttp => Arc::new(HttpTransport::new(
+                    &name,
+                    url,
+                    http_headers(config)?,
+                    timeout,
+                )?),
+                McpTransport::Sse => Arc::new(
+                    SseTransport::connect(&name, url, http_headers(config)?, timeout).await?,
+                ),
+            },
+            None => {
+                let (transport, stderr) = StdioTransport::spawn(config)?;
+                stderr_reader = stderr.map(|stderr| {
+                    tokio::spawn(read_stderr(
+                        name.clone(),
+                        stderr,
+                        stderr_log.clone(),
+                        config.stderr_lines.unwrap_or(DEFAULT_STDERR_LINES).max(1),
+                    ))
+                });
+                Arc::new(transport)
+            }
+        };
+        let pending: Pending = Default::default();
+        let dead = Arc::new(AtomicBool::new(false));
+        let reader = tokio::spawn(read_responses(
+            name.clone(),
+            transport.clone(),
+            pending.clone(),
+            dead.clone(),
+            roots(),
+        ));
+
+        let mut client = Self {
+            name,
+            transport,
+            pending,
+            next_id: AtomicU64::new(1),
+            timeout,
+            reader,
+            dead,
+            stderr_log,
+            stderr_reader,
+            protocol_version: PROTOCOL_VERSION.to_string(),
+            capabilities: json!({}),
+        };
+
+        // Initialize the MCP server; what it said on stderr usually explains a failure
+        if let Err(e) = client.initialize().await {
+            let tail = client.stderr_tail(INIT_ERROR_STDERR_LINES).await;
+            if tail.is_empty() {
+                return Err(e);
+            }
+            let tail: Vec<String> = tail.iter().map(|line| format!("  {}", line)).collect();
+            anyhow::bail!("{}; its stderr ended with:\n{}", e, tail.join("\n"));
+        }
 
         Ok(client)
     }
 
+    /// The last `n` stderr lines, after giving a server that is exiting a
+    /// moment to finish writing them.
+    async fn stderr_tail(&mut self, n: usize) -> Vec<String> {
+        if let Some(stderr_reader) = &mut self.stderr_reader {
+            let _ = tokio::time::timeout(STDERR_SETTLE, stderr_reader).await;
+        }
+        let log = self.stderr_log.lock().unwrap();
+        log.iter()
+            .skip(log.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
     async fn send_request(&self, method: &str, params: Option<Value>) -> Result<Value> {
-        let mut id = self.request_id.lock().await;
-        *id += 1;
-        let request_id = *id;
+        let request_id = self.next_id.fetch_add(1, Ordering::Relaxed);
 
         let request = MCPRequest {
             jsonrpc: "2.0".to_string(),
@@ -96,19 +812,28 @@ impl MCPClient {
             id: Some(json!(request_id)),
         };
 
-        let request_str = serde_json::to_string(&request)?;
-        
-        let mut stdin = self.stdin.lock().await;
-        stdin.write_all(request_str.as_bytes()).await?;
-        stdin.write_all(b"\n").await?;
-        stdin.flush().await?;
+        // Register before writing so a fast reply can't arrive unclaimed
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(request_id, tx);
 
-        // Read response
-        let mut reader = self.reader.lock().await;
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
+        if let Err(e) = self.write(&request).await {
+            self.pending.lock().unwrap().remove(&request_id);
+            return Err(e);
+        }
 
-        let response: MCPResponse = serde_json::from_str(&line)?;
+        let response = match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(_)) => anyhow::bail!("MCP server {} closed its output", self.name),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&request_id);
+                anyhow::bail!(
+                    "MCP server {}: {} timed out after {}s",
+                    self.name,
+                    method,
+                    self.timeout.as_secs()
+                );
+            }
+        };
 
         if let Some(error) = response.error {
             return Err(anyhow::anyhow!("MCP Error: {}", error.message));
@@ -119,23 +844,66 @@ impl MCPClient {
             .ok_or_else(|| anyhow::anyhow!("No result in response"))
     }
 
-    async fn initialize(&self) -> Result<()> {
+    /// Send a notification; servers don't answer these.
+    async fn send_notification(&self, method: &str, params: Option<Value>) -> Result<()> {
+        self.write(&MCPRequest {
+            jsonrpc: "2.0".to_string(),
+            method: method.to_string(),
+            params,
+            id: None,
+        })
+        .await
+    }
+
+    async fn write(&self, message: &MCPRequest) -> Result<()> {
+        if let Err(e) = self
+            .transport
+            .send_message(&serde_json::to_value(message)?)
+            .await
+        {
+            self.dead.store(true, Ordering::Relaxed);
+            if e.is::<SessionExpired>() {
+                return Err(e);
+            }
+            anyhow::bail!("MCP server {}: {}", self.name, e);
+        }
+        Ok(())
+    }
+
+    /// Handshake: offer our protocol version, adopt whichever the server
+    /// answers with, record its capabilities, then confirm with `initialized`.
+    async fn initialize(&mut self) -> Result<()> {
         let params = json!({
-            "protocolVersion": "1.0.0",
-            "capabilities": {
-                "tools": {}
-            },
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {"roots": {"listChanged": false}},
             "clientInfo": {
-                "name": "deepseek-cli",
-                "version": "0.1.0"
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION")
             }
         });
 
-        self.send_request("initialize", Some(params)).await?;
-        Ok(())
+        let result = self.send_request("initialize", Some(params)).await?;
+        if let Some(version) = result.get("protocolVersion").and_then(|v| v.as_str()) {
+            self.protocol_version = version.to_string();
+        }
+        self.capabilities = result.get("capabilities").cloned().unwrap_or(json!({}));
+        self.send_notification("notifications/initialized", None)
+            .await
+    }
+
+    pub fn protocol_version(&self) -> &str {
+        &self.protocol_version
+    }
+
+    /// Whether the server declared the `tools` capability.
+    pub fn supports_tools(&self) -> bool {
+        self.capabilities.get("tools").is_some()
     }
 
     pub async fn list_tools(&self) -> Result<Vec<MCPTool>> {
+        if !self.supports_tools() {
+            return Ok(vec![]);
+        }
         let result = self.send_request("tools/list", None).await?;
         let tools_result: ToolsListResult = serde_json::from_value(result)?;
         Ok(tools_result.tools)
@@ -149,100 +917,1301 @@ impl MCPClient {
 
         self.send_request("tools/call", Some(params)).await
     }
+
+    pub async fn list_resources(&self) -> Result<Vec<MCPResource>> {
+        if self.capabilities.get("resources").is_none() {
+            return Ok(vec![]);
+        }
+        let result = self.send_request("resources/list", None).await?;
+        Ok(serde_json::from_value(
+            result.get("resources").cloned().unwrap_or(json!([])),
+        )?)
+    }
+
+    pub async fn read_resource(&self, uri: &str) -> Result<Vec<ResourceContents>> {
+        let result = self
+            .send_request("resources/read", Some(json!({ "uri": uri })))
+            .await?;
+        Ok(serde_json::from_value(
+            result.get("contents").cloned().unwrap_or(json!([])),
+        )?)
+    }
+
+    pub async fn list_prompts(&self) -> Result<Vec<MCPPrompt>> {
+        if self.capabilities.get("prompts").is_none() {
+            return Ok(vec![]);
+        }
+        let result = self.send_request("prompts/list", None).await?;
+        Ok(serde_json::from_value(
+            result.get("prompts").cloned().unwrap_or(json!([])),
+        )?)
+    }
+
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<Vec<PromptMessage>> {
+        let result = self
+            .send_request(
+                "prompts/get",
+                Some(json!({ "name": name, "arguments": arguments })),
+            )
+            .await?;
+        Ok(serde_json::from_value(
+            result.get("messages").cloned().unwrap_or(json!([])),
+        )?)
+    }
+
+    /// False once the server has closed its output or a write to it failed.
+    pub fn is_alive(&self) -> bool {
+        !self.dead.load(Ordering::Relaxed)
+    }
+
+    /// Let the server go: close a process's stdin (killing it if it hasn't
+    /// exited after a short grace period) or end an HTTP session.
+    pub async fn shutdown(&self) {
+        self.transport.close().await;
+        self.dead.store(true, Ordering::Relaxed);
+        self.reader.abort();
+    }
+}
+
+impl Drop for MCPClient {
+    fn drop(&mut self) {
+        self.reader.abort();
+        // A grandchild holding stderr open would otherwise keep the task alive
+        if let Some(stderr_reader) = &self.stderr_reader {
+            stderr_reader.abort();
+            save_stderr(&self.name, &self.stderr_log);
+        }
+    }
+}
+
+/// Where a server's last stderr lines are saved for `mcp logs`.
+pub fn stderr_log_path(server: &str) -> std::path::PathBuf {
+    SessionStore::data_dir()
+        .join("mcp-logs")
+        .join(format!("{}.log", server))
+}
+
+static ECHO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Also print servers' stderr to the terminal as it arrives (`mcp call --verbose`).
+pub fn set_echo_stderr(on: bool) {
+    ECHO_STDERR.store(on, Ordering::Relaxed);
+}
+
+/// Forward a server's stderr to the log, keeping the last `cap` lines. Lines
+/// that look like errors are logged at `warn`, the rest at `debug`. Returns
+/// when the server closes stderr, normally by exiting.
+async fn read_stderr(name: String, stderr: ChildStderr, log: StderrLog, cap: usize) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if looks_like_error(&line) {
+            tracing::warn!(server = %name, "stderr: {}", line);
+        } else {
+            tracing::debug!(server = %name, "stderr: {}", line);
+        }
+        if ECHO_STDERR.load(Ordering::Relaxed) {
+            eprintln!("{} {}", format!("[{}]", name).dimmed(), line);
+        }
+        let mut log = log.lock().unwrap();
+        if log.len() == cap {
+            log.pop_front();
+        }
+        log.push_back(line);
+    }
+    save_stderr(&name, &log);
+}
+
+fn looks_like_error(line: &str) -> bool {
+    let line = line.to_ascii_lowercase();
+    ["error", "panic", "fatal", "exception", "traceback"]
+        .iter()
+        .any(|word| line.contains(word))
+}
+
+/// Write the captured lines to `stderr_log_path`; failures are ignored.
+fn save_stderr(name: &str, log: &StderrLog) {
+    let lines: Vec<String> = log.lock().unwrap().iter().cloned().collect();
+    if lines.is_empty() {
+        return;
+    }
+    let path = stderr_log_path(name);
+    if let Some(parent) = path.parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    let _ = std::fs::write(path, lines.join("\n") + "\n");
 }
 
+/// Where a handle's server is in its life.
+enum ClientState {
+    /// Not spawned yet; its tools came from the cache
+    Idle,
+    Running(Arc<MCPClient>),
+    /// Failed to start, or shut down
+    Down,
+}
+
+/// A configured server and its current client. The server is started on
+/// first use; a client whose server died is replaced once, on the next call.
+/// A server that fails to start is reported once and its tools are switched
+/// off for the rest of the session.
+pub struct MCPHandle {
+    config: McpServerConfig,
+    client: Mutex<ClientState>,
+    disabled: AtomicBool,
+}
+
+impl MCPHandle {
+    fn name(&self) -> &str {
+        self.config.name.get_ref()
+    }
+
+    fn new(config: &McpServerConfig, state: ClientState) -> Self {
+        Self {
+            config: config.clone(),
+            client: Mutex::new(state),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// A live client, starting the server on first use or respawning it if
+    /// the last one died.
+    async fn client(&self) -> Result<Arc<MCPClient>> {
+        let mut slot = self.client.lock().await;
+        let first_start = match &*slot {
+            ClientState::Running(client) if client.is_alive() => return Ok(client.clone()),
+            ClientState::Running(_) => {
+                eprintln!(
+                    "{}",
+                    format!("MCP server {} stopped; restarting...", self.name()).dimmed()
+                );
+                false
+            }
+            ClientState::Idle => {
+                eprintln!(
+                    "{}",
+                    format!("starting MCP server {}...", self.name()).dimmed()
+                );
+                true
+            }
+            ClientState::Down => anyhow::bail!("MCP server {} unavailable", self.name()),
+        };
+        match MCPClient::new(&self.config).await {
+            Ok(client) => {
+                if first_start {
+                    // Keep the cache in step with what the server offers now
+                    if let Ok(tools) = client.list_tools().await {
+                        cache_tools(&self.config, client.protocol_version(), &tools);
+                    }
+                }
+                let client = Arc::new(client);
+                *slot = ClientState::Running(client.clone());
+                Ok(client)
+            }
+            Err(e) => {
+                *slot = ClientState::Down;
+                self.disabled.store(true, Ordering::Relaxed);
+                eprintln!(
+                    "{} MCP server {} unavailable: {}; its tools are off for this session",
+                    "warning:".yellow(),
+                    self.name(),
+                    e
+                );
+                anyhow::bail!("MCP server {} unavailable: {}", self.name(), e)
+            }
+        }
+    }
+
+    /// Run `op` on a live client. A request the server refused because it
+    /// forgot our HTTP session was never processed, so it is sent once more
+    /// under a new session.
+    async fn with_client<T, F, Fut>(&self, op: F) -> Result<T>
+    where
+        F: Fn(Arc<MCPClient>) -> Fut,
+        Fut: std::future::Future<Output = Result<T>>,
+    {
+        match op(self.client().await?).await {
+            Err(e) if e.is::<SessionExpired>() => op(self.client().await?).await,
+            other => other,
+        }
+    }
+
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<Value> {
+        self.with_client(|c| {
+            let arguments = arguments.clone();
+            async move { c.call_tool(name, arguments).await }
+        })
+        .await
+    }
+
+    pub async fn list_resources(&self) -> Result<Vec<MCPResource>> {
+        self.with_client(|c| async move { c.list_resources().await })
+            .await
+    }
+
+    pub async fn read_resource(&self, uri: &str) -> Result<Vec<ResourceContents>> {
+        self.with_client(|c| async move { c.read_resource(uri).await })
+            .await
+    }
+
+    pub async fn list_prompts(&self) -> Result<Vec<MCPPrompt>> {
+        self.with_client(|c| async move { c.list_prompts().await })
+            .await
+    }
+
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<Vec<PromptMessage>> {
+        self.with_client(|c| async move { c.get_prompt(name, arguments).await })
+            .await
+    }
+
+    pub async fn shutdown(&self) {
+        let state = std::mem::replace(&mut *self.client.lock().await, ClientState::Down);
+        if let ClientState::Running(client) = state {
+            client.shutdown().await;
+        }
+    }
+}
+
+/// What a cached tool list was discovered from: the config entry and the
+/// modification times of the server program and of any arguments that are
+/// files (such as a script). Any change means the list is discovered again.
+fn fingerprint(config: &McpServerConfig) -> String {
+    let mut source = serde_json::to_string(config).unwrap_or_default();
+    let program = resolve_program(&config.command);
+    for path in program.iter().chain(
+        config
+            .args
+            .iter()
+            .map(std::path::PathBuf::from)
+            .collect::<Vec<_>>()
+            .iter(),
+    ) {
+        if let Ok(modified) = std::fs::metadata(path).and_then(|m| m.modified()) {
+            source.push_str(&format!("\n{}:{:?}", path.display(), modified));
+        }
+    }
+    let digest = Sha256::digest(source.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `command` as a path: as given when it names one, otherwise found on PATH.
+fn resolve_program(command: &str) -> Option<std::path::PathBuf> {
+    if command.is_empty() {
+        return None;
+    }
+    if command.contains(std::path::MAIN_SEPARATOR) {
+        return Some(command.into());
+    }
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(command))
+        .find(|path| path.is_file())
+}
+
+/// The tool list cached for this exact config and server program, if any.
+fn cached_tools(config: &McpServerConfig) -> Option<(String, Vec<MCPTool>)> {
+    let (protocol_version, tools) =
+        SessionStore::cached_mcp_tools(config.name.get_ref(), &fingerprint(config)).ok()??;
+    Some((protocol_version, serde_json::from_str(&tools).ok()?))
+}
+
+fn cache_tools(config: &McpServerConfig, protocol_version: &str, tools: &[MCPTool]) {
+    let Ok(tools) = serde_json::to_string(tools) else {
+        return;
+    };
+    if let Err(e) = SessionStore::cache_mcp_tools(
+        config.name.get_ref(),
+        &fingerprint(config),
+        protocol_version,
+        &tools,
+    ) {
+        tracing::debug!(server = %config.name.get_ref(), "caching tool list failed: {}", e);
+    }
+}
+
+/// The directories servers may work in, as `roots/list` results: `[mcp]
+/// roots` from the config, else the sandbox root or working directory.
+fn roots() -> Vec<Value> {
+    let configured = crate::config::Config::load()
+        .map(|cfg| cfg.mcp.roots)
+        .unwrap_or_default();
+    let dirs: Vec<String> = if configured.is_empty() {
+        crate::tools::sandbox_root()
+            .or_else(|| std::env::current_dir().ok())
+            .map(|dir| dir.to_string_lossy().into_owned())
+            .into_iter()
+            .collect()
+    } else {
+        configured
+    };
+    dirs.iter()
+        .filter_map(|dir| {
+            if dir.starts_with("file://") {
+                return Some(json!({ "uri": dir }));
+            }
+            let path = std::path::absolute(dir).ok()?;
+            let uri = reqwest::Url::from_file_path(&path).ok()?;
+            let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+            Some(match name {
+                Some(name) => json!({ "uri": uri.as_str(), "name": name }),
+                None => json!({ "uri": uri.as_str() }),
+            })
+        })
+        .collect()
+}
+
+/// Our answer to a request the server sent us.
+fn answer(method: &str, roots: &[Value]) -> std::result::Result<Value, MCPError> {
+    match method {
+        "roots/list" => Ok(json!({ "roots": roots })),
+        "ping" => Ok(json!({})),
+        _ => Err(MCPError {
+            code: METHOD_NOT_FOUND,
+            message: format!("method not supported: {}", method),
+            data: None,
+        }),
+    }
+}
+
+/// Route each response line to the request waiting on its id. Requests from
+/// the server (`roots/list`, `ping`) are answered, with an error for methods
+/// we don't support; notifications are logged and otherwise ignored. When
+/// the server's output ends, pending requests are dropped so their callers
+/// fail instead of waiting out the timeout, and the client is marked dead.
+async fn read_responses(
+    name: String,
+    transport: Arc<dyn Transport>,
+    pending: Pending,
+    dead: Arc<AtomicBool>,
+    roots: Vec<Value>,
+) {
+    while let Some(line) = transport.receive_message().await {
+        let message: Value = match serde_json::from_str(line.trim()) {
+            Ok(message) => message,
+            Err(_) => {
+                if !line.trim().is_empty() {
+                    tracing::debug!(server = %name, "ignoring non-JSON output: {}", line.trim());
+                }
+                continue;
+            }
+        };
+        if let Some(method) = message.get("method").and_then(|m| m.as_str()) {
+            let Some(id) = message.get("id") else {
+                tracing::debug!(server = %name, "ignoring server notification {}", method);
+                continue;
+            };
+            tracing::debug!(server = %name, "server request {}", method);
+            let (result, error) = match answer(method, &roots) {
+                Ok(result) => (Some(result), None),
+                Err(error) => (None, Some(error)),
+            };
+            let response = MCPResponse {
+                jsonrpc: "2.0".to_string(),
+                result,
+                error,
+                id: Some(id.clone()),
+            };
+            if let Ok(response) = serde_json::to_value(&response) {
+                if let Err(e) = transport.send_message(&response).await {
+                    tracing::debug!(server = %name, "answering {} failed: {}", method, e);
+                }
+            }
+            continue;
+        }
+        let Some(id) = message.get("id").and_then(|id| id.as_u64()) else {
+            continue;
+        };
+        let Some(tx) = pending.lock().unwrap().remove(&id) else {
+            tracing::debug!(server = %name, "response to unknown or expired request {}", id);
+            continue;
+        };
+        match serde_json::from_value::<MCPResponse>(message) {
+            Ok(response) => {
+                let _ = tx.send(response);
+            }
+            Err(e) => tracing::debug!(server = %name, "malformed response {}: {}", id, e),
+        }
+    }
+    dead.store(true, Ordering::Relaxed);
+    pending.lock().unwrap().clear();
+}
+
+#[derive(Clone)]
 pub struct MCPToolWrapper {
-    client: Arc<MCPClient>,
+    server: Arc<MCPHandle>,
     tool: MCPTool,
+    /// Name offered to the model: `<server>__<tool>`
+    exposed: String,
 }
 
 impl MCPToolWrapper {
-    pub fn new(client: Arc<MCPClient>, tool: MCPTool) -> Self {
-        Self { client, tool }
+    pub fn new(server: Arc<MCPHandle>, tool: MCPTool) -> Self {
+        let exposed = format!("{}__{}", server.name(), tool.name);
+        Self {
+            server,
+            tool,
+            exposed,
+        }
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for MCPToolWrapper {
+    fn name(&self) -> &str {
+        &self.exposed
+    }
+
+    // We can't tell what a server's tool touches, so treat it like a write
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+
+    fn available(&self) -> bool {
+        !self.server.disabled.load(Ordering::Relaxed)
     }
 
-    pub fn to_deepseek_tool(&self) -> crate::tools::Tool {
-        crate::tools::Tool {
+    fn definition(&self) -> Tool {
+        Tool {
             r#type: "function".to_string(),
-            function: crate::tools::Function {
-                name: self.tool.name.clone(),
-                description: self
-                    .tool
-                    .description
-                    .clone()
-                    .unwrap_or_else(|| "MCP tool".to_string()),
+            function: Function {
+                name: self.exposed.clone(),
+                description: format!(
+                    "[MCP server {}] {}",
+                    self.server.name(),
+                    self.tool.description.as_deref().unwrap_or("MCP tool")
+                ),
                 parameters: self.tool.input_schema.clone(),
             },
         }
     }
+
+    async fn execute(&self, args: &str) -> Result<String> {
+        let arguments: Value = if args.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(args)?
+        };
+        let result = self.server.call_tool(&self.tool.name, arguments).await?;
+        match call_result_text(&result) {
+            Some(Ok(text)) => Ok(text),
+            // The tool ran and reported failure: hand it to the model as an error
+            Some(Err(text)) => Err(anyhow::anyhow!(text)),
+            None => Ok(serde_json::to_string_pretty(&result)?),
+        }
+    }
 }
 
-#[async_trait]
-impl crate::tools::ToolExecutor for MCPToolWrapper {
-    fn name(&self) -> &str {
-        &self.tool.name
+/// The text of a `tools/call` result: text blocks joined, images and
+/// resources described by type and size. `Err` holds the text of a result
+/// flagged `isError`; `None` means it isn't the standard CallToolResult shape.
+pub fn call_result_text(result: &Value) -> Option<std::result::Result<String, String>> {
+    let blocks = result.get("content")?.as_array()?;
+    let mut parts = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        let part = match block.get("type").and_then(Value::as_str) {
+            Some("text") => block.get("text")?.as_str()?.to_string(),
+            Some(kind @ ("image" | "audio")) => {
+                let mime = block["mimeType"].as_str().unwrap_or(kind);
+                let bytes = block["data"].as_str().map_or(0, |d| d.len() * 3 / 4);
+                format!("[{} {}, ~{} bytes]", kind, mime, bytes)
+            }
+            Some("resource") => {
+                let resource = &block["resource"];
+                let uri = resource["uri"].as_str().unwrap_or("?");
+                let mime = resource["mimeType"].as_str().unwrap_or("unknown type");
+                let size = match (resource["text"].as_str(), resource["blob"].as_str()) {
+                    (Some(text), _) => format!("{} chars", text.chars().count()),
+                    (None, Some(blob)) => format!("~{} bytes", blob.len() * 3 / 4),
+                    _ => "empty".to_string(),
+                };
+                format!("[resource {} ({}), {}]", uri, mime, size)
+            }
+            Some("resource_link") => format!("[resource {}]", block["uri"].as_str().unwrap_or("?")),
+            _ => block.to_string(),
+        };
+        parts.push(part);
+    }
+    let text = parts.join("\n");
+    if result.get("isError").and_then(Value::as_bool) == Some(true) {
+        Some(Err(if text.is_empty() {
+            "tool reported an error".to_string()
+        } else {
+            text
+        }))
+    } else {
+        Some(Ok(text))
     }
+}
 
-    async fn execute(&self, args: &str) -> Result<String> {
-        let arguments: Value = serde_json::from_str(args)?;
-        let result = self.client.call_tool(&self.tool.name, arguments).await?;
-        Ok(serde_json::to_string_pretty(&result)?)
+/// A started server and the tools it offered.
+pub struct MCPServer {
+    pub name: String,
+    /// Protocol revision agreed in the handshake
+    pub protocol_version: String,
+    handle: Arc<MCPHandle>,
+    /// Ask before each call to one of its tools
+    pub confirm: bool,
+    pub tools: Vec<MCPToolWrapper>,
+}
+
+impl MCPServer {
+    /// Call a tool by the server's own name for it, returning the raw result.
+    pub async fn call_tool(&self, name: &str, arguments: Value) -> Result<Value> {
+        self.handle.call_tool(name, arguments).await
+    }
+
+    pub async fn list_resources(&self) -> Result<Vec<MCPResource>> {
+        self.handle.list_resources().await
+    }
+
+    pub async fn read_resource(&self, uri: &str) -> Result<Vec<ResourceContents>> {
+        self.handle.read_resource(uri).await
+    }
+
+    pub async fn list_prompts(&self) -> Result<Vec<MCPPrompt>> {
+        self.handle.list_prompts().await
+    }
+
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: &HashMap<String, String>,
+    ) -> Result<Vec<PromptMessage>> {
+        self.handle.get_prompt(name, arguments).await
     }
 }
 
+#[derive(Default)]
 pub struct MCPRegistry {
-    clients: Vec<Arc<MCPClient>>,
-    tools: HashMap<String, Box<dyn crate::tools::ToolExecutor>>,
+    servers: Vec<MCPServer>,
+    started: bool,
 }
 
 impl MCPRegistry {
-    pub async fn new() -> Result<Self> {
-        Ok(Self {
-            clients: Vec::new(),
-            tools: HashMap::new(),
-        })
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub async fn add_mcp_server(&mut self, command: &str, args: Vec<String>) -> Result<()> {
-        let client = Arc::new(MCPClient::new(command, args).await?);
-        let tools = client.list_tools().await?;
+    /// Load every configured server the first time it's called. A server
+    /// whose tool list is cached (and unchanged since) is only started when
+    /// first used; the rest are started now to discover their tools, and one
+    /// that fails to start is reported and left out.
+    pub async fn start(&mut self, configs: &[McpServerConfig]) {
+        if self.started {
+            return;
+        }
+        self.started = true;
+        for config in configs {
+            if let Some((protocol_version, tools)) = cached_tools(config) {
+                self.add_idle(config, protocol_version, tools);
+                continue;
+            }
+            let name = config.name.get_ref();
+            println!("{}", format!("starting MCP server {}...", name).dimmed());
+            if let Err(e) = self.add_server(config).await {
+                eprintln!(
+                    "{} MCP server {} unavailable: {}",
+                    "warning:".yellow(),
+                    name,
+                    e
+                );
+            }
+        }
+    }
 
-        for tool in tools {
-            let wrapper = Box::new(MCPToolWrapper::new(client.clone(), tool));
-            self.tools.insert(wrapper.name().to_string(), wrapper);
+    /// Add the servers' tools to `registry` as `<server>__<tool>`. A bare
+    /// tool name only one server offers also routes to that server, unless a
+    /// built-in has the name and `prefer` keeps the built-in. Returns the
+    /// names (prefixed and bare) that need confirming.
+    pub fn register_into(
+        &self,
+        registry: &mut ToolRegistry,
+        policy: &ToolPolicy,
+        prefer: ToolPreference,
+    ) -> Vec<String> {
+        let mut confirm = vec![];
+        // Bare name -> the registered tools offering it, and whether each needs confirming
+        let mut candidates: HashMap<&str, Vec<(&str, bool)>> = HashMap::new();
+        for server in &self.servers {
+            for tool in &server.tools {
+                if registry.contains(&tool.exposed) {
+                    eprintln!(
+                        "{} MCP tool `{}` clashes with another tool; skipped",
+                        "warning:".yellow(),
+                        tool.exposed
+                    );
+                    continue;
+                }
+                if registry.register_checked(Box::new(tool.clone()), policy) {
+                    if server.confirm {
+                        confirm.push(tool.exposed.clone());
+                    }
+                    candidates
+                        .entry(&tool.tool.name)
+                        .or_default()
+                        .push((&tool.exposed, server.confirm));
+                }
+            }
         }
+        for (bare, found) in candidates {
+            let [(target, needs_confirm)] = found[..] else {
+                continue;
+            };
+            if registry.contains(bare) && prefer == ToolPreference::Builtin {
+                continue;
+            }
+            registry.alias(bare, target);
+            if needs_confirm {
+                confirm.push(bare.to_string());
+            }
+        }
+        confirm
+    }
 
-        self.clients.push(client);
+    /// Start a configured server, handshake, and fetch its tool list (which
+    /// is cached for later sessions).
+    pub async fn add_server(&mut self, config: &McpServerConfig) -> Result<()> {
+        let client = Arc::new(MCPClient::new(config).await?);
+        let listed = client.list_tools().await;
+        let protocol_version = client.protocol_version().to_string();
+        let handle = Arc::new(MCPHandle::new(config, ClientState::Running(client)));
+        let listed = match listed {
+            Ok(listed) => listed,
+            Err(e) => {
+                handle.shutdown().await;
+                return Err(e);
+            }
+        };
+        cache_tools(config, &protocol_version, &listed);
+        self.push(config, protocol_version, handle, listed);
         Ok(())
     }
 
-    pub fn get_tool_definitions(&self) -> Vec<crate::tools::Tool> {
-        self.tools
-            .values()
-            .map(|tool| {
-                // This is a bit hacky but works for now
-                if let Some(wrapper) = tool.as_any().downcast_ref::<MCPToolWrapper>() {
-                    wrapper.to_deepseek_tool()
-                } else {
-                    // Fallback for non-MCP tools
-                    crate::tools::Tool {
-                        r#type: "function".to_string(),
-                        function: crate::tools::Function {
-                            name: tool.name().to_string(),
-                            description: format!("Tool: {}", tool.name()),
-                            parameters: json!({}),
-                        },
-                    }
-                }
+    /// Register a server from its cached tool list without starting it.
+    fn add_idle(
+        &mut self,
+        config: &McpServerConfig,
+        protocol_version: String,
+        tools: Vec<MCPTool>,
+    ) {
+        let handle = Arc::new(MCPHandle::new(config, ClientState::Idle));
+        self.push(config, protocol_version, handle, tools);
+    }
+
+    fn push(
+        &mut self,
+        config: &McpServerConfig,
+        protocol_version: String,
+        handle: Arc<MCPHandle>,
+        tools: Vec<MCPTool>,
+    ) {
+        let tools = tools
+            .into_iter()
+            .map(|tool| MCPToolWrapper::new(handle.clone(), tool))
+            .collect();
+        self.servers.push(MCPServer {
+            name: config.name.get_ref().clone(),
+            protocol_version,
+            handle,
+            confirm: config.confirm.unwrap_or(true),
+            tools,
+        });
+    }
+
+    pub fn servers(&self) -> &[MCPServer] {
+        &self.servers
+    }
+
+    pub fn server(&self, name: &str) -> Option<&MCPServer> {
+        self.servers.iter().find(|s| s.name == name)
+    }
+
+    /// Shut down every server; a later `start` brings them back.
+    pub async fn close(&mut self) {
+        for server in self.servers.drain(..) {
+            server.handle.shutdown().await;
+        }
+        self.started = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::test_support::{isolate_data_dir, scratch_dir};
+    use std::path::{Path, PathBuf};
+
+    /// A stdio MCP server in sh. It offers `echo`, plus the tool named in an
+    /// `also` file, and each `tools/call` does what its tool name says. Its
+    /// resources are a text file and a PNG blob. In the
+    /// directory given as its argument it appends its pid to `starts` and,
+    /// once its stdin closes, to `exits`, every tool it is asked to call to
+    /// `calls`, and every response we send it to `replies`.
+    const FAKE_SERVER: &str = r#"
+dir=$1
+echo $$ >> "$dir/starts"
+[ -e "$dir/broken" ] && exit 1
+reply() { printf '{"jsonrpc":"2.0","id":%s,"result":%s}\n' "$1" "$2"; }
+text() { reply "$1" "{\"content\":[{\"type\":\"text\",\"text\":\"$2\"}]}"; }
+fail() { printf '{"jsonrpc":"2.0","id":%s,"error":{"code":%s,"message":"%s"}}\n' "$1" "$2" "$3"; }
+while IFS= read -r line; do
+  case $line in
+    *'"method"'*) ;;
+    *) echo "$line" >> "$dir/replies"; continue ;;
+  esac
+  id=$(printf '%s\n' "$line" | sed -n 's/.*"id":\([0-9][0-9]*\).*/\1/p')
+  method=$(printf '%s\n' "$line" | sed -n 's/.*"method":"\([^"]*\)".*/\1/p')
+  tool=$(printf '%s\n' "$line" | sed -n 's/.*"name":"\([^"]*\)".*/\1/p')
+  [ "$method" = tools/call ] && echo "$tool" >> "$dir/calls"
+  case $method/$tool in
+    initialize/*) reply "$id" '{"protocolVersion":"2024-11-05","capabilities":{"tools":{},"resources":{}}}' ;;
+    tools/list/*)
+      tools='{"name":"echo","inputSchema":{"type":"object"}}'
+      [ -e "$dir/also" ] && tools="$tools,{\"name\":\"$(cat "$dir/also")\",\"inputSchema\":{\"type\":\"object\"}}"
+      reply "$id" "{\"tools\":[$tools]}" ;;
+    resources/list/*)
+      reply "$id" '{"resources":[{"uri":"file:///notes.txt","name":"notes","mimeType":"text/plain"},{"uri":"file:///logo.png","name":"logo","description":"The logo","mimeType":"image/png"}]}' ;;
+    resources/read/*)
+      uri=$(printf '%s\n' "$line" | sed -n 's/.*"uri":"\([^"]*\)".*/\1/p')
+      case $uri in
+        file:///notes.txt) reply "$id" '{"contents":[{"uri":"file:///notes.txt","mimeType":"text/plain","text":"buy milk"}]}' ;;
+        file:///logo.png) reply "$id" '{"contents":[{"uri":"file:///logo.png","mimeType":"image/png","blob":"iVBORw0KGgo="}]}' ;;
+        *) fail "$id" -32002 "Resource not found: $uri" ;;
+      esac ;;
+    tools/call/slow) (sleep 1; text "$id" slow) & ;;
+    tools/call/fast)
+      echo '{"jsonrpc":"2.0","method":"notifications/message","params":{"level":"info","data":"busy"}}'
+      echo 'not json'
+      text "$id" fast ;;
+    tools/call/never) ;;
+    tools/call/crash) exit 1 ;;
+    tools/call/ask)
+      echo '{"jsonrpc":"2.0","id":"r1","method":"roots/list"}'
+      echo '{"jsonrpc":"2.0","id":"r2","method":"ping"}'
+      echo '{"jsonrpc":"2.0","id":"r3","method":"sampling/createMessage","params":{}}'
+      text "$id" asked ;;
+    tools/call/*) text "$id" "$tool" ;;
+  esac
+done
+echo $$ >> "$dir/exits"
+"#;
+
+    /// Config for a fake server keeping its state in a fresh directory.
+    fn fake_server(timeout_secs: u64) -> (McpServerConfig, PathBuf) {
+        fake_server_named("fake", timeout_secs)
+    }
+
+    fn fake_server_named(name: &str, timeout_secs: u64) -> (McpServerConfig, PathBuf) {
+        isolate_data_dir();
+        let dir = scratch_dir("mcp");
+        let script = dir.join("server.sh");
+        std::fs::write(&script, FAKE_SERVER).unwrap();
+        let config = toml::from_str(&format!(
+            "name = {:?}\ncommand = \"sh\"\nargs = [{:?}, {:?}]\ntimeout_secs = {}\nstderr = false",
+            name,
+            script.display().to_string(),
+            dir.display().to_string(),
+            timeout_secs
+        ))
+        .unwrap();
+        (config, dir)
+    }
+
+    fn text(result: &Value) -> &str {
+        result["content"][0]["text"].as_str().unwrap_or_default()
+    }
+
+    fn lines(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn responses_are_matched_to_requests_by_id() {
+        let (config, _) = fake_server(5);
+        let client = MCPClient::new(&config).await.unwrap();
+        let started = std::time::Instant::now();
+        let (slow, fast) = tokio::join!(client.call_tool("slow", json!({})), async {
+            // Sent second, answered first, after a notification and noise
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let fast = client.call_tool("fast", json!({})).await;
+            (fast, started.elapsed())
+        });
+        let (fast, fast_took) = fast;
+        assert_eq!(text(&slow.unwrap()), "slow");
+        assert_eq!(text(&fast.unwrap()), "fast");
+        assert!(fast_took < Duration::from_millis(900), "{:?}", fast_took);
+        assert!(client.is_alive());
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn a_request_without_a_response_times_out() {
+        let (config, _) = fake_server(1);
+        let client = MCPClient::new(&config).await.unwrap();
+        let err = client.call_tool("never", json!({})).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "MCP server fake: tools/call timed out after 1s"
+        );
+        // The connection is still usable
+        let result = client.call_tool("echo", json!({})).await.unwrap();
+        assert_eq!(text(&result), "echo");
+        client.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn a_crashed_server_is_restarted_once() {
+        let (config, dir) = fake_server(5);
+        let starts = || lines(&dir.join("starts")).len();
+        let handle = MCPHandle::new(&config, ClientState::Idle);
+        assert_eq!(
+            text(&handle.call_tool("echo", json!({})).await.unwrap()),
+            "echo"
+        );
+        assert_eq!(starts(), 1);
+
+        let err = handle.call_tool("crash", json!({})).await.unwrap_err();
+        assert_eq!(err.to_string(), "MCP server fake closed its output");
+        assert_eq!(
+            text(&handle.call_tool("echo", json!({})).await.unwrap()),
+            "echo"
+        );
+        assert_eq!(starts(), 2);
+
+        // If the replacement can't start either, the server stays down
+        std::fs::write(dir.join("broken"), "").unwrap();
+        assert!(handle.call_tool("crash", json!({})).await.is_err());
+        let err = handle.call_tool("echo", json!({})).await.unwrap_err();
+        assert!(
+            err.to_string().starts_with("MCP server fake unavailable: "),
+            "{}",
+            err
+        );
+        assert_eq!(starts(), 3);
+        let err = handle.call_tool("echo", json!({})).await.unwrap_err();
+        assert_eq!(err.to_string(), "MCP server fake unavailable");
+        assert_eq!(starts(), 3);
+    }
+
+    #[tokio::test]
+    async fn requests_from_the_server_are_answered() {
+        let (config, dir) = fake_server(5);
+        let client = MCPClient::new(&config).await.unwrap();
+        let result = client.call_tool("ask", json!({})).await.unwrap();
+        assert_eq!(text(&result), "asked");
+        let mut replies = vec![];
+        for _ in 0..50 {
+            replies = lines(&dir.join("replies"));
+            if replies.len() == 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let replies: HashMap<String, Value> = replies
+            .iter()
+            .map(|line| {
+                let reply: Value = serde_json::from_str(line).unwrap();
+                (reply["id"].as_str().unwrap().to_string(), reply)
             })
+            .collect();
+
+        let roots = replies["r1"]["result"]["roots"].as_array().unwrap();
+        assert_eq!(roots.len(), 1);
+        assert!(roots[0]["uri"].as_str().unwrap().starts_with("file:///"));
+        assert_eq!(replies["r2"]["result"], json!({}));
+        assert_eq!(replies["r2"].get("error"), None);
+        assert_eq!(
+            replies["r3"]["error"],
+            json!({"code": METHOD_NOT_FOUND, "message": "method not supported: sampling/createMessage"})
+        );
+        assert_eq!(replies["r3"].get("result"), None);
+        client.shutdown().await;
+    }
+
+    /// Whether process `pid` is gone within five seconds.
+    async fn exits(pid: &str) -> bool {
+        for _ in 0..50 {
+            if !Path::new(&format!("/proc/{}", pid)).exists() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn close_stops_the_server_process() {
+        let (config, dir) = fake_server(5);
+        let client = MCPClient::new(&config).await.unwrap();
+        let pid = lines(&dir.join("starts")).remove(0);
+        client.shutdown().await;
+        assert!(exits(&pid).await);
+        // It saw its stdin close rather than being killed
+        assert_eq!(lines(&dir.join("exits")), vec![pid.clone()]);
+        assert!(!LIVE_SERVERS.lock().unwrap().contains(&pid.parse().unwrap()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dropping_a_client_stops_the_server_process() {
+        let (config, dir) = fake_server(5);
+        let client = MCPClient::new(&config).await.unwrap();
+        let pid = lines(&dir.join("starts")).remove(0);
+        let started = std::time::Instant::now();
+        drop(client);
+        // The shutdown runs on a detached task, not in drop
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert!(exits(&pid).await);
+        assert_eq!(lines(&dir.join("exits")), vec![pid.clone()]);
+        let pid: u32 = pid.parse().unwrap();
+        for _ in 0..50 {
+            if !LIVE_SERVERS.lock().unwrap().contains(&pid) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("server {} is still registered", pid);
+    }
+
+    #[tokio::test]
+    async fn resources_are_listed_and_read() {
+        let (config, _) = fake_server(5);
+        let client = MCPClient::new(&config).await.unwrap();
+        let resources = client.list_resources().await.unwrap();
+        let listed: Vec<_> = resources
+            .iter()
+            .map(|r| (r.uri.as_str(), r.name.as_str(), r.mime_type.as_deref()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("file:///notes.txt", "notes", Some("text/plain")),
+                ("file:///logo.png", "logo", Some("image/png"))
+            ]
+        );
+        assert_eq!(resources[1].description.as_deref(), Some("The logo"));
+
+        let notes = client.read_resource("file:///notes.txt").await.unwrap();
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].text.as_deref(), Some("buy milk"));
+        assert_eq!(notes[0].to_text(), "buy milk");
+
+        let logo = client.read_resource("file:///logo.png").await.unwrap();
+        assert_eq!(logo[0].blob.as_deref(), Some("iVBORw0KGgo="));
+        assert_eq!(logo[0].to_text(), "[binary image/png, 8 bytes omitted]");
+
+        let err = client.read_resource("file:///missing").await.unwrap_err();
+        assert!(err.to_string().contains("Resource not found"), "{}", err);
+    }
+
+    #[test]
+    fn call_results_join_their_text_blocks() {
+        let result = json!({"content": [
+            {"type": "text", "text": "first"},
+            {"type": "text", "text": "second"}
+        ]});
+        assert_eq!(call_result_text(&result), Some(Ok("first\nsecond".into())));
+        assert_eq!(
+            call_result_text(&json!({"content": []})),
+            Some(Ok("".into()))
+        );
+    }
+
+    #[test]
+    fn call_results_flagged_as_errors_are_err() {
+        let result =
+            json!({"content": [{"type": "text", "text": "no such file"}], "isError": true});
+        assert_eq!(call_result_text(&result), Some(Err("no such file".into())));
+        let result = json!({"content": [], "isError": true});
+        assert_eq!(
+            call_result_text(&result),
+            Some(Err("tool reported an error".into()))
+        );
+    }
+
+    #[test]
+    fn non_text_blocks_are_described() {
+        let result = json!({"content": [
+            {"type": "text", "text": "chart:"},
+            {"type": "image", "mimeType": "image/png", "data": "AAAA"},
+            {"type": "resource", "resource": {"uri": "file:///a.txt", "mimeType": "text/plain", "text": "héllo"}},
+            {"type": "resource", "resource": {"uri": "file:///b.bin", "blob": "AAAAAAAA"}},
+            {"type": "resource_link", "uri": "file:///c.txt"},
+            {"type": "hologram"}
+        ]});
+        let text = call_result_text(&result).unwrap().unwrap();
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            [
+                "chart:",
+                "[image image/png, ~3 bytes]",
+                "[resource file:///a.txt (text/plain), 5 chars]",
+                "[resource file:///b.bin (unknown type), ~6 bytes]",
+                "[resource file:///c.txt]",
+                r#"{"type":"hologram"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn results_without_standard_content_are_none() {
+        assert_eq!(call_result_text(&json!({"value": 42})), None);
+        assert_eq!(call_result_text(&json!({"content": "plain"})), None);
+        // A text block whose text isn't a string isn't standard either
+        assert_eq!(
+            call_result_text(&json!({"content": [{"type": "text"}]})),
+            None
+        );
+    }
+
+    /// Two servers that both offer `echo`, where `one` also offers the
+    /// built-in's `read_file` and `two` offers `weather`, registered with `prefer`.
+    async fn two_servers(prefer: ToolPreference) -> (ToolRegistry, PathBuf, PathBuf) {
+        let (one, one_dir) = fake_server_named("one", 5);
+        let (two, two_dir) = fake_server_named("two", 5);
+        std::fs::write(one_dir.join("also"), "read_file").unwrap();
+        std::fs::write(two_dir.join("also"), "weather").unwrap();
+        let mut servers = MCPRegistry::new();
+        servers.add_server(&one).await.unwrap();
+        servers.add_server(&two).await.unwrap();
+        let mut registry = ToolRegistry::new();
+        servers.register_into(&mut registry, &ToolPolicy::default(), prefer);
+        (registry, one_dir, two_dir)
+    }
+
+    #[tokio::test]
+    async fn a_tool_name_two_servers_share_is_only_offered_prefixed() {
+        for prefer in [ToolPreference::Builtin, ToolPreference::Mcp] {
+            let (registry, one_dir, two_dir) = two_servers(prefer).await;
+            let outcome = registry.run("echo", "{}").await;
+            assert!(!outcome.ok, "bare echo ran with {:?}", prefer);
+            assert!(registry.run("one__echo", "{}").await.ok);
+            assert!(registry.run("two__echo", "{}").await.ok);
+            assert!(registry.run("weather", "{}").await.ok);
+            assert_eq!(lines(&one_dir.join("calls")), ["echo"]);
+            assert_eq!(lines(&two_dir.join("calls")), ["echo", "weather"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn prefer_decides_whether_a_built_in_name_goes_to_the_server() {
+        let path = scratch_dir("prefer").join("file.txt");
+        std::fs::write(&path, "from disk").unwrap();
+        let args = json!({ "path": path }).to_string();
+
+        let (registry, one_dir, _) = two_servers(ToolPreference::Builtin).await;
+        let outcome = registry.run("read_file", &args).await;
+        assert!(outcome.output.contains("from disk"), "{}", outcome.output);
+        assert!(lines(&one_dir.join("calls")).is_empty());
+
+        let (registry, one_dir, _) = two_servers(ToolPreference::Mcp).await;
+        let outcome = registry.run("read_file", &args).await;
+        assert_eq!(outcome.output, "read_file");
+        assert_eq!(lines(&one_dir.join("calls")), ["read_file"]);
+        assert!(registry.run("one__read_file", &args).await.ok);
+        assert_eq!(lines(&one_dir.join("calls")), ["read_file", "read_file"]);
+    }
+
+    /// What the fake HTTP server has seen and which sessions it still knows.
+    #[derive(Default)]
+    struct HttpState {
+        next_session: usize,
+        live: Vec<String>,
+        /// `<JSON-RPC method or HTTP verb> <tool name or -> <session or ->`
+        seen: Vec<String>,
+    }
+
+    /// A streamable HTTP MCP server on a local port. `initialize` opens a
+    /// numbered session that every later request must carry, or it gets a 404.
+    /// Tool calls reply with their own name; `stream` replies over SSE, and
+    /// `drop` closes the connection without replying.
+    async fn fake_http_server() -> (McpServerConfig, Arc<std::sync::Mutex<HttpState>>) {
+        use tokio::io::AsyncReadExt;
+        isolate_data_dir();
+        let state: Arc<std::sync::Mutex<HttpState>> = Default::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let shared = state.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut raw = vec![];
+                let mut chunk = [0; 4096];
+                let (head, body) = loop {
+                    let n = stream.read(&mut chunk).await.unwrap_or(0);
+                    raw.extend_from_slice(&chunk[..n]);
+                    let text = String::from_utf8_lossy(&raw).into_owned();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|l| {
+                                l.to_lowercase()
+                                    .strip_prefix("content-length:")
+                                    .map(|v| v.trim().parse().unwrap_or(0))
+                            })
+                            .unwrap_or(0);
+                        if body.len() >= length || n == 0 {
+                            break (head.to_string(), body.to_string());
+                        }
+                    } else if n == 0 {
+                        break (text, String::new());
+                    }
+                };
+                let session = head.lines().find_map(|l| {
+                    let (name, value) = l.split_once(':')?;
+                    name.eq_ignore_ascii_case(SESSION_HEADER)
+                        .then(|| value.trim().to_string())
+                });
+                let request: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+                let method = match request["method"].as_str() {
+                    Some(method) => method.to_string(),
+                    None => head.split_whitespace().next().unwrap_or("").to_string(),
+                };
+                let tool = request["params"]["name"]
+                    .as_str()
+                    .unwrap_or("-")
+                    .to_string();
+                let reply = {
+                    let mut state = shared.lock().unwrap();
+                    state.seen.push(format!(
+                        "{} {} {}",
+                        method,
+                        tool,
+                        session.as_deref().unwrap_or("-")
+                    ));
+                    if method == "initialize" {
+                        state.next_session += 1;
+                        let id = format!("s{}", state.next_session);
+                        state.live.push(id.clone());
+                        let result = json!({"protocolVersion": PROTOCOL_VERSION, "capabilities": {"tools": {}}, "serverInfo": {"name": "fake-http"}});
+                        Some((
+                            200,
+                            Some(id),
+                            "application/json",
+                            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+                                .to_string(),
+                        ))
+                    } else if !session.as_ref().is_some_and(|s| state.live.contains(s)) {
+                        Some((404, None, "text/plain", "unknown session".to_string()))
+                    } else if request["id"].is_null() {
+                        Some((202, None, "text/plain", String::new()))
+                    } else {
+                        let result = match method.as_str() {
+                            "tools/list" => {
+                                json!({"tools": [{"name": "echo", "inputSchema": {"type": "object"}}]})
+                            }
+                            _ => json!({"content": [{"type": "text", "text": tool}]}),
+                        };
+                        let message =
+                            json!({"jsonrpc": "2.0", "id": request["id"], "result": result})
+                                .to_string();
+                        match tool.as_str() {
+                            "drop" => None,
+                            "stream" => Some((
+                                200,
+                                None,
+                                "text/event-stream",
+                                format!("event: message\ndata: {}\n\n", message),
+                            )),
+                            _ => Some((200, None, "application/json", message)),
+                        }
+                    }
+                };
+                let Some((status, new_session, content_type, body)) = reply else {
+                    continue;
+                };
+                let session_header = new_session
+                    .map(|id| format!("{}: {}\r\n", SESSION_HEADER, id))
+                    .unwrap_or_default();
+                let response = format!(
+                    "HTTP/1.1 {} X\r\nContent-Type: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content_type,
+                    session_header,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let config = toml::from_str(&format!(
+            "name = \"fake-http\"\nurl = \"http://127.0.0.1:{}/mcp\"\ntimeout_secs = 5",
+            port
+        ))
+        .unwrap();
+        (config, state)
+    }
+
+    fn seen(state: &std::sync::Mutex<HttpState>, method: &str) -> Vec<String> {
+        state
+            .lock()
+            .unwrap()
+            .seen
+            .iter()
+            .filter(|s| s.starts_with(&format!("{} ", method)))
+            .cloned()
             .collect()
     }
 
-    pub async fn execute(&self, name: &str, args: &str) -> Result<String> {
-        self.tools
-            .get(name)
-            .ok_or_else(|| anyhow::anyhow!("Tool {} not found", name))?
-            .execute(args)
-            .await
+    #[tokio::test]
+    async fn http_requests_carry_the_session_the_server_assigned() {
+        let (config, state) = fake_http_server().await;
+        let client = MCPClient::new(&config).await.unwrap();
+        assert_eq!(seen(&state, "initialize"), ["initialize - -"]);
+        assert_eq!(
+            seen(&state, "notifications/initialized"),
+            ["notifications/initialized - s1"]
+        );
+
+        let result = client.call_tool("plain", json!({})).await.unwrap();
+        assert_eq!(text(&result), "plain");
+        // An SSE response is read like a JSON one
+        let result = client.call_tool("stream", json!({})).await.unwrap();
+        assert_eq!(text(&result), "stream");
+        assert_eq!(
+            seen(&state, "tools/call"),
+            ["tools/call plain s1", "tools/call stream s1"]
+        );
+
+        client.shutdown().await;
+        assert_eq!(seen(&state, "DELETE"), ["DELETE - s1"]);
+    }
+
+    #[tokio::test]
+    async fn an_expired_http_session_is_replaced_and_the_call_sent_again() {
+        let (config, state) = fake_http_server().await;
+        let handle = MCPHandle::new(&config, ClientState::Idle);
+        let result = handle.call_tool("first", json!({})).await.unwrap();
+        assert_eq!(text(&result), "first");
+
+        // The server restarts and forgets every session
+        state.lock().unwrap().live.clear();
+        let result = handle.call_tool("second", json!({})).await.unwrap();
+        assert_eq!(text(&result), "second");
+        assert_eq!(seen(&state, "initialize").len(), 2);
+        assert_eq!(
+            seen(&state, "tools/call"),
+            [
+                "tools/call first s1",
+                "tools/call second s1",
+                "tools/call second s2"
+            ]
+        );
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn a_tool_call_whose_connection_drops_is_not_sent_twice() {
+        let (config, state) = fake_http_server().await;
+        let client = MCPClient::new(&config).await.unwrap();
+        assert!(client.call_tool("drop", json!({})).await.is_err());
+        assert_eq!(seen(&state, "tools/call"), ["tools/call drop s1"]);
+    }
+}
@@ -1,15 +1,57 @@
-use crate::api::Message;
+use crate::api::{Content, Message, Sampling};
 use anyhow::Result;
-use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, TransactionBehavior};
 use std::fs;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
+/// How long to wait on a lock held by another process.
+const BUSY_TIMEOUT: Duration = Duration::from_millis(5000);
+
+/// `chat --no-persist`: conversations stay in memory and nothing is read from
+/// or written to the sessions table.
+static EPHEMERAL: AtomicBool = AtomicBool::new(false);
+
 pub struct SessionStore;
 
+/// Totals for `sessions stats`.
+#[derive(Debug, Default)]
+pub struct SessionStats {
+    pub sessions: u64,
+    pub messages: u64,
+    /// (role, message count), most common first
+    pub by_role: Vec<(String, u64)>,
+    /// Completion tokens across assistant messages that recorded them
+    pub tokens: u64,
+    /// Assistant messages with no recorded token count
+    pub tokens_unknown: u64,
+}
+
 impl SessionStore {
-    fn data_dir() -> PathBuf {
-        let mut dir = dirs::data_dir().unwrap_or_else(|| std::path::PathBuf::from("."));
+    pub fn set_ephemeral(on: bool) {
+        EPHEMERAL.store(on, Ordering::Relaxed);
+    }
+
+    pub fn ephemeral() -> bool {
+        EPHEMERAL.load(Ordering::Relaxed)
+    }
+
+    /// Where sessions, history, backups and templates live: `RUSTY_DATA_DIR`,
+    /// then `$XDG_DATA_HOME/rusty-cli` (on every platform), then the platform
+    /// data dir, and as a last resort the temp dir rather than the cwd.
+    pub fn data_dir() -> PathBuf {
+        if let Some(dir) = std::env::var_os("RUSTY_DATA_DIR").filter(|d| !d.is_empty()) {
+            return PathBuf::from(dir);
+        }
+        // The XDG spec says relative values are invalid and must be ignored
+        let xdg = std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|d| d.is_absolute());
+        let mut dir = xdg
+            .or_else(dirs::data_dir)
+            .unwrap_or_else(std::env::temp_dir);
         dir.push("rusty-cli");
         dir
     }
@@ -28,6 +70,8 @@ impl SessionStore {
             fs::create_dir_all(parent)?;
         }
         let conn = Connection::open(path)?;
+        // Wait for other rusty-cli processes instead of failing with "database is locked"
+        conn.busy_timeout(BUSY_TIMEOUT)?;
         conn.execute_batch(
             "PRAGMA journal_mode=WAL; PRAGMA foreign_keys=ON;\n
              CREATE TABLE IF NOT EXISTS sessions (id TEXT PRIMARY KEY, created_at TEXT NOT NULL, updated_at TEXT NOT NULL);\n
@@ -38,6 +82,7 @@ impl SessionStore {
                content TEXT,
                name TEXT,
                tool_call_id TEXT,
+               tool_calls TEXT,
                PRIMARY KEY(session_id, idx),
                FOREIGN KEY(session_id) REFERENCES sessions(id) ON DELETE CASCADE
              );\n
@@ -47,6 +92,18 @@ impl SessionStore {
                backup_path TEXT NOT NULL,
                deleted_at TEXT NOT NULL
              );\n
+             CREATE TABLE IF NOT EXISTS response_cache (
+               key TEXT PRIMARY KEY,
+               response TEXT NOT NULL,
+               created_at TEXT NOT NULL
+             );\n
+             CREATE TABLE IF NOT EXISTS mcp_tools (
+               server TEXT PRIMARY KEY,
+               fingerprint TEXT NOT NULL,
+               protocol_version TEXT NOT NULL,
+               tools TEXT NOT NULL,
+               updated_at TEXT NOT NULL
+             );\n
              CREATE TABLE IF NOT EXISTS notes (
                id INTEGER PRIMARY KEY AUTOINCREMENT,
                title TEXT,
@@ -56,15 +113,43 @@ impl SessionStore {
                updated_at TEXT NOT NULL
              );",
         )?;
+        Self::ensure_column(&conn, "messages", "tool_calls", "TEXT")?;
+        Self::ensure_column(&conn, "messages", "content_parts", "TEXT")?;
+        Self::ensure_column(&conn, "sessions", "temperature", "REAL")?;
+        Self::ensure_column(&conn, "sessions", "top_p", "REAL")?;
+        Self::ensure_column(&conn, "sessions", "max_tokens", "INTEGER")?;
+        // NULL for messages saved before usage was recorded, or when none was reported
+        Self::ensure_column(&conn, "messages", "tokens", "INTEGER")?;
+        Self::ensure_column(&conn, "sessions", "cost", "REAL")?;
+        Self::ensure_column(&conn, "sessions", "unpriced_replies", "INTEGER")?;
+        // 'overwritten' for copies taken before a file was replaced in place
+        Self::ensure_column(&conn, "undelete", "kind", "TEXT NOT NULL DEFAULT 'deleted'")?;
         Ok(conn)
     }
 
+    /// Add a column to databases created before it existed.
+    fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+        let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+        let exists = stmt
+            .query_map([], |r| r.get::<_, String>(1))?
+            .filter_map(|r| r.ok())
+            .any(|name| name == column);
+        if !exists {
+            conn.execute_batch(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {}",
+                table, column, decl
+            ))?;
+        }
+        Ok(())
+    }
+
     pub fn conn_ro() -> Result<Connection> {
         let path = Self::db_path();
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
         let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
         Ok(conn)
     }
 
@@ -73,6 +158,9 @@ impl SessionStore {
     }
 
     pub fn last() -> Result<Option<String>> {
+        if Self::ephemeral() {
+            return Ok(None);
+        }
         let conn = Self::conn()?;
         let id: Option<String> = conn
             .query_row(
@@ -84,17 +172,42 @@ impl SessionStore {
         Ok(id)
     }
 
+    /// Every saved session id, oldest activity first.
+    pub fn ids() -> Result<Vec<String>> {
+        if Self::ephemeral() || !Self::db_path().exists() {
+            return Ok(vec![]);
+        }
+        let conn = Self::conn()?;
+        let mut stmt = conn.prepare("SELECT id FROM sessions ORDER BY updated_at ASC")?;
+        let ids = stmt
+            .query_map([], |r| r.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(ids)
+    }
+
     pub fn load(id: &str) -> Result<Vec<Message>> {
+        if Self::ephemeral() {
+            return Ok(vec![]);
+        }
         let conn = Self::conn()?;
         let mut stmt = conn.prepare(
-            "SELECT role, content, name, tool_call_id FROM messages WHERE session_id=? ORDER BY idx ASC",
+            "SELECT role, content, name, tool_call_id, tool_calls, content_parts, tokens FROM messages WHERE session_id=? ORDER BY idx ASC",
         )?;
         let rows = stmt.query_map([id], |r| {
+            let tool_calls = r
+                .get::<_, Option<String>>(4)?
+                .and_then(|json| serde_json::from_str(&json).ok());
+            // Multimodal messages keep their parts as JSON; plain text lives in `content`
+            let content = r
+                .get::<_, Option<String>>(5)?
+                .and_then(|json| serde_json::from_str::<Content>(&json).ok())
+                .or(r.get::<_, Option<String>>(1)?.map(Content::Text));
             Ok(Message {
                 role: r.get(0)?,
-                content: r.get::<_, Option<String>>(1)?,
-                tool_calls: None,
+                content,
+                tool_calls,
                 tool_call_id: r.get(3)?,
+                tokens: r.get::<_, Option<i64>>(6)?.map(|n| n as u32),
             })
         })?;
         let mut out = vec![];
@@ -105,49 +218,307 @@ impl SessionStore {
     }
 
     pub fn save(id: &str, messages: &[Message]) -> Result<()> {
+        if Self::ephemeral() {
+            return Ok(());
+        }
         let mut conn = Self::conn()?;
         let now = Self::now();
-        conn.execute(
+        // Take the write lock up front so concurrent writers queue on busy_timeout
+        // rather than failing when a deferred transaction tries to upgrade
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
             "INSERT OR IGNORE INTO sessions (id, created_at, updated_at) VALUES (?, ?, ?)",
             params![id, now, now],
         )?;
-        conn.execute(
+        tx.execute(
             "UPDATE sessions SET updated_at=? WHERE id=?",
             params![now, id],
         )?;
-        let tx = conn.transaction()?;
         tx.execute("DELETE FROM messages WHERE session_id=?", params![id])?;
         for (i, m) in messages.iter().enumerate() {
+            let tool_calls = match &m.tool_calls {
+                Some(calls) => Some(serde_json::to_string(calls)?),
+                None => None,
+            };
+            let parts = match &m.content {
+                Some(c @ Content::Parts(_)) => Some(serde_json::to_string(c)?),
+                _ => None,
+            };
             tx.execute(
-                "INSERT INTO messages (session_id, idx, role, content, name, tool_call_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-                params![id, i as i64, m.role, m.content, None::<String>, m.tool_call_id],
+                "INSERT INTO messages (session_id, idx, role, content, name, tool_call_id, tool_calls, content_parts, tokens) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                params![
+                    id,
+                    i as i64,
+                    m.role,
+                    m.content.as_ref().map(Content::text),
+                    None::<String>,
+                    m.tool_call_id,
+                    tool_calls,
+                    parts,
+                    m.tokens
+                ],
             )?;
         }
         tx.commit()?;
         Ok(())
     }
 
+    /// Overwrite a session's created/updated timestamps (RFC 3339), e.g. with
+    /// the dates of an imported conversation.
+    pub fn set_times(id: &str, created_at: &str, updated_at: &str) -> Result<()> {
+        if Self::ephemeral() {
+            return Ok(());
+        }
+        let conn = Self::conn()?;
+        conn.execute(
+            "UPDATE sessions SET created_at=?, updated_at=? WHERE id=?",
+            params![created_at, updated_at, id],
+        )?;
+        Ok(())
+    }
+
+    /// Sampling parameters last used in a session; `None` if the session has none stored.
+    pub fn load_sampling(id: &str) -> Result<Option<Sampling>> {
+        if Self::ephemeral() {
+            return Ok(None);
+        }
+        let conn = Self::conn()?;
+        let sampling = conn
+            .query_row(
+                "SELECT temperature, top_p, max_tokens FROM sessions WHERE id=?",
+                [id],
+                |r| {
+                    Ok(Sampling {
+                        temperature: r.get::<_, Option<f64>>(0)?.map(|v| v as f32),
+                        top_p: r.get::<_, Option<f64>>(1)?.map(|v| v as f32),
+                        max_tokens: r.get::<_, Option<i64>>(2)?.map(|v| v as u32),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(sampling.filter(|s| *s != Sampling::default()))
+    }
+
+    pub fn save_sampling(id: &str, sampling: &Sampling) -> Result<()> {
+        if Self::ephemeral() {
+            return Ok(());
+        }
+        let conn = Self::conn()?;
+        let now = Self::now();
+        conn.execute(
+            "INSERT OR IGNORE INTO sessions (id, created_at, updated_at) VALUES (?, ?, ?)",
+            params![id, now, now],
+        )?;
+        conn.execute(
+            "UPDATE sessions SET temperature=?, top_p=?, max_tokens=? WHERE id=?",
+            params![
+                sampling.temperature.map(f64::from),
+                sampling.top_p.map(f64::from),
+                sampling.max_tokens.map(i64::from),
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Add one reply's cost to the session's running total; `None` counts the
+    /// reply as unpriced (no usage reported, or no rates for the model).
+    pub fn add_cost(id: &str, cost: Option<f64>) -> Result<()> {
+        if Self::ephemeral() {
+            return Ok(());
+        }
+        let conn = Self::conn()?;
+        let now = Self::now();
+        conn.execute(
+            "INSERT OR IGNORE INTO sessions (id, created_at, updated_at) VALUES (?, ?, ?)",
+            params![id, now, now],
+        )?;
+        match cost {
+            Some(cost) => conn.execute(
+                "UPDATE sessions SET cost = COALESCE(cost, 0) + ? WHERE id=?",
+                params![cost, id],
+            )?,
+            None => conn.execute(
+                "UPDATE sessions SET unpriced_replies = COALESCE(unpriced_replies, 0) + 1 WHERE id=?",
+                params![id],
+            )?,
+        };
+        Ok(())
+    }
+
+    /// (total cost in USD, unpriced replies) for a session; `None` if it doesn't exist.
+    pub fn cost(id: &str) -> Result<Option<(f64, u64)>> {
+        if !Self::db_path().exists() {
+            return Ok(None);
+        }
+        let conn = Self::conn()?;
+        let row = conn
+            .query_row(
+                "SELECT COALESCE(cost, 0), COALESCE(unpriced_replies, 0) FROM sessions WHERE id=?",
+                [id],
+                |r| Ok((r.get::<_, f64>(0)?, r.get::<_, i64>(1)? as u64)),
+            )
+            .optional()?;
+        Ok(row)
+    }
+
+    /// A reply stored under `key` less than `ttl` ago.
+    pub fn cached_response(key: &str, ttl: Duration) -> Result<Option<String>> {
+        if Self::ephemeral() {
+            return Ok(None);
+        }
+        let conn = Self::conn()?;
+        let row: Option<(String, String)> = conn
+            .query_row(
+                "SELECT response, created_at FROM response_cache WHERE key=?",
+                [key],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?;
+        Ok(row.and_then(|(response, created_at)| {
+            let created = OffsetDateTime::parse(&created_at, &Rfc3339).ok()?;
+            (OffsetDateTime::now_utc() - created < ttl).then_some(response)
+        }))
+    }
+
+    pub fn cache_response(key: &str, response: &str) -> Result<()> {
+        if Self::ephemeral() {
+            return Ok(());
+        }
+        let conn = Self::conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO response_cache (key, response, created_at) VALUES (?, ?, ?)",
+            params![key, response, Self::now()],
+        )?;
+        Ok(())
+    }
+
+    /// Drop every cached reply, returning how many there were.
+    pub fn clear_cache() -> Result<usize> {
+        if !Self::db_path().exists() {
+            return Ok(0);
+        }
+        let conn = Self::conn()?;
+        Ok(conn.execute("DELETE FROM response_cache", [])?)
+    }
+
+    /// A server's tool list as JSON, with the protocol version it was
+    /// discovered under, if it was cached from the same `fingerprint`.
+    pub fn cached_mcp_tools(server: &str, fingerprint: &str) -> Result<Option<(String, String)>> {
+        if Self::ephemeral() {
+            return Ok(None);
+        }
+        let conn = Self::conn()?;
+        Ok(conn
+            .query_row(
+                "SELECT protocol_version, tools FROM mcp_tools WHERE server=? AND fingerprint=?",
+                [server, fingerprint],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .optional()?)
+    }
+
+    pub fn cache_mcp_tools(
+        server: &str,
+        fingerprint: &str,
+        protocol_version: &str,
+        tools: &str,
+    ) -> Result<()> {
+        if Self::ephemeral() {
+            return Ok(());
+        }
+        let conn = Self::conn()?;
+        conn.execute(
+            "INSERT OR REPLACE INTO mcp_tools (server, fingerprint, protocol_version, tools, updated_at)
+             VALUES (?, ?, ?, ?, ?)",
+            params![server, fingerprint, protocol_version, tools, Self::now()],
+        )?;
+        Ok(())
+    }
+
+    /// Forget cached tool lists (one server's, or all), returning how many.
+    pub fn clear_mcp_tools(server: Option<&str>) -> Result<usize> {
+        if !Self::db_path().exists() {
+            return Ok(0);
+        }
+        let conn = Self::conn()?;
+        Ok(match server {
+            Some(server) => conn.execute("DELETE FROM mcp_tools WHERE server=?", [server])?,
+            None => conn.execute("DELETE FROM mcp_tools", [])?,
+        })
+    }
+
+    /// Session and message counts, limited to sessions updated at or after
+    /// `since` (an RFC 3339 timestamp or prefix such as `2024-05-01`).
+    pub fn stats(since: Option<&str>) -> Result<SessionStats> {
+        if !Self::db_path().exists() {
+            return Ok(SessionStats::default());
+        }
+        // Read-write so a database from before the `tokens` column gets migrated
+        let conn = Self::conn()?;
+        let since = since.unwrap_or("");
+        let sessions = conn.query_row(
+            "SELECT COUNT(*) FROM sessions WHERE updated_at >= ?1",
+            [since],
+            |r| r.get::<_, i64>(0),
+        )? as u64;
+        let mut stmt = conn.prepare(
+            "SELECT m.role, COUNT(*) FROM messages m JOIN sessions s ON s.id = m.session_id
+             WHERE s.updated_at >= ?1 GROUP BY m.role ORDER BY COUNT(*) DESC, m.role",
+        )?;
+        let rows = stmt.query_map([since], |r| Ok((r.get(0)?, r.get::<_, i64>(1)? as u64)))?;
+        let mut by_role = vec![];
+        for r in rows {
+            by_role.push(r?);
+        }
+        let (tokens, tokens_unknown) = conn.query_row(
+            "SELECT COALESCE(SUM(m.tokens), 0), COUNT(*) - COUNT(m.tokens)
+             FROM messages m JOIN sessions s ON s.id = m.session_id
+             WHERE s.updated_at >= ?1 AND m.role = 'assistant'",
+            [since],
+            |r| Ok((r.get::<_, i64>(0)? as u64, r.get::<_, i64>(1)? as u64)),
+        )?;
+        Ok(SessionStats {
+            sessions,
+            messages: by_role.iter().map(|(_, n)| n).sum(),
+            by_role,
+            tokens,
+            tokens_unknown,
+        })
+    }
+
     pub fn record_deleted(original_path: &str, backup_path: &str) -> Result<()> {
+        Self::record_backup(original_path, backup_path, "deleted")
+    }
+
+    /// Record the previous content of a file that is about to be replaced.
+    pub fn record_overwritten(original_path: &str, backup_path: &str) -> Result<()> {
+        Self::record_backup(original_path, backup_path, "overwritten")
+    }
+
+    fn record_backup(original_path: &str, backup_path: &str, kind: &str) -> Result<()> {
         let conn = Self::conn()?;
         let now = Self::now();
         conn.execute(
-            "INSERT INTO undelete (original_path, backup_path, deleted_at) VALUES (?, ?, ?)",
-            params![original_path, backup_path, now],
+            "INSERT INTO undelete (original_path, backup_path, deleted_at, kind) VALUES (?, ?, ?, ?)",
+            params![original_path, backup_path, now, kind],
         )?;
         Ok(())
     }
 
-    pub fn pop_latest_deleted(original_path: &str) -> Result<Option<String>> {
+    /// The newest backup of `original_path` and whether it was taken before an
+    /// overwrite (rather than a deletion); the record is removed.
+    pub fn pop_latest_deleted(original_path: &str) -> Result<Option<(String, bool)>> {
         let conn = Self::conn()?;
         let mut stmt = conn.prepare(
-            "SELECT id, backup_path FROM undelete WHERE original_path = ? ORDER BY id DESC LIMIT 1",
+            "SELECT id, backup_path, kind FROM undelete WHERE original_path = ? ORDER BY id DESC LIMIT 1",
         )?;
-        let row: Option<(i64, String)> = stmt
-            .query_row([original_path], |r| Ok((r.get(0)?, r.get(1)?)))
+        let row: Option<(i64, String, String)> = stmt
+            .query_row([original_path], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))
             .optional()?;
-        if let Some((id, backup)) = row {
+        if let Some((id, backup, kind)) = row {
             conn.execute("DELETE FROM undelete WHERE id = ?", params![id])?;
-            Ok(Some(backup))
+            Ok(Some((backup, kind == "overwritten")))
         } else {
             Ok(None)
         }
@@ -157,11 +528,61 @@ impl SessionStore {
         Self::data_dir().join("undelete")
     }
 
-    pub fn list_deleted(limit: usize) -> Result<Vec<(String, String)>> {
+    /// Prompt template files, one per template (`<name>.md`, `<name>.txt` or `<name>`).
+    pub fn templates_dir() -> PathBuf {
+        Self::data_dir().join("templates")
+    }
+
+    /// Size of the database plus its WAL and shared-memory files.
+    fn db_size() -> u64 {
+        let db = Self::db_path();
+        ["", "-wal", "-shm"]
+            .iter()
+            .filter_map(|suffix| {
+                let mut path = db.clone().into_os_string();
+                path.push(suffix);
+                fs::metadata(path).ok().map(|m| m.len())
+            })
+            .sum()
+    }
+
+    /// VACUUM and truncate the WAL; returns the on-disk size before and after.
+    pub fn vacuum() -> Result<(u64, u64)> {
+        let before = Self::db_size();
+        let conn = Self::conn_rw()?;
+        conn.execute_batch("VACUUM;")?;
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+        drop(conn);
+        Ok((before, Self::db_size()))
+    }
+
+    /// Problems reported by `PRAGMA integrity_check`; empty when healthy.
+    pub fn integrity_check() -> Result<Vec<String>> {
+        let conn = Self::conn_rw()?;
+        let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+        let rows = stmt.query_map([], |r| r.get::<_, String>(0))?;
+        let mut problems = vec![];
+        for r in rows {
+            let line = r?;
+            if line != "ok" {
+                problems.push(line);
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Prompt history shared by the interactive modes.
+    pub fn history_path() -> PathBuf {
+        Self::data_dir().join("history.txt")
+    }
+
+    /// Newest first: (original path, time, kind), kind being `deleted` or `overwritten`.
+    pub fn list_deleted(limit: usize) -> Result<Vec<(String, String, String)>> {
         let conn = Self::conn()?;
-        let mut stmt = conn
-            .prepare("SELECT original_path, deleted_at FROM undelete ORDER BY id DESC LIMIT ?1")?;
-        let rows = stmt.query_map([limit as i64], |r| Ok((r.get(0)?, r.get(1)?)))?;
+        let mut stmt = conn.prepare(
+            "SELECT original_path, deleted_at, kind FROM undelete ORDER BY id DESC LIMIT ?1",
+        )?;
+        let rows = stmt.query_map([limit as i64], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?)))?;
         let mut out = vec![];
         for r in rows {
             out.push(r?);
@@ -169,3 +590,105 @@ impl SessionStore {
         Ok(out)
     }
 }
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Once;
+
+    /// A new empty directory under the temp dir, unique to this test process.
+    pub fn scratch_dir(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "rusty-cli-test-{}-{}-{}",
+            std::process::id(),
+            name,
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Keep the session DB, backups and logs of this test process out of the
+    /// real data dir, and the user's config out of the tests. Every test
+    /// touching `SessionStore` or `Config` calls this first.
+    pub fn isolate_data_dir() {
+        static ONCE: Once = Once::new();
+        ONCE.call_once(|| {
+            let dir = scratch_dir("data");
+            std::env::set_var("RUSTY_CONFIG_PATH", dir.join("config.toml"));
+            std::env::set_var("RUSTY_DATA_DIR", dir);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::isolate_data_dir;
+    use super::*;
+
+    fn user(text: &str) -> Message {
+        Message {
+            role: "user".to_string(),
+            content: Some(text.to_string().into()),
+            tool_calls: None,
+            tool_call_id: None,
+            tokens: None,
+        }
+    }
+
+    #[test]
+    fn concurrent_writers_both_land() {
+        isolate_data_dir();
+        let writers: Vec<_> = ["writer-a", "writer-b"]
+            .into_iter()
+            .map(|id| {
+                std::thread::spawn(move || {
+                    let mut messages = vec![];
+                    for i in 0..50 {
+                        messages.push(user(&format!("{} {}", id, i)));
+                        SessionStore::save(id, &messages)?;
+                        SessionStore::add_cost(id, Some(0.01))?;
+                    }
+                    anyhow::Ok(())
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        for id in ["writer-a", "writer-b"] {
+            let messages = SessionStore::load(id).unwrap();
+            assert_eq!(messages.len(), 50);
+            assert_eq!(
+                messages[49].content.as_ref().unwrap().text(),
+                format!("{} 49", id)
+            );
+            let (cost, _) = SessionStore::cost(id).unwrap().unwrap();
+            assert!((cost - 0.5).abs() < 1e-9, "{} cost {}", id, cost);
+        }
+    }
+
+    #[test]
+    fn a_writer_waits_for_the_lock_instead_of_failing() {
+        isolate_data_dir();
+        SessionStore::save("held", &[user("before")]).unwrap();
+        let mut holder = SessionStore::conn_rw().unwrap();
+        let tx = holder
+            .transaction_with_behavior(TransactionBehavior::Immediate)
+            .unwrap();
+        tx.execute(
+            "UPDATE sessions SET updated_at=? WHERE id=?",
+            params![SessionStore::now(), "held"],
+        )
+        .unwrap();
+        let writer = std::thread::spawn(|| SessionStore::save("held", &[user("after")]));
+        std::thread::sleep(Duration::from_millis(300));
+        tx.commit().unwrap();
+        writer.join().unwrap().unwrap();
+        let messages = SessionStore::load("held").unwrap();
+        assert_eq!(messages[0].content.as_ref().unwrap().text(), "after");
+    }
+}
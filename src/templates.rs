@@ -0,0 +1,109 @@
+use crate::config::Config;
+use crate::session::SessionStore;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Extensions tried, in order, for template files in the data dir.
+const EXTENSIONS: &[&str] = &["md", "txt", ""];
+
+/// Template `name` from `[templates]` in config, else from the templates dir.
+pub fn load(name: &str) -> Result<String> {
+    let cfg = Config::load().unwrap_or_default();
+    if let Some(text) = cfg.templates.get(name) {
+        return Ok(text.clone());
+    }
+    if let Some(path) = file_for(name) {
+        return Ok(std::fs::read_to_string(&path)?);
+    }
+    let known = names();
+    if known.is_empty() {
+        anyhow::bail!(
+            "no template named `{}`; add it under [templates] in config or as {}",
+            name,
+            SessionStore::templates_dir()
+                .join(format!("{}.md", name))
+                .display()
+        );
+    }
+    anyhow::bail!("no template named `{}` (known: {})", name, known.join(", "))
+}
+
+fn file_for(name: &str) -> Option<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return None;
+    }
+    let dir = SessionStore::templates_dir();
+    EXTENSIONS
+        .iter()
+        .map(|ext| {
+            if ext.is_empty() {
+                dir.join(name)
+            } else {
+                dir.join(format!("{}.{}", name, ext))
+            }
+        })
+        .find(|p| p.is_file())
+}
+
+/// Every template name from config and the templates dir, sorted.
+pub fn names() -> Vec<String> {
+    let cfg = Config::load().unwrap_or_default();
+    let mut names: Vec<String> = cfg.templates.keys().cloned().collect();
+    if let Ok(entries) = std::fs::read_dir(SessionStore::templates_dir()) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                if path.is_file() && !stem.starts_with('.') {
+                    names.push(stem.to_string());
+                }
+            }
+        }
+    }
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// `{var}` placeholders in the order they first appear.
+pub fn placeholders(text: &str) -> Vec<String> {
+    let re = regex::Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    let mut names: Vec<String> = Vec::new();
+    for cap in re.captures_iter(text) {
+        if !names.iter().any(|n| n == &cap[1]) {
+            names.push(cap[1].to_string());
+        }
+    }
+    names
+}
+
+/// Fill every placeholder from `vars`; errors name all the unfilled ones.
+/// Braces that don't wrap an identifier (JSON, code) are left alone.
+pub fn render(name: &str, text: &str, vars: &HashMap<String, String>) -> Result<String> {
+    let missing: Vec<String> = placeholders(text)
+        .into_iter()
+        .filter(|p| !vars.contains_key(p))
+        .collect();
+    if !missing.is_empty() {
+        anyhow::bail!(
+            "template `{}` has unfilled variables: {}",
+            name,
+            missing.join(", ")
+        );
+    }
+    let re = regex::Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+    Ok(re
+        .replace_all(text, |cap: &regex::Captures| vars[&cap[1]].clone())
+        .into_owned())
+}
+
+/// Parse `--var key=value` arguments.
+pub fn parse_vars(args: &[String]) -> Result<HashMap<String, String>> {
+    args.iter()
+        .map(|arg| {
+            arg.split_once('=')
+                .map(|(k, v)| (k.trim().to_string(), v.to_string()))
+                .ok_or_else(|| anyhow::anyhow!("--var {}: expected key=value", arg))
+        })
+        .collect()
+}
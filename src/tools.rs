@@ -6,6 +6,8 @@ use serde_json::{json, Value};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::io::Write as _;
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
@@ -35,36 +37,805 @@ pub struct Tool {
     pub function: Function,
 }
 
+/// What a tool can reach, checked against `[tool_policy]`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToolAccess {
+    Read,
+    Write,
+    Network,
+    Shell,
+}
+
 #[async_trait]
 pub trait ToolExecutor: Send + Sync {
     fn name(&self) -> &str;
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Read
+    }
+    /// Schema advertised to the model; its name must match `name()`.
+    fn definition(&self) -> Tool;
     async fn execute(&self, args: &str) -> Result<String>;
+    /// `execute` plus, for tools that run a command, how that command ended.
+    async fn execute_with_status(&self, args: &str) -> Result<(String, Option<CommandStatus>)> {
+        Ok((self.execute(args).await?, None))
+    }
+    /// False once the tool can't run this session (its MCP server failed to
+    /// start); it is then no longer offered.
+    fn available(&self) -> bool {
+        true
+    }
 }
 
 // Example built-in tools
 
-pub struct ShellTool;
+pub struct ShellTool {
+    timeout: std::time::Duration,
+    max_output: usize,
+}
+
+impl ShellTool {
+    pub fn new(cfg: &crate::config::ShellConfig) -> Self {
+        Self {
+            timeout: std::time::Duration::from_secs(cfg.timeout_secs.unwrap_or(30)),
+            max_output: cfg.max_output_bytes.unwrap_or(32 * 1024),
+        }
+    }
+}
 
 #[async_trait]
 impl ToolExecutor for ShellTool {
     fn name(&self) -> &str {
         "shell"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Shell
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "shell".to_string(),
+                description: "Execute a shell command; reports the exit code, kills it after a timeout, and trims long output from the middle".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {
+                            "type": "string",
+                            "description": "The shell command to execute"
+                        }
+                    },
+                    "required": ["command"]
+                }),
+            },
+        }
+    }
 
     async fn execute(&self, args: &str) -> Result<String> {
+        Ok(self.execute_with_status(args).await?.0)
+    }
+
+    async fn execute_with_status(&self, args: &str) -> Result<(String, Option<CommandStatus>)> {
         let params: Value = serde_json::from_str(args)?;
         let command = params["command"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing command parameter"))?;
 
-        let output = Command::new("sh").arg("-c").arg(command).output().await?;
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        let (output, status) = run_captured(cmd, self.timeout, self.max_output).await?;
+        Ok((output, Some(status)))
+    }
+}
+
+/// Run a command the user typed as `!!` with the shell tool's limits.
+pub async fn run_shell(command: &str, cfg: &crate::config::ShellConfig) -> Result<String> {
+    ShellTool::new(cfg)
+        .execute(&json!({ "command": command }).to_string())
+        .await
+}
+
+/// How a command run by `run_captured` ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandStatus {
+    Exited(i32),
+    /// Killed by a signal or ended some other way, described
+    Abnormal(String),
+    TimedOut(std::time::Duration),
+}
+
+impl std::fmt::Display for CommandStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandStatus::Exited(code) => write!(f, "exit code: {}", code),
+            CommandStatus::Abnormal(how) => write!(f, "exit code: {}", how),
+            CommandStatus::TimedOut(after) => write!(
+                f,
+                "timed out after {}s; command killed (partial output below)",
+                after.as_secs()
+            ),
+        }
+    }
+}
+
+/// Run `cmd` with no stdin, killing its process group after `timeout`, and
+/// report the exit status plus stdout/stderr trimmed to `max_output` bytes.
+/// The report leads with the status, which is also returned as data.
+async fn run_captured(
+    mut cmd: Command,
+    timeout: std::time::Duration,
+    max_output: usize,
+) -> Result<(String, CommandStatus)> {
+    cmd.stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    // Own process group so a timeout also kills pipelines and grandchildren
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let mut child = cmd.spawn()?;
+    let pid = child.id();
+    // Kills the whole group if this future is dropped (e.g. Ctrl-C) or times out
+    let group = KillGroupOnDrop(pid);
+    let (stdout_pipe, stderr_pipe) = (child.stdout.take(), child.stderr.take());
+    let mut stdout = Captured::default();
+    let mut stderr = Captured::default();
+
+    // Waiting and draining share one deadline: the output is complete only
+    // once every process holding the pipes has gone
+    let finished = tokio::time::timeout(timeout, async {
+        let wait = async {
+            let status = child.wait().await;
+            // Whatever the shell left running (`server &`) would keep the pipes open
+            if let Some(pid) = pid {
+                signal_group(pid, Signal::Kill);
+            }
+            status
+        };
+        let (status, out, err) = tokio::join!(
+            wait,
+            read_capped(stdout_pipe, max_output, &mut stdout),
+            read_capped(stderr_pipe, max_output, &mut stderr)
+        );
+        out?;
+        err?;
+        anyhow::Ok(status?)
+    })
+    .await;
+    drop(group);
+    let status = match finished {
+        Ok(status) => exit_status(&status?),
+        Err(_) => {
+            let _ = child.kill().await;
+            CommandStatus::TimedOut(timeout)
+        }
+    };
+    // Split the byte budget, letting one stream use what the other leaves
+    let err_budget = stderr
+        .len()
+        .min(max_output - stdout.len().min(max_output / 2));
+    let out_budget = max_output - err_budget;
+    let report = format!(
+        "{}\nstdout:\n{}\nstderr:\n{}",
+        status,
+        stdout.render(out_budget),
+        stderr.render(err_budget)
+    );
+    Ok((report, status))
+}
+
+/// Lines kept per background process; older output is dropped.
+const PROCESS_LOG_LINES: usize = 2000;
+
+/// Process groups started with process_start and not yet killed, for exits
+/// that skip dropping their table.
+static LIVE_GROUPS: std::sync::Mutex<Vec<u32>> = std::sync::Mutex::new(Vec::new());
+
+/// Kill every background process still running. For exits that can't wait
+/// for tools mode to unwind, such as a second Ctrl-C.
+pub fn kill_processes() {
+    for pgid in LIVE_GROUPS.lock().unwrap().drain(..) {
+        signal_group(pgid, Signal::Kill);
+    }
+}
+
+/// Background processes started with process_start, shared by the process_*
+/// tools. Anything still running is killed when the table is dropped, i.e.
+/// when tools mode or the CLI exits.
+#[derive(Default)]
+pub struct ProcessTable {
+    inner: std::sync::Mutex<ProcessTableInner>,
+}
+
+#[derive(Default)]
+struct ProcessTableInner {
+    next_id: u32,
+    procs: std::collections::BTreeMap<u32, BackgroundProcess>,
+}
+
+struct BackgroundProcess {
+    name: Option<String>,
+    command: String,
+    pid: u32,
+    started: std::time::Instant,
+    log: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<String>>>,
+    /// Exit status once the process has ended
+    status: std::sync::Arc<std::sync::Mutex<Option<String>>>,
+}
+
+impl BackgroundProcess {
+    fn status(&self) -> String {
+        match &*self.status.lock().unwrap() {
+            Some(status) => format!("exited ({})", status),
+            None => "running".to_string(),
+        }
+    }
+}
+
+impl ProcessTable {
+    fn start(&self, command: &str, name: Option<String>) -> Result<(u32, u32)> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(command)
+            .stdin(std::process::Stdio::null())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        #[cfg(unix)]
+        cmd.process_group(0);
+        let mut child = cmd.spawn()?;
+        let pid = child
+            .id()
+            .ok_or_else(|| anyhow::anyhow!("process exited before it could be tracked"))?;
+        LIVE_GROUPS.lock().unwrap().push(pid);
+        let log = std::sync::Arc::new(std::sync::Mutex::new(std::collections::VecDeque::new()));
+        let status = std::sync::Arc::new(std::sync::Mutex::new(None));
+        let stdout = tokio::spawn(collect_lines(child.stdout.take(), "", log.clone()));
+        let stderr = tokio::spawn(collect_lines(child.stderr.take(), "[stderr] ", log.clone()));
+        let done = status.clone();
+        tokio::spawn(async move {
+            // Kills the group if the runtime shuts down first
+            let mut group = KillGroupOnDrop(Some(pid));
+            let result = child.wait().await;
+            group.0 = None;
+            let _ = tokio::join!(stdout, stderr);
+            LIVE_GROUPS.lock().unwrap().retain(|p| *p != pid);
+            *done.lock().unwrap() = Some(match result {
+                Ok(s) if s.code().is_some() => format!("exit code {}", exit_code(&s)),
+                Ok(s) => exit_code(&s),
+                Err(e) => e.to_string(),
+            });
+        });
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.procs.insert(
+            id,
+            BackgroundProcess {
+                name,
+                command: command.to_string(),
+                pid,
+                started: std::time::Instant::now(),
+                log,
+                status,
+            },
+        );
+        Ok((id, pid))
+    }
+
+    /// Find a process by numeric id or by name.
+    fn with<T>(&self, id: &Value, f: impl FnOnce(u32, &BackgroundProcess) -> T) -> Result<T> {
+        let inner = self.inner.lock().unwrap();
+        let found = match id {
+            Value::Number(n) => n
+                .as_u64()
+                .and_then(|n| inner.procs.get_key_value(&(n as u32))),
+            Value::String(s) => match s.parse::<u32>() {
+                Ok(n) => inner.procs.get_key_value(&n),
+                Err(_) => inner
+                    .procs
+                    .iter()
+                    .find(|(_, p)| p.name.as_deref() == Some(s.as_str())),
+            },
+            _ => None,
+        };
+        let (id_num, proc) = found
+            .ok_or_else(|| anyhow::anyhow!("no background process {}; see process_list", id))?;
+        Ok(f(*id_num, proc))
+    }
+}
+
+impl Drop for ProcessTable {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut().unwrap();
+        let mut live = LIVE_GROUPS.lock().unwrap();
+        for proc in inner.procs.values() {
+            if proc.status.lock().unwrap().is_none() {
+                signal_group(proc.pid, Signal::Kill);
+            }
+            live.retain(|p| *p != proc.pid);
+        }
+    }
+}
+
+async fn collect_lines<R: tokio::io::AsyncRead + Unpin>(
+    reader: Option<R>,
+    prefix: &'static str,
+    log: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<String>>>,
+) {
+    use tokio::io::AsyncBufReadExt;
+    let Some(reader) = reader else {
+        return;
+    };
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut buf = Vec::new();
+    while let Ok(n) = reader.read_until(b'\n', &mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line: String = line
+            .trim_end_matches(['\n', '\r'])
+            .chars()
+            .take(2000)
+            .collect();
+        let mut log = log.lock().unwrap();
+        if log.len() == PROCESS_LOG_LINES {
+            log.pop_front();
+        }
+        log.push_back(format!("{}{}", prefix, line));
+        buf.clear();
+    }
+}
+
+pub struct ProcessStartTool(std::sync::Arc<ProcessTable>);
+
+#[async_trait]
+impl ToolExecutor for ProcessStartTool {
+    fn name(&self) -> &str {
+        "process_start"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Shell
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "process_start".to_string(),
+                description: "Start a long-running shell command (dev server, watcher) in the background and return its id; read its output with process_logs. It is killed when the session ends"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "command": {"type": "string"},
+                        "name": {"type": "string", "description": "Optional label to refer to it by"}
+                    },
+                    "required": ["command"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let command = params["command"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing command"))?;
+        let name = params["name"].as_str().map(str::to_string);
+        let (id, pid) = self.0.start(command, name)?;
+        // Give it a moment so an immediate failure shows up in the reply
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let status = self.0.with(&json!(id), |_, p| p.status())?;
+        Ok(format!(
+            "started process {} (pid {}): {}; use process_logs with id {}",
+            id, pid, status, id
+        ))
+    }
+}
+
+pub struct ProcessLogsTool(std::sync::Arc<ProcessTable>);
+
+#[async_trait]
+impl ToolExecutor for ProcessLogsTool {
+    fn name(&self) -> &str {
+        "process_logs"
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "process_logs".to_string(),
+                description: "Show recent output of a background process (stderr lines are prefixed with [stderr])"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "id": {"type": ["integer", "string"], "description": "Process id or name"},
+                        "tail_lines": {"type": "integer", "default": 50}
+                    },
+                    "required": ["id"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let tail = params
+            .get("tail_lines")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(50) as usize;
+        self.0.with(&params["id"], |id, p| {
+            let log = p.log.lock().unwrap();
+            let skip = log.len().saturating_sub(tail);
+            let mut out = format!(
+                "process {} ({}), {} lines of output",
+                id,
+                p.status(),
+                log.len()
+            );
+            if skip > 0 {
+                out.push_str(&format!(", showing the last {}", log.len() - skip));
+            }
+            out.push('\n');
+            for line in log.iter().skip(skip) {
+                out.push_str(line);
+                out.push('\n');
+            }
+            out
+        })
+    }
+}
+
+pub struct ProcessListTool(std::sync::Arc<ProcessTable>);
+
+#[async_trait]
+impl ToolExecutor for ProcessListTool {
+    fn name(&self) -> &str {
+        "process_list"
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "process_list".to_string(),
+                description: "List background processes started with process_start".to_string(),
+                parameters: json!({"type": "object", "properties": {}}),
+            },
+        }
+    }
+    async fn execute(&self, _args: &str) -> Result<String> {
+        let inner = self.0.inner.lock().unwrap();
+        if inner.procs.is_empty() {
+            return Ok("no background processes".to_string());
+        }
+        let mut out = String::new();
+        for (id, p) in &inner.procs {
+            out.push_str(&format!(
+                "{} {} pid={} {} {}s: {}\n",
+                id,
+                p.name.as_deref().unwrap_or("-"),
+                p.pid,
+                p.status(),
+                p.started.elapsed().as_secs(),
+                p.command
+            ));
+        }
+        Ok(out)
+    }
+}
+
+pub struct ProcessKillTool(std::sync::Arc<ProcessTable>);
+
+#[async_trait]
+impl ToolExecutor for ProcessKillTool {
+    fn name(&self) -> &str {
+        "process_kill"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Shell
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "process_kill".to_string(),
+                description: "Send a signal to a background process and everything it started"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "id": {"type": ["integer", "string"], "description": "Process id or name"},
+                        "signal": {"type": "string", "enum": ["TERM", "INT", "HUP", "KILL"], "default": "TERM"}
+                    },
+                    "required": ["id"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let name = params["signal"].as_str().unwrap_or("TERM").to_uppercase();
+        let Some(signal) = Signal::parse(&name) else {
+            anyhow::bail!("unsupported signal {}; use TERM, INT, HUP or KILL", name);
+        };
+        let (id, pid, running) = self.0.with(&params["id"], |id, p| {
+            (id, p.pid, p.status.lock().unwrap().is_none())
+        })?;
+        if !running {
+            return Ok(format!("process {} has already exited", id));
+        }
+        if !signal_group(pid, signal) {
+            anyhow::bail!(
+                "SIG{} failed for process {}: {}",
+                name,
+                id,
+                std::io::Error::last_os_error()
+            );
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        let now = self.0.with(&json!(id), |_, p| p.status())?;
+        Ok(format!("sent SIG{} to process {}; now {}", name, id, now))
+    }
+}
+
+/// A `[[custom_tools]]` entry from config: an external command whose
+/// `{param}` placeholders are filled from the call's arguments.
+pub struct CommandTool {
+    spec: crate::config::CustomTool,
+}
+
+impl CommandTool {
+    pub fn new(spec: crate::config::CustomTool) -> Self {
+        Self { spec }
+    }
+
+    /// Substitute `{param}` in one template piece. A piece that is exactly a
+    /// placeholder expands to one argv element per array item, or to nothing
+    /// when the parameter is absent; values are quoted when `quote` is set.
+    fn substitute(piece: &str, params: &Value, quote: bool) -> Result<Vec<String>> {
+        let re = regex::Regex::new(r"\{([A-Za-z_][A-Za-z0-9_]*)\}").unwrap();
+        let render = |name: &str, v: &Value| {
+            let s = match v {
+                Value::String(s) => s.clone(),
+                Value::Null => String::new(),
+                other => other.to_string(),
+            };
+            if s.contains('\0') {
+                anyhow::bail!("value for {{{}}} contains a NUL byte", name);
+            }
+            Ok(if quote {
+                shlex::try_quote(&s)?.into_owned()
+            } else {
+                s
+            })
+        };
+        if let Some(caps) = re.captures(piece).filter(|c| &c[0] == piece) {
+            let name = &caps[1];
+            return match params.get(name) {
+                None | Some(Value::Null) => Ok(vec![]),
+                Some(Value::Array(items)) => items.iter().map(|v| render(name, v)).collect(),
+                Some(v) => Ok(vec![render(name, v)?]),
+            };
+        }
+        let mut out = String::new();
+        let mut last = 0;
+        for caps in re.captures_iter(piece) {
+            let whole = caps.get(0).unwrap();
+            out.push_str(&piece[last..whole.start()]);
+            if let Some(v) = params.get(&caps[1]) {
+                out.push_str(&render(&caps[1], v)?);
+            }
+            last = whole.end();
+        }
+        out.push_str(&piece[last..]);
+        Ok(vec![out])
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for CommandTool {
+    fn name(&self) -> &str {
+        self.spec.name.get_ref()
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Shell
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: self.spec.name.get_ref().clone(),
+                description: self.spec.description.clone(),
+                parameters: self.spec.parameters(),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        Ok(self.execute_with_status(args).await?.0)
+    }
+
+    async fn execute_with_status(&self, args: &str) -> Result<(String, Option<CommandStatus>)> {
+        let params: Value = if args.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(args)?
+        };
+        let cmd = if self.spec.shell {
+            let line = Self::substitute(&self.spec.command.line(), &params, true)?.join(" ");
+            let mut cmd = Command::new("sh");
+            cmd.arg("-c").arg(line);
+            cmd
+        } else {
+            // Values become whole argv elements; nothing is parsed by a shell
+            let mut argv: Vec<String> = vec![];
+            for piece in self.spec.command.args().unwrap_or_default() {
+                argv.extend(Self::substitute(&piece, &params, false)?);
+            }
+            let (program, rest) = argv
+                .split_first()
+                .ok_or_else(|| anyhow::anyhow!("command is empty after substitution"))?;
+            let mut cmd = Command::new(program);
+            cmd.args(rest);
+            cmd
+        };
+        let timeout = std::time::Duration::from_secs(self.spec.timeout_secs.unwrap_or(30));
+        let (output, status) = run_captured(cmd, timeout, 32 * 1024).await?;
+        Ok((output, Some(status)))
+    }
+
+}
+
+struct KillGroupOnDrop(Option<u32>);
+
+impl Drop for KillGroupOnDrop {
+    fn drop(&mut self) {
+        if let Some(pid) = self.0.take() {
+            signal_group(pid, Signal::Kill);
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Signal {
+    Term,
+    Int,
+    Hup,
+    Kill,
+}
+
+impl Signal {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "TERM" => Some(Self::Term),
+            "INT" => Some(Self::Int),
+            "HUP" => Some(Self::Hup),
+            "KILL" => Some(Self::Kill),
+            _ => None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn number(self) -> libc::c_int {
+        match self {
+            Self::Term => libc::SIGTERM,
+            Self::Int => libc::SIGINT,
+            Self::Hup => libc::SIGHUP,
+            Self::Kill => libc::SIGKILL,
+        }
+    }
+}
+
+/// Send `signal` to every process in the group `pgid` leads; false when
+/// there is no such group (or off Unix, where groups aren't used).
+pub fn signal_group(pgid: u32, signal: Signal) -> bool {
+    #[cfg(unix)]
+    // SAFETY: killpg only reads its arguments
+    return unsafe { libc::killpg(pgid as libc::pid_t, signal.number()) } == 0;
+    #[cfg(not(unix))]
+    {
+        let _ = (pgid, signal);
+        false
+    }
+}
+
+/// Send `signal` to the single process `pid`.
+pub fn signal_process(pid: u32, signal: Signal) -> bool {
+    #[cfg(unix)]
+    // SAFETY: kill only reads its arguments
+    return unsafe { libc::kill(pid as libc::pid_t, signal.number()) } == 0;
+    #[cfg(not(unix))]
+    {
+        let _ = (pid, signal);
+        false
+    }
+}
+
+fn exit_code(status: &std::process::ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return code.to_string();
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt as _;
+        if let Some(signal) = status.signal() {
+            return format!("killed by signal {}", signal);
+        }
+    }
+    "unknown".to_string()
+}
+
+fn exit_status(status: &std::process::ExitStatus) -> CommandStatus {
+    match status.code() {
+        Some(code) => CommandStatus::Exited(code),
+        None => CommandStatus::Abnormal(exit_code(status)),
+    }
+}
+
+/// Start and end of a stream whose middle may have been dropped.
+#[derive(Default)]
+struct Captured {
+    head: Vec<u8>,
+    tail: std::collections::VecDeque<u8>,
+    dropped: usize,
+}
 
-        let stdout = String::from_utf8_lossy(&output.stdout);
-        let stderr = String::from_utf8_lossy(&output.stderr);
+impl Captured {
+    fn len(&self) -> usize {
+        self.head.len() + self.tail.len()
+    }
 
-        Ok(format!("stdout:\n{}\nstderr:\n{}", stdout, stderr))
+    /// Render within `budget` bytes, marking how much was cut from the middle.
+    fn render(mut self, budget: usize) -> String {
+        if self.len() > budget {
+            let keep_head = self.head.len().min(budget / 2);
+            let keep_tail = budget - keep_head;
+            self.dropped += self.head.len() - keep_head;
+            self.head.truncate(keep_head);
+            while self.tail.len() > keep_tail {
+                self.tail.pop_front();
+                self.dropped += 1;
+            }
+        }
+        let mut out = String::from_utf8_lossy(&self.head).into_owned();
+        if self.dropped > 0 {
+            out.push_str(&format!("\n[... truncated {} bytes ...]\n", self.dropped));
+        }
+        out.push_str(&String::from_utf8_lossy(self.tail.make_contiguous()));
+        out
     }
+}
 
+/// Drain a pipe into `captured`, keeping only the first and last `limit / 2`
+/// bytes so a command that spews output cannot exhaust memory. What was read
+/// stays in `captured` if the read is abandoned part way.
+async fn read_capped<R>(pipe: Option<R>, limit: usize, captured: &mut Captured) -> Result<()>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use tokio::io::AsyncReadExt as _;
+    let Some(mut pipe) = pipe else {
+        return Ok(());
+    };
+    let half = (limit / 2).max(1);
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = pipe.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        let mut chunk = &buf[..n];
+        if captured.head.len() < half {
+            let take = chunk.len().min(half - captured.head.len());
+            captured.head.extend_from_slice(&chunk[..take]);
+            chunk = &chunk[take..];
+        }
+        captured.tail.extend(chunk);
+        if captured.tail.len() > half {
+            let excess = captured.tail.len() - half;
+            captured.tail.drain(..excess);
+            captured.dropped += excess;
+        }
+    }
+    Ok(())
 }
 
 pub struct CalculatorTool;
@@ -74,6 +845,25 @@ impl ToolExecutor for CalculatorTool {
     fn name(&self) -> &str {
         "calculator"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "calculator".to_string(),
+                description: "Perform mathematical calculations".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "expression": {
+                            "type": "string",
+                            "description": "Mathematical expression to evaluate"
+                        }
+                    },
+                    "required": ["expression"]
+                }),
+            },
+        }
+    }
 
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
@@ -108,12 +898,35 @@ impl ToolExecutor for FileReadTool {
     fn name(&self) -> &str {
         "read_file"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "read_file".to_string(),
+                description: "Read contents of a file (optionally a line range)".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path to the file to read"
+                        },
+                        "start_line": {"type": "integer", "minimum": 1},
+                        "end_line": {"type": "integer", "minimum": 1},
+                        "max_bytes": {"type": "integer", "minimum": 1}
+                    },
+                    "required": ["path"]
+                }),
+            },
+        }
+    }
 
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params["path"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing path parameter"))?;
+        let path = &sandbox_path(path)?;
         let start_line = params
             .get("start_line")
             .and_then(|v| v.as_u64())
@@ -155,12 +968,42 @@ impl ToolExecutor for FileWriteTool {
     fn name(&self) -> &str {
         "write_file"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "write_file".to_string(),
+                description: "Write or append content to a file".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {
+                            "type": "string",
+                            "description": "Path to the file to write"
+                        },
+                        "content": {
+                            "type": "string",
+                            "description": "Content to write to the file"
+                        },
+                        "append": {"type": "boolean", "default": false, "description": "Append instead of replacing"},
+                        "overwrite": {"type": "boolean", "default": false, "description": "Required to replace an existing file; the old content is backed up"},
+                        "create_dirs": {"type": "boolean", "default": false, "description": "Create missing parent directories"}
+                    },
+                    "required": ["path", "content"]
+                }),
+            },
+        }
+    }
 
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params["path"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing path parameter"))?;
+        let path = &sandbox_path(path)?;
         let content = params["content"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing content parameter"))?;
@@ -171,25 +1014,52 @@ impl ToolExecutor for FileWriteTool {
         let overwrite = params
             .get("overwrite")
             .and_then(|v| v.as_bool())
-            .unwrap_or(true);
+            .unwrap_or(false);
+        let create_dirs = params
+            .get("create_dirs")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        if create_dirs {
+            if let Some(parent) = std::path::Path::new(path).parent() {
+                if !parent.as_os_str().is_empty() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+            }
+        }
 
         use tokio::fs::OpenOptions;
         use tokio::io::AsyncWriteExt as _;
         let mut opts = OpenOptions::new();
         opts.create(true);
+        let mut backup = None;
         if append {
             opts.append(true);
         } else {
-            opts.write(true);
-        }
-        if !overwrite && !append {
-            // fail if file exists
-            if tokio::fs::try_exists(path).await.unwrap_or(false) {
-                anyhow::bail!("file exists and overwrite=false");
+            if let Ok(meta) = tokio::fs::metadata(path).await {
+                if !overwrite {
+                    return Ok(format!(
+                        "refused: {} already exists ({} bytes); call again with \"overwrite\": true to replace it or \"append\": true to add to it",
+                        path,
+                        meta.len()
+                    ));
+                }
+                backup = Some(copy_to_backups(path).await?);
             }
+            opts.write(true).truncate(true);
         }
         let mut file = opts.open(path).await?;
         file.write_all(content.as_bytes()).await?;
+        // tokio finishes writes in the background; don't report ok before they land
+        file.flush().await?;
+        if let Some(backup) = backup {
+            return Ok(format!(
+                "ok: {} bytes (previous content saved to {}; `rusty-cli undelete {}` restores it)",
+                content.len(),
+                backup.display(),
+                path
+            ));
+        }
         Ok(format!("ok: {} bytes", content.len()))
     }
 
@@ -197,34 +1067,332 @@ impl ToolExecutor for FileWriteTool {
 
 pub struct ToolRegistry {
     tools: HashMap<String, Box<dyn ToolExecutor>>,
+    schemas: HashMap<String, ArgumentSchema>,
+    /// Bare names routed to a registered MCP tool (`read_file` ->
+    /// `fs__read_file`); a built-in of the same name is then not offered
+    aliases: HashMap<String, String>,
+    /// Tools left out by `[tool_policy]` / `--read-only`
+    hidden: Vec<String>,
+    log: Option<ToolLog>,
+    limits: crate::config::ToolsConfig,
 }
 
-impl ToolRegistry {
-    pub fn new() -> Self {
-        let mut registry = Self {
-            tools: HashMap::new(),
+static READ_ONLY: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Force read-only tools for this run (`--read-only`), whatever the config says.
+pub fn set_read_only(on: bool) {
+    READ_ONLY.store(on, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Appended to every tool description offered to the model so it can read results.
+pub const ENVELOPE_NOTE: &str =
+    "Result: JSON {ok, output, error, meta}; check ok before trusting output.";
+
+/// A tool result as the model sees it: `{ok, output, error, meta}` as JSON,
+/// so a failure can't be mistaken for output. `meta` carries what the loop
+/// measured (duration, size) and, for commands, the exit code.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolOutcome {
+    pub ok: bool,
+    pub output: String,
+    pub error: Option<String>,
+    pub meta: serde_json::Map<String, Value>,
+}
+
+impl ToolOutcome {
+    /// A call that never ran or ran and failed, with nothing to show.
+    pub fn failed(error: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            output: String::new(),
+            error: Some(error.into()),
+            meta: Default::default(),
+        }
+    }
+
+    fn from_result(
+        result: Result<(String, Option<CommandStatus>)>,
+        elapsed: std::time::Duration,
+    ) -> Self {
+        let (mut outcome, status) = match result {
+            Ok((output, status)) => (
+                Self {
+                    ok: true,
+                    output,
+                    error: None,
+                    meta: Default::default(),
+                },
+                status,
+            ),
+            Err(e) => (Self::failed(e.to_string()), None),
         };
+        // A command that ran but failed still has output worth showing
+        match status {
+            None => {}
+            Some(CommandStatus::Exited(code)) => {
+                outcome.meta.insert("exit_code".into(), json!(code));
+                if code != 0 {
+                    outcome.ok = false;
+                    outcome.error = Some(format!("command exited with code {}", code));
+                }
+            }
+            Some(CommandStatus::Abnormal(how)) => {
+                outcome.ok = false;
+                outcome.error = Some(format!("command {}", how));
+            }
+            Some(CommandStatus::TimedOut(after)) => {
+                outcome.ok = false;
+                outcome.meta.insert("timed_out".into(), json!(true));
+                outcome.error = Some(format!(
+                    "timed out after {}s; command killed",
+                    after.as_secs()
+                ));
+            }
+        }
+        outcome
+            .meta
+            .insert("duration_ms".into(), json!(elapsed.as_millis() as u64));
+        outcome
+            .meta
+            .insert("bytes".into(), json!(outcome.output.len()));
+        outcome
+    }
 
-        // Register default tools
-        registry.register(Box::new(ShellTool));
-        registry.register(Box::new(CalculatorTool));
+    /// Serialized for the tool message, with `output` trimmed by `trim`
+    /// so the JSON stays valid when the result is capped.
+    pub fn to_message(&self, trim: impl Fn(&str) -> String) -> String {
+        let mut sent = self.clone();
+        sent.output = trim(&self.output);
+        serde_json::to_string(&sent).unwrap_or_else(|_| self.output.clone())
+    }
+}
+
+/// A tool's argument schema, compiled once when the tool is registered.
+struct ArgumentSchema {
+    /// Top-level properties; other keys draw a warning
+    properties: Option<serde_json::Map<String, Value>>,
+    /// `None` when the schema doesn't compile, which is the tool's problem,
+    /// not the model's
+    validator: Option<jsonschema::Validator>,
+}
+
+impl ArgumentSchema {
+    fn new(schema: &Value) -> Self {
+        Self {
+            properties: schema["properties"].as_object().cloned(),
+            validator: jsonschema::validator_for(schema).ok(),
+        }
+    }
+}
+
+/// Check tool-call arguments against the tool's JSON schema so the model
+/// gets a list of what to fix instead of a serde error. Unknown top-level
+/// keys are not an error; they come back as a warning for the result.
+fn check_arguments(
+    name: &str,
+    schema: &ArgumentSchema,
+    args: &str,
+) -> std::result::Result<Option<String>, String> {
+    let instance: Value = if args.trim().is_empty() {
+        json!({})
+    } else {
+        serde_json::from_str(args).map_err(|e| {
+            format!(
+                "invalid arguments for {}: not valid JSON ({}); send a JSON object",
+                name, e
+            )
+        })?
+    };
+    let unknown: Vec<&str> = match (instance.as_object(), &schema.properties) {
+        (Some(given), Some(known)) => given
+            .keys()
+            .filter(|k| !known.contains_key(k.as_str()))
+            .map(|k| k.as_str())
+            .collect(),
+        _ => vec![],
+    };
+    let Some(validator) = &schema.validator else {
+        return Ok(None);
+    };
+    let problems: Vec<String> = validator
+        .iter_errors(&instance)
+        .filter_map(|e| {
+            let path = e.instance_path().to_string();
+            match e.kind() {
+                jsonschema::error::ValidationErrorKind::AdditionalProperties { .. }
+                    if path.is_empty() =>
+                {
+                    None
+                }
+                jsonschema::error::ValidationErrorKind::Required { property } => {
+                    Some(format!("missing required field {}", property))
+                }
+                _ if path.is_empty() => Some(e.to_string()),
+                _ => Some(format!("{}: {}", path, e)),
+            }
+        })
+        .collect();
+    if !problems.is_empty() {
+        return Err(format!(
+            "invalid arguments for {}:\n- {}\nFix these and call the tool again.",
+            name,
+            problems.join("\n- ")
+        ));
+    }
+    Ok((!unknown.is_empty()).then(|| format!("ignored unknown arguments: {}", unknown.join(", "))))
+}
+
+/// Tool log path from `--tool-log`; takes precedence over `[tools] log_file`.
+static TOOL_LOG: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+pub fn set_tool_log(path: Option<PathBuf>) {
+    *TOOL_LOG.write().unwrap() = path;
+}
+
+/// Appends one JSON line per tool call for reviewing what a session did.
+struct ToolLog {
+    path: PathBuf,
+    redact: Vec<String>,
+}
+
+impl ToolLog {
+    fn from_config(cfg: &crate::config::ToolsConfig) -> Option<Self> {
+        let path = TOOL_LOG
+            .read()
+            .unwrap()
+            .clone()
+            .or_else(|| cfg.log_file.as_ref().map(PathBuf::from))?;
+        Some(Self {
+            path,
+            redact: cfg.log_redact.clone(),
+        })
+    }
+
+    fn record(
+        &self,
+        name: &str,
+        args: &str,
+        result: std::result::Result<&str, &anyhow::Error>,
+        elapsed: std::time::Duration,
+    ) {
+        let mut arguments =
+            serde_json::from_str::<Value>(args).unwrap_or_else(|_| Value::String(args.into()));
+        self.redact_keys(&mut arguments);
+        let timestamp = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let mut entry = json!({
+            "timestamp": timestamp,
+            "tool": name,
+            "arguments": arguments,
+            "ok": result.is_ok(),
+            "duration_ms": elapsed.as_millis() as u64,
+        });
+        match result {
+            Ok(out) => entry["result_chars"] = json!(out.chars().count()),
+            Err(e) => entry["error"] = json!(e.to_string()),
+        }
+        if let Err(e) = self.append(&entry) {
+            eprintln!("warning: tool log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn redact_keys(&self, value: &mut Value) {
+        match value {
+            Value::Object(map) => {
+                for (key, v) in map.iter_mut() {
+                    if self.redact.iter().any(|r| r.eq_ignore_ascii_case(key)) {
+                        *v = json!("[redacted]");
+                    } else {
+                        self.redact_keys(v);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|v| self.redact_keys(v)),
+            _ => {}
+        }
+    }
+
+    fn append(&self, entry: &Value) -> Result<()> {
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    }
+}
+
+pub fn policy_allows(policy: &crate::config::ToolPolicy, access: ToolAccess) -> bool {
+    if READ_ONLY.load(std::sync::atomic::Ordering::Relaxed) || policy.read_only.unwrap_or(false) {
+        return access == ToolAccess::Read;
+    }
+    match access {
+        ToolAccess::Read => true,
+        ToolAccess::Write => policy.allow_file_write.unwrap_or(true),
+        ToolAccess::Network => policy.allow_network.unwrap_or(true),
+        ToolAccess::Shell => policy.allow_shell.unwrap_or(true),
+    }
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        let mut registry = Self {
+            tools: HashMap::new(),
+            schemas: HashMap::new(),
+            aliases: HashMap::new(),
+            hidden: vec![],
+            log: None,
+            limits: Default::default(),
+        };
+
+        // Register default tools
+        let cfg = crate::config::Config::load().unwrap_or_else(|e| {
+            eprintln!("warning: ignoring config for tools: {}", e);
+            crate::config::Config::default()
+        });
+        registry.log = ToolLog::from_config(&cfg.tools);
+        registry.limits = cfg.tools.clone();
+        registry.register(Box::new(ShellTool::new(&cfg.tools.shell)));
+        registry.register(Box::new(CalculatorTool));
         registry.register(Box::new(FileReadTool));
         registry.register(Box::new(FileWriteTool));
         registry.register(Box::new(ListDirTool));
+        registry.register(Box::new(GlobTool));
         registry.register(Box::new(FindTextTool));
         registry.register(Box::new(EditFileTool));
+        registry.register(Box::new(SearchReplaceTool));
+        registry.register(Box::new(ApplyPatchTool));
         registry.register(Box::new(DeleteFileTool));
         registry.register(Box::new(UndeleteFileTool));
+        registry.register(Box::new(CreateDirectoryTool));
+        registry.register(Box::new(MovePathTool));
+        registry.register(Box::new(CopyPathTool));
         registry.register(Box::new(GitStatusTool));
         registry.register(Box::new(GitDiffTool));
+        registry.register(Box::new(GitLogTool));
         registry.register(Box::new(GitApplyTool));
-        registry.register(Box::new(HttpGetTool));
-        registry.register(Box::new(HttpPostTool));
+        if cfg.tools.network.unwrap_or(true) {
+            registry.register(Box::new(HttpGetTool));
+            registry.register(Box::new(FetchPageTool));
+            registry.register(Box::new(HttpPostTool));
+        }
+        let processes = std::sync::Arc::new(ProcessTable::default());
+        registry.register(Box::new(ProcessStartTool(processes.clone())));
+        registry.register(Box::new(ProcessLogsTool(processes.clone())));
+        registry.register(Box::new(ProcessListTool(processes.clone())));
+        registry.register(Box::new(ProcessKillTool(processes)));
         registry.register(Box::new(FileInfoTool));
+        registry.register(Box::new(ReadImageTool));
         registry.register(Box::new(ChecksumTool));
         registry.register(Box::new(JsonQueryTool));
         registry.register(Box::new(YamlQueryTool));
         registry.register(Box::new(CargoTool));
+        registry.register(Box::new(CargoCheckTool));
+        registry.register(Box::new(CargoTestTool));
         registry.register(Box::new(FormatRustTool));
         registry.register(Box::new(ZipTool));
         registry.register(Box::new(UnzipTool));
@@ -232,331 +1400,152 @@ impl ToolRegistry {
         registry.register(Box::new(GitCommitTool));
         registry.register(Box::new(GitBranchTool));
         registry.register(Box::new(DbQueryTool));
+        registry.register(Box::new(SqliteQueryTool));
         registry.register(Box::new(AddNoteTool));
         registry.register(Box::new(ListNotesTool));
 
+        if cfg.tools.network.unwrap_or(true) {
+            match WebSearchTool::from_config(&cfg.tools.web_search) {
+                Ok(Some(tool)) => registry.register(Box::new(tool)),
+                Ok(None) => {}
+                Err(e) => eprintln!("warning: web_search disabled: {}", e),
+            }
+        }
+
+        if cfg.tools.clipboard.unwrap_or(false) {
+            registry.register(Box::new(ClipboardTool));
+        }
+
+        for spec in cfg.custom_tools {
+            if registry.tools.contains_key(spec.name.get_ref()) {
+                eprintln!(
+                    "warning: custom tool `{}` clashes with a built-in tool; skipped",
+                    spec.name.get_ref()
+                );
+                continue;
+            }
+            registry.register(Box::new(CommandTool::new(spec)));
+        }
+
+        // Disallowed tools are dropped so the model never sees them
+        let mut hidden: Vec<String> = registry
+            .tools
+            .iter()
+            .filter(|(_, t)| !policy_allows(&cfg.tool_policy, t.access()))
+            .map(|(name, _)| name.clone())
+            .collect();
+        hidden.sort();
+        for name in &hidden {
+            registry.tools.remove(name);
+            registry.schemas.remove(name);
+        }
+        registry.hidden = hidden;
+
         registry
     }
 
     pub fn register(&mut self, tool: Box<dyn ToolExecutor>) {
+        let schema = ArgumentSchema::new(&tool.definition().function.parameters);
+        self.schemas.insert(tool.name().to_string(), schema);
         self.tools.insert(tool.name().to_string(), tool);
     }
 
-    pub async fn execute(&self, name: &str, args: &str) -> Result<String> {
+    /// Register a tool added after startup (such as an MCP server's),
+    /// hiding it like the built-ins when the policy disallows its access.
+    /// Returns whether it will be offered.
+    pub fn register_checked(
+        &mut self,
+        tool: Box<dyn ToolExecutor>,
+        policy: &crate::config::ToolPolicy,
+    ) -> bool {
+        if policy_allows(policy, tool.access()) {
+            self.register(tool);
+            true
+        } else {
+            self.hidden.push(tool.name().to_string());
+            self.hidden.sort();
+            false
+        }
+    }
+
+    /// Whether `name` is registered, offered or not.
+    pub fn contains(&self, name: &str) -> bool {
+        self.tools.contains_key(name) || self.hidden.iter().any(|h| h == name)
+    }
+
+    /// Route calls to the bare `name` to the registered tool `target`.
+    pub fn alias(&mut self, name: &str, target: &str) {
+        self.aliases.insert(name.to_string(), target.to_string());
+    }
+
+    /// The registered name a call to `name` runs.
+    fn resolve<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    /// Whether a registered tool can still run this session.
+    pub fn available(&self, name: &str) -> bool {
         self.tools
-            .get(name)
-            .ok_or_else(|| anyhow::anyhow!("Tool {} not found", name))?
-            .execute(args)
-            .await
+            .get(self.resolve(name))
+            .is_some_and(|t| t.available())
     }
 
-    pub fn get_tool_definitions(&self) -> Vec<Tool> {
-        vec![
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "shell".to_string(),
-                    description: "Execute a shell command".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "command": {
-                                "type": "string",
-                                "description": "The shell command to execute"
-                            }
-                        },
-                        "required": ["command"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "calculator".to_string(),
-                    description: "Perform mathematical calculations".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "expression": {
-                                "type": "string",
-                                "description": "Mathematical expression to evaluate"
-                            }
-                        },
-                        "required": ["expression"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "read_file".to_string(),
-                    description: "Read contents of a file (optionally a line range)".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "path": {
-                                "type": "string",
-                                "description": "Path to the file to read"
-                            },
-                            "start_line": {"type": "integer", "minimum": 1},
-                            "end_line": {"type": "integer", "minimum": 1},
-                            "max_bytes": {"type": "integer", "minimum": 1}
-                        },
-                        "required": ["path"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "write_file".to_string(),
-                    description: "Write or append content to a file".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "path": {
-                                "type": "string",
-                                "description": "Path to the file to write"
-                            },
-                            "content": {
-                                "type": "string",
-                                "description": "Content to write to the file"
-                            },
-                            "append": {"type": "boolean", "default": false},
-                            "overwrite": {"type": "boolean", "default": true}
-                        },
-                        "required": ["path", "content"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "list_dir".to_string(),
-                    description: "List files in a directory".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "path": {"type": "string"},
-                            "recursive": {"type": "boolean", "default": false},
-                            "max_items": {"type": "integer", "default": 200}
-                        },
-                        "required": ["path"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "find_text".to_string(),
-                    description: "Search text in files under a directory".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "root": {"type": "string"},
-                            "pattern": {"type": "string"},
-                            "max_results": {"type": "integer", "default": 200}
-                        },
-                        "required": ["root", "pattern"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "edit_file".to_string(),
-                    description: "Apply a unified diff to a file".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "path": {"type": "string"},
-                            "diff": {"type": "string", "description": "Unified diff text with @@ hunks"}
-                        },
-                        "required": ["path", "diff"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "delete_file".to_string(),
-                    description: "Move a file to the OS recycle bin (Trash)".to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": {
-                            "path": {"type": "string", "description": "Path to delete (moved to Trash)"}
-                        },
-                        "required": ["path"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "undelete_file".to_string(),
-                    description: "Restore a previously deleted file from the CLI backup store"
-                        .to_string(),
-                    parameters: json!({
-                        "type": "object",
-                        "properties": { "path": {"type": "string"} },
-                        "required": ["path"]
-                    }),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "git_status".to_string(),
-                    description: "Show git status (porcelain)".to_string(),
-                    parameters: json!({"type":"object","properties":{},"additionalProperties":false}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "git_diff".to_string(),
-                    description: "Show git diff for a rev and optional path".to_string(),
-                    parameters: json!({"type":"object","properties":{"rev":{"type":"string"},"path":{"type":"string"}},"additionalProperties":false}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "git_apply".to_string(),
-                    description: "Apply a unified diff via git".to_string(),
-                    parameters: json!({"type":"object","properties":{"diff":{"type":"string"}},"required":["diff"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "http_get".to_string(),
-                    description: "Fetch a URL (text, limited)".to_string(),
-                    parameters: json!({"type":"object","properties":{"url":{"type":"string"},"max_bytes":{"type":"integer","default":262144}},"required":["url"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "http_post".to_string(),
-                    description: "POST to a URL (text/JSON), return response text (limited)"
-                        .to_string(),
-                    parameters: json!({"type":"object","properties":{"url":{"type":"string"},"body":{"type":"string"},"content_type":{"type":"string","default":"application/json"},"max_bytes":{"type":"integer","default":262144}},"required":["url","body"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "file_info".to_string(),
-                    description: "Get file info (size, mtime, type)".to_string(),
-                    parameters: json!({"type":"object","properties":{"path":{"type":"string"}},"required":["path"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "checksum".to_string(),
-                    description: "SHA256 checksum of a file".to_string(),
-                    parameters: json!({"type":"object","properties":{"path":{"type":"string"}},"required":["path"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "json_query".to_string(),
-                    description: "Query JSON file via JSON Pointer".to_string(),
-                    parameters: json!({"type":"object","properties":{"path":{"type":"string"},"pointer":{"type":"string"}},"required":["path","pointer"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "yaml_query".to_string(),
-                    description: "Query YAML file via JSON Pointer".to_string(),
-                    parameters: json!({"type":"object","properties":{"path":{"type":"string"},"pointer":{"type":"string"}},"required":["path","pointer"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "run_cargo".to_string(),
-                    description: "Run cargo with args".to_string(),
-                    parameters: json!({"type":"object","properties":{"args":{"type":"string"}},"required":["args"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "format_rust".to_string(),
-                    description: "Format Rust code (cargo fmt or path)".to_string(),
-                    parameters: json!({"type":"object","properties":{"path":{"type":"string"}},"additionalProperties":false}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "git_commit".to_string(),
-                    description: "Create a git commit; optionally add all".to_string(),
-                    parameters: json!({"type":"object","properties":{"message":{"type":"string"},"add_all":{"type":"boolean","default":false}},"required":["message"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "git_branch".to_string(),
-                    description: "branch ops: current|list|switch|create".to_string(),
-                    parameters: json!({"type":"object","properties":{"action":{"type":"string"},"name":{"type":"string"}},"required":["action"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "zip".to_string(),
-                    description: "Create a zip archive from a directory or file".to_string(),
-                    parameters: json!({"type":"object","properties":{"input":{"type":"string"},"output":{"type":"string"}},"required":["input","output"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "unzip".to_string(),
-                    description: "Extract a zip archive to a directory".to_string(),
-                    parameters: json!({"type":"object","properties":{"archive":{"type":"string"},"output_dir":{"type":"string"}},"required":["archive","output_dir"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "list_deleted".to_string(),
-                    description: "List recently deleted files recorded by the CLI".to_string(),
-                    parameters: json!({"type":"object","properties":{"limit":{"type":"integer","default":50}},"additionalProperties":false}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "db_query".to_string(),
-                    description: "Run a read-only SQL query against Rusty CLI DB".to_string(),
-                    parameters: json!({"type":"object","properties":{"sql":{"type":"string"},"params":{"type":"array","items":{"type":["string","number","boolean","null"]}}},"required":["sql"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "add_note".to_string(),
-                    description: "Add a note to the Rusty CLI DB".to_string(),
-                    parameters: json!({"type":"object","properties":{"title":{"type":"string"},"content":{"type":"string"},"tags":{"type":"string"}},"required":["content"]}),
-                },
-            },
-            Tool {
-                r#type: "function".to_string(),
-                function: Function {
-                    name: "list_notes".to_string(),
-                    description: "List recent notes (optionally filter by search)".to_string(),
-                    parameters: json!({"type":"object","properties":{"search":{"type":"string"},"limit":{"type":"integer","default":50}},"additionalProperties":false}),
-                },
+    /// Run a tool call: its output, plus the command status for tools that run one.
+    async fn execute(&self, name: &str, args: &str) -> Result<(String, Option<CommandStatus>)> {
+        let started = std::time::Instant::now();
+        let name = self.resolve(name);
+        let result = match self.tools.get(name) {
+            Some(tool) => match check_arguments(name, &self.schemas[name], args) {
+                Err(problems) => Err(anyhow::anyhow!(problems)),
+                Ok(warning) => {
+                    let limit = self.limits.timeout(name);
+                    // Dropping the future kills any child process the tool started
+                    tokio::time::timeout(limit, tool.execute_with_status(args))
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(anyhow::anyhow!("tool timed out after {}s", limit.as_secs()))
+                        })
+                        .map(|(out, status)| match warning {
+                            Some(w) => (format!("{}\n[warning: {}]", out, w), status),
+                            None => (out, status),
+                        })
+                }
             },
-        ]
+            None if self.hidden.iter().any(|h| h == name) => Err(anyhow::anyhow!(
+                "Tool {} is disabled by the tool policy",
+                name
+            )),
+            None => Err(anyhow::anyhow!("Tool {} not found", name)),
+        };
+        if let Some(log) = &self.log {
+            let logged = result.as_ref().map(|(out, _)| out.as_str());
+            log.record(name, args, logged, started.elapsed());
+        }
+        result
+    }
+
+    /// Run a tool call and wrap the result in the envelope sent to the model.
+    pub async fn run(&self, name: &str, args: &str) -> ToolOutcome {
+        let started = std::time::Instant::now();
+        let result = self.execute(name, args).await;
+        ToolOutcome::from_result(result, started.elapsed())
+    }
+
+    /// Names of tools withheld by the tool policy, sorted.
+    pub fn hidden(&self) -> &[String] {
+        &self.hidden
+    }
+
+    /// Definitions of every registered tool, sorted by name.
+    pub fn get_tool_definitions(&self) -> Vec<Tool> {
+        let mut defs: Vec<Tool> = self
+            .tools
+            .iter()
+            .filter(|(name, _)| !self.aliases.contains_key(*name))
+            .map(|(_, t)| t.definition())
+            .collect();
+        defs.sort_by(|a, b| a.function.name.cmp(&b.function.name));
+        defs
     }
 }
 
@@ -568,38 +1557,172 @@ impl ToolExecutor for ListDirTool {
     fn name(&self) -> &str {
         "list_dir"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "list_dir".to_string(),
+                description: "List directory entries with type and size, sorted by name"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string"},
+                        "recursive": {"type": "boolean", "default": false},
+                        "max_entries": {"type": "integer", "default": 200},
+                        "show_hidden": {"type": "boolean", "default": false}
+                    },
+                    "required": ["path"]
+                }),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params["path"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = &sandbox_path(path)?;
         let recursive = params
             .get("recursive")
             .and_then(|v| v.as_bool())
             .unwrap_or(false);
-        let max_items = params
-            .get("max_items")
+        let max_entries = params
+            .get("max_entries")
+            .or_else(|| params.get("max_items"))
             .and_then(|v| v.as_u64())
             .unwrap_or(200) as usize;
-        let mut out: Vec<String> = Vec::new();
-        if recursive {
-            for entry in walkdir::WalkDir::new(path).into_iter().flatten() {
-                if out.len() >= max_items {
-                    break;
+        let show_hidden = params
+            .get("show_hidden")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let root = std::path::PathBuf::from(path);
+        tokio::task::spawn_blocking(move || {
+            let walker = walkdir::WalkDir::new(&root)
+                .min_depth(1)
+                .max_depth(if recursive { usize::MAX } else { 1 })
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|e| show_hidden || !e.file_name().to_string_lossy().starts_with('.'));
+            let mut out: Vec<String> = Vec::new();
+            let mut total = 0usize;
+            for entry in walker.flatten() {
+                total += 1;
+                if out.len() >= max_entries {
+                    continue;
                 }
-                let p = entry.path();
-                out.push(p.display().to_string());
+                let rel = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+                let ft = entry.file_type();
+                let (kind, size) = if ft.is_dir() {
+                    ("dir", "-".to_string())
+                } else if ft.is_symlink() {
+                    ("link", "-".to_string())
+                } else {
+                    let len = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                    ("file", len.to_string())
+                };
+                out.push(format!("{:<4} {:>10}  {}", kind, size, rel.display()));
             }
-        } else {
-            let mut read = tokio::fs::read_dir(path).await?;
-            while let Some(entry) = read.next_entry().await? {
-                if out.len() >= max_items {
-                    break;
-                }
-                out.push(entry.path().display().to_string());
+            if total > out.len() {
+                out.push(format!("... {} more entries", total - out.len()));
             }
+            anyhow::Ok(out.join("\n"))
+        })
+        .await?
+    }
+}
+
+pub struct GlobTool;
+
+#[async_trait]
+impl ToolExecutor for GlobTool {
+    fn name(&self) -> &str {
+        "glob"
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "glob".to_string(),
+                description: "Find files matching a glob like src/**/*.rs (respects .gitignore)"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "pattern": {"type": "string", "description": "Glob relative to root"},
+                        "root": {"type": "string", "default": "."},
+                        "max_results": {"type": "integer", "default": 200},
+                        "respect_gitignore": {"type": "boolean", "default": true},
+                        "include_dirs": {"type": "boolean", "default": false, "description": "Also return matching directories"}
+                    },
+                    "required": ["pattern"]
+                }),
+            },
         }
-        Ok(out.join("\n"))
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let pattern = params["pattern"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing pattern"))?;
+        let root = params.get("root").and_then(|v| v.as_str()).unwrap_or(".");
+        let root = sandbox_path(root)?;
+        let max_results = params
+            .get("max_results")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(200) as usize;
+        let respect_gitignore = params
+            .get("respect_gitignore")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        let include_dirs = params
+            .get("include_dirs")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let matcher = globset::GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()?
+            .compile_matcher();
+        tokio::task::spawn_blocking(move || {
+            let root_path = std::path::PathBuf::from(&root);
+            let walker = ignore::WalkBuilder::new(&root_path)
+                .git_ignore(respect_gitignore)
+                .git_exclude(respect_gitignore)
+                .ignore(respect_gitignore)
+                .require_git(false)
+                .build();
+            let mut hits: Vec<String> = walker
+                .flatten()
+                .filter_map(|entry| {
+                    let rel = entry.path().strip_prefix(&root_path).ok()?;
+                    if rel.as_os_str().is_empty() || !matcher.is_match(rel) {
+                        return None;
+                    }
+                    let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+                    if is_dir && !include_dirs {
+                        return None;
+                    }
+                    // Directories end in `/` so they can't be mistaken for files
+                    let suffix = if is_dir { "/" } else { "" };
+                    Some(format!("{}{}", rel.display(), suffix))
+                })
+                .collect();
+            hits.sort();
+            let total = hits.len();
+            hits.truncate(max_results);
+            let mut out = hits.join("\n");
+            if total > max_results {
+                out.push_str(&format!(
+                    "\n... trimmed {} more matches",
+                    total - max_results
+                ));
+            }
+            if total == 0 {
+                out = format!("no files match {}", matcher.glob());
+            }
+            anyhow::Ok(out)
+        })
+        .await?
     }
 }
 
@@ -610,11 +1733,30 @@ impl ToolExecutor for FindTextTool {
     fn name(&self) -> &str {
         "find_text"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "find_text".to_string(),
+                description: "Search text in files under a directory".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "root": {"type": "string"},
+                        "pattern": {"type": "string"},
+                        "max_results": {"type": "integer", "default": 200}
+                    },
+                    "required": ["root", "pattern"]
+                }),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let root = params["root"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing root"))?;
+        let root = &sandbox_path(root)?;
         let pattern = params["pattern"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing pattern"))?;
@@ -658,156 +1800,1499 @@ impl ToolExecutor for FindTextTool {
     }
 }
 
-// Edit file using unified diff
-pub struct EditFileTool;
+// Multi-file edits in the "*** Begin Patch" envelope format
+pub struct ApplyPatchTool;
 
-#[async_trait]
-impl ToolExecutor for EditFileTool {
-    fn name(&self) -> &str {
-        "edit_file"
-    }
-    async fn execute(&self, args: &str) -> Result<String> {
-        let params: Value = serde_json::from_str(args)?;
-        let path = params["path"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
-        let diff = params["diff"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing diff"))?;
-        let original = tokio::fs::read_to_string(path).await?;
-        let new = apply_unified_diff(&original, diff)?;
-        tokio::fs::write(path, new).await?;
-        Ok("ok".into())
-    }
+/// One file operation from a patch envelope.
+enum PatchOp {
+    Add {
+        path: String,
+        content: String,
+    },
+    Delete {
+        path: String,
+    },
+    Update {
+        path: String,
+        move_to: Option<String>,
+        chunks: Vec<PatchChunk>,
+    },
 }
 
-fn apply_unified_diff(original: &str, diff: &str) -> Result<String> {
-    use regex::Regex;
-    let lines: Vec<String> = original.split('\n').map(|s| s.to_string()).collect();
-    let re_hunk = Regex::new(r"^@@ -([0-9]+)(?:,([0-9]+))? \+([0-9]+)(?:,([0-9]+))? @@").unwrap();
-    // skip headers --- +++ if present
-    let mut iter = diff.lines().peekable();
-    while let Some(line) = iter.peek() {
-        if line.starts_with("--- ") || line.starts_with("+++ ") {
-            iter.next();
-        } else {
+/// An `@@` section: optional anchor line, then the old and new text.
+struct PatchChunk {
+    anchor: Option<String>,
+    old: Vec<String>,
+    new: Vec<String>,
+}
+
+fn parse_patch_envelope(patch: &str) -> Result<Vec<PatchOp>> {
+    let mut lines = patch.lines().map(|l| l.trim_end_matches('\r')).peekable();
+    // Allow leading blank lines or a stray heredoc marker before the envelope
+    while lines.peek().is_some_and(|l| l.trim() != "*** Begin Patch") {
+        lines.next();
+    }
+    if lines.next().is_none() {
+        anyhow::bail!("patch must start with *** Begin Patch");
+    }
+    let mut ops = vec![];
+    loop {
+        let Some(line) = lines.next() else {
+            anyhow::bail!("patch is missing *** End Patch");
+        };
+        if line.trim() == "*** End Patch" {
             break;
         }
-    }
-    let mut output: Vec<String> = Vec::new();
-    let mut src_index = 0usize; // 0-based in our buffer
-    while let Some(line) = iter.next() {
-        if let Some(caps) = re_hunk.captures(line) {
-            let old_start: usize = caps.get(1).unwrap().as_str().parse().unwrap();
-            // let old_count = caps.get(2).map(|m| m.as_str().parse::<usize>().unwrap()).unwrap_or(1);
-            // Append unchanged lines before the hunk
-            let target_index = old_start.saturating_sub(1);
-            while src_index < target_index {
-                output.push(lines[src_index].clone());
-                src_index += 1;
-            }
-            // Now consume hunk lines until next hunk or EOF
-            while let Some(next) = iter.peek() {
-                let c = next.as_bytes().first().copied().unwrap_or(b' ');
-                if c == b'@' {
-                    break;
+        if let Some(path) = line.strip_prefix("*** Add File: ") {
+            let mut content = String::new();
+            while let Some(l) = lines.next_if(|l| !l.starts_with("*** ")) {
+                let body = l.strip_prefix('+').ok_or_else(|| {
+                    anyhow::anyhow!("Add File {}: every line must start with +", path)
+                })?;
+                content.push_str(body);
+                content.push('\n');
+            }
+            ops.push(PatchOp::Add {
+                path: path.trim().to_string(),
+                content,
+            });
+        } else if let Some(path) = line.strip_prefix("*** Delete File: ") {
+            ops.push(PatchOp::Delete {
+                path: path.trim().to_string(),
+            });
+        } else if let Some(path) = line.strip_prefix("*** Update File: ") {
+            let move_to = lines
+                .next_if(|l| l.starts_with("*** Move to: "))
+                .map(|l| l["*** Move to: ".len()..].trim().to_string());
+            let mut chunks: Vec<PatchChunk> = vec![];
+            while let Some(l) = lines.next_if(|l| !l.starts_with("*** ") || *l == "*** End of File")
+            {
+                if l == "*** End of File" {
+                    continue;
                 }
-                let l = iter.next().unwrap();
-                match c {
-                    b' ' => {
-                        // context
-                        let ctx = &l[1..];
-                        if src_index >= lines.len() || lines[src_index] != ctx {
-                            anyhow::bail!("context mismatch applying diff");
-                        }
-                        output.push(lines[src_index].clone());
-                        src_index += 1;
-                    }
-                    b'-' => {
-                        // removal
-                        let old = &l[1..];
-                        if src_index >= lines.len() || lines[src_index] != old {
-                            anyhow::bail!("deletion mismatch applying diff");
-                        }
-                        src_index += 1;
+                if let Some(anchor) = l.strip_prefix("@@") {
+                    let anchor = anchor.trim();
+                    chunks.push(PatchChunk {
+                        anchor: (!anchor.is_empty()).then(|| anchor.to_string()),
+                        old: vec![],
+                        new: vec![],
+                    });
+                    continue;
+                }
+                if chunks.is_empty() {
+                    // A first chunk may omit its @@ line
+                    chunks.push(PatchChunk {
+                        anchor: None,
+                        old: vec![],
+                        new: vec![],
+                    });
+                }
+                let chunk = chunks.last_mut().unwrap();
+                match l.chars().next() {
+                    Some('+') => chunk.new.push(l[1..].to_string()),
+                    Some('-') => chunk.old.push(l[1..].to_string()),
+                    Some(' ') => {
+                        chunk.old.push(l[1..].to_string());
+                        chunk.new.push(l[1..].to_string());
                     }
-                    b'+' => {
-                        // addition
-                        output.push(l[1..].to_string());
+                    None => {
+                        chunk.old.push(String::new());
+                        chunk.new.push(String::new());
                     }
-                    _ => {}
+                    _ => anyhow::bail!("Update File {}: unexpected line {:?}", path, l),
                 }
             }
+            if chunks.is_empty() && move_to.is_none() {
+                anyhow::bail!("Update File {} has no changes", path);
+            }
+            ops.push(PatchOp::Update {
+                path: path.trim().to_string(),
+                move_to,
+                chunks,
+            });
+        } else if !line.trim().is_empty() {
+            anyhow::bail!("unexpected line in patch: {:?}", line);
         }
     }
-    // append remainder
-    while src_index < lines.len() {
-        output.push(lines[src_index].clone());
-        src_index += 1;
-    }
-    Ok(output.join("\n"))
+    Ok(ops)
 }
 
-pub struct DeleteFileTool;
-
-#[async_trait]
-impl ToolExecutor for DeleteFileTool {
-    fn name(&self) -> &str {
-        "delete_file"
+/// Find `needle` in `lines` at or after `from`, first exactly, then ignoring
+/// trailing whitespace, then ignoring surrounding whitespace.
+fn seek_lines(lines: &[String], needle: &[String], from: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(from.min(lines.len()));
     }
-    async fn execute(&self, args: &str) -> Result<String> {
-        let params: Value = serde_json::from_str(args)?;
-        let path = params["path"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
-        // Copy to backup store for easy undelete
-        let backups = SessionStore::backups_dir();
-        tokio::fs::create_dir_all(&backups).await.ok();
-        let file_name = std::path::Path::new(path)
-            .file_name()
-            .and_then(|s| s.to_str())
-            .unwrap_or("file");
-        let backup_path = backups.join(format!(
-            "{}-{}",
-            file_name,
-            time::OffsetDateTime::now_utc().unix_timestamp()
-        ));
-        // Best-effort copy; if it fails, proceed with trash anyway
-        let _ = tokio::fs::copy(path, &backup_path).await;
-        if backup_path.exists() {
-            let _ = SessionStore::record_deleted(path, &backup_path.to_string_lossy());
+    let normalizers: [fn(&str) -> &str; 3] = [|s| s, str::trim_end, str::trim];
+    for norm in normalizers {
+        let found = (from..=lines.len().saturating_sub(needle.len())).find(|&i| {
+            lines[i..i + needle.len()]
+                .iter()
+                .zip(needle)
+                .all(|(a, b)| norm(a) == norm(b))
+        });
+        if found.is_some() {
+            return found;
         }
-        trash::delete(path)?;
-        Ok(format!("moved to recycle bin: {}", path))
     }
+    None
 }
 
-pub struct UndeleteFileTool;
+/// Apply update chunks in order, each searched for after the previous one.
+fn apply_patch_chunks(path: &str, original: &str, chunks: &[PatchChunk]) -> Result<String> {
+    let mut lines: Vec<String> = original.lines().map(|l| l.to_string()).collect();
+    let mut pos = 0;
+    for (n, chunk) in chunks.iter().enumerate() {
+        if let Some(anchor) = &chunk.anchor {
+            match seek_lines(&lines, std::slice::from_ref(anchor), pos) {
+                Some(i) => pos = i + 1,
+                None => anyhow::bail!("{}: chunk {}: anchor {:?} not found", path, n + 1, anchor),
+            }
+        }
+        let Some(at) = seek_lines(&lines, &chunk.old, pos) else {
+            anyhow::bail!(
+                "{}: chunk {}: lines to replace not found:\n{}",
+                path,
+                n + 1,
+                chunk.old.join("\n")
+            );
+        };
+        lines.splice(at..at + chunk.old.len(), chunk.new.iter().cloned());
+        pos = at + chunk.new.len();
+    }
+    let mut out = lines.join("\n");
+    if original.ends_with('\n') || original.is_empty() {
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+#[async_trait]
+impl ToolExecutor for ApplyPatchTool {
+    fn name(&self) -> &str {
+        "apply_patch"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "apply_patch".to_string(),
+                description: "Apply a multi-file patch: either a unified diff (git apply --3way in a repo, reporting conflicts per file) or the *** Begin Patch / *** Add File / *** Update File / *** Delete File / *** End Patch format".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "patch": {"type": "string", "description": "Unified diff with ---/+++ headers, or a full *** Begin Patch envelope"},
+                        "check_only": {"type": "boolean", "default": false, "description": "Report whether it applies without writing"}
+                    },
+                    "required": ["patch"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let patch = params["patch"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing patch"))?;
+        let check_only = params
+            .get("check_only")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        if !patch.contains("*** Begin Patch") {
+            return apply_unified_patch(patch, check_only).await;
+        }
+        let ops = parse_patch_envelope(patch)?;
+
+        // Work out every resulting file before touching the disk
+        let mut writes: Vec<(String, String)> = vec![];
+        let mut removals: Vec<String> = vec![];
+        let mut summary = vec![];
+        for op in &ops {
+            match op {
+                PatchOp::Add { path, content } => {
+                    let path = sandbox_path(path)?;
+                    if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+                        anyhow::bail!("Add File {}: already exists", path);
+                    }
+                    summary.push(format!("A {}", path));
+                    writes.push((path, content.clone()));
+                }
+                PatchOp::Delete { path } => {
+                    let path = sandbox_entry_path(path)?;
+                    if !tokio::fs::metadata(&path).await?.is_file() {
+                        anyhow::bail!("Delete File {}: not a file", path);
+                    }
+                    summary.push(format!("D {}", path));
+                    removals.push(path);
+                }
+                PatchOp::Update {
+                    path,
+                    move_to,
+                    chunks,
+                } => {
+                    let path = sandbox_path(path)?;
+                    let original = tokio::fs::read_to_string(&path).await?;
+                    let updated = apply_patch_chunks(&path, &original, chunks)?;
+                    match move_to {
+                        Some(dest) => {
+                            let dest = sandbox_path(dest)?;
+                            summary.push(format!("R {} -> {}", path, dest));
+                            writes.push((dest, updated));
+                            removals.push(path);
+                        }
+                        None => {
+                            summary.push(format!("M {}", path));
+                            writes.push((path, updated));
+                        }
+                    }
+                }
+            }
+        }
+
+        if check_only {
+            summary.push("check passed; nothing written".to_string());
+            return Ok(summary.join("\n"));
+        }
+
+        // Write, remembering what each path held so a failure can be undone
+        let mut undo: Vec<(String, Option<Vec<u8>>)> = vec![];
+        let result: Result<()> = async {
+            for (path, content) in &writes {
+                let previous = tokio::fs::read(path).await.ok();
+                if previous.is_some() {
+                    copy_to_backups(path).await?;
+                } else if let Some(parent) = Path::new(path).parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                undo.push((path.clone(), previous));
+                tokio::fs::write(path, content).await?;
+            }
+            for path in &removals {
+                let previous = tokio::fs::read(path).await.ok();
+                move_to_backups(path).await?;
+                undo.push((path.clone(), previous));
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            for (path, previous) in undo.into_iter().rev() {
+                let _ = match previous {
+                    Some(bytes) => tokio::fs::write(&path, bytes).await,
+                    None => tokio::fs::remove_file(&path).await,
+                };
+            }
+            anyhow::bail!("patch failed and was rolled back: {}", e);
+        }
+        summary.push(format!(
+            "applied {} file change(s); previous versions are in the undelete store",
+            ops.len()
+        ));
+        Ok(summary.join("\n"))
+    }
+}
+
+/// Apply a plain unified diff (possibly spanning files): `git apply --3way`
+/// inside a work tree, otherwise the edit_file patcher file by file.
+async fn apply_unified_patch(patch: &str, check_only: bool) -> Result<String> {
+    let dir = match sandbox_root() {
+        Some(root) => root,
+        None => std::env::current_dir()?,
+    };
+    let in_git = Command::new("git")
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .current_dir(&dir)
+        .output()
+        .await
+        .is_ok_and(|o| o.status.success());
+    if !in_git {
+        return apply_unified_without_git(&dir, patch, check_only).await;
+    }
+
+    let tmp = std::env::temp_dir().join(format!(
+        "rusty-patch-{}-{}.diff",
+        std::process::id(),
+        time::OffsetDateTime::now_utc().unix_timestamp_nanos()
+    ));
+    tokio::fs::write(&tmp, patch).await?;
+    let result = git_apply_file(&dir, &tmp, check_only).await;
+    let _ = tokio::fs::remove_file(&tmp).await;
+    result
+}
+
+async fn git_apply_file(dir: &Path, patch_file: &Path, check_only: bool) -> Result<String> {
+    let patch_arg = patch_file.to_string_lossy().to_string();
+    let numstat = Command::new("git")
+        .args(["apply", "--numstat", &patch_arg])
+        .current_dir(dir)
+        .output()
+        .await?;
+    if !numstat.status.success() {
+        anyhow::bail!(
+            "git could not read the patch: {}",
+            String::from_utf8_lossy(&numstat.stderr).trim()
+        );
+    }
+    let touched: Vec<String> = String::from_utf8_lossy(&numstat.stdout)
+        .lines()
+        .filter_map(|l| l.splitn(3, '\t').nth(2).map(str::to_string))
+        .collect();
+    let mut backups = 0;
+    for path in &touched {
+        let full = sandbox_path(&dir.join(path).to_string_lossy())?;
+        if !check_only && Path::new(&full).is_file() {
+            copy_to_backups(&full).await?;
+            backups += 1;
+        }
+    }
+
+    let mode = if check_only { "--check" } else { "--3way" };
+    let out = Command::new("git")
+        .args([
+            "apply",
+            mode,
+            "--verbose",
+            "--whitespace=nowarn",
+            &patch_arg,
+        ])
+        .current_dir(dir)
+        .output()
+        .await?;
+    let log = format!(
+        "{}{}",
+        String::from_utf8_lossy(&out.stdout),
+        String::from_utf8_lossy(&out.stderr)
+    );
+
+    // Per-file outcome from git's verbose output, in patch order
+    let mut status: Vec<(String, &str)> = touched.iter().map(|p| (p.clone(), "ok")).collect();
+    let mut set = |path: &str, state: &'static str| match status.iter_mut().find(|(p, _)| p == path)
+    {
+        Some(entry) => entry.1 = state,
+        None => status.push((path.to_string(), state)),
+    };
+    let quoted = |line: &str| line.split('\'').nth(1).map(str::to_string);
+    let mut errors = vec![];
+    for line in log.lines() {
+        if line.starts_with("Applied patch to ") {
+            if let Some(path) = quoted(line) {
+                set(
+                    &path,
+                    if line.contains("with conflicts") {
+                        "conflicted"
+                    } else {
+                        "applied"
+                    },
+                );
+            }
+        } else if let Some(path) = line.strip_prefix("U ") {
+            set(path.trim(), "conflicted");
+        } else if let Some(rest) = line.strip_prefix("error: patch failed: ") {
+            let path = rest.rsplit_once(':').map_or(rest, |(p, _)| p);
+            set(path, "failed");
+            errors.push(line.to_string());
+        } else if line == "error: while searching for:" {
+            // Followed by the context git looked for; the next errors say enough
+            continue;
+        } else if let Some(rest) = line.strip_prefix("error: ") {
+            if let Some((path, _)) = rest.split_once(": patch does not apply") {
+                set(path, "failed");
+            }
+            errors.push(line.to_string());
+        }
+    }
+    if !out.status.success() {
+        // A failed check or non-3way failure leaves nothing applied
+        for entry in status.iter_mut().filter(|(_, s)| *s == "ok") {
+            entry.1 = if check_only { "ok" } else { "not applied" };
+        }
+    } else if !check_only {
+        for entry in status.iter_mut().filter(|(_, s)| *s == "ok") {
+            entry.1 = "applied";
+        }
+    }
+
+    let mut lines: Vec<String> = status
+        .iter()
+        .map(|(path, state)| format!("{:<11} {}", state, path))
+        .collect();
+    lines.extend(errors);
+    let verdict = match (check_only, out.status.success()) {
+        (true, true) => "check passed; nothing written".to_string(),
+        (true, false) => "check failed; nothing written".to_string(),
+        (false, true) => format!(
+            "applied with git apply --3way ({} files backed up)",
+            backups
+        ),
+        (false, false) if status.iter().any(|(_, s)| *s == "conflicted") => {
+            "applied with conflicts; resolve the <<<<<<< markers in the conflicted files"
+                .to_string()
+        }
+        (false, false) => "git apply failed".to_string(),
+    };
+    lines.push(verdict);
+    Ok(lines.join("\n"))
+}
+
+/// Split a multi-file unified diff into (old path, new path, diff text) per file.
+fn split_unified_diff(patch: &str) -> Vec<(String, String, String)> {
+    let clean = |header: &str| {
+        let path = header.split('\t').next().unwrap_or("").trim();
+        path.strip_prefix("a/")
+            .or_else(|| path.strip_prefix("b/"))
+            .unwrap_or(path)
+            .to_string()
+    };
+    let lines: Vec<&str> = patch.lines().collect();
+    let mut files: Vec<(String, String, String)> = vec![];
+    let mut i = 0;
+    while i < lines.len() {
+        if let (Some(old), Some(new)) = (
+            lines[i].strip_prefix("--- "),
+            lines.get(i + 1).and_then(|l| l.strip_prefix("+++ ")),
+        ) {
+            files.push((clean(old), clean(new), String::new()));
+            i += 2;
+            continue;
+        }
+        if let Some(file) = files.last_mut() {
+            if !lines[i].starts_with("diff --git ") {
+                file.2.push_str(lines[i]);
+                file.2.push('\n');
+            }
+        }
+        i += 1;
+    }
+    files
+}
+
+/// Fallback outside git: every file must patch cleanly before any is written.
+async fn apply_unified_without_git(dir: &Path, patch: &str, check_only: bool) -> Result<String> {
+    let files = split_unified_diff(patch);
+    if files.is_empty() {
+        anyhow::bail!("patch has no ---/+++ file headers");
+    }
+    let mut writes = vec![];
+    let mut removals = vec![];
+    let mut summary = vec![];
+    for (old, new, diff) in &files {
+        if new == "/dev/null" {
+            let path = sandbox_entry_path(&dir.join(old).to_string_lossy())?;
+            summary.push(format!("D {}", path));
+            removals.push(path);
+            continue;
+        }
+        let path = sandbox_path(&dir.join(new).to_string_lossy())?;
+        let original = if old == "/dev/null" {
+            String::new()
+        } else {
+            tokio::fs::read_to_string(&path).await?
+        };
+        let (updated, detail) =
+            apply_unified_diff(&original, diff).map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        summary.push(format!("M {}: {}", path, detail));
+        writes.push((path, updated));
+    }
+    if check_only {
+        summary.push(
+            "check passed (no git repository; used the built-in patcher); nothing written".into(),
+        );
+        return Ok(summary.join("\n"));
+    }
+    for (path, content) in &writes {
+        if Path::new(path).is_file() {
+            copy_to_backups(path).await?;
+        } else if let Some(parent) = Path::new(path).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, content).await?;
+    }
+    for path in &removals {
+        move_to_backups(path).await?;
+    }
+    summary.push(format!(
+        "applied {} file change(s) with the built-in patcher (not a git repository)",
+        files.len()
+    ));
+    Ok(summary.join("\n"))
+}
+
+// Regex search and replace across a tree
+pub struct SearchReplaceTool;
+
+#[async_trait]
+impl ToolExecutor for SearchReplaceTool {
+    fn name(&self) -> &str {
+        "search_replace"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "search_replace".to_string(),
+                description: "Regex search and replace across files under a directory (respects .gitignore); dry_run returns the diff without writing".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "root": {"type": "string", "default": "."},
+                        "pattern": {"type": "string", "description": "Regex (Rust syntax)"},
+                        "replacement": {"type": "string", "description": "Replacement text; $1 or ${name} refer to capture groups"},
+                        "glob": {"type": "string", "description": "Only files matching this glob relative to root, e.g. src/**/*.rs"},
+                        "dry_run": {"type": "boolean", "default": false}
+                    },
+                    "required": ["pattern", "replacement"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let root = params.get("root").and_then(|v| v.as_str()).unwrap_or(".");
+        let root = PathBuf::from(sandbox_path(root)?);
+        let pattern = params["pattern"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing pattern"))?;
+        let re = regex::Regex::new(pattern)?;
+        let replacement = params["replacement"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing replacement"))?
+            .to_string();
+        let matcher = match params.get("glob").and_then(|v| v.as_str()) {
+            Some(glob) => Some(
+                globset::GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()?
+                    .compile_matcher(),
+            ),
+            None => None,
+        };
+        let dry_run = params
+            .get("dry_run")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+
+        // Collect rewritten contents first so nothing is written on a walk error
+        let changes = tokio::task::spawn_blocking(move || {
+            let mut changes = vec![];
+            let mut files: Vec<PathBuf> = ignore::WalkBuilder::new(&root)
+                .require_git(false)
+                .build()
+                .flatten()
+                .filter(|e| e.file_type().is_some_and(|t| t.is_file()))
+                .map(|e| e.into_path())
+                .filter(|p| match &matcher {
+                    Some(m) => p.strip_prefix(&root).is_ok_and(|rel| m.is_match(rel)),
+                    None => true,
+                })
+                .collect();
+            files.sort();
+            for path in files {
+                // Binary and non-UTF-8 files are skipped
+                let Ok(original) = std::fs::read_to_string(&path) else {
+                    continue;
+                };
+                let count = re.find_iter(&original).count();
+                if count == 0 {
+                    continue;
+                }
+                let updated = re.replace_all(&original, replacement.as_str()).into_owned();
+                if updated != original {
+                    changes.push((path, original, updated, count));
+                }
+            }
+            changes
+        })
+        .await?;
+
+        if changes.is_empty() {
+            return Ok(format!("no matches for {}", pattern));
+        }
+        let total: usize = changes.iter().map(|c| c.3).sum();
+        let mut out = vec![];
+        for (path, original, updated, count) in &changes {
+            let shown = path.display().to_string();
+            if dry_run {
+                let diff = similar::TextDiff::from_lines(original, updated);
+                out.push(
+                    diff.unified_diff()
+                        .context_radius(2)
+                        .header(&shown, &shown)
+                        .to_string(),
+                );
+            } else {
+                let backup = copy_to_backups(&shown).await?;
+                tokio::fs::write(path, updated).await?;
+                out.push(format!(
+                    "{}: {} replacement(s) (backup: {})",
+                    shown,
+                    count,
+                    backup.display()
+                ));
+            }
+        }
+        let verb = if dry_run { "would replace" } else { "replaced" };
+        out.push(format!(
+            "{} {} match(es) in {} file(s)",
+            verb,
+            total,
+            changes.len()
+        ));
+        Ok(out.join("\n"))
+    }
+}
+
+// Edit file using a unified diff or a find/replace substitution
+pub struct EditFileTool;
+
+#[async_trait]
+impl ToolExecutor for EditFileTool {
+    fn name(&self) -> &str {
+        "edit_file"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "edit_file".to_string(),
+                description:
+                    "Edit a file with a unified diff, or replace literal text via find/replace"
+                        .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string"},
+                        "diff": {"type": "string", "description": "Unified diff text with @@ hunks"},
+                        "find": {"type": "string", "description": "Literal text to replace (instead of diff)"},
+                        "replace": {"type": "string", "description": "Replacement text for find"},
+                        "occurrence": {"type": "integer", "minimum": 0, "description": "1-based match to replace, 0 for all; required when find matches more than once"}
+                    },
+                    "required": ["path"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let path = params["path"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = &sandbox_path(path)?;
+        let original = tokio::fs::read_to_string(path).await?;
+        if let Some(diff) = params.get("diff").and_then(|v| v.as_str()) {
+            let (new, summary) = apply_unified_diff(&original, diff)?;
+            tokio::fs::write(path, new).await?;
+            return Ok(format!("{}: {}", path, summary));
+        }
+        let find = params["find"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing diff (or find/replace)"))?;
+        let replace = params["replace"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing replace"))?;
+        if find.is_empty() {
+            anyhow::bail!("find must not be empty");
+        }
+        let count = original.matches(find).count();
+        let occurrence = params.get("occurrence").and_then(|v| v.as_u64());
+        let new = match occurrence {
+            _ if count == 0 => anyhow::bail!("find text not found in {}", path),
+            Some(0) => original.replace(find, replace),
+            Some(n) => {
+                let n = n as usize;
+                if n > count {
+                    anyhow::bail!("occurrence {} requested but only {} found", n, count);
+                }
+                let (idx, _) = original.match_indices(find).nth(n - 1).unwrap();
+                format!(
+                    "{}{}{}",
+                    &original[..idx],
+                    replace,
+                    &original[idx + find.len()..]
+                )
+            }
+            None if count > 1 => anyhow::bail!(
+                "find text matches {} times; pass occurrence (1-based, or 0 for all)",
+                count
+            ),
+            None => original.replacen(find, replace, 1),
+        };
+        tokio::fs::write(path, new).await?;
+        let replaced = if occurrence == Some(0) { count } else { 1 };
+        Ok(format!("{}: replaced {} occurrence(s)", path, replaced))
+    }
+}
+
+struct Hunk {
+    header: String,
+    old_start: usize,
+    lines: Vec<(u8, String)>,
+    /// Lines still owed to the old and new side by the header counts
+    old_left: usize,
+    new_left: usize,
+    /// `\ No newline at end of file` followed the hunk's last old / new line
+    old_no_newline: bool,
+    new_no_newline: bool,
+}
+
+/// Hunks of a single-file unified diff. A hunk ends once its header counts
+/// are used up; a second `---`/`+++` pair means the diff covers more files.
+fn parse_hunks(diff: &str) -> Result<Vec<Hunk>> {
+    use regex::Regex;
+    let re_hunk = Regex::new(r"^@@ -([0-9]+)(?:,([0-9]+))? \+([0-9]+)(?:,([0-9]+))? @@").unwrap();
+    let count = |m: Option<regex::Match>| m.map_or(Ok(1), |m| m.as_str().parse::<usize>());
+    let mut hunks: Vec<Hunk> = Vec::new();
+    for line in diff.lines() {
+        let n = hunks.len();
+        if let Some(hunk) = hunks.last_mut() {
+            if hunk.old_left > 0 || hunk.new_left > 0 {
+                let kind = match line.as_bytes().first().copied() {
+                    Some(c @ (b' ' | b'-' | b'+')) => c,
+                    // blank context lines often lose their leading space
+                    None => b' ',
+                    Some(b'\\') => {
+                        mark_no_newline(hunk);
+                        continue;
+                    }
+                    _ => anyhow::bail!(
+                        "hunk {} ({}) ends early: {:?} is not a diff line",
+                        n,
+                        hunk.header,
+                        line
+                    ),
+                };
+                let (old, new) = match kind {
+                    b' ' => (1, 1),
+                    b'-' => (1, 0),
+                    _ => (0, 1),
+                };
+                if hunk.old_left < old || hunk.new_left < new {
+                    anyhow::bail!(
+                        "hunk {} ({}) has more lines than its header counts",
+                        n,
+                        hunk.header
+                    );
+                }
+                hunk.old_left -= old;
+                hunk.new_left -= new;
+                hunk.lines
+                    .push((kind, line.get(1..).unwrap_or("").to_string()));
+                continue;
+            }
+            if line.starts_with('\\') {
+                mark_no_newline(hunk);
+                continue;
+            }
+            if line.starts_with("--- ") || line.starts_with("+++ ") || line.starts_with("diff ") {
+                anyhow::bail!(
+                    "diff covers more than one file; edit_file changes one file (use apply_patch for several)"
+                );
+            }
+            if line.starts_with([' ', '-', '+']) {
+                anyhow::bail!(
+                    "hunk {} ({}) has more lines than its header counts",
+                    n,
+                    hunk.header
+                );
+            }
+        }
+        if let Some(caps) = re_hunk.captures(line) {
+            hunks.push(Hunk {
+                header: caps.get(0).unwrap().as_str().to_string(),
+                old_start: caps.get(1).unwrap().as_str().parse()?,
+                lines: Vec::new(),
+                old_left: count(caps.get(2))?,
+                new_left: count(caps.get(4))?,
+                old_no_newline: false,
+                new_no_newline: false,
+            });
+        }
+        // headers (--- / +++) before the first hunk and stray text between hunks
+    }
+    if let Some(hunk) = hunks.last() {
+        if hunk.old_left > 0 || hunk.new_left > 0 {
+            anyhow::bail!(
+                "hunk {} ({}) is cut short: {} old and {} new line(s) missing",
+                hunks.len(),
+                hunk.header,
+                hunk.old_left,
+                hunk.new_left
+            );
+        }
+    } else {
+        anyhow::bail!("diff contains no @@ hunks");
+    }
+    Ok(hunks)
+}
+
+/// `\ No newline at end of file` applies to the line before it: a removed
+/// line is the old file's last, an added one the new file's, context both.
+fn mark_no_newline(hunk: &mut Hunk) {
+    match hunk.lines.last() {
+        Some((b'-', _)) => hunk.old_no_newline = true,
+        Some((b'+', _)) => hunk.new_no_newline = true,
+        Some(_) => {
+            hunk.old_no_newline = true;
+            hunk.new_no_newline = true;
+        }
+        None => {}
+    }
+}
+
+/// Apply a unified diff, tolerating hunks that moved by a few lines.
+/// Returns the new text and a short summary of what changed.
+fn apply_unified_diff(original: &str, diff: &str) -> Result<(String, String)> {
+    let lines: Vec<&str> = original.split('\n').collect();
+    let hunks = parse_hunks(diff)?;
+    let mut output: Vec<String> = Vec::new();
+    let mut src_index = 0usize; // 0-based in our buffer
+    let mut drift: isize = 0;
+    let (mut added, mut removed) = (0usize, 0usize);
+    let mut offsets = Vec::new();
+    for (n, hunk) in hunks.iter().enumerate() {
+        let old: Vec<&str> = hunk
+            .lines
+            .iter()
+            .filter(|(c, _)| *c != b'+')
+            .map(|(_, l)| l.as_str())
+            .collect();
+        let expected =
+            (hunk.old_start.saturating_sub(1) as isize + drift).max(src_index as isize) as usize;
+        let matches_at = |pos: usize| {
+            pos + old.len() <= lines.len() && old.iter().zip(&lines[pos..]).all(|(a, b)| a == b)
+        };
+        // Search outward from the expected position, never before consumed lines
+        let limit = lines.len().max(expected);
+        let found = (0..=limit).find_map(|d| {
+            [Some(expected + d), expected.checked_sub(d)]
+                .into_iter()
+                .flatten()
+                .find(|&p| p >= src_index && matches_at(p))
+        });
+        let Some(pos) = found else {
+            let (i, want) = old
+                .iter()
+                .enumerate()
+                .find(|(i, want)| lines.get(expected + i) != Some(*want))
+                .map(|(i, w)| (i, *w))
+                .unwrap_or((0, ""));
+            anyhow::bail!(
+                "hunk {} ({}) does not apply: line {} expected {:?}, found {:?}",
+                n + 1,
+                hunk.header,
+                expected + i + 1,
+                want,
+                lines.get(expected + i).copied().unwrap_or("<end of file>")
+            );
+        };
+        if pos != expected {
+            offsets.push(format!(
+                "hunk {} offset {:+}",
+                n + 1,
+                pos as isize - expected as isize
+            ));
+            drift += pos as isize - expected as isize;
+        }
+        output.extend(lines[src_index..pos].iter().map(|l| l.to_string()));
+        src_index = pos;
+        for (c, l) in &hunk.lines {
+            match c {
+                b' ' => {
+                    output.push(l.clone());
+                    src_index += 1;
+                }
+                b'-' => {
+                    removed += 1;
+                    src_index += 1;
+                }
+                _ => {
+                    added += 1;
+                    output.push(l.clone());
+                }
+            }
+        }
+    }
+    // append remainder
+    output.extend(lines[src_index..].iter().map(|l| l.to_string()));
+    // The last hunk may add or drop the newline at the end of the file
+    if let Some(last) = hunks.last() {
+        if last.new_no_newline && output.last().is_some_and(|l| l.is_empty()) {
+            output.pop();
+        } else if last.old_no_newline && !last.new_no_newline && src_index == lines.len() {
+            output.push(String::new());
+        }
+    }
+    let mut summary = format!(
+        "applied {} hunk(s), +{} -{} lines",
+        hunks.len(),
+        added,
+        removed
+    );
+    if !offsets.is_empty() {
+        summary.push_str(&format!(" ({})", offsets.join(", ")));
+    }
+    Ok((output.join("\n"), summary))
+}
+
+pub struct DeleteFileTool;
+
+#[async_trait]
+impl ToolExecutor for DeleteFileTool {
+    fn name(&self) -> &str {
+        "delete_file"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "delete_file".to_string(),
+                description:
+                    "Delete a file, or a directory with recursive=true. Nothing is unlinked: the target moves to the CLI backup store and can be restored with undelete_file"
+                        .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string", "description": "Path to delete"},
+                        "recursive": {"type": "boolean", "default": false, "description": "Required to delete a directory"}
+                    },
+                    "required": ["path"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let path = params["path"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = &sandbox_entry_path(path)?;
+        let recursive = params
+            .get("recursive")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let meta = tokio::fs::symlink_metadata(path).await?;
+        if meta.is_dir() && !recursive {
+            anyhow::bail!("{} is a directory; pass recursive=true to delete it", path);
+        }
+        let backup = move_to_backups(path).await?;
+        let kind = if meta.is_dir() { "directory" } else { "file" };
+        Ok(format!(
+            "deleted {} {} (backup: {}); restore with undelete_file or `rusty-cli undelete {}`",
+            kind,
+            path,
+            backup.display(),
+            path
+        ))
+    }
+}
+
+pub struct UndeleteFileTool;
+
+#[async_trait]
+impl ToolExecutor for UndeleteFileTool {
+    fn name(&self) -> &str {
+        "undelete_file"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "undelete_file".to_string(),
+                description:
+                    "Restore the most recently deleted copy of a path from the CLI backup store"
+                        .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": { "path": {"type": "string"} },
+                    "required": ["path"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let path = params["path"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        match restore_deleted(path).await? {
+            Some(restored) => Ok(format!("restored {}", restored)),
+            None => Ok(format!("nothing to restore for {}", path)),
+        }
+    }
+}
+
+pub struct CreateDirectoryTool;
+
+#[async_trait]
+impl ToolExecutor for CreateDirectoryTool {
+    fn name(&self) -> &str {
+        "create_directory"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "create_directory".to_string(),
+                description:
+                    "Create a directory; with recursive=true missing parents are created too"
+                        .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string", "description": "Directory to create"},
+                        "recursive": {"type": "boolean", "default": true, "description": "Also create missing parent directories"}
+                    },
+                    "required": ["path"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let path = params["path"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = sandbox_path(path)?;
+        let recursive = params
+            .get("recursive")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(true);
+        match tokio::fs::metadata(&path).await {
+            Ok(meta) if meta.is_dir() => return Ok(format!("{} already exists", path)),
+            Ok(_) => anyhow::bail!("{} exists and is not a directory", path),
+            Err(_) => {}
+        }
+        if recursive {
+            tokio::fs::create_dir_all(&path).await?;
+        } else {
+            tokio::fs::create_dir(&path).await.map_err(|e| {
+                anyhow::anyhow!("{}: {} (pass recursive=true to create parents)", path, e)
+            })?;
+        }
+        Ok(format!("created directory {}", path))
+    }
+}
+
+pub struct MovePathTool;
+
+#[async_trait]
+impl ToolExecutor for MovePathTool {
+    fn name(&self) -> &str {
+        "move_path"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "move_path".to_string(),
+                description: "Move or rename a file or directory. An existing destination is only replaced with overwrite=true, and the replaced copy goes to the undelete store"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "from": {"type": "string", "description": "Path to move"},
+                        "to": {"type": "string", "description": "New path (not a directory to move into)"},
+                        "overwrite": {"type": "boolean", "default": false, "description": "Replace an existing destination"}
+                    },
+                    "required": ["from", "to"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let (from, to) = from_to_params(&params)?;
+        let from = sandbox_entry_path(from)?;
+        let to = sandbox_entry_path(to)?;
+        let overwrite = params
+            .get("overwrite")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let meta = tokio::fs::symlink_metadata(&from).await?;
+        if meta.is_dir() && Path::new(&to).starts_with(&from) {
+            anyhow::bail!("cannot move {} into itself ({})", from, to);
+        }
+        if Path::new(&from) == Path::new(&to) {
+            return Ok(format!("{} is already at {}", from, to));
+        }
+        let replaced = replace_destination(&to, overwrite).await?;
+        if let Some(parent) = Path::new(&to).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let entries = if meta.is_dir() {
+            walkdir::WalkDir::new(&from).into_iter().count()
+        } else {
+            1
+        };
+        move_path(Path::new(&from), Path::new(&to)).await?;
+        let mut out = if meta.is_dir() {
+            format!("moved directory {} -> {} ({} entries)", from, to, entries)
+        } else {
+            format!("moved {} -> {} ({} bytes)", from, to, meta.len())
+        };
+        if let Some(backup) = replaced {
+            out.push_str(&format!(
+                "; previous {} backed up to {}",
+                to,
+                backup.display()
+            ));
+        }
+        Ok(out)
+    }
+}
+
+pub struct CopyPathTool;
+
+#[async_trait]
+impl ToolExecutor for CopyPathTool {
+    fn name(&self) -> &str {
+        "copy_path"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "copy_path".to_string(),
+                description: "Copy a file, or a directory with recursive=true. An existing destination is only replaced with overwrite=true, and the replaced copy goes to the undelete store"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "from": {"type": "string", "description": "File or directory to copy"},
+                        "to": {"type": "string", "description": "Path of the copy (not a directory to copy into)"},
+                        "recursive": {"type": "boolean", "default": false, "description": "Required to copy a directory"},
+                        "overwrite": {"type": "boolean", "default": false, "description": "Replace an existing destination"}
+                    },
+                    "required": ["from", "to"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let (from, to) = from_to_params(&params)?;
+        let from = sandbox_path(from)?;
+        let to = sandbox_entry_path(to)?;
+        let flag = |name: &str| params.get(name).and_then(|v| v.as_bool()).unwrap_or(false);
+        let meta = tokio::fs::metadata(&from).await?;
+        if meta.is_dir() && !flag("recursive") {
+            anyhow::bail!("{} is a directory; pass recursive=true to copy it", from);
+        }
+        if meta.is_dir() && Path::new(&to).starts_with(&from) {
+            anyhow::bail!("cannot copy {} into itself ({})", from, to);
+        }
+        let replaced = replace_destination(&to, flag("overwrite")).await?;
+        if let Some(parent) = Path::new(&to).parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut out = if meta.is_dir() {
+            let (src, dst) = (PathBuf::from(&from), PathBuf::from(&to));
+            let (files, bytes, skipped) =
+                tokio::task::spawn_blocking(move || copy_tree(&src, &dst)).await??;
+            let mut out = format!(
+                "copied directory {} -> {} ({} files, {} bytes)",
+                from, to, files, bytes
+            );
+            if skipped > 0 {
+                out.push_str(&format!("; skipped {} symlinks", skipped));
+            }
+            out
+        } else {
+            let bytes = tokio::fs::copy(&from, &to).await?;
+            format!("copied {} -> {} ({} bytes)", from, to, bytes)
+        };
+        if let Some(backup) = replaced {
+            out.push_str(&format!(
+                "; previous {} backed up to {}",
+                to,
+                backup.display()
+            ));
+        }
+        Ok(out)
+    }
+}
+
+fn from_to_params(params: &Value) -> Result<(&str, &str)> {
+    let from = params["from"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Missing from"))?;
+    let to = params["to"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Missing to"))?;
+    Ok((from, to))
+}
+
+/// Clear the way for a move or copy: refuse an existing `to` unless
+/// `overwrite` is set, in which case it goes to the undelete store.
+async fn replace_destination(to: &str, overwrite: bool) -> Result<Option<PathBuf>> {
+    let Ok(meta) = tokio::fs::symlink_metadata(to).await else {
+        return Ok(None);
+    };
+    if !overwrite {
+        anyhow::bail!("{} already exists; pass overwrite=true to replace it", to);
+    }
+    if meta.is_file() {
+        // Recorded as an overwrite so undelete can swap it back in
+        let backup = copy_to_backups(to).await?;
+        tokio::fs::remove_file(to).await?;
+        return Ok(Some(backup));
+    }
+    Ok(Some(move_to_backups(to).await?))
+}
+
+/// Copy a directory tree, returning (files, bytes, symlinks skipped).
+/// Symlinks are not followed so a copy cannot pull in files from outside the sandbox.
+fn copy_tree(from: &Path, to: &Path) -> Result<(usize, u64, usize)> {
+    let (mut files, mut bytes, mut skipped) = (0, 0, 0);
+    for entry in walkdir::WalkDir::new(from) {
+        let entry = entry?;
+        let target = to.join(entry.path().strip_prefix(from)?);
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_symlink() {
+            skipped += 1;
+        } else {
+            bytes += std::fs::copy(entry.path(), &target)?;
+            files += 1;
+        }
+    }
+    Ok((files, bytes, skipped))
+}
 
-#[async_trait]
-impl ToolExecutor for UndeleteFileTool {
-    fn name(&self) -> &str {
-        "undelete_file"
+/// Directory the file tools are confined to; `None` means unrestricted.
+static SANDBOX_ROOT: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Set (or clear with `None`) the sandbox root used by the file tools. It
+/// isn't checked until [`check_sandbox_root`], so commands that never build
+/// tools don't care whether it exists.
+pub fn set_sandbox_root(root: Option<&Path>) {
+    *SANDBOX_ROOT.write().unwrap() =
+        root.map(|r| std::path::absolute(r).unwrap_or_else(|_| r.to_path_buf()));
+}
+
+/// Resolve the sandbox root to its canonical path, failing when it doesn't
+/// exist. Called before tools are offered.
+pub fn check_sandbox_root() -> Result<()> {
+    let mut root = SANDBOX_ROOT.write().unwrap();
+    if let Some(r) = root.as_ref() {
+        let canonical = std::fs::canonicalize(r)
+            .map_err(|e| anyhow::anyhow!("sandbox root {}: {}", r.display(), e))?;
+        *root = Some(canonical);
     }
-    async fn execute(&self, args: &str) -> Result<String> {
-        let params: Value = serde_json::from_str(args)?;
-        let path = params["path"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
-        if let Some(backup) = SessionStore::pop_latest_deleted(path)? {
-            let parent = std::path::Path::new(path)
-                .parent()
-                .map(|p| p.to_path_buf())
-                .unwrap_or_else(|| std::path::PathBuf::from("."));
-            tokio::fs::create_dir_all(&parent).await.ok();
-            tokio::fs::copy(&backup, path).await?;
-            // remove backup file; ignore errors
-            let _ = tokio::fs::remove_file(&backup).await;
-            Ok(format!("restored {}", path))
+    Ok(())
+}
+
+#[cfg(test)]
+thread_local! {
+    /// A sandbox root for the current test only, so confining the tools
+    /// doesn't leak into tests running alongside it.
+    static TEST_SANDBOX_ROOT: std::cell::RefCell<Option<PathBuf>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+pub fn sandbox_root() -> Option<PathBuf> {
+    #[cfg(test)]
+    if let Some(root) = TEST_SANDBOX_ROOT.with(|r| r.borrow().clone()) {
+        return Some(root);
+    }
+    SANDBOX_ROOT.read().unwrap().clone()
+}
+
+/// Resolve `path` for a file tool and refuse it if it lands outside the
+/// sandbox root. Relative paths are taken from the root. Symlinks are
+/// followed; for paths that do not exist yet the nearest existing ancestor
+/// is resolved and the rest applied lexically.
+pub fn sandbox_path(path: &str) -> Result<String> {
+    let Some(root) = sandbox_root() else {
+        return Ok(path.to_string());
+    };
+    Ok(confine(path, &root)?.to_string_lossy().into_owned())
+}
+
+fn confine(path: &str, root: &Path) -> Result<PathBuf> {
+    let resolved = resolve_path(&root.join(path))?;
+    check_in_root(path, &resolved, root)?;
+    Ok(resolved)
+}
+
+/// Like [`sandbox_path`] but leaves a final symlink unresolved, for tools
+/// that act on the link itself (delete).
+pub fn sandbox_entry_path(path: &str) -> Result<String> {
+    let Some(root) = sandbox_root() else {
+        return Ok(path.to_string());
+    };
+    let absolute = root.join(path);
+    let resolved = match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(name)) => resolve_path(parent)?.join(name),
+        _ => resolve_path(&absolute)?,
+    };
+    check_in_root(path, &resolved, &root)?;
+    if resolved == root {
+        anyhow::bail!("refused: {} is the sandbox root itself", path);
+    }
+    Ok(resolved.to_string_lossy().into_owned())
+}
+
+fn resolve_path(absolute: &Path) -> Result<PathBuf> {
+    let mut absolute = absolute.to_path_buf();
+    for _ in 0..40 {
+        let existing = absolute
+            .ancestors()
+            .find(|a| a.symlink_metadata().is_ok())
+            .unwrap_or(Path::new("/"));
+        let rest = absolute.strip_prefix(existing).unwrap_or(Path::new(""));
+        let mut resolved = match std::fs::canonicalize(existing) {
+            Ok(resolved) => resolved,
+            // A dangling symlink: writing through it creates its target,
+            // so that is the path to check
+            Err(_) if existing.is_symlink() => {
+                let target = std::fs::read_link(existing)?;
+                let parent = existing.parent().unwrap_or(Path::new("/"));
+                absolute = parent.join(target).join(rest);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        for component in rest.components() {
+            match component {
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(name) => resolved.push(name),
+                _ => {}
+            }
+        }
+        return Ok(resolved);
+    }
+    anyhow::bail!("{}: too many levels of symbolic links", absolute.display())
+}
+
+fn check_in_root(requested: &str, resolved: &Path, root: &Path) -> Result<()> {
+    if !resolved.starts_with(root) {
+        anyhow::bail!(
+            "refused: {} resolves to {}, outside the sandbox root {}",
+            requested,
+            resolved.display(),
+            root.display()
+        );
+    }
+    Ok(())
+}
+
+/// Move `path` into the undelete store under a unique name and record it.
+pub async fn move_to_backups(path: &str) -> Result<std::path::PathBuf> {
+    let original = std::path::absolute(path)?;
+    let backup_path = unique_backup_path(&original).await?;
+    move_path(&original, &backup_path).await?;
+    SessionStore::record_deleted(&original.to_string_lossy(), &backup_path.to_string_lossy())?;
+    Ok(backup_path)
+}
+
+/// Copy a file into the backups dir before it is overwritten; undelete puts it back.
+pub async fn copy_to_backups(path: &str) -> Result<std::path::PathBuf> {
+    let original = std::path::absolute(path)?;
+    let backup_path = unique_backup_path(&original).await?;
+    tokio::fs::copy(&original, &backup_path).await?;
+    SessionStore::record_overwritten(&original.to_string_lossy(), &backup_path.to_string_lossy())?;
+    Ok(backup_path)
+}
+
+async fn unique_backup_path(original: &std::path::Path) -> Result<std::path::PathBuf> {
+    let backups = SessionStore::backups_dir();
+    tokio::fs::create_dir_all(&backups).await?;
+    let file_name = original
+        .file_name()
+        .and_then(|s| s.to_str())
+        .unwrap_or("file");
+    let stamp = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+    let mut backup_path = backups.join(format!("{}-{}", file_name, stamp));
+    let mut n = 1;
+    while tokio::fs::try_exists(&backup_path).await.unwrap_or(false) {
+        backup_path = backups.join(format!("{}-{}-{}", file_name, stamp, n));
+        n += 1;
+    }
+    Ok(backup_path)
+}
+
+/// Restore the most recent backup of `path`; `None` when nothing is recorded.
+/// A deleted path must still be free; content saved before an overwrite
+/// replaces the current file, which is backed up first so this can be undone.
+pub async fn restore_deleted(path: &str) -> Result<Option<String>> {
+    let original = std::path::absolute(path)?;
+    let original_s = original.to_string_lossy().into_owned();
+    let Some((backup, overwritten)) = SessionStore::pop_latest_deleted(&original_s)? else {
+        return Ok(None);
+    };
+    if tokio::fs::try_exists(&original).await.unwrap_or(false) {
+        if overwritten && tokio::fs::metadata(&original).await?.is_file() {
+            copy_to_backups(&original_s).await?;
         } else {
-            anyhow::bail!("no backup found for {}", path)
+            // Put the record back so the backup is not orphaned
+            if overwritten {
+                SessionStore::record_overwritten(&original_s, &backup)?;
+            } else {
+                SessionStore::record_deleted(&original_s, &backup)?;
+            }
+            anyhow::bail!("{} already exists; move it away before restoring", path);
         }
     }
+    if let Some(parent) = original.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    move_path(std::path::Path::new(&backup), &original).await?;
+    Ok(Some(original_s))
+}
+
+/// Rename, falling back to copy + remove when crossing filesystems.
+async fn move_path(from: &std::path::Path, to: &std::path::Path) -> Result<()> {
+    if tokio::fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+    let from = from.to_path_buf();
+    let to = to.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        if from.is_dir() {
+            for entry in walkdir::WalkDir::new(&from) {
+                let entry = entry?;
+                let target = to.join(entry.path().strip_prefix(&from)?);
+                if entry.file_type().is_dir() {
+                    std::fs::create_dir_all(&target)?;
+                } else {
+                    std::fs::copy(entry.path(), &target)?;
+                }
+            }
+            std::fs::remove_dir_all(&from)?;
+        } else {
+            std::fs::copy(&from, &to)?;
+            std::fs::remove_file(&from)?;
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    Ok(())
 }
 
 pub struct GitStatusTool;
@@ -817,14 +3302,90 @@ impl ToolExecutor for GitStatusTool {
     fn name(&self) -> &str {
         "git_status"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "git_status".to_string(),
+                description: "Show the current branch and changed files grouped as staged, unstaged, untracked and conflicted".to_string(),
+                parameters: json!({"type":"object","properties":{},"additionalProperties":false}),
+            },
+        }
+    }
     async fn execute(&self, _args: &str) -> Result<String> {
-        let out = Command::new("git")
-            .arg("status")
-            .arg("--porcelain")
-            .output()
-            .await?;
-        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+        git_status(&std::env::current_dir()?).await
+    }
+}
+
+async fn git_status(dir: &Path) -> Result<String> {
+    let out = run_git(
+        dir,
+        &["status", "--porcelain", "--branch"],
+        GIT_OUTPUT_LIMIT,
+    )
+    .await?;
+    if out.starts_with("not a git repository") {
+        return Ok(out);
+    }
+    Ok(summarize_porcelain(&out))
+}
+
+/// Group `git status --porcelain --branch` lines by what they mean.
+fn summarize_porcelain(porcelain: &str) -> String {
+    let describe = |c: char| match c {
+        'M' => "modified",
+        'A' => "added",
+        'D' => "deleted",
+        'R' => "renamed",
+        'C' => "copied",
+        'T' => "type changed",
+        _ => "changed",
+    };
+    let mut branch = String::new();
+    let (mut staged, mut unstaged, mut untracked, mut conflicted) =
+        (vec![], vec![], vec![], vec![]);
+    for line in porcelain.lines() {
+        if let Some(b) = line.strip_prefix("## ") {
+            branch = b.to_string();
+            continue;
+        }
+        let mut chars = line.chars();
+        let (Some(x), Some(y)) = (chars.next(), chars.next()) else {
+            continue;
+        };
+        let path = line.get(3..).unwrap_or("").to_string();
+        match (x, y) {
+            ('?', '?') => untracked.push(path),
+            ('!', '!') => {}
+            ('U', _) | (_, 'U') | ('A', 'A') | ('D', 'D') => conflicted.push(path),
+            _ => {
+                if x != ' ' {
+                    staged.push(format!("{} {}", describe(x), path));
+                }
+                if y != ' ' {
+                    unstaged.push(format!("{} {}", describe(y), path));
+                }
+            }
+        }
     }
+    let mut out = format!("branch: {}\n", branch);
+    for (label, items) in [
+        ("staged", &staged),
+        ("unstaged", &unstaged),
+        ("untracked", &untracked),
+        ("conflicted", &conflicted),
+    ] {
+        if !items.is_empty() {
+            out.push_str(&format!("{} ({}):\n", label, items.len()));
+            for item in items {
+                out.push_str(&format!("  {}\n", item));
+            }
+        }
+    }
+    if staged.is_empty() && unstaged.is_empty() && untracked.is_empty() && conflicted.is_empty() {
+        out.push_str("working tree clean\n");
+    }
+    out
 }
 
 pub struct GitDiffTool;
@@ -834,18 +3395,136 @@ impl ToolExecutor for GitDiffTool {
     fn name(&self) -> &str {
         "git_diff"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "git_diff".to_string(),
+                description: "Show git diff for a rev (default HEAD) and optional path".to_string(),
+                parameters: json!({"type":"object","properties":{"rev":{"type":"string"},"path":{"type":"string"},"max_bytes":{"type":"integer","default":65536}},"additionalProperties":false}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
-        let params: Value = serde_json::from_str(args)?;
-        let rev = params.get("rev").and_then(|v| v.as_str()).unwrap_or("HEAD");
-        let path = params.get("path").and_then(|v| v.as_str());
-        let mut cmd = Command::new("git");
-        cmd.arg("diff").arg(rev);
-        if let Some(p) = path {
-            cmd.arg("--").arg(p);
+        git_diff(&std::env::current_dir()?, args).await
+    }
+}
+
+async fn git_diff(dir: &Path, args: &str) -> Result<String> {
+    let params: Value = serde_json::from_str(args)?;
+    let rev = params.get("rev").and_then(|v| v.as_str()).unwrap_or("HEAD");
+    let path = params.get("path").and_then(|v| v.as_str());
+    let max = params
+        .get("max_bytes")
+        .and_then(|v| v.as_u64())
+        .map(|v| v as usize)
+        .unwrap_or(GIT_OUTPUT_LIMIT);
+    let mut git_args = vec!["diff", rev];
+    if let Some(p) = path {
+        git_args.extend(["--", p]);
+    }
+    let out = run_git(dir, &git_args, max).await?;
+    if out.is_empty() {
+        return Ok("no changes".into());
+    }
+    Ok(out)
+}
+
+pub struct GitLogTool;
+
+#[async_trait]
+impl ToolExecutor for GitLogTool {
+    fn name(&self) -> &str {
+        "git_log"
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "git_log".to_string(),
+                description: "Show recent commits (short hash, date, author, subject)".to_string(),
+                parameters: json!({"type":"object","properties":{"max_count":{"type":"integer","default":20}},"additionalProperties":false}),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        git_log(&std::env::current_dir()?, args).await
+    }
+}
+
+async fn git_log(dir: &Path, args: &str) -> Result<String> {
+    let params: Value = serde_json::from_str(args).unwrap_or(json!({}));
+    let max_count = params
+        .get("max_count")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(20);
+    let max_count = format!("--max-count={}", max_count);
+    run_git(
+        dir,
+        &[
+            "log",
+            &max_count,
+            "--date=short",
+            "--pretty=format:%h %ad %an  %s",
+        ],
+        GIT_OUTPUT_LIMIT,
+    )
+    .await
+}
+
+const GIT_OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// Run git and return its stdout without the trailing newline, failing with git's message otherwise.
+async fn git_checked(args: &[&str]) -> Result<String> {
+    let out = Command::new("git").args(args).output().await?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        if stderr.contains("not a git repository") {
+            anyhow::bail!("not a git repository (current directory is outside a git work tree)");
+        }
+        anyhow::bail!("git {} failed: {}", args[0], stderr.trim());
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim_end().to_string())
+}
+
+/// Run git in `dir` for a tool call. "Not a git repository" is returned as a
+/// normal result so the model can adjust instead of the turn aborting.
+async fn run_git(dir: &Path, args: &[&str], max_bytes: usize) -> Result<String> {
+    let out = Command::new("git")
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .await?;
+    if !out.status.success() {
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        // `git diff` says "Not a git repository" and then prints its usage
+        if stderr.to_lowercase().contains("not a git repository") {
+            return Ok(
+                "not a git repository (current directory is outside a git work tree)".into(),
+            );
         }
-        let out = cmd.output().await?;
-        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+        anyhow::bail!(stderr.trim().to_string());
+    }
+    Ok(truncate_output(
+        &String::from_utf8_lossy(&out.stdout),
+        max_bytes,
+    ))
+}
+
+/// Cut `text` to at most `max_bytes` on a char boundary, noting what was dropped.
+fn truncate_output(text: &str, max_bytes: usize) -> String {
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut cut = max_bytes;
+    while !text.is_char_boundary(cut) {
+        cut -= 1;
     }
+    format!(
+        "{}\n[... truncated {} bytes ...]",
+        &text[..cut],
+        text.len() - cut
+    )
 }
 
 pub struct GitApplyTool;
@@ -855,6 +3534,19 @@ impl ToolExecutor for GitApplyTool {
     fn name(&self) -> &str {
         "git_apply"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "git_apply".to_string(),
+                description: "Apply a unified diff via git".to_string(),
+                parameters: json!({"type":"object","properties":{"diff":{"type":"string"}},"required":["diff"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let diff = params["diff"]
@@ -880,38 +3572,612 @@ impl ToolExecutor for GitApplyTool {
     }
 }
 
-pub struct HttpGetTool;
-
-#[async_trait]
-impl ToolExecutor for HttpGetTool {
-    fn name(&self) -> &str {
-        "http_get"
+pub struct HttpGetTool;
+
+#[async_trait]
+impl ToolExecutor for HttpGetTool {
+    fn name(&self) -> &str {
+        "http_get"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Network
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "http_get".to_string(),
+                description:
+                    "Fetch an http(s) URL; returns status, content-type and body (limited)"
+                        .to_string(),
+                parameters: json!({"type":"object","properties":{"url":{"type":"string"},"max_bytes":{"type":"integer","default":65536},"timeout_secs":{"type":"integer","default":30},"as_text":{"type":"boolean","default":false,"description":"Strip HTML tags from HTML responses"}},"required":["url"]}),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let url = params["url"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing url"))?;
+        let max = params
+            .get("max_bytes")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(64 * 1024) as usize;
+        let timeout = params
+            .get("timeout_secs")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(30);
+        let as_text = params
+            .get("as_text")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        let resp = guarded_get(url, std::time::Duration::from_secs(timeout)).await?;
+        let status = resp.status();
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_string();
+        let content_length = resp.content_length();
+        let (body, truncated) = read_limited(resp, max).await?;
+        let mut text = String::from_utf8_lossy(&body).into_owned();
+        if as_text && content_type.contains("html") {
+            text = html_to_text(&text);
+        }
+        let mut out = format!("status: {}\ncontent-type: {}\n", status, content_type);
+        if truncated {
+            let total = content_length
+                .map(|n| n.to_string())
+                .unwrap_or_else(|| "unknown".into());
+            out.push_str(&format!(
+                "[body truncated to {} bytes; content-length: {}]\n",
+                max, total
+            ));
+        }
+        out.push('\n');
+        out.push_str(&text);
+        Ok(out)
+    }
+}
+
+const MAX_REDIRECTS: usize = 5;
+
+/// GET a URL, following up to five redirects and re-checking every hop so the
+/// model can't reach cloud metadata or link-local services.
+/// Where web_search sends queries.
+enum SearchBackend {
+    Brave { api_key: String },
+    Searxng { url: String },
+}
+
+pub struct WebSearchTool {
+    backend: SearchBackend,
+    default_results: usize,
+}
+
+/// Longest result list returned to the model, in bytes of JSON.
+const SEARCH_MAX_BYTES: usize = 8 * 1024;
+
+impl WebSearchTool {
+    /// Build from `[tools.web_search]`; `None` when no usable backend is configured.
+    pub fn from_config(cfg: &crate::config::WebSearchConfig) -> Result<Option<Self>> {
+        let backend = match cfg.backend.as_deref() {
+            None => return Ok(None),
+            Some("brave") => {
+                let var = cfg.api_key_env.as_deref().unwrap_or("BRAVE_API_KEY");
+                match std::env::var(var) {
+                    Ok(api_key) if !api_key.is_empty() => SearchBackend::Brave { api_key },
+                    _ => anyhow::bail!("web_search backend brave needs ${} set", var),
+                }
+            }
+            Some("searxng") => match &cfg.url {
+                Some(url) => SearchBackend::Searxng {
+                    url: url.trim_end_matches('/').to_string(),
+                },
+                None => anyhow::bail!("web_search backend searxng needs url set"),
+            },
+            Some(other) => anyhow::bail!(
+                "unknown web_search backend {:?} (expected brave or searxng)",
+                other
+            ),
+        };
+        Ok(Some(Self {
+            backend,
+            default_results: cfg.max_results.unwrap_or(5),
+        }))
+    }
+
+    /// (title, url, snippet) for each hit, in backend order.
+    async fn query(&self, query: &str, count: usize) -> Result<Vec<(String, String, String)>> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(20))
+            .build()?;
+        let field = |v: &Value, key: &str| v[key].as_str().unwrap_or("").to_string();
+        match &self.backend {
+            SearchBackend::Brave { api_key } => {
+                let body: Value = client
+                    .get("https://api.search.brave.com/res/v1/web/search")
+                    .query(&[("q", query), ("count", &count.min(20).to_string())])
+                    .header("Accept", "application/json")
+                    .header("X-Subscription-Token", api_key)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                Ok(body["web"]["results"]
+                    .as_array()
+                    .map(|hits| {
+                        hits.iter()
+                            .map(|h| (field(h, "title"), field(h, "url"), field(h, "description")))
+                            .collect()
+                    })
+                    .unwrap_or_default())
+            }
+            SearchBackend::Searxng { url } => {
+                let body: Value = client
+                    .get(format!("{}/search", url))
+                    .query(&[("q", query), ("format", "json")])
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await?;
+                Ok(body["results"]
+                    .as_array()
+                    .map(|hits| {
+                        hits.iter()
+                            .map(|h| (field(h, "title"), field(h, "url"), field(h, "content")))
+                            .collect()
+                    })
+                    .unwrap_or_default())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for WebSearchTool {
+    fn name(&self) -> &str {
+        "web_search"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Network
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "web_search".to_string(),
+                description: "Search the web; returns JSON [{title, url, snippet}]. Follow up with http_get to read a page".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "query": {"type": "string"},
+                        "max_results": {"type": "integer", "default": self.default_results}
+                    },
+                    "required": ["query"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let query = params["query"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing query"))?;
+        let max_results = params
+            .get("max_results")
+            .and_then(|v| v.as_u64())
+            .map(|n| n as usize)
+            .unwrap_or(self.default_results)
+            .max(1);
+        let hits = self.query(query, max_results).await?;
+
+        // The same page often shows up with different query strings or fragments
+        let mut seen = std::collections::HashSet::new();
+        let mut results = vec![];
+        let mut size = 2;
+        for (title, url, snippet) in hits {
+            let key = match reqwest::Url::parse(&url) {
+                Ok(u) => format!(
+                    "{}{}",
+                    u.host_str().unwrap_or("").trim_start_matches("www."),
+                    u.path().trim_end_matches('/')
+                ),
+                Err(_) => url.clone(),
+            };
+            if !seen.insert(key) {
+                continue;
+            }
+            let snippet: String = html_to_text(&snippet).trim().chars().take(300).collect();
+            let entry = json!({"title": title, "url": url, "snippet": snippet});
+            size += entry.to_string().len() + 1;
+            if size > SEARCH_MAX_BYTES || results.len() >= max_results {
+                break;
+            }
+            results.push(entry);
+        }
+        if results.is_empty() {
+            return Ok(format!("no results for {:?}", query));
+        }
+        Ok(Value::Array(results).to_string())
+    }
+}
+
+/// GET `url`, following redirects by hand so every hop is checked. Each hop's
+/// connection goes to the addresses that passed the check, so a DNS answer
+/// that changes between the check and the request can't reach a blocked one.
+async fn guarded_get(url: &str, timeout: std::time::Duration) -> Result<reqwest::Response> {
+    let mut url = reqwest::Url::parse(url)?;
+    for _ in 0..=MAX_REDIRECTS {
+        let addrs = check_url_allowed(&url).await?;
+        let mut builder = reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent(concat!("rusty-cli/", env!("CARGO_PKG_VERSION")))
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some(domain) = url.domain() {
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+        let resp = builder.build()?.get(url.clone()).send().await?;
+        if !resp.status().is_redirection() {
+            return Ok(resp);
+        }
+        let location = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| anyhow::anyhow!("redirect without Location header"))?;
+        url = url.join(location)?;
+    }
+    anyhow::bail!("too many redirects (limit {})", MAX_REDIRECTS)
+}
+
+/// Resolve `url`'s host and refuse it if any address is blocked; returns the
+/// addresses checked, for the request to connect to.
+async fn check_url_allowed(url: &reqwest::Url) -> Result<Vec<std::net::SocketAddr>> {
+    if !matches!(url.scheme(), "http" | "https") {
+        anyhow::bail!(
+            "refusing {} URL: only http and https are allowed",
+            url.scheme()
+        );
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("URL has no host"))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<std::net::SocketAddr> = tokio::net::lookup_host((host, port)).await?.collect();
+    for addr in &addrs {
+        if is_blocked_ip(addr.ip()) {
+            anyhow::bail!(
+                "refusing {}: resolves to link-local/metadata address {}",
+                host,
+                addr.ip()
+            );
+        }
+    }
+    Ok(addrs)
+}
+
+fn is_blocked_ip(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(v4) => v4.is_link_local() || v4.is_unspecified(),
+        std::net::IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_blocked_ip(std::net::IpAddr::V4(v4));
+            }
+            // fe80::/10 link-local and the AWS IPv6 metadata endpoint
+            (v6.segments()[0] & 0xffc0) == 0xfe80
+                || v6 == std::net::Ipv6Addr::new(0xfd00, 0xec2, 0, 0, 0, 0, 0, 0x254)
+                || v6.is_unspecified()
+        }
+    }
+}
+
+/// Read at most `max` bytes of a response body; the flag reports truncation.
+async fn read_limited(resp: reqwest::Response, max: usize) -> Result<(Vec<u8>, bool)> {
+    use futures_util::TryStreamExt;
+    let mut stream = resp.bytes_stream();
+    let mut out = Vec::new();
+    while let Some(chunk) = stream.try_next().await? {
+        let n = std::cmp::min(max - out.len(), chunk.len());
+        out.extend_from_slice(&chunk[..n]);
+        if n < chunk.len() {
+            return Ok((out, true));
+        }
+        if out.len() >= max {
+            let more = stream.try_next().await?.is_some_and(|c| !c.is_empty());
+            return Ok((out, more));
+        }
+    }
+    Ok((out, false))
+}
+
+/// Crude HTML to text: drop scripts, styles, page chrome and tags, decode common entities,
+/// and collapse whitespace.
+fn html_to_text(html: &str) -> String {
+    use regex::Regex;
+    let blocks =
+        Regex::new(r"(?is)<(script|style|noscript|head|nav|header|footer|aside|svg)\b.*?</(script|style|noscript|head|nav|header|footer|aside|svg)>")
+            .unwrap();
+    let breaks = Regex::new(r"(?i)<(br|/p|/div|/li|/h[1-6]|/tr)\b[^>]*>").unwrap();
+    let tags = Regex::new(r"(?s)<[^>]*>").unwrap();
+    let text = blocks.replace_all(html, " ");
+    let text = breaks.replace_all(&text, "\n");
+    let text = tags.replace_all(&text, " ");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    let mut out = String::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !line.is_empty() {
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// Fetch a page and keep only its readable content
+pub struct FetchPageTool;
+
+/// Elements that never hold the main text.
+const PAGE_CHROME: &[&str] = &[
+    "script", "style", "noscript", "nav", "header", "footer", "aside", "form", "svg", "iframe",
+    "button", "template",
+];
+
+/// Elements that start a new line in the extracted text.
+const PAGE_BLOCKS: &[&str] = &[
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "ul",
+    "ol",
+    "table",
+    "tr",
+    "blockquote",
+    "figure",
+    "dl",
+    "dt",
+    "dd",
+];
+
+#[async_trait]
+impl ToolExecutor for FetchPageTool {
+    fn name(&self) -> &str {
+        "fetch_page"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Network
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "fetch_page".to_string(),
+                description: "Fetch a web page and return its title, canonical URL and main content as markdown-ish text with [links](url); prefer this over http_get for reading pages".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "url": {"type": "string"},
+                        "max_bytes": {"type": "integer", "default": 32768, "description": "Budget for the extracted text"},
+                        "format": {"type": "string", "enum": ["markdown", "text"], "default": "markdown", "description": "text drops headings and link targets"}
+                    },
+                    "required": ["url"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let url = params["url"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing url"))?;
+        let max = params
+            .get("max_bytes")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(32 * 1024) as usize;
+        let plain = params.get("format").and_then(|v| v.as_str()) == Some("text");
+        let resp = guarded_get(url, std::time::Duration::from_secs(30)).await?;
+        let status = resp.status();
+        let final_url = resp.url().clone();
+        let content_type = resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_lowercase();
+        // Pages carry a lot of markup, so read well past the text budget
+        let (body, _) = read_limited(resp, 4 * 1024 * 1024).await?;
+        let mut out = format!("status: {}\n", status);
+
+        let is_html = content_type.contains("html") || content_type.is_empty();
+        let is_text = content_type.starts_with("text/")
+            || content_type.contains("json")
+            || content_type.contains("xml");
+        if !is_html && !is_text {
+            out.push_str(&format!(
+                "binary content skipped ({}, {} bytes)",
+                content_type,
+                body.len()
+            ));
+            return Ok(out);
+        }
+        let raw = String::from_utf8_lossy(&body);
+        if !is_html {
+            out.push_str(&format!("content-type: {}\n\n", content_type));
+            out.push_str(&truncate_output(&raw, max));
+            return Ok(out);
+        }
+
+        let page = extract_page(&raw, &final_url, plain);
+        out.push_str(&format!("title: {}\nurl: {}\n\n", page.0, page.1));
+        if page.2.trim().is_empty() {
+            out.push_str(&format!(
+                "no readable content extracted (status {})",
+                status
+            ));
+        } else {
+            out.push_str(&truncate_output(&page.2, max));
+        }
+        Ok(out)
+    }
+}
+
+/// Title, canonical URL and readable text of an HTML page; `plain` drops the
+/// markdown (headings, link targets).
+fn extract_page(html: &str, base: &reqwest::Url, plain: bool) -> (String, String, String) {
+    use scraper::{Html, Selector};
+    let doc = Html::parse_document(html);
+    let select = |css: &str| Selector::parse(css).unwrap();
+    let title = doc
+        .select(&select("title"))
+        .next()
+        .map(|t| t.text().collect::<String>())
+        .or_else(|| {
+            doc.select(&select(r#"meta[property="og:title"]"#))
+                .next()
+                .and_then(|m| m.value().attr("content").map(str::to_string))
+        })
+        .map(|t| t.split_whitespace().collect::<Vec<_>>().join(" "))
+        .unwrap_or_default();
+    let canonical = doc
+        .select(&select(r#"link[rel="canonical"]"#))
+        .next()
+        .and_then(|l| l.value().attr("href"))
+        .and_then(|href| base.join(href).ok())
+        .unwrap_or_else(|| base.clone());
+
+    // The candidate container with the most text is taken as the main content
+    let text_len = |el: &scraper::ElementRef| el.text().map(|t| t.trim().len()).sum::<usize>();
+    let main = doc
+        .select(&select(
+            r#"article, main, [role="main"], #content, #main, .content, .post, .entry-content"#,
+        ))
+        .max_by_key(text_len)
+        .or_else(|| doc.select(&select("body")).next())
+        .unwrap_or_else(|| doc.root_element());
+
+    if plain {
+        return (title, canonical.to_string(), html_to_text(&main.html()));
+    }
+    let mut text = String::new();
+    render_page_node(main, base, &mut text, false);
+    // Tidy: trim line ends and keep at most one blank line in a row
+    let mut tidy = String::new();
+    let mut blank = 0;
+    for line in text.lines() {
+        let line = line.trim_end();
+        if line.trim().is_empty() {
+            blank += 1;
+            if blank > 1 {
+                continue;
+            }
+            tidy.push('\n');
+        } else {
+            blank = 0;
+            tidy.push_str(line);
+            tidy.push('\n');
+        }
+    }
+    (title, canonical.to_string(), tidy.trim().to_string())
+}
+
+fn render_page_node(el: scraper::ElementRef, base: &reqwest::Url, out: &mut String, pre: bool) {
+    use scraper::node::Node;
+    let name = el.value().name();
+    if PAGE_CHROME.contains(&name) {
+        return;
     }
-    async fn execute(&self, args: &str) -> Result<String> {
-        let params: Value = serde_json::from_str(args)?;
-        let url = params["url"]
-            .as_str()
-            .ok_or_else(|| anyhow::anyhow!("Missing url"))?;
-        let max = params
-            .get("max_bytes")
-            .and_then(|v| v.as_u64())
-            .unwrap_or(256 * 1024) as usize;
-        let client = reqwest::Client::new();
-        let resp = client.get(url).send().await?;
-        let mut stream = resp.bytes_stream();
-        let mut out = Vec::new();
-        use futures_util::TryStreamExt;
-        while let Some(chunk) = stream.try_next().await? {
-            if out.len() >= max {
-                break;
+    let children = |out: &mut String, pre: bool| {
+        for child in el.children() {
+            match child.value() {
+                Node::Text(t) if pre => out.push_str(t),
+                Node::Text(t) => {
+                    let collapsed = t.split_whitespace().collect::<Vec<_>>().join(" ");
+                    if collapsed.is_empty() {
+                        if !t.is_empty() && !out.ends_with([' ', '\n']) {
+                            out.push(' ');
+                        }
+                        continue;
+                    }
+                    if t.starts_with(char::is_whitespace) && !out.ends_with([' ', '\n']) {
+                        out.push(' ');
+                    }
+                    out.push_str(&collapsed);
+                    if t.ends_with(char::is_whitespace) {
+                        out.push(' ');
+                    }
+                }
+                Node::Element(_) => {
+                    if let Some(child) = scraper::ElementRef::wrap(child) {
+                        render_page_node(child, base, out, pre);
+                    }
+                }
+                _ => {}
             }
-            let n = std::cmp::min(max - out.len(), chunk.len());
-            out.extend_from_slice(&chunk[..n]);
-            if out.len() >= max {
-                break;
+        }
+    };
+    match name {
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let level = name[1..].parse::<usize>().unwrap_or(1);
+            let mut inner = String::new();
+            children(&mut inner, false);
+            out.push_str(&format!("\n\n{} {}\n\n", "#".repeat(level), inner.trim()));
+        }
+        "a" => {
+            let mut inner = String::new();
+            children(&mut inner, pre);
+            let label = inner.trim();
+            let href = el
+                .value()
+                .attr("href")
+                .filter(|h| !h.starts_with('#') && !h.starts_with("javascript:"))
+                .and_then(|h| base.join(h).ok());
+            match href {
+                Some(href) if !label.is_empty() => {
+                    if inner.starts_with(' ') && !out.ends_with([' ', '\n']) {
+                        out.push(' ');
+                    }
+                    out.push_str(&format!("[{}]({})", label, href));
+                    if inner.ends_with(' ') {
+                        out.push(' ');
+                    }
+                }
+                _ => out.push_str(&inner),
             }
         }
-        Ok(String::from_utf8_lossy(&out).into_owned())
+        "li" => {
+            out.push_str("\n- ");
+            children(out, pre);
+        }
+        "br" => out.push('\n'),
+        "pre" => {
+            out.push_str("\n\n```\n");
+            children(out, true);
+            out.push_str("\n```\n\n");
+        }
+        "code" if !pre => {
+            out.push('`');
+            children(out, false);
+            out.push('`');
+        }
+        _ if PAGE_BLOCKS.contains(&name) => {
+            out.push_str("\n\n");
+            children(out, pre);
+            out.push_str("\n\n");
+        }
+        _ => children(out, pre),
     }
 }
 
@@ -922,6 +4188,20 @@ impl ToolExecutor for HttpPostTool {
     fn name(&self) -> &str {
         "http_post"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Network
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "http_post".to_string(),
+                description: "POST to a URL (text/JSON), return response text (limited)"
+                    .to_string(),
+                parameters: json!({"type":"object","properties":{"url":{"type":"string"},"body":{"type":"string"},"content_type":{"type":"string","default":"application/json"},"max_bytes":{"type":"integer","default":262144}},"required":["url","body"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let url = params["url"]
@@ -962,6 +4242,196 @@ impl ToolExecutor for HttpPostTool {
     }
 }
 
+// System clipboard access, only registered when `[tools] clipboard = true`
+pub struct ClipboardTool;
+
+#[async_trait]
+impl ToolExecutor for ClipboardTool {
+    fn name(&self) -> &str {
+        "clipboard"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "clipboard".to_string(),
+                description: "Read the system clipboard (get) or replace its text (set)"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "action": {"type": "string", "enum": ["get", "set"]},
+                        "text": {"type": "string", "description": "Text to copy for set"},
+                        "max_chars": {"type": "integer", "default": 16000}
+                    },
+                    "required": ["action"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let action = params["action"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing action"))?
+            .to_string();
+        let text = params
+            .get("text")
+            .and_then(|v| v.as_str())
+            .map(str::to_string);
+        let max_chars = params
+            .get("max_chars")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(16000) as usize;
+        // arboard talks to the display server synchronously
+        tokio::task::spawn_blocking(move || {
+            let mut clipboard = match arboard::Clipboard::new() {
+                Ok(cb) => cb,
+                Err(e) => return Ok(format!("clipboard unavailable: {}", e)),
+            };
+            match action.as_str() {
+                "get" => match clipboard.get_text() {
+                    Ok(text) => {
+                        let total = text.chars().count();
+                        let mut out: String = text.chars().take(max_chars).collect();
+                        if total > max_chars {
+                            out.push_str(&format!("\n[... {} more chars]", total - max_chars));
+                        }
+                        Ok(out)
+                    }
+                    Err(arboard::Error::ContentNotAvailable) => {
+                        Ok("clipboard is empty or holds no text".to_string())
+                    }
+                    Err(e) => Ok(format!("clipboard unavailable: {}", e)),
+                },
+                "set" => {
+                    let text = text.ok_or_else(|| anyhow::anyhow!("Missing text for set"))?;
+                    match clipboard.set_text(text.clone()) {
+                        Ok(()) => Ok(format!("copied {} chars", text.chars().count())),
+                        Err(e) => Ok(format!("clipboard unavailable: {}", e)),
+                    }
+                }
+                other => anyhow::bail!("unknown action {:?} (expected get or set)", other),
+            }
+        })
+        .await?
+    }
+}
+
+/// Largest image `read_image` will return, whatever the model asks for.
+const IMAGE_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+pub struct ReadImageTool;
+
+#[async_trait]
+impl ToolExecutor for ReadImageTool {
+    fn name(&self) -> &str {
+        "read_image"
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "read_image".to_string(),
+                description: "Look at a png, jpeg, gif or webp file. The image is attached for you to view (downscaled if larger than max_bytes); models without vision get its size and dimensions only"
+                    .to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string"},
+                        "max_bytes": {"type": "integer", "default": 1048576, "description": "Downscale above this size (ceiling 4 MiB)"}
+                    },
+                    "required": ["path"]
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args)?;
+        let path = params["path"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = sandbox_path(path)?;
+        let max_bytes = params
+            .get("max_bytes")
+            .and_then(|v| v.as_u64())
+            .map(|n| n as usize)
+            .unwrap_or(1024 * 1024)
+            .clamp(16 * 1024, IMAGE_MAX_BYTES);
+        let bytes = tokio::fs::read(&path).await?;
+        let Some(mime) = image_mime(&bytes) else {
+            anyhow::bail!("{} is not a png, jpeg, gif or webp image", path);
+        };
+        let original_len = bytes.len();
+        let (mime, bytes, width, height, resized) =
+            tokio::task::spawn_blocking(move || fit_image(bytes, mime, max_bytes)).await??;
+        use base64::Engine;
+        let data_url = format!(
+            "data:{};base64,{}",
+            mime,
+            base64::engine::general_purpose::STANDARD.encode(&bytes)
+        );
+        Ok(json!({
+            "path": path,
+            "mime": mime,
+            "width": width,
+            "height": height,
+            "bytes": bytes.len(),
+            "original_bytes": original_len,
+            "resized": resized,
+            "data_url": data_url,
+        })
+        .to_string())
+    }
+}
+
+/// Image type from magic bytes, not the file name.
+fn image_mime(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Keep the image as is when it fits `max_bytes`, otherwise shrink it as a
+/// JPEG until it does. Returns (mime, bytes, width, height, resized).
+fn fit_image(
+    bytes: Vec<u8>,
+    mime: &'static str,
+    max_bytes: usize,
+) -> Result<(&'static str, Vec<u8>, u32, u32, bool)> {
+    let img = image::load_from_memory(&bytes)?;
+    let (width, height) = (img.width(), img.height());
+    if bytes.len() <= max_bytes {
+        return Ok((mime, bytes, width, height, false));
+    }
+    let rgb = image::DynamicImage::ImageRgb8(img.to_rgb8());
+    let mut scale = 1.0_f64;
+    loop {
+        let w = ((width as f64 * scale) as u32).max(1);
+        let h = ((height as f64 * scale) as u32).max(1);
+        let small = rgb.resize(w, h, image::imageops::FilterType::Triangle);
+        let mut out = std::io::Cursor::new(Vec::new());
+        small.write_to(&mut out, image::ImageFormat::Jpeg)?;
+        let out = out.into_inner();
+        if out.len() <= max_bytes || (w <= 64 && h <= 64) {
+            return Ok(("image/jpeg", out, small.width(), small.height(), true));
+        }
+        // Encoded size tracks pixel count, so aim by area with some margin
+        scale *= ((max_bytes as f64 / out.len() as f64).sqrt() * 0.9).min(0.9);
+    }
+}
+
 pub struct FileInfoTool;
 
 #[async_trait]
@@ -969,11 +4439,22 @@ impl ToolExecutor for FileInfoTool {
     fn name(&self) -> &str {
         "file_info"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "file_info".to_string(),
+                description: "Get file info (size, mtime, type)".to_string(),
+                parameters: json!({"type":"object","properties":{"path":{"type":"string"}},"required":["path"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params["path"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = &sandbox_path(path)?;
         let meta = tokio::fs::metadata(path).await?;
         let size = meta.len();
         let is_dir = meta.is_dir();
@@ -988,11 +4469,22 @@ impl ToolExecutor for ChecksumTool {
     fn name(&self) -> &str {
         "checksum"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "checksum".to_string(),
+                description: "SHA256 checksum of a file".to_string(),
+                parameters: json!({"type":"object","properties":{"path":{"type":"string"}},"required":["path"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params["path"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = &sandbox_path(path)?;
         let data = tokio::fs::read(path).await?;
         let mut hasher = Sha256::new();
         hasher.update(&data);
@@ -1008,11 +4500,22 @@ impl ToolExecutor for JsonQueryTool {
     fn name(&self) -> &str {
         "json_query"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "json_query".to_string(),
+                description: "Query JSON file via JSON Pointer".to_string(),
+                parameters: json!({"type":"object","properties":{"path":{"type":"string"},"pointer":{"type":"string"}},"required":["path","pointer"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params["path"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = &sandbox_path(path)?;
         let pointer = params["pointer"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing pointer"))?;
@@ -1030,11 +4533,22 @@ impl ToolExecutor for YamlQueryTool {
     fn name(&self) -> &str {
         "yaml_query"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "yaml_query".to_string(),
+                description: "Query YAML file via JSON Pointer".to_string(),
+                parameters: json!({"type":"object","properties":{"path":{"type":"string"},"pointer":{"type":"string"}},"required":["path","pointer"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params["path"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = &sandbox_path(path)?;
         let pointer = params["pointer"]
             .as_str()
             .ok_or_else(|| anyhow::anyhow!("Missing pointer"))?;
@@ -1046,6 +4560,302 @@ impl ToolExecutor for YamlQueryTool {
     }
 }
 
+// Cargo runs that return filtered diagnostics instead of raw output
+pub struct CargoCheckTool;
+pub struct CargoTestTool;
+
+/// Diagnostics listed before the rest are only counted.
+const CARGO_MAX_DIAGNOSTICS: usize = 40;
+
+/// Common `{package, features, timeout_secs}` arguments as cargo flags.
+fn cargo_selection(params: &Value) -> (Vec<String>, std::time::Duration) {
+    let mut flags = vec![];
+    if let Some(pkg) = params.get("package").and_then(|v| v.as_str()) {
+        flags.push("-p".to_string());
+        flags.push(pkg.to_string());
+    }
+    let features = match params.get("features") {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(a)) => a
+            .iter()
+            .filter_map(|f| f.as_str())
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => String::new(),
+    };
+    if !features.is_empty() {
+        flags.push("--features".to_string());
+        flags.push(features);
+    }
+    let timeout = params
+        .get("timeout_secs")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(600);
+    (flags, std::time::Duration::from_secs(timeout))
+}
+
+/// Run cargo in the sandbox root; `None` when it was killed for taking too long.
+async fn run_cargo_json(
+    args: &[String],
+    timeout: std::time::Duration,
+) -> Result<Option<std::process::Output>> {
+    let mut cmd = Command::new("cargo");
+    cmd.args(args)
+        .env("CARGO_TERM_COLOR", "never")
+        // Panic backtraces would crowd out the assertion messages
+        .env("RUST_BACKTRACE", "0")
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true);
+    if let Some(root) = sandbox_root() {
+        cmd.current_dir(root);
+    }
+    #[cfg(unix)]
+    cmd.process_group(0);
+    let child = cmd.spawn()?;
+    let mut group = KillGroupOnDrop(child.id());
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(out) => {
+            group.0 = None;
+            Ok(Some(out?))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Compiler errors and warnings from `--message-format=json` lines, as
+/// `level[code] file:line:col: message`, plus counts per level.
+fn cargo_diagnostics(stdout: &str) -> (Vec<String>, usize, usize) {
+    let mut seen = std::collections::HashSet::new();
+    let mut lines = vec![];
+    let (mut errors, mut warnings) = (0, 0);
+    for line in stdout.lines().filter(|l| l.starts_with('{')) {
+        let Ok(v) = serde_json::from_str::<Value>(line) else {
+            continue;
+        };
+        if v["reason"] != "compiler-message" {
+            continue;
+        }
+        let msg = &v["message"];
+        let level = msg["level"].as_str().unwrap_or("");
+        if !matches!(level, "error" | "warning") {
+            continue;
+        }
+        let text = msg["message"].as_str().unwrap_or("");
+        // The "aborting due to" / "N warnings emitted" summaries carry no location
+        let span = msg["spans"]
+            .as_array()
+            .and_then(|s| s.iter().find(|s| s["is_primary"] == true).or(s.first()));
+        let Some(span) = span else {
+            continue;
+        };
+        let code = msg["code"]["code"]
+            .as_str()
+            .map(|c| format!("[{}]", c))
+            .unwrap_or_default();
+        let entry = format!(
+            "{}{} {}:{}:{}: {}",
+            level,
+            code,
+            span["file_name"].as_str().unwrap_or("?"),
+            span["line_start"],
+            span["column_start"],
+            text
+        );
+        if !seen.insert(entry.clone()) {
+            continue;
+        }
+        if level == "error" {
+            errors += 1;
+        } else {
+            warnings += 1;
+        }
+        lines.push(entry);
+    }
+    // Errors first; they are what the model needs to fix
+    lines.sort_by_key(|l| !l.starts_with("error"));
+    (lines, errors, warnings)
+}
+
+fn push_diagnostics(out: &mut String, diagnostics: &[String]) {
+    for d in diagnostics.iter().take(CARGO_MAX_DIAGNOSTICS) {
+        out.push_str(d);
+        out.push('\n');
+    }
+    if diagnostics.len() > CARGO_MAX_DIAGNOSTICS {
+        out.push_str(&format!(
+            "... {} more diagnostics\n",
+            diagnostics.len() - CARGO_MAX_DIAGNOSTICS
+        ));
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for CargoCheckTool {
+    fn name(&self) -> &str {
+        "cargo_check"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Shell
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "cargo_check".to_string(),
+                description: "Run cargo check (all targets) in the project root and return errors and warnings as file:line:col: message".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "package": {"type": "string"},
+                        "features": {"type": "string", "description": "Comma-separated features"},
+                        "timeout_secs": {"type": "integer", "default": 600}
+                    }
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = if args.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(args)?
+        };
+        let (flags, timeout) = cargo_selection(&params);
+        let mut cargo_args: Vec<String> = ["check", "--all-targets", "--message-format=json"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        cargo_args.extend(flags);
+        let Some(out) = run_cargo_json(&cargo_args, timeout).await? else {
+            return Ok(format!(
+                "cargo check timed out after {}s and was killed",
+                timeout.as_secs()
+            ));
+        };
+        let (diagnostics, errors, warnings) =
+            cargo_diagnostics(&String::from_utf8_lossy(&out.stdout));
+        let verdict = if out.status.success() { "ok" } else { "failed" };
+        let mut result = format!(
+            "cargo check {} ({} errors, {} warnings)\n",
+            verdict, errors, warnings
+        );
+        push_diagnostics(&mut result, &diagnostics);
+        if !out.status.success() && errors == 0 {
+            // Failures before compiling (bad manifest, unknown package) only show on stderr
+            result.push_str(&truncate_output(
+                &String::from_utf8_lossy(&out.stderr),
+                4096,
+            ));
+        }
+        Ok(result)
+    }
+}
+
+#[async_trait]
+impl ToolExecutor for CargoTestTool {
+    fn name(&self) -> &str {
+        "cargo_test"
+    }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Shell
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "cargo_test".to_string(),
+                description: "Run cargo test in the project root; returns compile errors, failing tests with their output, and the result lines".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "package": {"type": "string"},
+                        "test_name": {"type": "string", "description": "Only run tests whose name contains this"},
+                        "features": {"type": "string", "description": "Comma-separated features"},
+                        "timeout_secs": {"type": "integer", "default": 600}
+                    }
+                }),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = if args.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(args)?
+        };
+        let (flags, timeout) = cargo_selection(&params);
+        let mut cargo_args: Vec<String> = vec!["test".into(), "--message-format=json".into()];
+        cargo_args.extend(flags);
+        if let Some(name) = params.get("test_name").and_then(|v| v.as_str()) {
+            cargo_args.push(name.to_string());
+        }
+        let Some(out) = run_cargo_json(&cargo_args, timeout).await? else {
+            return Ok(format!(
+                "cargo test timed out after {}s and was killed",
+                timeout.as_secs()
+            ));
+        };
+        let stdout = String::from_utf8_lossy(&out.stdout);
+        let (diagnostics, errors, _) = cargo_diagnostics(&stdout);
+        let verdict = if out.status.success() { "ok" } else { "failed" };
+        let mut result = format!("cargo test {}\n", verdict);
+        if errors > 0 {
+            result.push_str(&format!("{} compile errors:\n", errors));
+            let errors: Vec<String> = diagnostics
+                .into_iter()
+                .filter(|d| d.starts_with("error"))
+                .collect();
+            push_diagnostics(&mut result, &errors);
+            return Ok(result);
+        }
+
+        // libtest prints plain text between cargo's JSON lines
+        let mut failed = vec![];
+        let mut details = String::new();
+        let mut summaries = vec![];
+        let mut in_failure = false;
+        for line in stdout.lines().filter(|l| !l.starts_with('{')) {
+            if line.starts_with("test ") && line.ends_with(" FAILED") {
+                failed.push(line["test ".len()..line.len() - " ... FAILED".len()].to_string());
+            } else if line.starts_with("test result:") {
+                summaries.push(line.to_string());
+                in_failure = false;
+            } else if line.starts_with("---- ") {
+                in_failure = true;
+                details.push_str(line);
+                details.push('\n');
+            } else if line == "failures:" {
+                // Opens the output section, and later the list of names we already have
+                in_failure = false;
+            } else if in_failure {
+                details.push_str(line);
+                details.push('\n');
+            }
+        }
+        if !failed.is_empty() {
+            result.push_str(&format!("failed tests ({}):\n", failed.len()));
+            for name in &failed {
+                result.push_str(&format!("  {}\n", name));
+            }
+            result.push_str(&truncate_output(details.trim_end(), 12 * 1024));
+            result.push('\n');
+        }
+        for summary in &summaries {
+            result.push_str(summary);
+            result.push('\n');
+        }
+        if summaries.is_empty() && !out.status.success() {
+            result.push_str(&truncate_output(
+                &String::from_utf8_lossy(&out.stderr),
+                4096,
+            ));
+        }
+        Ok(result)
+    }
+}
+
 pub struct CargoTool;
 
 #[async_trait]
@@ -1053,6 +4863,19 @@ impl ToolExecutor for CargoTool {
     fn name(&self) -> &str {
         "run_cargo"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Shell
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "run_cargo".to_string(),
+                description: "Run cargo with args".to_string(),
+                parameters: json!({"type":"object","properties":{"args":{"type":"string"}},"required":["args"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let args = params["args"]
@@ -1076,6 +4899,19 @@ impl ToolExecutor for GitCommitTool {
     fn name(&self) -> &str {
         "git_commit"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "git_commit".to_string(),
+                description: "Stage the given paths (or everything with add_all) and commit; returns the new commit SHA".to_string(),
+                parameters: json!({"type":"object","properties":{"message":{"type":"string"},"paths":{"type":"array","items":{"type":"string"},"description":"Files to stage before committing"},"add_all":{"type":"boolean","default":false}},"required":["message"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let message = params["message"]
@@ -1085,22 +4921,28 @@ impl ToolExecutor for GitCommitTool {
             .get("add_all")
             .and_then(|v| v.as_bool())
             .unwrap_or(false);
+        let paths: Vec<String> = params
+            .get("paths")
+            .and_then(|v| v.as_array())
+            .map(|a| {
+                a.iter()
+                    .filter_map(|p| p.as_str().map(sandbox_path))
+                    .collect::<Result<_>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
         if add_all {
-            let out = Command::new("git").arg("add").arg("-A").output().await?;
-            if !out.status.success() {
-                anyhow::bail!(String::from_utf8_lossy(&out.stderr).to_string());
-            }
+            git_checked(&["add", "-A"]).await?;
         }
-        let out = Command::new("git")
-            .arg("commit")
-            .arg("-m")
-            .arg(message)
-            .output()
-            .await?;
-        if !out.status.success() {
-            anyhow::bail!(String::from_utf8_lossy(&out.stderr).to_string());
+        if !paths.is_empty() {
+            let mut add = vec!["add", "--"];
+            add.extend(paths.iter().map(String::as_str));
+            git_checked(&add).await?;
         }
-        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+        git_checked(&["commit", "-m", message]).await?;
+        let sha = git_checked(&["rev-parse", "HEAD"]).await?;
+        let stat = git_checked(&["show", "--stat", "--format=", "HEAD"]).await?;
+        Ok(format!("committed {}\n{}", sha, stat))
     }
 }
 
@@ -1111,6 +4953,19 @@ impl ToolExecutor for GitBranchTool {
     fn name(&self) -> &str {
         "git_branch"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "git_branch".to_string(),
+                description: "branch ops: current|list|switch|create".to_string(),
+                parameters: json!({"type":"object","properties":{"action":{"type":"string"},"name":{"type":"string"}},"required":["action"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let action = params["action"]
@@ -1122,7 +4977,7 @@ impl ToolExecutor for GitBranchTool {
                     .args(["rev-parse", "--abbrev-ref", "HEAD"])
                     .output()
                     .await?;
-                Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+                Ok(String::from_utf8_lossy(&out.stdout).trim_end().to_string())
             }
             "list" => {
                 let out = Command::new("git")
@@ -1169,6 +5024,19 @@ impl ToolExecutor for FormatRustTool {
     fn name(&self) -> &str {
         "format_rust"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "format_rust".to_string(),
+                description: "Format Rust code (cargo fmt or path)".to_string(),
+                parameters: json!({"type":"object","properties":{"path":{"type":"string"}},"additionalProperties":false}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let path = params.get("path").and_then(|v| v.as_str());
@@ -1192,6 +5060,19 @@ impl ToolExecutor for ZipTool {
     fn name(&self) -> &str {
         "zip"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "zip".to_string(),
+                description: "Create a zip archive from a directory or file".to_string(),
+                parameters: json!({"type":"object","properties":{"input":{"type":"string"},"output":{"type":"string"}},"required":["input","output"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let input = params["input"]
@@ -1244,6 +5125,19 @@ impl ToolExecutor for UnzipTool {
     fn name(&self) -> &str {
         "unzip"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "unzip".to_string(),
+                description: "Extract a zip archive to a directory".to_string(),
+                parameters: json!({"type":"object","properties":{"archive":{"type":"string"},"output_dir":{"type":"string"}},"required":["archive","output_dir"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let archive = params["archive"]
@@ -1269,32 +5163,235 @@ impl ToolExecutor for UnzipTool {
                 if let Some(parent) = outpath.parent() {
                     std::fs::create_dir_all(parent)?;
                 }
-                let mut outfile = std::fs::File::create(&outpath)?;
-                std::io::copy(&mut f, &mut outfile)?;
+                let mut outfile = std::fs::File::create(&outpath)?;
+                std::io::copy(&mut f, &mut outfile)?;
+            }
+            anyhow::Ok(())
+        })
+        .await??;
+        Ok(format!("extracted to {}", out_report))
+    }
+}
+
+pub struct ListDeletedTool;
+
+#[async_trait]
+impl ToolExecutor for ListDeletedTool {
+    fn name(&self) -> &str {
+        "list_deleted"
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "list_deleted".to_string(),
+                description: "List recently deleted or overwritten files recorded by the CLI"
+                    .to_string(),
+                parameters: json!({"type":"object","properties":{"limit":{"type":"integer","default":50}},"additionalProperties":false}),
+            },
+        }
+    }
+    async fn execute(&self, args: &str) -> Result<String> {
+        let params: Value = serde_json::from_str(args).unwrap_or(json!({}));
+        let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
+        let rows = SessionStore::list_deleted(limit)?;
+        let mut out = String::new();
+        for (path, ts, kind) in rows {
+            out.push_str(&format!("{}\t{}\t{}\n", ts, kind, path));
+        }
+        Ok(out)
+    }
+}
+
+// Read-only queries against any SQLite file in the sandbox
+pub struct SqliteQueryTool;
+
+/// Reject anything but one SELECT/WITH/PRAGMA statement. `;` inside quotes or
+/// comments doesn't count; a trailing `;` is allowed.
+fn single_read_statement(sql: &str) -> Result<&str> {
+    let bytes = sql.as_bytes();
+    let mut i = 0;
+    let mut end = sql.len();
+    while i < bytes.len() {
+        match bytes[i] {
+            q @ (b'\'' | b'"' | b'`') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != q {
+                    i += 1;
+                }
+            }
+            b'[' => {
+                while i < bytes.len() && bytes[i] != b']' {
+                    i += 1;
+                }
+            }
+            b'-' if bytes.get(i + 1) == Some(&b'-') => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i += 2;
+                while i + 1 < bytes.len() && !(bytes[i] == b'*' && bytes[i + 1] == b'/') {
+                    i += 1;
+                }
+                i += 1;
+            }
+            b';' => {
+                end = i;
+                let rest = sql[i + 1..].trim();
+                if !rest.is_empty() && !rest.chars().all(|c| c == ';') {
+                    anyhow::bail!("only a single statement is allowed");
+                }
+                break;
             }
-            anyhow::Ok(())
-        })
-        .await??;
-        Ok(format!("extracted to {}", out_report))
+            _ => {}
+        }
+        i += 1;
     }
+    let stmt = sql[..end].trim();
+    let keyword = stmt
+        .split(|c: char| !c.is_ascii_alphabetic())
+        .next()
+        .unwrap_or("")
+        .to_lowercase();
+    if !matches!(keyword.as_str(), "select" | "with" | "pragma") {
+        anyhow::bail!("only SELECT, WITH and PRAGMA statements are allowed");
+    }
+    Ok(stmt)
 }
 
-pub struct ListDeletedTool;
+/// Rows as space-aligned columns under a header line.
+fn format_table(columns: &[String], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = columns.iter().map(|c| c.chars().count()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let line = |cells: &[String]| {
+        cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<w$}", c, w = *w))
+            .collect::<Vec<_>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    let mut out = vec![line(columns)];
+    out.push(
+        widths
+            .iter()
+            .map(|w| "-".repeat(*w))
+            .collect::<Vec<_>>()
+            .join("  "),
+    );
+    out.extend(rows.iter().map(|r| line(r)));
+    out.join("\n")
+}
 
 #[async_trait]
-impl ToolExecutor for ListDeletedTool {
+impl ToolExecutor for SqliteQueryTool {
     fn name(&self) -> &str {
-        "list_deleted"
+        "sqlite_query"
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "sqlite_query".to_string(),
+                description: "Run one read-only SELECT/WITH/PRAGMA statement against a SQLite database file; returns headers and rows".to_string(),
+                parameters: json!({
+                    "type": "object",
+                    "properties": {
+                        "path": {"type": "string", "description": "SQLite database file"},
+                        "query": {"type": "string"},
+                        "max_rows": {"type": "integer", "default": 100},
+                        "format": {"type": "string", "enum": ["table", "json"], "default": "table"}
+                    },
+                    "required": ["path", "query"]
+                }),
+            },
+        }
     }
     async fn execute(&self, args: &str) -> Result<String> {
-        let params: Value = serde_json::from_str(args).unwrap_or(json!({}));
-        let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(50) as usize;
-        let rows = SessionStore::list_deleted(limit)?;
-        let mut out = String::new();
-        for (path, ts) in rows {
-            out.push_str(&format!("{}\t{}\n", ts, path));
+        let params: Value = serde_json::from_str(args)?;
+        let path = params["path"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing path"))?;
+        let path = sandbox_path(path)?;
+        let query = params["query"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("Missing query"))?;
+        let query = single_read_statement(query)?.to_string();
+        let max_rows = params
+            .get("max_rows")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(100) as usize;
+        let json_out = params.get("format").and_then(|v| v.as_str()) == Some("json");
+        if !Path::new(&path).is_file() {
+            anyhow::bail!("{} is not a file", path);
         }
-        Ok(out)
+        tokio::task::spawn_blocking(move || {
+            let conn = rusqlite::Connection::open_with_flags(
+                &path,
+                rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+            )?;
+            let mut stmt = conn.prepare(&query)?;
+            // Catches writes the keyword check can't see, e.g. PRAGMA user_version = 3
+            if !stmt.readonly() {
+                anyhow::bail!("statement would modify the database");
+            }
+            let columns: Vec<String> = stmt.column_names().iter().map(|s| s.to_string()).collect();
+            let mut rows = stmt.query([])?;
+            let mut values: Vec<Vec<Value>> = vec![];
+            let mut more = false;
+            while let Some(row) = rows.next()? {
+                if values.len() == max_rows {
+                    more = true;
+                    break;
+                }
+                values.push(
+                    (0..columns.len())
+                        .map(|i| sql_value_to_json(row, i))
+                        .collect(),
+                );
+            }
+            let note = if more {
+                format!("\n[stopped at max_rows={}; more rows exist]", max_rows)
+            } else {
+                format!("\n({} rows)", values.len())
+            };
+            if json_out {
+                let objects: Vec<Value> = values
+                    .into_iter()
+                    .map(|row| Value::Object(columns.iter().cloned().zip(row).collect()))
+                    .collect();
+                return Ok(format!("{}{}", Value::Array(objects), note));
+            }
+            let cells: Vec<Vec<String>> = values
+                .iter()
+                .map(|row| {
+                    row.iter()
+                        .map(|v| {
+                            let text = match v {
+                                Value::String(s) => s.replace('\n', "\\n"),
+                                Value::Null => "NULL".to_string(),
+                                other => other.to_string(),
+                            };
+                            if text.chars().count() > 60 {
+                                format!("{}...", text.chars().take(57).collect::<String>())
+                            } else {
+                                text
+                            }
+                        })
+                        .collect()
+                })
+                .collect();
+            Ok(format!("{}{}", format_table(&columns, &cells), note))
+        })
+        .await?
     }
 }
 
@@ -1305,6 +5402,16 @@ impl ToolExecutor for DbQueryTool {
     fn name(&self) -> &str {
         "db_query"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "db_query".to_string(),
+                description: "Run a read-only SQL query against Rusty CLI DB".to_string(),
+                parameters: json!({"type":"object","properties":{"sql":{"type":"string"},"params":{"type":"array","items":{"type":["string","number","boolean","null"]}}},"required":["sql"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let sql = params["sql"]
@@ -1382,6 +5489,19 @@ impl ToolExecutor for AddNoteTool {
     fn name(&self) -> &str {
         "add_note"
     }
+    fn access(&self) -> ToolAccess {
+        ToolAccess::Write
+    }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "add_note".to_string(),
+                description: "Add a note to the Rusty CLI DB".to_string(),
+                parameters: json!({"type":"object","properties":{"title":{"type":"string"},"content":{"type":"string"},"tags":{"type":"string"}},"required":["content"]}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args)?;
         let content = params["content"]
@@ -1409,6 +5529,16 @@ impl ToolExecutor for ListNotesTool {
     fn name(&self) -> &str {
         "list_notes"
     }
+    fn definition(&self) -> Tool {
+        Tool {
+            r#type: "function".to_string(),
+            function: Function {
+                name: "list_notes".to_string(),
+                description: "List recent notes (optionally filter by search)".to_string(),
+                parameters: json!({"type":"object","properties":{"search":{"type":"string"},"limit":{"type":"integer","default":50}},"additionalProperties":false}),
+            },
+        }
+    }
     async fn execute(&self, args: &str) -> Result<String> {
         let params: Value = serde_json::from_str(args).unwrap_or(json!({}));
         let limit = params.get("limit").and_then(|v| v.as_u64()).unwrap_or(50) as i64;
@@ -1449,3 +5579,1066 @@ impl ToolExecutor for ListNotesTool {
         Ok(serde_json::Value::Array(out).to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::test_support::{isolate_data_dir, scratch_dir};
+
+    const FRUIT: &str = "apple\nbanana\ncherry\ndate\n";
+
+    #[test]
+    fn unified_diff_applies_cleanly() {
+        let diff =
+            "--- a/fruit\n+++ b/fruit\n@@ -1,3 +1,3 @@\n apple\n-banana\n+blueberry\n cherry\n";
+        let (new, summary) = apply_unified_diff(FRUIT, diff).unwrap();
+        assert_eq!(new, "apple\nblueberry\ncherry\ndate\n");
+        assert_eq!(summary, "applied 1 hunk(s), +1 -1 lines");
+    }
+
+    #[test]
+    fn unified_diff_applies_at_an_offset() {
+        let original = format!("intro\nmore\n{}", FRUIT);
+        let diff = "@@ -2,2 +2,2 @@\n banana\n-cherry\n+coconut\n";
+        let (new, summary) = apply_unified_diff(&original, diff).unwrap();
+        assert_eq!(new, "intro\nmore\napple\nbanana\ncoconut\ndate\n");
+        assert!(summary.ends_with("(hunk 1 offset +2)"), "{}", summary);
+    }
+
+    #[test]
+    fn unified_diff_rejects_a_hunk_that_does_not_match() {
+        let diff = "@@ -2,1 +2,1 @@\n-blackberry\n+blueberry\n";
+        let err = apply_unified_diff(FRUIT, diff).unwrap_err().to_string();
+        assert!(
+            err.contains("hunk 1 (@@ -2,1 +2,1 @@) does not apply"),
+            "{}",
+            err
+        );
+        assert!(err.contains("\"blackberry\""), "{}", err);
+    }
+
+    #[test]
+    fn unified_diff_refuses_several_files() {
+        let diff = "--- a/fruit\n+++ b/fruit\n@@ -1 +1 @@\n-apple\n+apricot\n\
+                    --- a/veg\n+++ b/veg\n@@ -1 +1 @@\n-leek\n+kale\n";
+        let err = apply_unified_diff(FRUIT, diff).unwrap_err().to_string();
+        assert!(err.contains("more than one file"), "{}", err);
+    }
+
+    #[test]
+    fn unified_diff_stops_a_hunk_at_its_header_counts() {
+        let diff = "@@ -1 +1 @@\n-apple\n+apricot\n-banana\n";
+        let err = apply_unified_diff(FRUIT, diff).unwrap_err().to_string();
+        assert!(err.contains("more lines than its header counts"), "{}", err);
+    }
+
+    #[test]
+    fn unified_diff_follows_no_newline_markers() {
+        // Adds the missing final newline
+        let diff = "@@ -2 +2 @@\n-b\n\\ No newline at end of file\n+b\n";
+        assert_eq!(apply_unified_diff("a\nb", diff).unwrap().0, "a\nb\n");
+        // Drops it
+        let diff = "@@ -2 +2 @@\n-b\n+b\n\\ No newline at end of file\n";
+        assert_eq!(apply_unified_diff("a\nb\n", diff).unwrap().0, "a\nb");
+        // Context on a file that never had one
+        let diff = "@@ -1,2 +1,2 @@\n-a\n+z\n b\n\\ No newline at end of file\n";
+        assert_eq!(apply_unified_diff("a\nb", diff).unwrap().0, "z\nb");
+    }
+
+    /// Answer every HTTP request on a local port with `respond(path)`.
+    async fn serve(respond: fn(&str) -> String) -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; 4096];
+                let n = stream.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]).into_owned();
+                let path = request.split_whitespace().nth(1).unwrap_or("/").to_string();
+                let _ = stream.write_all(respond(&path).as_bytes()).await;
+            }
+        });
+        port
+    }
+
+    fn redirect_to(location: &str) -> String {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            location
+        )
+    }
+
+    #[tokio::test]
+    async fn guarded_get_follows_redirects_by_name() {
+        let port = serve(|path| match path {
+            "/start" => redirect_to("/done"),
+            _ => "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok".into(),
+        })
+        .await;
+        let url = format!("http://localhost:{}/start", port);
+        let resp = guarded_get(&url, std::time::Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(resp.url().path(), "/done");
+        assert_eq!(resp.text().await.unwrap(), "ok");
+    }
+
+    #[tokio::test]
+    async fn guarded_get_refuses_a_redirect_to_the_metadata_address() {
+        let port = serve(|_| redirect_to("http://169.254.169.254/latest/meta-data/")).await;
+        let url = format!("http://127.0.0.1:{}/", port);
+        let err = guarded_get(&url, std::time::Duration::from_secs(5))
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("refusing 169.254.169.254"), "{}", err);
+    }
+
+    #[test]
+    fn sandbox_paths_are_relative_to_the_root_not_the_cwd() {
+        let root = std::fs::canonicalize(scratch_dir("sandbox")).unwrap();
+        assert_ne!(std::env::current_dir().unwrap(), root);
+        assert_eq!(
+            confine("notes/todo.md", &root).unwrap(),
+            root.join("notes/todo.md")
+        );
+        assert_eq!(
+            confine(&root.join("a.txt").to_string_lossy(), &root).unwrap(),
+            root.join("a.txt")
+        );
+        let err = confine("../escape.txt", &root).unwrap_err().to_string();
+        assert!(err.contains("outside the sandbox root"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_sandbox_are_refused() {
+        use std::os::unix::fs::symlink;
+        let root = std::fs::canonicalize(scratch_dir("sandbox")).unwrap();
+        let outside = std::fs::canonicalize(scratch_dir("outside")).unwrap();
+        std::fs::write(outside.join("secret.txt"), "secret").unwrap();
+        symlink(outside.join("secret.txt"), root.join("file-link")).unwrap();
+        symlink(&outside, root.join("dir-link")).unwrap();
+        symlink(outside.join("new.txt"), root.join("dangling-link")).unwrap();
+        std::fs::write(root.join("inside.txt"), "").unwrap();
+        symlink(root.join("inside.txt"), root.join("inside-link")).unwrap();
+
+        // Reading an existing file through a link
+        for path in ["file-link", "dir-link/secret.txt"] {
+            let err = confine(path, &root).unwrap_err().to_string();
+            assert!(
+                err.contains("outside the sandbox root"),
+                "{}: {}",
+                path,
+                err
+            );
+        }
+        // Writing a file that doesn't exist yet through a link
+        for path in ["dir-link/new.txt", "dir-link/sub/new.txt", "dangling-link"] {
+            let err = confine(path, &root).unwrap_err().to_string();
+            assert!(
+                err.contains("outside the sandbox root"),
+                "{}: {}",
+                path,
+                err
+            );
+        }
+        // Links that stay inside are fine
+        assert_eq!(
+            confine("inside-link", &root).unwrap(),
+            root.join("inside.txt")
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_then_undelete_restores_the_file() {
+        isolate_data_dir();
+        let path = scratch_dir("undelete").join("notes.txt");
+        std::fs::write(&path, "keep me").unwrap();
+        let args = json!({ "path": path }).to_string();
+
+        let out = DeleteFileTool.execute(&args).await.unwrap();
+        assert!(out.starts_with("deleted file"), "{}", out);
+        assert!(!path.exists());
+
+        let out = UndeleteFileTool.execute(&args).await.unwrap();
+        assert!(out.starts_with("restored"), "{}", out);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+    }
+
+    #[tokio::test]
+    async fn write_file_creates_refuses_overwrites_and_appends() {
+        isolate_data_dir();
+        let path = scratch_dir("write-file").join("notes.txt");
+        let write = |extra: Value| {
+            let mut args = json!({ "path": path, "content": "second\n" });
+            args.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            async move { FileWriteTool.execute(&args.to_string()).await.unwrap() }
+        };
+
+        std::fs::write(&path, "first\n").unwrap();
+        let out = write(json!({})).await;
+        assert!(out.starts_with("refused:"), "{}", out);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\n");
+
+        let out = write(json!({ "append": true })).await;
+        assert_eq!(out, "ok: 7 bytes");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+
+        let out = write(json!({ "overwrite": true })).await;
+        assert!(out.contains("previous content saved to"), "{}", out);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n");
+
+        let fresh = path.with_file_name("fresh.txt");
+        let out = FileWriteTool
+            .execute(&json!({ "path": fresh, "content": "new" }).to_string())
+            .await
+            .unwrap();
+        assert_eq!(out, "ok: 3 bytes");
+        assert_eq!(std::fs::read_to_string(&fresh).unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn overwritten_content_survives_a_later_delete() {
+        isolate_data_dir();
+        let path = scratch_dir("write-file").join("config.txt");
+        std::fs::write(&path, "old").unwrap();
+        let path_s = path.to_string_lossy();
+        let args = json!({ "path": path });
+
+        let overwrite = json!({ "path": path, "content": "new", "overwrite": true });
+        FileWriteTool.execute(&overwrite.to_string()).await.unwrap();
+        DeleteFileTool.execute(&args.to_string()).await.unwrap();
+
+        // The deletion comes back first, then the content from before the overwrite
+        restore_deleted(&path_s).await.unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        restore_deleted(&path_s).await.unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+
+        // Restoring over a file backs it up, so undelete undoes itself
+        restore_deleted(&path_s).await.unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn undelete_refuses_to_replace_a_recreated_file() {
+        isolate_data_dir();
+        let path = scratch_dir("undelete").join("notes.txt");
+        std::fs::write(&path, "deleted").unwrap();
+        let args = json!({ "path": path }).to_string();
+        DeleteFileTool.execute(&args).await.unwrap();
+        std::fs::write(&path, "recreated").unwrap();
+
+        let err = UndeleteFileTool.execute(&args).await.unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "recreated");
+        std::fs::remove_file(&path).unwrap();
+        UndeleteFileTool.execute(&args).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "deleted");
+    }
+
+    #[tokio::test]
+    async fn undelete_without_a_backup_has_nothing_to_restore() {
+        isolate_data_dir();
+        let path = scratch_dir("undelete").join("never-deleted.txt");
+        let path = path.to_string_lossy();
+        assert_eq!(restore_deleted(&path).await.unwrap(), None);
+
+        let out = UndeleteFileTool
+            .execute(&json!({ "path": path }).to_string())
+            .await
+            .unwrap();
+        assert_eq!(out, format!("nothing to restore for {}", path));
+    }
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    #[tokio::test]
+    async fn run_captured_kills_a_command_that_overruns() {
+        let started = std::time::Instant::now();
+        let (out, status) = run_captured(
+            sh("echo started; sleep 30"),
+            std::time::Duration::from_secs(1),
+            1000,
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(
+            status,
+            CommandStatus::TimedOut(std::time::Duration::from_secs(1))
+        );
+        assert!(out.contains("timed out after 1s"), "{}", out);
+        assert!(out.contains("started"), "{}", out);
+    }
+
+    #[tokio::test]
+    async fn run_captured_keeps_the_ends_of_long_output() {
+        let (out, status) = run_captured(
+            sh("echo first; yes middle | head -n 100000; echo last"),
+            std::time::Duration::from_secs(30),
+            200,
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, CommandStatus::Exited(0));
+        assert!(out.contains("exit code: 0"), "{}", out);
+        assert!(out.contains("first") && out.contains("last"), "{}", out);
+        assert!(out.contains("truncated"), "{}", out);
+        assert!(out.len() < 1000, "{}", out);
+    }
+
+    #[tokio::test]
+    async fn run_captured_reaps_what_the_shell_left_running() {
+        let dir = scratch_dir("background");
+        let pid_file = dir.join("pid");
+        let started = std::time::Instant::now();
+        let (_, status) = run_captured(
+            sh(&format!("sleep 100 & echo $! > {}", pid_file.display())),
+            std::time::Duration::from_secs(30),
+            1000,
+        )
+        .await
+        .unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(10));
+        assert_eq!(status, CommandStatus::Exited(0));
+        let pid: u32 = std::fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        // Killed and reaped by init once the group is signalled
+        assert!(exits(pid), "sleep {} is still running", pid);
+    }
+
+    #[tokio::test]
+    async fn outcomes_come_from_the_command_status_not_the_output() {
+        isolate_data_dir();
+        let registry = ToolRegistry::new();
+
+        let outcome = registry
+            .run(
+                "shell",
+                &json!({ "command": "echo partial; exit 3" }).to_string(),
+            )
+            .await;
+        assert!(!outcome.ok);
+        assert_eq!(outcome.meta["exit_code"], 3);
+        assert_eq!(outcome.error.as_deref(), Some("command exited with code 3"));
+        assert!(outcome.output.contains("partial"), "{}", outcome.output);
+
+        // Output that merely looks like a status line is just output
+        let path = scratch_dir("status").join("log.txt");
+        std::fs::write(&path, "exit code: 1\ntimed out after 5s\n").unwrap();
+        let outcome = registry
+            .run("read_file", &json!({ "path": path }).to_string())
+            .await;
+        assert!(outcome.ok, "{:?}", outcome.error);
+        assert!(!outcome.meta.contains_key("exit_code"));
+        assert!(!outcome.meta.contains_key("timed_out"));
+    }
+
+    /// Whether process `pid` is gone (killed and reaped) within five seconds.
+    fn exits(pid: u32) -> bool {
+        (0..50).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            !std::path::Path::new(&format!("/proc/{}", pid)).exists()
+        })
+    }
+
+    fn pid_of(reply: &str) -> u32 {
+        let rest = reply.split("(pid ").nth(1).unwrap();
+        rest[..rest.find(')').unwrap()].parse().unwrap()
+    }
+
+    // One test, since kill_processes reaches every table in the process
+    #[tokio::test(flavor = "multi_thread")]
+    async fn background_processes_live_until_killed() {
+        let table = std::sync::Arc::new(ProcessTable::default());
+        let start = ProcessStartTool(table.clone());
+        let logs = ProcessLogsTool(table.clone());
+        let list = ProcessListTool(table.clone());
+        let kill = ProcessKillTool(table.clone());
+
+        let reply = start
+            .execute(r#"{"command": "sleep 100", "name": "nap"}"#)
+            .await
+            .unwrap();
+        assert!(reply.contains(": running;"), "{}", reply);
+        let sleeper = pid_of(&reply);
+        let listed = list.execute("{}").await.unwrap();
+        assert!(
+            listed.contains("1 nap pid=") && listed.contains("sleep 100"),
+            "{}",
+            listed
+        );
+        let reply = kill.execute(r#"{"id": "nap"}"#).await.unwrap();
+        assert!(
+            reply.starts_with("sent SIGTERM to process 1; now exited"),
+            "{}",
+            reply
+        );
+        assert!(exits(sleeper));
+        let reply = kill.execute(r#"{"id": 1}"#).await.unwrap();
+        assert_eq!(reply, "process 1 has already exited");
+
+        // Output is kept to the last PROCESS_LOG_LINES lines
+        let yes = pid_of(&start.execute(r#"{"command": "yes"}"#).await.unwrap());
+        let reply = logs.execute(r#"{"id": 2, "tail_lines": 2}"#).await.unwrap();
+        assert!(
+            reply.starts_with(&format!(
+                "process 2 (running), {} lines of output, showing the last 2\ny\ny\n",
+                PROCESS_LOG_LINES
+            )),
+            "{}",
+            reply
+        );
+        kill_processes();
+        assert!(exits(yes));
+
+        // Dropping the table kills what is left
+        let sleeper = pid_of(&start.execute(r#"{"command": "sleep 100"}"#).await.unwrap());
+        drop((start, logs, list, kill, table));
+        assert!(exits(sleeper));
+        assert!(LIVE_GROUPS.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn registered_names_match_their_definitions() {
+        isolate_data_dir();
+        let mut registry = ToolRegistry::new();
+        // Off by default
+        registry.register(Box::new(ClipboardTool));
+        let search = crate::config::WebSearchConfig {
+            backend: Some("searxng".into()),
+            url: Some("http://localhost:8080".into()),
+            ..Default::default()
+        };
+        registry.register(Box::new(
+            WebSearchTool::from_config(&search).unwrap().unwrap(),
+        ));
+        assert!(registry.tools.len() > 40);
+        for (name, tool) in &registry.tools {
+            assert_eq!(name, &tool.definition().function.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn shell_command_tools_quote_values() {
+        let dir = scratch_dir("command-tool");
+        let spec: crate::config::CustomTool = toml::from_str(
+            r#"
+            name = "greet"
+            description = "say hi"
+            command = "echo hi {name}"
+            shell = true
+            parameters = { type = "object", properties = { name = { type = "string" } } }
+            "#,
+        )
+        .unwrap();
+        let tool = CommandTool::new(spec);
+        let marker = dir.join("ran");
+        let name = format!(
+            "$(touch {}) `touch {}`; x",
+            marker.display(),
+            marker.display()
+        );
+        let out = tool
+            .execute(&json!({ "name": name }).to_string())
+            .await
+            .unwrap();
+        assert!(out.contains(&format!("hi {}", name)), "{}", out);
+        assert!(!marker.exists());
+
+        let err = tool
+            .execute(&json!({ "name": "a\0b" }).to_string())
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "value for {name} contains a NUL byte");
+    }
+
+    fn file_schema() -> ArgumentSchema {
+        ArgumentSchema::new(&json!({
+            "type": "object",
+            "properties": {
+                "path": {"type": "string"},
+                "max_lines": {"type": "integer"}
+            },
+            "required": ["path"]
+        }))
+    }
+
+    #[test]
+    fn check_arguments_accepts_valid_arguments() {
+        let schema = file_schema();
+        assert_eq!(
+            check_arguments("read", &schema, r#"{"path": "a.txt", "max_lines": 3}"#),
+            Ok(None)
+        );
+        assert_eq!(
+            check_arguments("read", &schema, r#"{"path": "a.txt", "lines": 3}"#),
+            Ok(Some("ignored unknown arguments: lines".to_string()))
+        );
+    }
+
+    #[test]
+    fn check_arguments_reports_a_missing_required_field() {
+        let err = check_arguments("read", &file_schema(), r#"{"max_lines": 3}"#).unwrap_err();
+        assert!(err.starts_with("invalid arguments for read:"), "{}", err);
+        assert!(err.contains("- missing required field \"path\""), "{}", err);
+    }
+
+    #[test]
+    fn check_arguments_reports_a_wrong_type() {
+        let err = check_arguments("read", &file_schema(), r#"{"path": 7, "max_lines": "3"}"#)
+            .unwrap_err();
+        assert!(
+            err.contains("- /path: 7 is not of type \"string\""),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("- /max_lines: \"3\" is not of type \"integer\""),
+            "{}",
+            err
+        );
+        let err = check_arguments("read", &file_schema(), "{path").unwrap_err();
+        assert!(err.contains("not valid JSON"), "{}", err);
+    }
+
+    /// A database with a `fruit` table of `(name, price)` rows.
+    fn fruit_db(rows: usize) -> String {
+        let path = scratch_dir("sqlite").join("shop.db");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        conn.execute_batch("CREATE TABLE fruit (name TEXT, price REAL)")
+            .unwrap();
+        for i in 0..rows {
+            conn.execute(
+                "INSERT INTO fruit VALUES (?, ?)",
+                rusqlite::params![format!("fruit{}", i), i as f64 + 0.5],
+            )
+            .unwrap();
+        }
+        path.to_string_lossy().into_owned()
+    }
+
+    async fn run_query(path: &str, sql: &str, extra: Value) -> Result<String> {
+        let mut args = json!({ "path": path, "query": sql });
+        args.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        SqliteQueryTool.execute(&args.to_string()).await
+    }
+
+    #[tokio::test]
+    async fn sqlite_query_returns_headers_and_rows() {
+        let db = fruit_db(2);
+        let out = run_query(
+            &db,
+            "SELECT name, price FROM fruit ORDER BY name",
+            json!({}),
+        )
+        .await
+        .unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines[0], "name    price");
+        assert_eq!(lines[2..], ["fruit0  0.5", "fruit1  1.5", "(2 rows)"]);
+
+        let out = run_query(
+            &db,
+            "SELECT name FROM fruit WHERE price > 1;",
+            json!({ "format": "json" }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(out, "[{\"name\":\"fruit1\"}]\n(1 rows)");
+    }
+
+    #[tokio::test]
+    async fn sqlite_query_stops_at_max_rows() {
+        let db = fruit_db(5);
+        let out = run_query(&db, "SELECT name FROM fruit", json!({ "max_rows": 3 }))
+            .await
+            .unwrap();
+        assert_eq!(out.lines().count(), 2 + 3 + 1, "{}", out);
+        assert!(
+            out.ends_with("[stopped at max_rows=3; more rows exist]"),
+            "{}",
+            out
+        );
+    }
+
+    #[tokio::test]
+    async fn sqlite_query_refuses_anything_that_writes() {
+        let db = fruit_db(1);
+        let other = scratch_dir("sqlite").join("other.db");
+        let attach = format!("ATTACH DATABASE '{}' AS other", other.display());
+        for (sql, refusal) in [
+            ("INSERT INTO fruit VALUES ('kiwi', 1)", "only SELECT"),
+            ("DROP TABLE fruit", "only SELECT"),
+            (attach.as_str(), "only SELECT"),
+            ("SELECT 1; DROP TABLE fruit", "single statement"),
+            ("PRAGMA user_version = 3", "would modify"),
+        ] {
+            let err = run_query(&db, sql, json!({})).await.unwrap_err();
+            assert!(err.to_string().contains(refusal), "{}: {}", sql, err);
+        }
+        assert!(!other.exists());
+        let out = run_query(&db, "SELECT count(*) AS n FROM fruit", json!({}))
+            .await
+            .unwrap();
+        assert!(out.contains("\n1"), "{}", out);
+        let out = run_query(&db, "PRAGMA user_version", json!({}))
+            .await
+            .unwrap();
+        assert!(out.contains("\n0"), "{}", out);
+    }
+
+    /// A directory holding `a.txt` and `b.txt`, committed to a new git repo
+    /// when `git` is set.
+    fn patch_tree(git: bool) -> PathBuf {
+        let dir = scratch_dir("patch");
+        std::fs::write(dir.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        std::fs::write(dir.join("b.txt"), "alpha\nbeta\n").unwrap();
+        if git {
+            for args in [
+                &["init", "-q"][..],
+                &["add", "."],
+                &[
+                    "-c",
+                    "user.name=t",
+                    "-c",
+                    "user.email=t@t",
+                    "commit",
+                    "-qm",
+                    "base",
+                ],
+            ] {
+                let status = std::process::Command::new("git")
+                    .args(args)
+                    .current_dir(&dir)
+                    .status()
+                    .unwrap();
+                assert!(status.success(), "git {:?}", args);
+            }
+        }
+        dir
+    }
+
+    fn tree(dir: &Path) -> (String, String) {
+        (
+            std::fs::read_to_string(dir.join("a.txt")).unwrap(),
+            std::fs::read_to_string(dir.join("b.txt")).unwrap(),
+        )
+    }
+
+    const PATCH_A: &str = "--- a/a.txt\n+++ b/a.txt\n@@ -1,3 +1,3 @@\n one\n-two\n+TWO\n three\n";
+    /// Changes a `b.txt` line that isn't there
+    const PATCH_B_STALE: &str =
+        "--- a/b.txt\n+++ b/b.txt\n@@ -1,2 +1,2 @@\n alpha\n-gamma\n+GAMMA\n";
+
+    async fn git_apply(dir: &Path, patch: &str, check_only: bool) -> String {
+        let file = scratch_dir("patch-file").join("change.diff");
+        std::fs::write(&file, patch).unwrap();
+        git_apply_file(dir, &file, check_only).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn git_patches_apply_cleanly_or_check_without_writing() {
+        isolate_data_dir();
+        let dir = patch_tree(true);
+        let before = tree(&dir);
+
+        let out = git_apply(&dir, PATCH_A, true).await;
+        assert!(out.ends_with("check passed; nothing written"), "{}", out);
+        assert_eq!(tree(&dir), before);
+
+        let out = git_apply(&dir, PATCH_A, false).await;
+        assert!(out.starts_with("applied     a.txt"), "{}", out);
+        assert!(
+            out.contains("applied with git apply --3way (1 files backed up)"),
+            "{}",
+            out
+        );
+        assert_eq!(tree(&dir).0, "one\nTWO\nthree\n");
+    }
+
+    #[tokio::test]
+    async fn a_git_patch_that_does_not_apply_changes_nothing() {
+        isolate_data_dir();
+        let dir = patch_tree(true);
+        let before = tree(&dir);
+        let patch = format!("{}{}", PATCH_A, PATCH_B_STALE);
+
+        let out = git_apply(&dir, &patch, true).await;
+        assert!(out.ends_with("check failed; nothing written"), "{}", out);
+        assert!(out.contains("failed      b.txt"), "{}", out);
+        assert_eq!(tree(&dir), before);
+
+        let out = git_apply(&dir, &patch, false).await;
+        assert!(out.contains("failed      b.txt"), "{}", out);
+        assert!(out.contains("not applied a.txt"), "{}", out);
+        assert_eq!(tree(&dir), before);
+    }
+
+    #[tokio::test]
+    async fn without_git_a_patch_applies_all_or_nothing() {
+        isolate_data_dir();
+        let dir = patch_tree(false);
+        let before = tree(&dir);
+
+        let out = apply_unified_without_git(&dir, PATCH_A, true)
+            .await
+            .unwrap();
+        assert!(out.contains("check passed"), "{}", out);
+        assert_eq!(tree(&dir), before);
+
+        let patch = format!("{}{}", PATCH_A, PATCH_B_STALE);
+        let err = apply_unified_without_git(&dir, &patch, false)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("b.txt"), "{}", err);
+        assert_eq!(tree(&dir), before);
+
+        let out = apply_unified_without_git(&dir, PATCH_A, false)
+            .await
+            .unwrap();
+        assert!(out.contains("applied 1 file change(s)"), "{}", out);
+        assert_eq!(tree(&dir).0, "one\nTWO\nthree\n");
+    }
+
+    #[tokio::test]
+    async fn patch_envelopes_apply_check_and_refuse_stale_chunks() {
+        isolate_data_dir();
+        let dir = patch_tree(false);
+        let before = tree(&dir);
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        let update = |path: &Path, old: &str, new: &str| {
+            format!(
+                "*** Update File: {}\n@@\n-{}\n+{}\n",
+                path.display(),
+                old,
+                new
+            )
+        };
+        let envelope = |ops: &[String]| format!("*** Begin Patch\n{}*** End Patch\n", ops.concat());
+        let apply = |patch: String, check_only: bool| async move {
+            ApplyPatchTool
+                .execute(&json!({ "patch": patch, "check_only": check_only }).to_string())
+                .await
+        };
+
+        let clean = envelope(&[update(&a, "two", "TWO"), update(&b, "beta", "BETA")]);
+        let out = apply(clean.clone(), true).await.unwrap();
+        assert!(out.ends_with("check passed; nothing written"), "{}", out);
+        assert_eq!(tree(&dir), before);
+
+        let stale = envelope(&[update(&a, "two", "TWO"), update(&b, "gamma", "GAMMA")]);
+        assert!(apply(stale, false).await.is_err());
+        assert_eq!(tree(&dir), before);
+
+        apply(clean, false).await.unwrap();
+        assert_eq!(
+            tree(&dir),
+            ("one\nTWO\nthree\n".into(), "alpha\nBETA\n".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn git_tools_report_on_a_repository() {
+        isolate_data_dir();
+        let dir = patch_tree(true);
+        std::fs::write(dir.join("a.txt"), "one\n2\nthree\n").unwrap();
+        std::fs::write(dir.join("c.txt"), "new\n").unwrap();
+        std::fs::write(dir.join("d.txt"), "scratch\n").unwrap();
+        let staged = std::process::Command::new("git")
+            .args(["add", "c.txt"])
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(staged.success());
+
+        let status = git_status(&dir).await.unwrap();
+        assert!(status.starts_with("branch: "), "{}", status);
+        assert!(
+            status.ends_with(
+                "staged (1):\n  added c.txt\nunstaged (1):\n  modified a.txt\nuntracked (1):\n  d.txt\n"
+            ),
+            "{}",
+            status
+        );
+
+        let diff = git_diff(&dir, "{}").await.unwrap();
+        assert!(diff.contains("-two\n+2"), "{}", diff);
+        assert!(diff.contains("+++ b/c.txt"), "{}", diff);
+        let diff = git_diff(&dir, r#"{"path": "a.txt"}"#).await.unwrap();
+        assert!(!diff.contains("c.txt"), "{}", diff);
+        let diff = git_diff(&dir, r#"{"path": "b.txt"}"#).await.unwrap();
+        assert_eq!(diff, "no changes");
+
+        let log = git_log(&dir, "{}").await.unwrap();
+        assert_eq!(log.lines().count(), 1, "{}", log);
+        assert!(log.ends_with(" t  base"), "{}", log);
+    }
+
+    #[tokio::test]
+    async fn git_tools_outside_a_repository_say_so() {
+        let dir = scratch_dir("not-a-repo");
+        let expected = "not a git repository (current directory is outside a git work tree)";
+        assert_eq!(git_status(&dir).await.unwrap(), expected);
+        assert_eq!(git_log(&dir, "{}").await.unwrap(), expected);
+        assert_eq!(git_diff(&dir, "{}").await.unwrap(), expected);
+    }
+
+    /// Confines the file tools to a fresh directory until dropped.
+    struct Sandbox(PathBuf);
+
+    impl Sandbox {
+        fn new(name: &str) -> Self {
+            let root = std::fs::canonicalize(scratch_dir(name)).unwrap();
+            TEST_SANDBOX_ROOT.with(|r| *r.borrow_mut() = Some(root.clone()));
+            Sandbox(root)
+        }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            TEST_SANDBOX_ROOT.with(|r| r.borrow_mut().take());
+        }
+    }
+
+    async fn run(tool: &dyn ToolExecutor, args: Value) -> Result<String> {
+        tool.execute(&args.to_string()).await
+    }
+
+    fn assert_escape_refused(result: Result<String>) {
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("outside the sandbox root"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn create_directory_makes_parents_and_stays_in_the_sandbox() {
+        let sandbox = Sandbox::new("mkdir");
+        let out = run(&CreateDirectoryTool, json!({ "path": "a/b" }))
+            .await
+            .unwrap();
+        assert!(out.starts_with("created directory"), "{}", out);
+        assert!(sandbox.0.join("a/b").is_dir());
+
+        let out = run(&CreateDirectoryTool, json!({ "path": "a/b" }))
+            .await
+            .unwrap();
+        assert!(out.ends_with("already exists"), "{}", out);
+        std::fs::write(sandbox.0.join("file"), "").unwrap();
+        let err = run(&CreateDirectoryTool, json!({ "path": "file" }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("is not a directory"), "{}", err);
+        let err = run(
+            &CreateDirectoryTool,
+            json!({ "path": "x/y", "recursive": false }),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("recursive=true"), "{}", err);
+
+        assert_escape_refused(run(&CreateDirectoryTool, json!({ "path": "../escaped" })).await);
+        assert!(!sandbox.0.with_file_name("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn move_path_renames_without_clobbering_or_escaping() {
+        isolate_data_dir();
+        let sandbox = Sandbox::new("move");
+        std::fs::write(sandbox.0.join("a.txt"), "a").unwrap();
+        std::fs::write(sandbox.0.join("b.txt"), "b").unwrap();
+
+        let out = run(&MovePathTool, json!({ "from": "a.txt", "to": "sub/c.txt" }))
+            .await
+            .unwrap();
+        assert!(out.starts_with("moved"), "{}", out);
+        assert!(!sandbox.0.join("a.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(sandbox.0.join("sub/c.txt")).unwrap(),
+            "a"
+        );
+
+        let err = run(&MovePathTool, json!({ "from": "sub/c.txt", "to": "b.txt" }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("overwrite=true"), "{}", err);
+        assert_eq!(
+            std::fs::read_to_string(sandbox.0.join("b.txt")).unwrap(),
+            "b"
+        );
+        assert!(sandbox.0.join("sub/c.txt").exists());
+
+        let out = run(
+            &MovePathTool,
+            json!({ "from": "sub/c.txt", "to": "b.txt", "overwrite": true }),
+        )
+        .await
+        .unwrap();
+        assert!(out.contains("previous"), "{}", out);
+        assert_eq!(
+            std::fs::read_to_string(sandbox.0.join("b.txt")).unwrap(),
+            "a"
+        );
+
+        assert_escape_refused(
+            run(&MovePathTool, json!({ "from": "b.txt", "to": "../b.txt" })).await,
+        );
+        assert!(sandbox.0.join("b.txt").exists());
+        let outside = scratch_dir("move-outside").join("secret.txt");
+        std::fs::write(&outside, "secret").unwrap();
+        assert_escape_refused(
+            run(
+                &MovePathTool,
+                json!({ "from": outside, "to": "stolen.txt" }),
+            )
+            .await,
+        );
+        assert!(outside.exists());
+    }
+
+    #[tokio::test]
+    async fn copy_path_copies_without_clobbering_or_escaping() {
+        isolate_data_dir();
+        let sandbox = Sandbox::new("copy");
+        std::fs::create_dir(sandbox.0.join("dir")).unwrap();
+        std::fs::write(sandbox.0.join("dir/a.txt"), "a").unwrap();
+        std::fs::write(sandbox.0.join("b.txt"), "b").unwrap();
+
+        let out = run(&CopyPathTool, json!({ "from": "dir/a.txt", "to": "c.txt" }))
+            .await
+            .unwrap();
+        assert!(out.starts_with("copied"), "{}", out);
+        assert_eq!(
+            std::fs::read_to_string(sandbox.0.join("c.txt")).unwrap(),
+            "a"
+        );
+        assert!(sandbox.0.join("dir/a.txt").exists());
+
+        let err = run(&CopyPathTool, json!({ "from": "c.txt", "to": "b.txt" }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("overwrite=true"), "{}", err);
+        assert_eq!(
+            std::fs::read_to_string(sandbox.0.join("b.txt")).unwrap(),
+            "b"
+        );
+
+        let err = run(&CopyPathTool, json!({ "from": "dir", "to": "dir2" }))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("recursive=true"), "{}", err);
+        run(
+            &CopyPathTool,
+            json!({ "from": "dir", "to": "dir2", "recursive": true }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(sandbox.0.join("dir2/a.txt")).unwrap(),
+            "a"
+        );
+
+        assert_escape_refused(
+            run(&CopyPathTool, json!({ "from": "b.txt", "to": "../b.txt" })).await,
+        );
+        assert!(!sandbox.0.with_file_name("b.txt").exists());
+        assert_escape_refused(
+            run(
+                &CopyPathTool,
+                json!({ "from": "/etc/hostname", "to": "host" }),
+            )
+            .await,
+        );
+        assert!(!sandbox.0.join("host").exists());
+    }
+
+    fn http_ok(content_type: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            content_type,
+            body.len(),
+            body
+        )
+    }
+
+    const PAGE: &str =
+        "<html><head><title>Fruit guide</title><script>var tracking = 1;</script></head>\
+        <body><nav><a href=\"/\">Home</a></nav><main><h1>Apples</h1>\
+        <p>Apples are red or green.</p><p>See <a href=\"/about\">about us</a>.</p></main>\
+        <footer>Copyright</footer></body></html>";
+
+    async fn fetch(port: u16, path: &str, extra: Value) -> String {
+        let mut args = json!({ "url": format!("http://localhost:{}{}", port, path) });
+        args.as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        FetchPageTool.execute(&args.to_string()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn fetch_page_extracts_the_main_text() {
+        let port = serve(|path| match path {
+            "/long" => http_ok(
+                "text/html",
+                &format!("<html><body><p>{}</p></body></html>", "word ".repeat(500)),
+            ),
+            _ => http_ok("text/html; charset=utf-8", PAGE),
+        })
+        .await;
+
+        let out = fetch(port, "/page", json!({})).await;
+        assert!(
+            out.starts_with("status: 200 OK\ntitle: Fruit guide\n"),
+            "{}",
+            out
+        );
+        assert!(out.contains("Apples are red or green."), "{}", out);
+        assert!(
+            out.contains(&format!("[about us](http://localhost:{}/about)", port)),
+            "{}",
+            out
+        );
+        assert!(!out.contains("tracking"), "{}", out);
+
+        let out = fetch(port, "/page", json!({ "format": "text" })).await;
+        assert!(out.contains("about us"), "{}", out);
+        assert!(!out.contains("](http"), "{}", out);
+
+        let out = fetch(port, "/long", json!({ "max_bytes": 100 })).await;
+        assert!(out.contains("[... truncated"), "{}", out);
+        assert!(out.len() < 400, "{}", out);
+    }
+
+    #[tokio::test]
+    async fn fetch_page_passes_text_through_and_skips_binary() {
+        let port = serve(|path| match path {
+            "/data.json" => http_ok("application/json", r#"{"fruit": "apple"}"#),
+            _ => http_ok("image/png", "\u{89}PNG\r\n"),
+        })
+        .await;
+
+        let out = fetch(port, "/data.json", json!({})).await;
+        assert_eq!(
+            out,
+            "status: 200 OK\ncontent-type: application/json\n\n{\"fruit\": \"apple\"}"
+        );
+        let out = fetch(port, "/logo.png", json!({})).await;
+        assert_eq!(
+            out,
+            "status: 200 OK\nbinary content skipped (image/png, 7 bytes)"
+        );
+    }
+}
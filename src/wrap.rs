@@ -0,0 +1,250 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How streamed replies are wrapped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    /// Terminal width when stdout is a TTY, otherwise no wrapping
+    Auto,
+    Off,
+    Columns(usize),
+}
+
+static MODE: Mutex<WrapMode> = Mutex::new(WrapMode::Auto);
+
+pub fn set_mode(mode: WrapMode) {
+    *MODE.lock().unwrap() = mode;
+}
+
+pub fn mode() -> WrapMode {
+    *MODE.lock().unwrap()
+}
+
+/// Show a live `[N tok/s]` readout while replies stream (`--timings`).
+static LIVE_RATE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_live_rate(on: bool) {
+    LIVE_RATE.store(on, Ordering::Relaxed);
+}
+
+/// How often the readout is redrawn.
+const RATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Throughput of the reply being streamed, drawn just past the cursor on
+/// stderr and erased before more text is printed.
+struct LiveRate {
+    started: Instant,
+    chars: usize,
+    drawn: Option<Instant>,
+    visible: bool,
+}
+
+impl LiveRate {
+    fn new() -> Option<Self> {
+        let enabled = LIVE_RATE.load(Ordering::Relaxed)
+            && io::stdout().is_terminal()
+            && io::stderr().is_terminal();
+        enabled.then(|| Self {
+            started: Instant::now(),
+            chars: 0,
+            drawn: None,
+            visible: false,
+        })
+    }
+
+    fn clear(&mut self) {
+        if self.visible {
+            eprint!("\x1b[K");
+            self.visible = false;
+        }
+    }
+
+    /// Redraw after `col` if the interval passed and the readout fits on the line.
+    fn draw(&mut self, col: usize) {
+        let now = Instant::now();
+        if self
+            .drawn
+            .is_some_and(|at| now.duration_since(at) < RATE_INTERVAL)
+        {
+            return;
+        }
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        if elapsed < RATE_INTERVAL.as_secs_f64() {
+            return;
+        }
+        self.drawn = Some(now);
+        let tokens = self.chars.div_ceil(4) as f64;
+        let label = format!(" [{:.0} tok/s]", tokens / elapsed);
+        let columns = terminal_size::terminal_size().map_or(80, |(w, _)| w.0 as usize);
+        self.clear();
+        if col + label.len() < columns {
+            // Save the cursor, draw dimmed, restore so the reply continues in place
+            eprint!("\x1b7\x1b[2m{}\x1b[0m\x1b8", label);
+            self.visible = true;
+        }
+    }
+}
+
+/// File that streamed text is copied to as it arrives (`chat --output`).
+static TEE: Mutex<Option<Tee>> = Mutex::new(None);
+
+struct Tee {
+    file: std::fs::File,
+    wrote: bool,
+}
+
+/// Start copying streamed text to `file`.
+pub fn set_tee(file: std::fs::File) {
+    *TEE.lock().unwrap() = Some(Tee { file, wrote: false });
+}
+
+/// Stop copying; returns the file and whether any text reached it.
+pub fn take_tee() -> Option<(std::fs::File, bool)> {
+    TEE.lock().unwrap().take().map(|t| (t.file, t.wrote))
+}
+
+fn tee(text: &str) -> io::Result<()> {
+    if let Some(tee) = TEE.lock().unwrap().as_mut() {
+        tee.file.write_all(text.as_bytes())?;
+        tee.wrote = true;
+    }
+    Ok(())
+}
+
+/// Parse `auto`, `off`/`0`, or a column count.
+pub fn parse_mode(s: &str) -> Option<WrapMode> {
+    match s.trim().to_lowercase().as_str() {
+        "auto" => Some(WrapMode::Auto),
+        "off" | "0" => Some(WrapMode::Off),
+        n => n.parse().ok().map(WrapMode::Columns),
+    }
+}
+
+fn width() -> Option<usize> {
+    match mode() {
+        WrapMode::Off => None,
+        WrapMode::Columns(n) => Some(n),
+        WrapMode::Auto => {
+            if !io::stdout().is_terminal() {
+                return None;
+            }
+            terminal_size::terminal_size().map(|(w, _)| w.0 as usize)
+        }
+    }
+}
+
+/// Prints streamed text, holding back partial words so lines break between
+/// words. `col` starts at `start_col` to account for a label like "Rusty: ".
+pub struct StreamPrinter {
+    width: Option<usize>,
+    col: usize,
+    word: String,
+    space: bool,
+    rate: Option<LiveRate>,
+}
+
+impl StreamPrinter {
+    pub fn new(start_col: usize) -> Self {
+        Self {
+            width: width(),
+            col: start_col,
+            word: String::new(),
+            space: false,
+            rate: LiveRate::new(),
+        }
+    }
+
+    pub fn push(&mut self, text: &str) -> io::Result<()> {
+        tee(text)?;
+        if let Some(rate) = self.rate.as_mut() {
+            rate.clear();
+            rate.chars += text.chars().count();
+        }
+        self.print(text)?;
+        if let Some(rate) = self.rate.as_mut() {
+            rate.draw(self.col + visible_len(&self.word));
+        }
+        Ok(())
+    }
+
+    fn print(&mut self, text: &str) -> io::Result<()> {
+        let Some(width) = self.width else {
+            print!("{text}");
+            // Track the column only for placing the rate readout
+            self.col = match text.rfind('\n') {
+                Some(i) => visible_len(&text[i + 1..]),
+                None => self.col + visible_len(text),
+            };
+            return io::stdout().flush();
+        };
+        for ch in text.chars() {
+            match ch {
+                '\n' => {
+                    self.flush_word(width);
+                    println!();
+                    self.col = 0;
+                    self.space = false;
+                }
+                ' ' | '\t' => {
+                    self.flush_word(width);
+                    self.space = true;
+                }
+                _ => self.word.push(ch),
+            }
+        }
+        io::stdout().flush()
+    }
+
+    /// Emit whatever is still buffered.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(rate) = self.rate.as_mut() {
+            rate.clear();
+        }
+        if let Some(width) = self.width {
+            self.flush_word(width);
+        }
+        io::stdout().flush()
+    }
+
+    fn flush_word(&mut self, width: usize) {
+        if self.word.is_empty() {
+            return;
+        }
+        let len = visible_len(&self.word);
+        let gap = usize::from(self.space && self.col > 0);
+        if self.col > 0 && self.col + gap + len > width {
+            println!();
+            self.col = 0;
+        } else if gap == 1 {
+            print!(" ");
+            self.col += 1;
+        }
+        print!("{}", self.word);
+        self.col += len;
+        self.word.clear();
+        self.space = false;
+    }
+}
+
+/// Character count ignoring ANSI escape sequences.
+fn visible_len(s: &str) -> usize {
+    let mut len = 0;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI: ESC [ params final-byte
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        len += 1;
+    }
+    len
+}